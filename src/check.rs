@@ -0,0 +1,85 @@
+use std::error::Error;
+
+use defibrillator::{
+    duration::Duration as ParsableDuration,
+    rules::{client_builder, OrRules, ProbeConfig, ServerEvents, Unsatisfiable},
+};
+use structopt::StructOpt;
+use tokio::time::timeout;
+use tracing::{event, Level};
+
+/// Evaluate a set of rules against the current system, without running
+/// anything. Exits with 0 if they're satisfied, or 1 if they aren't.
+#[derive(StructOpt)]
+#[structopt(name = "defibrillator check")]
+pub struct CheckArgs {
+    /// The rules to evaluate. Since there's no server, `matches` and `notify`
+    /// rules are never satisfied.
+    rules: OrRules,
+
+    /// How long to wait for the rules to be satisfied. By default, they're
+    /// only given a single --poll-interval, so each probe is tried about once.
+    #[structopt(short = "t", long)]
+    timeout: Option<ParsableDuration>,
+
+    /// How often to poll rules like `tcp` and `http` that don't specify their
+    /// own interval with `every`
+    #[structopt(long, default_value = "1s")]
+    poll_interval: ParsableDuration,
+
+    /// How long a single probe, like an HTTP request, a connection attempt, or
+    /// a `cmd` command, may take before it's considered a failure, for rules
+    /// that don't specify their own with `timeout`
+    #[structopt(long, default_value = "10s")]
+    probe_timeout: ParsableDuration,
+
+    /// Send probes to loopback addresses through the proxy set in the
+    /// environment (`HTTP_PROXY` and friends), rather than directly. Rules
+    /// with their own TLS settings (`insecure` or `ca`) always send them
+    /// directly.
+    #[structopt(long)]
+    probe_proxy: bool,
+
+    /// Filter directives to pass to the logger
+    #[structopt(short, long, default_value = "info")]
+    pub log_filters: String,
+}
+
+/// Evaluate the rules, returning an exit code for defibrillator
+pub async fn check(args: CheckArgs) -> i32 {
+    let client = match client_builder(args.probe_proxy).build() {
+        Ok(client) => client,
+        Err(err) => {
+            let err: &dyn Error = &err;
+            event!(Level::ERROR, error = err, "Failed to create an HTTP client");
+            return 1;
+        }
+    };
+
+    let probes = ProbeConfig {
+        client: &client,
+        poll_interval: args.poll_interval.get(),
+        timeout: args.probe_timeout.get(),
+    };
+
+    // There's no process, so there are no events for `matches` or `notify`
+    // rules
+    let events = ServerEvents::new(1);
+    let rules = args.rules.build(probes, &events).wait();
+    let limit = args.timeout.unwrap_or(args.poll_interval).get();
+
+    match timeout(limit, rules).await {
+        Ok(Ok(satisfied)) => {
+            event!(Level::INFO, group = satisfied.group, "rules are satisfied");
+            0
+        }
+        Ok(Err(Unsatisfiable)) => {
+            event!(Level::ERROR, "rules can no longer be satisfied");
+            1
+        }
+        Err(..) => {
+            event!(Level::ERROR, "timed out waiting for rules");
+            1
+        }
+    }
+}
@@ -0,0 +1,317 @@
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+use std::{
+    error::Error,
+    fs, io,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use defibrillator::rules::PendingRules;
+#[cfg(windows)]
+use defibrillator::windows;
+use futures::future::pending;
+use serde_json::json;
+#[cfg(unix)]
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    signal::unix::Signal,
+    time::sleep,
+};
+use tokio::{
+    sync::{mpsc, watch},
+    time::Instant,
+};
+use tracing::warn;
+#[cfg(unix)]
+use tracing::{debug, info};
+
+#[cfg(unix)]
+use crate::event_log::{EventLog, Transition};
+use crate::metrics::State;
+
+/// A command sent to the supervision loop through the control socket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Gracefully stop the server and launch a new instance. Nothing sends
+    /// this on Windows, which has no control socket, --watch, or SIGHUP.
+    #[cfg_attr(windows, allow(dead_code))]
+    Restart,
+
+    /// Gracefully stop the server, then exit
+    Stop,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct StatusInner {
+    state: State,
+    attempt: u64,
+    pid: Option<u32>,
+    started: Option<Instant>,
+    ready: Option<Instant>,
+}
+
+/// The current state of the supervised server, as reported by the `status`
+/// command
+#[derive(Debug)]
+pub struct Status {
+    inner: Mutex<StatusInner>,
+
+    /// The rules that haven't been satisfied yet, while the server is
+    /// starting
+    pending: PendingRules,
+
+    /// Updated with each change of state, so that the --proxy can wait for
+    /// the server to be ready
+    changes: watch::Sender<State>,
+}
+
+impl Status {
+    pub fn new() -> Self {
+        Self {
+            inner: Mutex::new(StatusInner {
+                state: State::Stopped,
+                attempt: 0,
+                pid: None,
+                started: None,
+                ready: None,
+            }),
+            pending: PendingRules::default(),
+            changes: watch::Sender::new(State::Stopped),
+        }
+    }
+
+    /// Where each attempt's readiness rules are listed until they're
+    /// satisfied
+    pub fn pending(&self) -> &PendingRules {
+        &self.pending
+    }
+
+    /// Record that a new instance of the server was launched
+    pub fn starting(&self, attempt: u64, pid: Option<u32>) {
+        *self.inner.lock().unwrap() = StatusInner {
+            state: State::Starting,
+            attempt,
+            pid,
+            started: Some(Instant::now()),
+            ready: None,
+        };
+
+        self.changes.send_replace(State::Starting);
+    }
+
+    /// The process ID of the server, if it's running
+    #[cfg(unix)]
+    pub fn pid(&self) -> Option<u32> {
+        self.inner.lock().unwrap().pid
+    }
+
+    pub fn ready(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.state = State::Ready;
+        inner.ready = Some(Instant::now());
+        self.changes.send_replace(State::Ready);
+    }
+
+    /// Wait until the server is ready, returning immediately if it already is
+    pub async fn wait_until_ready(&self) {
+        // The sender is never dropped while `self` is alive
+        let _ = self
+            .changes
+            .subscribe()
+            .wait_for(|state| *state == State::Ready)
+            .await;
+    }
+
+    pub fn is_ready(&self) -> bool {
+        *self.changes.borrow() == State::Ready
+    }
+
+    /// How long the server has been ready, if it is
+    pub fn ready_for(&self) -> Option<Duration> {
+        self.inner
+            .lock()
+            .unwrap()
+            .ready
+            .map(|ready| ready.elapsed())
+    }
+
+    pub fn stopped(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.state = State::Stopped;
+        inner.pid = None;
+        inner.started = None;
+        inner.ready = None;
+        self.changes.send_replace(State::Stopped);
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let inner = *self.inner.lock().unwrap();
+
+        // Liveness rules are listed too, once the server is ready, but
+        // they're not what this is for
+        let pending = match inner.state {
+            State::Starting => self.pending.list(),
+            _ => Vec::new(),
+        };
+
+        json!({
+            "state": inner.state.as_str(),
+            "attempt": inner.attempt,
+            "pid": inner.pid,
+            "uptime": inner.started.map(|started| started.elapsed().as_secs_f64()),
+            "ready_for": inner.ready.map(|ready| ready.elapsed().as_secs_f64()),
+            "pending": pending,
+        })
+    }
+
+    /// Log the status each time defibrillator receives a signal (SIGUSR1),
+    /// and record it to the --events stream, if there is one
+    #[cfg(unix)]
+    pub async fn report_on_signal(
+        self: Arc<Self>,
+        mut signals: Signal,
+        event_log: Option<Arc<EventLog>>,
+    ) {
+        while signals.recv().await.is_some() {
+            let status = self.to_json();
+            info!(%status, "status report");
+
+            if let Some(ref event_log) = event_log {
+                let attempt = self.inner.lock().unwrap().attempt;
+                event_log.record(attempt, Transition::Status(status));
+            }
+        }
+    }
+}
+
+/// Bind the control socket at `path`, replacing any stale socket left behind
+/// by a previous run
+#[cfg(unix)]
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+        _ => {}
+    }
+
+    UnixListener::bind(path)
+}
+
+/// Remove the control socket at `path`, before defibrillator exits
+pub fn unbind(path: &Path) {
+    match fs::remove_file(path) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => {
+            let err: &dyn Error = &err;
+            warn!(error = err, path = %path.display(), "failed to remove the control socket");
+        }
+    }
+}
+
+/// Accept connections on the control socket forever. Each connection sends
+/// newline-separated commands (`status`, `restart`, or `stop`), and receives
+/// one line in response to each.
+#[cfg(unix)]
+pub async fn serve(listener: UnixListener, status: Arc<Status>, commands: mpsc::Sender<Command>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                {
+                    let err: &dyn Error = &err;
+                    warn!(error = err, "failed to accept control connection");
+                }
+
+                // Errors like EMFILE persist until some connection closes, so
+                // back off rather than spinning on them
+                sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let status = Arc::clone(&status);
+        let commands = commands.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_connection(stream, &status, &commands).await {
+                let err: &dyn Error = &err;
+                debug!(error = err, "control connection failed");
+            }
+        });
+    }
+}
+
+#[cfg(unix)]
+async fn handle_connection(
+    stream: UnixStream,
+    status: &Status,
+    commands: &mpsc::Sender<Command>,
+) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let response = match line.trim() {
+            "status" => status.to_json(),
+            "restart" => send_command(commands, Command::Restart).await,
+            "stop" => send_command(commands, Command::Stop).await,
+            other => json!({ "error": format!("unknown command: {:?}", other) }),
+        };
+
+        writer
+            .write_all(format!("{}\n", response).as_bytes())
+            .await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn send_command(commands: &mpsc::Sender<Command>, command: Command) -> serde_json::Value {
+    info!(?command, "received control command");
+
+    match commands.send(command).await {
+        Ok(()) => json!({ "ok": true }),
+        Err(..) => json!({ "error": "defibrillator is shutting down" }),
+    }
+}
+
+/// The control socket is a Unix socket, which Windows doesn't have
+#[cfg(windows)]
+#[derive(Debug)]
+pub enum Listener {}
+
+#[cfg(windows)]
+pub fn bind(_path: &Path) -> io::Result<Listener> {
+    Err(windows::unsupported("--control-socket"))
+}
+
+#[cfg(windows)]
+pub async fn serve(listener: Listener, _status: Arc<Status>, _commands: mpsc::Sender<Command>) {
+    match listener {}
+}
+
+/// The receiving end of the control command channel
+#[derive(Debug)]
+pub struct Commands {
+    receiver: mpsc::Receiver<Command>,
+}
+
+impl Commands {
+    /// Create a new command channel
+    pub fn channel() -> (mpsc::Sender<Command>, Self) {
+        let (sender, receiver) = mpsc::channel(8);
+        (sender, Self { receiver })
+    }
+
+    /// Wait for the next command. If every sender has been dropped, this
+    /// never resolves.
+    pub async fn recv(&mut self) -> Command {
+        match self.receiver.recv().await {
+            Some(command) => command,
+            None => pending().await,
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use std::collections::VecDeque;
+
+use defibrillator::output::{LogLine, Stream};
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use tracing::{error, warn};
+
+/// The last few lines of the server's output, kept so that they can be shown
+/// if it fails to start
+#[derive(Debug)]
+pub struct RecentLines {
+    lines: VecDeque<LogLine>,
+    capacity: usize,
+}
+
+impl RecentLines {
+    /// Keep the last `capacity` lines from `lines`, until the channel is
+    /// closed
+    pub async fn collect(capacity: usize, mut lines: Receiver<LogLine>) -> Self {
+        let mut recent = Self {
+            lines: VecDeque::with_capacity(capacity),
+            capacity,
+        };
+
+        loop {
+            match lines.recv().await {
+                Ok(line) => recent.push(line),
+                Err(RecvError::Lagged(lines)) => warn!(missed = lines, "crash context lagged"),
+                Err(RecvError::Closed) => return recent,
+            }
+        }
+    }
+
+    fn push(&mut self, line: LogLine) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.lines.len() == self.capacity {
+            self.lines.pop_front();
+        }
+
+        self.lines.push_back(line);
+    }
+
+    /// The lines, as they were written, one after another
+    pub fn contents(&self) -> Vec<u8> {
+        let mut contents = Vec::new();
+
+        for line in &self.lines {
+            contents.extend_from_slice(&line.content);
+
+            // The last line might have been cut off by the server exiting
+            if !line.content.ends_with(b"\n") {
+                contents.push(b'\n');
+            }
+        }
+
+        contents
+    }
+
+    /// Log the lines, between delimiters so that they stand out from the
+    /// rest of the output
+    pub fn dump(&self) {
+        error!(
+            lines = self.lines.len(),
+            "----- last output before the server failed -----"
+        );
+
+        for line in &self.lines {
+            let stream = match line.stream {
+                Stream::Stdout => "stdout",
+                Stream::Stderr => "stderr",
+            };
+
+            let content = String::from_utf8_lossy(&line.content);
+            error!(stream, "| {}", content.trim_end_matches(&['\r', '\n'][..]));
+        }
+
+        error!("----- end of output -----");
+    }
+}
@@ -1,9 +1,10 @@
-use std::{str::FromStr, time::Duration as StdDuration};
+use std::{fmt, str::FromStr, time::Duration as StdDuration};
 
 use nom::{
     branch::alt,
-    character::complete::{digit0, space0},
+    character::complete::{char, digit1, space0},
     error::ParseError,
+    multi::many0,
     IResult, Parser,
 };
 use nom_supreme::{
@@ -46,18 +47,63 @@ fn parse_duration_suffix(input: &str) -> IResult<&str, StdDuration, ErrorTree<&s
     alt((
         three_tags("s", "second", "seconds").value(StdDuration::from_secs(1)),
         three_tags("ms", "millisecond", "milliseconds").value(StdDuration::from_millis(1)),
-        three_tags("μs", "microsecond", "microseconds").value(StdDuration::from_micros(1)),
+        three_tags("us", "microsecond", "microseconds").value(StdDuration::from_micros(1)),
+        // Both the Greek letter mu and the micro sign are accepted
+        alt((tag_no_case("\u{03BC}s"), tag_no_case("\u{00B5}s")))
+            .value(StdDuration::from_micros(1)),
+        three_tags("ns", "nanosecond", "nanoseconds").value(StdDuration::from_nanos(1)),
         three_tags("m", "minute", "minutes").value(StdDuration::from_secs(60)),
+        three_tags("h", "hour", "hours").value(StdDuration::from_secs(60 * 60)),
+        three_tags("d", "day", "days").value(StdDuration::from_secs(24 * 60 * 60)),
     ))
     .parse(input)
 }
 
-pub fn parse_duration(input: &str) -> IResult<&str, StdDuration, ErrorTree<&str>> {
-    digit0
+#[derive(Debug, Clone, Copy)]
+struct DurationOverflow;
+
+impl fmt::Display for DurationOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "duration is too large")
+    }
+}
+
+impl std::error::Error for DurationOverflow {}
+
+/// Parse a single count, which may be fractional, followed by its units
+fn parse_duration_term(input: &str) -> IResult<&str, StdDuration, ErrorTree<&str>> {
+    digit1
         .parse_from_str()
+        .and(char('.').precedes(digit1).recognize().opt())
         .terminated(space0)
         .and(parse_duration_suffix.context("duration suffix"))
-        .map(|(count, units): (u32, StdDuration)| units * count)
+        .map_res(
+            |((whole, fraction), units): ((u32, Option<&str>), StdDuration)| {
+                let fraction = match fraction {
+                    // Unwrap safety: ".<digits>" is always a valid float
+                    Some(fraction) => units.mul_f64(fraction.parse().unwrap()),
+                    None => StdDuration::ZERO,
+                };
+
+                units
+                    .checked_mul(whole)
+                    .and_then(|whole| whole.checked_add(fraction))
+                    .ok_or(DurationOverflow)
+            },
+        )
+        .parse(input)
+}
+
+/// Parse a duration, which is one or more counts with units, like `1m30s`
+/// or `1.5 seconds`. The terms are added together.
+pub fn parse_duration(input: &str) -> IResult<&str, StdDuration, ErrorTree<&str>> {
+    parse_duration_term
+        .and(many0(parse_duration_term.preceded_by(space0)))
+        .map_res(|(first, rest)| {
+            rest.into_iter()
+                .try_fold(first, |total, term| total.checked_add(term))
+                .ok_or(DurationOverflow)
+        })
         .parse(input)
 }
 
@@ -0,0 +1,69 @@
+use std::{fmt, fs, io, path::Path, str::FromStr};
+
+/// A `KEY=VALUE` environment variable assignment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvVar {
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct InvalidEnvVar(String);
+
+impl fmt::Display for InvalidEnvVar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid assignment {:?}; expected KEY=VALUE", self.0)
+    }
+}
+
+impl std::error::Error for InvalidEnvVar {}
+
+impl FromStr for EnvVar {
+    type Err = InvalidEnvVar;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((key, value)) if !key.is_empty() => Ok(EnvVar {
+                key: key.to_owned(),
+                value: value.to_owned(),
+            }),
+            _ => Err(InvalidEnvVar(s.to_owned())),
+        }
+    }
+}
+
+/// Remove a single layer of matching quotes from a value, as written in an
+/// env file
+fn unquote(value: &str) -> &str {
+    for &quote in ['"', '\''].iter() {
+        if let Some(inner) = value
+            .strip_prefix(quote)
+            .and_then(|value| value.strip_suffix(quote))
+        {
+            return inner;
+        }
+    }
+
+    value
+}
+
+/// Read the assignments from an env file. Each non-empty line that isn't a
+/// `#` comment is a `KEY=VALUE` assignment, optionally preceded by `export`;
+/// values may be wrapped in quotes.
+pub fn read_env_file(path: &Path) -> io::Result<Vec<EnvVar>> {
+    fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let line = line.strip_prefix("export ").unwrap_or(line);
+
+            line.parse()
+                .map(|var: EnvVar| EnvVar {
+                    key: var.key.trim_end().to_owned(),
+                    value: unquote(var.value.trim_start()).to_owned(),
+                })
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+        })
+        .collect()
+}
@@ -0,0 +1,160 @@
+use std::{
+    convert::Infallible,
+    error::Error,
+    fmt,
+    fs::OpenOptions,
+    io::{self, Write},
+    path::PathBuf,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use libc::c_int;
+use serde_json::{json, Value};
+use tracing::warn;
+
+use crate::ready_fd::{inherit_fd, RawFd};
+use crate::signals::signal_name;
+
+/// Where to write the event stream: `-` for stdout, a number for an
+/// inherited file descriptor, or otherwise a path, which is appended to.
+#[derive(Debug, Clone)]
+pub enum EventTarget {
+    Stdout,
+    Fd(RawFd),
+    Path(PathBuf),
+}
+
+impl FromStr for EventTarget {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "-" => EventTarget::Stdout,
+            _ => match s.parse() {
+                Ok(fd) => EventTarget::Fd(fd),
+                Err(..) => EventTarget::Path(s.into()),
+            },
+        })
+    }
+}
+
+/// A change in the state of the server, as reported by --events
+#[derive(Debug, Clone)]
+pub enum Transition {
+    Spawned {
+        pid: Option<u32>,
+    },
+
+    /// The rules in the `group`th OR branch were satisfied, each this long
+    /// after the server was spawned
+    Ready {
+        group: usize,
+        rules: Vec<(String, Duration)>,
+    },
+    Exited {
+        exit_code: i32,
+
+        /// The signal that killed the server, if it was killed by one
+        signal: Option<c_int>,
+    },
+    TimedOut,
+    Restarting,
+    GaveUp {
+        exit_code: i32,
+    },
+
+    /// A snapshot of the server's status, in the format of the control
+    /// socket's `status` command, recorded on SIGUSR1
+    #[cfg(unix)]
+    Status(Value),
+}
+
+impl Transition {
+    fn to_json(&self) -> Value {
+        match *self {
+            Transition::Spawned { pid } => json!({ "event": "spawned", "pid": pid }),
+            Transition::Ready { group, ref rules } => json!({
+                "event": "ready",
+                "group": group,
+                "rules": rules
+                    .iter()
+                    .map(|(rule, elapsed)| json!({
+                        "rule": rule,
+                        "elapsed": elapsed.as_secs_f64(),
+                    }))
+                    .collect::<Vec<_>>(),
+            }),
+            Transition::Exited { exit_code, signal } => json!({
+                "event": "exited",
+                "exit_code": exit_code,
+                "signal": signal.map(signal_name),
+            }),
+            Transition::TimedOut => json!({ "event": "timed-out" }),
+            Transition::Restarting => json!({ "event": "restarting" }),
+            Transition::GaveUp { exit_code } => {
+                json!({ "event": "gave-up", "exit_code": exit_code })
+            }
+            #[cfg(unix)]
+            Transition::Status(ref status) => {
+                let mut record = status.clone();
+                record["event"] = json!("status");
+                record
+            }
+        }
+    }
+}
+
+/// A stream of newline-delimited JSON records, one for each state transition
+pub struct EventLog {
+    output: Mutex<Box<dyn Write + Send>>,
+}
+
+impl fmt::Debug for EventLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventLog").finish_non_exhaustive()
+    }
+}
+
+impl EventLog {
+    pub fn open(target: &EventTarget) -> io::Result<Self> {
+        let output: Box<dyn Write + Send> = match *target {
+            EventTarget::Stdout | EventTarget::Fd(1) => Box::new(io::stdout()),
+            EventTarget::Fd(2) => Box::new(io::stderr()),
+            EventTarget::Fd(fd) => Box::new(inherit_fd(fd)?),
+            EventTarget::Path(ref path) => {
+                Box::new(OpenOptions::new().create(true).append(true).open(path)?)
+            }
+        };
+
+        Ok(Self {
+            output: Mutex::new(output),
+        })
+    }
+
+    /// Write a record of a transition. Failures are logged, but otherwise
+    /// ignored.
+    pub fn record(&self, attempt: u64, transition: Transition) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs_f64())
+            .unwrap_or(0.0);
+
+        let mut record = transition.to_json();
+        record["timestamp"] = json!(timestamp);
+        record["attempt"] = json!(attempt);
+
+        let mut line = record.to_string();
+        line.push('\n');
+
+        let mut output = self.output.lock().unwrap();
+        if let Err(err) = output
+            .write_all(line.as_bytes())
+            .and_then(|()| output.flush())
+        {
+            let err: &dyn Error = &err;
+            warn!(error = err, "failed to write to the event stream");
+        }
+    }
+}
@@ -0,0 +1,29 @@
+use std::sync::Arc;
+
+use tokio::net::TcpListener;
+
+use crate::control::Status;
+use crate::http_server::{self, Response};
+
+/// Serve the server's health over HTTP on `listener` forever, for load
+/// balancers and orchestrators to probe. This answers `GET /ready` and
+/// `GET /status`.
+pub async fn serve(listener: TcpListener, status: Arc<Status>) {
+    http_server::serve(listener, "health", move |path| match path {
+        b"/ready" => Some(match status.is_ready() {
+            true => Response::new("200 OK", "text/plain", "ready\n".to_owned()),
+            false => Response::new(
+                "503 Service Unavailable",
+                "text/plain",
+                "not ready\n".to_owned(),
+            ),
+        }),
+        b"/status" => Some(Response::new(
+            "200 OK",
+            "application/json",
+            status.to_json().to_string() + "\n",
+        )),
+        _ => None,
+    })
+    .await
+}
@@ -0,0 +1,286 @@
+use std::{error::Error, process::Stdio, time::Duration};
+
+use futures::future::join;
+use reqwest::{header::CONTENT_TYPE, Client, StatusCode, Url};
+use serde_json::{json, Value};
+use tokio::{io::AsyncWriteExt, time::sleep};
+use tracing::{debug, warn};
+
+use crate::init::spawn_tracked;
+use crate::shell;
+use crate::template::TemplateVars;
+
+/// A point in the server's lifecycle at which hooks are run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Ready,
+    Exit,
+    Restart,
+    Failure,
+
+    /// The server exited without being asked to
+    Crash,
+
+    /// defibrillator stopped restarting the server, and is about to exit
+    GiveUp,
+}
+
+impl Event {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Event::Ready => "ready",
+            Event::Exit => "exit",
+            Event::Restart => "restart",
+            Event::Failure => "failure",
+            Event::Crash => "crash",
+            Event::GiveUp => "give-up",
+        }
+    }
+}
+
+/// A description of the attempt that triggered a hook. This is passed to hook
+/// commands as `DEFIBRILLATOR_*` environment variables, and to hook URLs as a
+/// JSON payload.
+#[derive(Debug, Clone, Copy)]
+pub struct HookContext {
+    pub event: Event,
+
+    /// The attempt number, counted since the server was last ready. For
+    /// restart hooks, this is the attempt that's about to be made.
+    pub attempt: u64,
+
+    /// The server's process ID, if it's still running
+    pub pid: Option<u32>,
+
+    /// The exit code of the server, if it's exited
+    pub exit_code: Option<i32>,
+}
+
+/// A shell command and/or URL to notify when an event happens
+#[derive(Debug, Clone, Default)]
+pub struct Hook {
+    pub command: Option<String>,
+    pub url: Option<Url>,
+}
+
+impl Hook {
+    /// Run the hook command and POST to the hook URL, waiting for both to
+    /// finish. Failures are logged, but otherwise ignored.
+    #[tracing::instrument(name = "hook", skip(self, client), fields(event = context.event.as_str()))]
+    pub async fn run(&self, client: &Client, context: HookContext) {
+        join(
+            async {
+                if let Some(ref command) = self.command {
+                    run_command(command, &context, None).await
+                }
+            },
+            async {
+                if let Some(ref url) = self.url {
+                    post(client, url, &context).await
+                }
+            },
+        )
+        .await;
+    }
+}
+
+/// Run the --on-failure command for an attempt that exited or timed out
+/// before becoming ready. `output` is written to its stdin: the last lines of
+/// the server's output, if --crash-context is enabled.
+#[tracing::instrument(name = "hook", skip(command, output), fields(event = context.event.as_str()))]
+pub async fn run_failure_command(command: &str, context: HookContext, output: &[u8]) {
+    run_command(command, &context, Some(output)).await
+}
+
+/// The hooks for every lifecycle event
+#[derive(Debug, Clone, Default)]
+pub struct Hooks {
+    pub on_ready: Hook,
+    pub on_exit: Hook,
+    pub on_restart: Hook,
+}
+
+async fn run_command(command: &str, context: &HookContext, input: Option<&[u8]>) {
+    debug!(command, "running hook command");
+
+    let mut builder = shell(command);
+
+    builder
+        .env("DEFIBRILLATOR_EVENT", context.event.as_str())
+        .env("DEFIBRILLATOR_ATTEMPT", context.attempt.to_string())
+        .stdin(match input {
+            Some(..) => Stdio::piped(),
+            None => Stdio::inherit(),
+        })
+        .kill_on_drop(true);
+
+    if let Some(pid) = context.pid {
+        builder.env("DEFIBRILLATOR_PID", pid.to_string());
+    }
+
+    if let Some(exit_code) = context.exit_code {
+        builder.env("DEFIBRILLATOR_EXIT_CODE", exit_code.to_string());
+    }
+
+    let status = match spawn_tracked(&mut builder) {
+        Ok((mut child, _tracked)) => {
+            // The command doesn't have to read its input, so a closed pipe
+            // isn't an error
+            if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+                let _ = stdin.write_all(input).await;
+            }
+
+            child.wait().await
+        }
+        Err(err) => Err(err),
+    };
+
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => warn!(%status, "hook command failed"),
+        Err(err) => {
+            let err: &dyn Error = &err;
+            warn!(error = err, "hook command failed to spawn")
+        }
+    }
+}
+
+async fn post(client: &Client, url: &Url, context: &HookContext) {
+    debug!(%url, "sending hook request");
+
+    let payload = json!({
+        "event": context.event.as_str(),
+        "attempt": context.attempt,
+        "pid": context.pid,
+        "exit_code": context.exit_code,
+    });
+
+    let result = client
+        .post(url.clone())
+        .json(&payload)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+
+    if let Err(err) = result {
+        let err: &dyn Error = &err;
+        warn!(error = err, "hook request failed")
+    }
+}
+
+/// A webhook for --notify-url, which is sent when the server becomes ready,
+/// crashes, or is given up on
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    pub url: Url,
+
+    /// The request body, with `${event}`, `${attempt}`, `${pid}`,
+    /// `${exit_code}`, `${command}`, and `${message}` replaced. If it's not
+    /// given, the body is a JSON object with each of these.
+    pub template: Option<String>,
+
+    /// The server's command, for the message
+    pub command: String,
+
+    /// How long to wait for each request
+    pub timeout: Duration,
+
+    /// How many more times to send a request that failed
+    pub retries: u32,
+}
+
+impl Webhook {
+    /// A one-line description of the event, for chat messages
+    fn message(&self, context: &HookContext) -> String {
+        let exit_code = context
+            .exit_code
+            .map(|code| format!(" with exit code {}", code))
+            .unwrap_or_default();
+
+        match context.event {
+            Event::Ready => format!("{} is ready (attempt {})", self.command, context.attempt),
+            Event::GiveUp => format!("gave up on {}{}", self.command, exit_code),
+            _ => format!("{} exited{}", self.command, exit_code),
+        }
+    }
+
+    fn body(&self, context: &HookContext) -> String {
+        let message = self.message(context);
+        let values = json!({
+            "event": context.event.as_str(),
+            "attempt": context.attempt,
+            "pid": context.pid,
+            "exit_code": context.exit_code,
+            "command": self.command,
+            "message": message,
+        });
+
+        let template = match self.template {
+            Some(ref template) => template,
+            None => return values.to_string(),
+        };
+
+        // Each value is escaped so that it can be put in a JSON string. Values
+        // that are missing are null, so that they're also valid unquoted.
+        let mut vars = TemplateVars::default();
+        if let Value::Object(values) = values {
+            for (name, value) in values {
+                let value = match value {
+                    Value::String(value) => {
+                        let escaped = Value::String(value).to_string();
+                        escaped[1..escaped.len() - 1].to_owned()
+                    }
+                    value => value.to_string(),
+                };
+                vars.set(name, value);
+            }
+        }
+
+        vars.expand(template, |_| None).into_owned()
+    }
+
+    /// Send the webhook, retrying failed requests with an increasing delay.
+    /// Failures are logged, but otherwise ignored.
+    #[tracing::instrument(name = "webhook", skip(self, client), fields(event = context.event.as_str()))]
+    pub async fn send(&self, client: &Client, context: HookContext) {
+        let body = self.body(&context);
+        let mut delay = Duration::from_secs(1);
+
+        for attempt in 0..=self.retries {
+            if attempt > 0 {
+                sleep(delay).await;
+                delay *= 2;
+            }
+
+            debug!(url = %self.url, attempt, "sending webhook");
+
+            let result = client
+                .post(self.url.clone())
+                .header(CONTENT_TYPE, "application/json")
+                .timeout(self.timeout)
+                .body(body.clone())
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+
+            let err = match result {
+                Ok(..) => return,
+                Err(err) => err,
+            };
+
+            // Other client errors won't be fixed by sending the same request
+            // again
+            let retry = match err.status() {
+                Some(status) if status.is_client_error() => status == StatusCode::TOO_MANY_REQUESTS,
+                _ => true,
+            };
+
+            let err: &dyn Error = &err;
+            warn!(error = err, "webhook request failed");
+
+            if !retry {
+                return;
+            }
+        }
+    }
+}
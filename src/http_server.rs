@@ -0,0 +1,116 @@
+use std::{error::Error, io, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::{sleep, timeout},
+};
+use tracing::{debug, warn};
+
+/// How long a client has to send its request before it's disconnected, so
+/// that idle connections don't pile up
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most of a request that's read, looking for the end of its headers
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// A response to a `GET` request
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: &'static str,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn new(status: &'static str, content_type: &'static str, body: String) -> Self {
+        Self {
+            status,
+            content_type,
+            body,
+        }
+    }
+
+    fn encode(&self) -> String {
+        format!(
+            "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+            self.status,
+            self.content_type,
+            self.body.len(),
+            self.body
+        )
+    }
+}
+
+/// Serve HTTP on `listener` forever. This is a deliberately minimal HTTP/1.0
+/// server, for the metrics and health endpoints: each `GET` is answered with
+/// `handle`'s response for its path, or a 404 if there isn't one, and then
+/// the connection is closed. `name` describes the server in the logs.
+pub async fn serve<F>(listener: TcpListener, name: &'static str, handle: F)
+where
+    F: Fn(&[u8]) -> Option<Response> + Send + Sync + 'static,
+{
+    let handle = Arc::new(handle);
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(err) => {
+                {
+                    let err: &dyn Error = &err;
+                    warn!(error = err, server = name, "failed to accept connection");
+                }
+
+                // Give whatever caused this, like hitting the file
+                // descriptor limit, a moment to clear up
+                sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let handle = Arc::clone(&handle);
+        tokio::spawn(async move {
+            if let Err(err) = respond(stream, &*handle).await {
+                let err: &dyn Error = &err;
+                debug!(error = err, server = name, %peer, "failed to respond to request");
+            }
+        });
+    }
+}
+
+async fn respond(
+    mut stream: TcpStream,
+    handle: &(dyn Fn(&[u8]) -> Option<Response> + Send + Sync),
+) -> io::Result<()> {
+    let mut request = Vec::with_capacity(1024);
+
+    // Read until the end of the headers; we don't care about anything after
+    // the request line, but the client might not read our response until
+    // it's done sending
+    let read = async {
+        while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+            if request.len() > MAX_REQUEST_SIZE || stream.read_buf(&mut request).await? == 0 {
+                break;
+            }
+        }
+
+        Ok::<_, io::Error>(())
+    };
+
+    timeout(REQUEST_TIMEOUT, read)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "timed out reading the request"))??;
+
+    let response = match request.split(|&b| b == b' ').take(2).collect::<Vec<_>>()[..] {
+        [b"GET", path] => handle(path),
+        _ => None,
+    };
+
+    let response = match response {
+        Some(response) => response.encode(),
+        None => "HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_owned(),
+    };
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
@@ -0,0 +1,259 @@
+#[cfg(windows)]
+use std::collections::BTreeMap;
+#[cfg(unix)]
+use std::{collections::BTreeSet, fs, process};
+use std::{
+    error::Error,
+    io,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+#[cfg(windows)]
+use defibrillator::windows::{self, Job};
+#[cfg(unix)]
+use libc::pid_t;
+use tokio::process::{Child, Command};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
+#[cfg(unix)]
+use tracing::debug;
+use tracing::warn;
+
+/// The PIDs of the children that defibrillator waits for itself, like the
+/// server, sidecars, and hooks. The reaper leaves these alone, even when
+/// they're in a process group of their own.
+#[cfg(unix)]
+static TRACKED: Mutex<BTreeSet<pid_t>> = Mutex::new(BTreeSet::new());
+
+#[cfg(unix)]
+fn tracked() -> MutexGuard<'static, BTreeSet<pid_t>> {
+    TRACKED.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Keeps the reaper away from a child until it's dropped, which should be
+/// after the child has been waited for
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct Tracked {
+    pid: Option<pid_t>,
+}
+
+#[cfg(unix)]
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        if let Some(pid) = self.pid {
+            tracked().remove(&pid);
+        }
+    }
+}
+
+/// Spawn `command`, and keep the reaper from reaping it for as long as the
+/// returned `Tracked` is alive. The set is locked across the spawn, so the
+/// reaper can't get to the child before it's recorded, even if it exits
+/// immediately.
+#[cfg(unix)]
+pub fn spawn_tracked(command: &mut Command) -> io::Result<(Child, Tracked)> {
+    let mut tracked = tracked();
+    let child = command.spawn()?;
+    let pid = child.id().map(|pid| pid as pid_t);
+
+    if let Some(pid) = pid {
+        tracked.insert(pid);
+    }
+
+    Ok((child, Tracked { pid }))
+}
+
+/// The fields of `/proc/<pid>/stat` needed to identify orphaned zombies
+#[cfg(unix)]
+#[derive(Debug, Clone, Copy)]
+struct ProcStat {
+    state: char,
+    parent: pid_t,
+    group: pid_t,
+}
+
+#[cfg(unix)]
+fn read_stat(pid: pid_t) -> Option<ProcStat> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+
+    // The command name is in parentheses, and may itself contain spaces or
+    // parentheses, so skip past the last closing parenthesis
+    let mut fields = stat[stat.rfind(')')? + 1..].split_whitespace();
+
+    Some(ProcStat {
+        state: fields.next()?.chars().next()?,
+        parent: fields.next()?.parse().ok()?,
+        group: fields.next()?.parse().ok()?,
+    })
+}
+
+/// Reap every zombie child that defibrillator didn't spawn itself. Our own
+/// children are reaped by tokio: the ones that were spawned with
+/// `spawn_tracked` are skipped explicitly, and the rest (probes and so on)
+/// share our process group. Anything else is an orphan that was reparented
+/// to us.
+#[cfg(unix)]
+fn reap_orphans() -> io::Result<()> {
+    let own_pid = process::id() as pid_t;
+
+    // Safety: getpgrp has no memory safety implications
+    let own_group = unsafe { libc::getpgrp() };
+
+    // Hold the lock for the whole scan, so that nothing is spawned and
+    // recorded in the meantime
+    let tracked = tracked();
+
+    for entry in fs::read_dir("/proc")? {
+        let pid: pid_t = match entry?
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        {
+            Some(pid) => pid,
+            None => continue,
+        };
+
+        if tracked.contains(&pid) {
+            continue;
+        }
+
+        let orphaned = match read_stat(pid) {
+            Some(stat) => stat.state == 'Z' && stat.parent == own_pid && stat.group != own_group,
+            None => false,
+        };
+
+        // Safety: waitpid with a null status pointer has no memory safety
+        // implications
+        if orphaned && unsafe { libc::waitpid(pid, std::ptr::null_mut(), libc::WNOHANG) } == pid {
+            debug!(pid, "reaped orphaned process");
+        }
+    }
+
+    Ok(())
+}
+
+/// Make defibrillator the reaper for orphaned descendants of the server, as
+/// though it were PID 1. When it actually is PID 1, this is already the case.
+#[cfg(unix)]
+pub fn become_subreaper() -> io::Result<()> {
+    // Safety: prctl with these arguments has no memory safety implications
+    match unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Reaps orphaned zombie processes whenever a child exits
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct Reaper {
+    children: Signal,
+}
+
+#[cfg(unix)]
+impl Reaper {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            children: signal(SignalKind::child())?,
+        })
+    }
+
+    pub async fn run(mut self) {
+        loop {
+            self.children.recv().await;
+
+            if let Err(err) = reap_orphans() {
+                let err: &dyn Error = &err;
+                warn!(error = err, "failed to reap orphaned processes");
+            }
+        }
+    }
+}
+
+/// The job objects of the children that defibrillator waits for itself, like
+/// the server, sidecars, and hooks, by process ID. Each one stands in for the
+/// process group that the child would lead on Unix.
+#[cfg(windows)]
+static JOBS: Mutex<BTreeMap<u32, Job>> = Mutex::new(BTreeMap::new());
+
+#[cfg(windows)]
+fn jobs() -> MutexGuard<'static, BTreeMap<u32, Job>> {
+    JOBS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Closes the child's job object when it's dropped, which should be after
+/// the child has been waited for. Anything still running in the job is
+/// killed.
+#[cfg(windows)]
+#[derive(Debug)]
+pub struct Tracked {
+    pid: Option<u32>,
+}
+
+#[cfg(windows)]
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        if let Some(pid) = self.pid {
+            jobs().remove(&pid);
+        }
+    }
+}
+
+/// Spawn `command` in a console process group and a job object of its own,
+/// so that it can be sent CTRL_BREAK, and so that whatever it starts can be
+/// killed along with it. The job lasts for as long as the returned `Tracked`
+/// is alive.
+#[cfg(windows)]
+pub fn spawn_tracked(command: &mut Command) -> io::Result<(Child, Tracked)> {
+    let child = windows::new_process_group(command).spawn()?;
+    let pid = child.id();
+
+    if let Some(pid) = pid {
+        match Job::containing(&child) {
+            Ok(job) => {
+                jobs().insert(pid, job);
+            }
+            Err(err) => {
+                let err: &dyn Error = &err;
+                warn!(
+                    error = err,
+                    pid, "failed to create a job object; the child's processes may outlive it"
+                );
+            }
+        }
+    }
+
+    Ok((child, Tracked { pid }))
+}
+
+/// Kill every process in the job object of the tracked child `pid`,
+/// returning whether there was one
+#[cfg(windows)]
+pub fn terminate_job(pid: u32) -> bool {
+    match jobs().get(&pid) {
+        Some(job) => job.terminate().is_ok(),
+        None => false,
+    }
+}
+
+/// Windows has no subreapers; orphans are never reparented
+#[cfg(windows)]
+pub fn become_subreaper() -> io::Result<()> {
+    Err(windows::unsupported("--init"))
+}
+
+#[cfg(windows)]
+#[derive(Debug)]
+pub enum Reaper {}
+
+#[cfg(windows)]
+impl Reaper {
+    pub fn new() -> io::Result<Self> {
+        Err(windows::unsupported("--init"))
+    }
+
+    pub async fn run(self) {
+        match self {}
+    }
+}
@@ -0,0 +1,40 @@
+//! The readiness engine behind the `defibrillator` command line tool.
+//!
+//! Rules describe the conditions under which a server is considered ready,
+//! like accepting TCP connections, answering HTTP requests, or printing a
+//! particular log line. They can be parsed from the same expression syntax
+//! used by `--rules`, or assembled directly:
+//!
+//! ```no_run
+//! use std::num::NonZeroU16;
+//!
+//! use defibrillator::rules::{AndRules, OrRules, Tcp};
+//! # async fn example() {
+//! let client = reqwest::Client::new();
+//!
+//! // Parsed from an expression
+//! let parsed: OrRules = "tcp port 8080 ready".parse().unwrap();
+//!
+//! // Assembled by hand
+//! let port = NonZeroU16::new(8080).unwrap();
+//! let built = OrRules::from(AndRules::from(Tcp::new(None, port, None)));
+//!
+//! // There's no server output to watch here, so `matches` rules would never
+//! // be satisfied
+//! let (log_lines, _) = tokio::sync::broadcast::channel(16);
+//! parsed.wait(&client, &log_lines).await.unwrap();
+//! built.wait(&client, &log_lines).await.unwrap();
+//! # }
+//! ```
+
+// The sd_notify socket may be in the abstract namespace, which only exists on
+// Linux. Windows has its own process handling, and no sd_notify.
+#[cfg(not(any(target_os = "linux", windows)))]
+compile_error!("defibrillator only supports Linux and Windows");
+
+pub mod duration;
+pub mod output;
+pub mod rules;
+pub mod sd_notify;
+#[cfg(windows)]
+pub mod windows;
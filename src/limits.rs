@@ -0,0 +1,117 @@
+#[cfg(unix)]
+use std::io;
+use std::{fmt, str::FromStr};
+
+#[cfg(unix)]
+use libc::{rlim_t, RLIM_INFINITY};
+
+/// Windows has no resource limits, but they're parsed all the same, so that
+/// giving one can be reported as unsupported rather than as invalid
+#[cfg(windows)]
+#[allow(non_camel_case_types)]
+type rlim_t = u64;
+
+#[cfg(windows)]
+const RLIM_INFINITY: rlim_t = rlim_t::MAX;
+
+/// A resource whose usage can be limited with `setrlimit`
+#[cfg(unix)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    /// The maximum number of open file descriptors
+    Nofile,
+
+    /// The maximum size of a core dump, in bytes
+    Core,
+
+    /// The maximum size of the address space, in bytes
+    As,
+}
+
+/// A soft and hard resource limit. Parsed from a single value, which sets
+/// both limits, or from `SOFT:HARD`; either may be `unlimited`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(windows, allow(dead_code))]
+pub struct Limit {
+    soft: rlim_t,
+    hard: rlim_t,
+}
+
+#[derive(Debug, Clone)]
+pub struct InvalidLimit(String);
+
+impl fmt::Display for InvalidLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid limit {:?}; expected a number, \"unlimited\", or SOFT:HARD",
+            self.0
+        )
+    }
+}
+
+fn parse_limit_value(s: &str) -> Option<rlim_t> {
+    match s {
+        "unlimited" | "infinity" => Some(RLIM_INFINITY),
+        _ => s.parse().ok(),
+    }
+}
+
+impl FromStr for Limit {
+    type Err = InvalidLimit;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (soft, hard) = s.split_once(':').unwrap_or((s, s));
+
+        match (parse_limit_value(soft), parse_limit_value(hard)) {
+            (Some(soft), Some(hard)) => Ok(Limit { soft, hard }),
+            _ => Err(InvalidLimit(s.to_owned())),
+        }
+    }
+}
+
+/// A set of resource limits to apply to the server
+#[cfg(unix)]
+#[derive(Debug, Clone, Default)]
+pub struct ResourceLimits {
+    limits: Vec<(Resource, Limit)>,
+}
+
+#[cfg(unix)]
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        self.limits.is_empty()
+    }
+
+    /// Add a limit, if there is one
+    pub fn with(mut self, resource: Resource, limit: Option<Limit>) -> Self {
+        self.limits.extend(limit.map(|limit| (resource, limit)));
+        self
+    }
+
+    /// Apply the limits to the current process. This is called in the child
+    /// between fork and exec.
+    pub fn apply(&self) -> io::Result<()> {
+        for &(resource, limit) in &self.limits {
+            let rlimit = libc::rlimit {
+                rlim_cur: limit.soft,
+                rlim_max: limit.hard,
+            };
+
+            // Safety: rlimit is a valid pointer for the duration of the call
+            let result = unsafe {
+                match resource {
+                    Resource::Nofile => libc::setrlimit(libc::RLIMIT_NOFILE, &rlimit),
+                    Resource::Core => libc::setrlimit(libc::RLIMIT_CORE, &rlimit),
+                    Resource::As => libc::setrlimit(libc::RLIMIT_AS, &rlimit),
+                }
+            };
+
+            if result == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,57 @@
+use std::time::Duration;
+
+use tokio::time::{sleep, timeout};
+use tracing::{debug, warn};
+
+use defibrillator::rules::{OrRules, ProbeConfig, ServerEvents, Unsatisfiable};
+
+/// Rules that are evaluated periodically after the server becomes ready, to
+/// check that it's still healthy.
+#[derive(Debug)]
+pub struct Liveness {
+    pub rules: OrRules,
+
+    /// How often to evaluate the rules
+    pub interval: Duration,
+
+    /// How long the rules have to succeed during each evaluation
+    pub timeout: Duration,
+
+    /// How many consecutive failed evaluations are tolerated before the
+    /// server is considered dead
+    pub failure_threshold: u32,
+}
+
+impl Liveness {
+    /// Evaluate the liveness rules forever, returning only once they've
+    /// failed `failure_threshold` times in a row.
+    #[tracing::instrument(name = "liveness", skip_all)]
+    pub async fn monitor(&self, probes: ProbeConfig<'_>, events: ServerEvents) {
+        let mut failures: u32 = 0;
+
+        loop {
+            sleep(self.interval).await;
+
+            let rules = self.rules.build(probes, &events).wait();
+
+            match timeout(self.timeout, rules).await {
+                Ok(Ok(..)) => {
+                    debug!("liveness check succeeded");
+                    failures = 0;
+                }
+                Ok(Err(Unsatisfiable)) | Err(..) => {
+                    failures += 1;
+                    warn!(
+                        failures,
+                        threshold = self.failure_threshold,
+                        "liveness check failed"
+                    );
+
+                    if failures >= self.failure_threshold {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,140 @@
+#[cfg(windows)]
+use std::os::windows::fs::OpenOptionsExt;
+use std::{
+    error::Error,
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Seek, Write},
+    path::{Path, PathBuf},
+    process,
+};
+#[cfg(unix)]
+use std::{io::Read, os::unix::io::AsRawFd};
+
+#[cfg(windows)]
+use windows_sys::Win32::Foundation::ERROR_SHARING_VIOLATION;
+
+#[derive(Debug)]
+pub enum LockError {
+    /// Another process holds the lock. The PID is whatever that process wrote
+    /// to the file, if anything.
+    Held {
+        path: PathBuf,
+        pid: Option<u32>,
+    },
+    Io(io::Error),
+}
+
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockError::Held { path, pid: Some(pid) } => write!(
+                f,
+                "{} is locked by another defibrillator (PID {}), which is already supervising this service",
+                path.display(),
+                pid
+            ),
+            LockError::Held { path, pid: None } => write!(
+                f,
+                "{} is locked by another defibrillator, which is already supervising this service",
+                path.display()
+            ),
+            LockError::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl Error for LockError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LockError::Held { .. } => None,
+            LockError::Io(err) => err.source(),
+        }
+    }
+}
+
+impl From<io::Error> for LockError {
+    fn from(err: io::Error) -> Self {
+        LockError::Io(err)
+    }
+}
+
+/// An exclusive lock on a --lock-file, held for as long as defibrillator
+/// runs, so that two instances can't supervise the same service. The lock is
+/// released by the kernel when defibrillator exits, however it exits, so the
+/// file itself is left in place.
+#[derive(Debug)]
+pub struct LockFile {
+    _file: File,
+}
+
+impl LockFile {
+    /// Take the lock without waiting, and write our PID to the file
+    pub fn acquire(path: &Path) -> Result<Self, LockError> {
+        let mut file = lock(path)?;
+
+        file.set_len(0)?;
+        file.rewind()?;
+        writeln!(file, "{}", process::id())?;
+
+        Ok(Self { _file: file })
+    }
+}
+
+/// Open the file and lock it with flock
+#[cfg(unix)]
+fn lock(path: &Path) -> Result<File, LockError> {
+    // Files are opened close-on-exec, so the server doesn't hold the lock
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+
+    // Safety: flock doesn't touch memory
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == -1 {
+        let err = io::Error::last_os_error();
+
+        return match err.raw_os_error() {
+            Some(libc::EWOULDBLOCK) => {
+                let mut contents = String::new();
+                let _ = file.read_to_string(&mut contents);
+
+                Err(LockError::Held {
+                    path: path.to_owned(),
+                    pid: contents.trim().parse().ok(),
+                })
+            }
+            _ => Err(err.into()),
+        };
+    }
+
+    Ok(file)
+}
+
+/// Open the file without sharing it, which locks it until it's closed. Other
+/// processes can't open it at all in the meantime, so the PID of the one
+/// that holds the lock can't be read.
+#[cfg(windows)]
+fn lock(path: &Path) -> Result<File, LockError> {
+    // Handles aren't inheritable, so the server doesn't hold the lock
+    let result = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .share_mode(0)
+        .open(path);
+
+    match result {
+        Ok(file) => Ok(file),
+        Err(err) if err.raw_os_error() == Some(ERROR_SHARING_VIOLATION as i32) => {
+            Err(LockError::Held {
+                path: path.to_owned(),
+                pid: None,
+            })
+        }
+        Err(err) => Err(err.into()),
+    }
+}
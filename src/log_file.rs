@@ -0,0 +1,146 @@
+use std::{error::Error, ffi::OsString, io, path::PathBuf, time::Duration};
+
+use defibrillator::output::LogLine;
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::broadcast::{error::RecvError, Receiver},
+    sync::Mutex,
+    time::Instant,
+};
+use tracing::{debug, warn};
+
+/// When to rotate the log file
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Rotation {
+    /// Rotate before the file would grow past this many bytes
+    pub max_size: Option<u64>,
+
+    /// Rotate once the file has been written to for this long
+    pub max_age: Option<Duration>,
+
+    /// How many rotated files to keep, as `<path>.1` (the newest) through
+    /// `<path>.<keep>`
+    pub keep: usize,
+}
+
+/// A file that the server's output is appended to, which is rotated as it
+/// grows or ages. It's kept open across attempts.
+#[derive(Debug)]
+pub struct LogFile {
+    path: PathBuf,
+    rotation: Rotation,
+    file: File,
+    size: u64,
+    opened: Instant,
+}
+
+impl LogFile {
+    pub async fn open(path: PathBuf, rotation: Rotation) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+        let size = file.metadata().await?.len();
+
+        Ok(Self {
+            path,
+            rotation,
+            file,
+            size,
+            opened: Instant::now(),
+        })
+    }
+
+    /// The path of the `index`th rotated file
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = OsString::from(self.path.as_os_str());
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    fn needs_rotation(&self, len: usize) -> bool {
+        let too_big = self
+            .rotation
+            .max_size
+            .is_some_and(|max_size| self.size > 0 && self.size + len as u64 > max_size);
+        let too_old = self
+            .rotation
+            .max_age
+            .is_some_and(|max_age| self.opened.elapsed() >= max_age);
+
+        too_big || too_old
+    }
+
+    /// Shift each rotated file up by one, discarding the oldest, move the
+    /// current file to `<path>.1`, and start a new one
+    async fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush().await?;
+
+        match self.rotation.keep {
+            0 => fs::remove_file(&self.path).await?,
+            keep => {
+                for index in (1..keep).rev() {
+                    match fs::rename(self.rotated_path(index), self.rotated_path(index + 1)).await {
+                        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                        _ => {}
+                    }
+                }
+
+                fs::rename(&self.path, self.rotated_path(1)).await?;
+            }
+        }
+
+        debug!(path = ?self.path, "rotated log file");
+        *self = Self::open(self.path.clone(), self.rotation).await?;
+        Ok(())
+    }
+
+    async fn write_line(&mut self, content: &[u8]) -> io::Result<()> {
+        let newline: &[u8] = match content.ends_with(b"\n") {
+            true => b"",
+            false => b"\n",
+        };
+        let len = content.len() + newline.len();
+
+        if self.needs_rotation(len) {
+            self.rotate().await?;
+        }
+
+        self.file.write_all(content).await?;
+        self.file.write_all(newline).await?;
+        self.size += len as u64;
+        Ok(())
+    }
+
+    /// Append each line from `lines`, from either stream, until the channel
+    /// is closed. Write failures are logged, but otherwise ignored. The file
+    /// is only locked while each line is written, so that overlapping
+    /// instances of the server can share it.
+    pub async fn write_lines(log_file: &Mutex<Self>, mut lines: Receiver<LogLine>) {
+        loop {
+            match lines.recv().await {
+                Ok(line) => {
+                    let mut log_file = log_file.lock().await;
+
+                    if let Err(err) = log_file.write_line(&line.content).await {
+                        let err: &dyn Error = &err;
+                        warn!(error = err, path = ?log_file.path, "failed to write to the log file");
+                    }
+                }
+                Err(RecvError::Lagged(lines)) => {
+                    warn!(missed = lines, "log file writer lagged")
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+
+        let mut log_file = log_file.lock().await;
+
+        if let Err(err) = log_file.file.flush().await {
+            let err: &dyn Error = &err;
+            warn!(error = err, path = ?log_file.path, "failed to flush the log file");
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use std::{fmt, str::FromStr};
+
+use tracing_subscriber::{EnvFilter, FmtSubscriber};
+
+/// The format of defibrillator's own log output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable lines, with each event's spans and fields
+    Full,
+
+    /// Shorter human-readable lines
+    Compact,
+
+    /// Multi-line, indented events, for reading during development
+    Pretty,
+
+    /// One JSON object per line, for log aggregation pipelines
+    Json,
+}
+
+#[derive(Debug, Clone)]
+pub struct InvalidLogFormat(String);
+
+impl fmt::Display for InvalidLogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown log format {:?}; expected full, compact, pretty, or json",
+            self.0
+        )
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = InvalidLogFormat;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "full" => Ok(LogFormat::Full),
+            "compact" => Ok(LogFormat::Compact),
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(InvalidLogFormat(s.to_owned())),
+        }
+    }
+}
+
+/// Install the global subscriber for defibrillator's own logs
+pub fn init(format: LogFormat, filter: EnvFilter) {
+    let builder = FmtSubscriber::builder().with_env_filter(filter);
+
+    // Each format has its own builder type, so each has to be installed
+    // separately
+    match format {
+        LogFormat::Full => builder.init(),
+        LogFormat::Compact => builder.compact().init(),
+        LogFormat::Pretty => builder.pretty().init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
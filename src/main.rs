@@ -1,84 +1,714 @@
-mod duration;
-mod rules;
+mod check;
+mod control;
+mod crash_context;
+mod env;
+mod event_log;
+mod health;
+mod hooks;
+mod http_server;
+mod init;
+mod limits;
+mod liveness;
+mod lock_file;
+mod log_file;
+mod logging;
+mod memory;
+mod metrics;
+#[cfg(unix)]
+mod multi;
+mod ports;
+mod probe;
+mod proxy;
+mod pty;
+mod ready_fd;
+mod reload;
+mod restarts;
+mod rules_file;
+mod sidecar;
+mod signals;
+mod sockets;
+mod state_files;
+mod stdin;
+mod summary;
 mod task;
+mod template;
+#[cfg(unix)]
+mod user;
+mod watch;
+
+// Supervision on Unix is built on Linux throughout: process groups and
+// signals, but also prctl and /proc for --init, inotify for --watch, pipe2,
+// and abstract sockets for sd_notify. Other Unix platforms would need
+// replacements for those. On Windows, the server is supervised with job
+// objects and console control events instead, and the options that rely on
+// Linux are rejected.
+#[cfg(not(any(target_os = "linux", windows)))]
+compile_error!("defibrillator only supports Linux and Windows");
 
 use std::{
     error::Error,
-    future::Future,
-    io,
-    pin::Pin,
-    process::{exit, Stdio},
-    task::{Context, Poll},
-    time::Duration,
+    fs, io,
+    net::SocketAddr,
+    num::NonZeroUsize,
+    path::PathBuf,
+    process::{self, exit, ExitStatus, Stdio},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+#[cfg(unix)]
+use std::{
+    ffi::OsStr,
+    os::unix::{ffi::OsStrExt, process::ExitStatusExt},
 };
 
-use broadcast::error::RecvError;
-use bytes::{Bytes, BytesMut};
+use defibrillator::{
+    duration::Duration as ParsableDuration,
+    output::{handle_output, LagPolicy, LogBuffer, Stream},
+    rules::{
+        client_builder, OrRules, PendingRules, ProbeConfig, Rules, ServerEvents, Unsatisfiable,
+        Variables,
+    },
+    sd_notify::{Notifier, NotifyListener, NOTIFY_SOCKET},
+};
 use futures::{
-    future::{join, pending, select, Either, FutureExt},
+    future::{join, pending, Either, FusedFuture, FutureExt, OptionFuture},
     pin_mut, select_biased,
-    stream::TryStreamExt,
 };
-use memchr::memchr;
-use reqwest::Client;
-use rules::OrRules;
+use libc::c_int;
+#[cfg(unix)]
+use libc::pid_t;
+use reqwest::{Client, Url};
 use structopt::StructOpt;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::{
-    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
-    process::Command,
-    sync::broadcast::{self, Sender},
-    task::JoinHandle,
+    io::AsyncRead,
+    net::TcpListener,
+    process::{Child, Command},
+    sync::Mutex as AsyncMutex,
     time::{sleep_until, Instant},
 };
 use tracing::{event, span, Instrument, Level};
-use tracing_subscriber::{EnvFilter, FmtSubscriber};
+use tracing_subscriber::EnvFilter;
 
-use crate::duration::Duration as ParsableDuration;
+use crate::check::{check, CheckArgs};
+use crate::control::{Command as ControlCommand, Commands, Status};
+use crate::crash_context::RecentLines;
+use crate::env::{read_env_file, EnvVar};
+use crate::event_log::{EventLog, EventTarget, Transition};
+use crate::hooks::{run_failure_command, Event, Hook, HookContext, Hooks, Webhook};
+use crate::init::{become_subreaper, spawn_tracked, Reaper, Tracked};
+use crate::limits::Limit;
+#[cfg(unix)]
+use crate::limits::{Resource, ResourceLimits};
+use crate::liveness::Liveness;
+use crate::lock_file::LockFile;
+use crate::log_file::{LogFile, Rotation};
+use crate::logging::LogFormat;
+use crate::memory::{ByteSize, MemoryLimit};
+use crate::metrics::{Metrics, State};
+#[cfg(unix)]
+use crate::multi::{multi, MultiArgs};
+use crate::ports::{alloc_ports, AllocatedPort, PortName};
+use crate::probe::{probe, ProbeArgs};
+use crate::proxy::{ProxyMode, ProxySpec};
+use crate::pty::PtyMaster;
+use crate::ready_fd::ReadyFd;
+use crate::reload::HangupAction;
+#[cfg(unix)]
+use crate::reload::Reloader;
+use crate::restarts::{ExitCodePolicy, ExitCodes, RestartLimit};
+use crate::rules_file::{read_rules_file, RulesTemplate};
+use crate::sidecar::{stop_sidecars, Sidecar, SidecarOutput};
+#[cfg(windows)]
+use crate::signals::pid_t;
+use crate::signals::{kill_group, signal_name, stop_child, SignalArg, TerminationSignals};
+use crate::sockets::{BindAddr, ListenSockets};
+use crate::state_files::StateFiles;
+use crate::stdin::{StdinForwarder, StdinMode};
+use crate::summary::Summary;
 use crate::task::ScopedTask;
+use crate::template::TemplateVars;
+#[cfg(unix)]
+use crate::user::Credentials;
+use crate::watch::{WatchPath, Watcher};
 
 #[derive(StructOpt)]
 struct Args {
-    /// The set of rules that determine when the server process is ready
-    #[structopt(short, long)]
-    rules: OrRules,
+    /// The set of rules that determine when the server process is ready.
+    /// Rules after a trailing `fails-if` mark the attempt as failed as soon
+    /// as they're satisfied. `${NAME}` and `${NAME:-default}` are replaced
+    /// with variables from the server's environment, including the
+    /// $DEFIBRILLATOR_* variables that describe each attempt, before each
+    /// attempt. A value is quoted if it would otherwise split an argument.
+    #[structopt(short, long, required_unless = "rules-file")]
+    rules: Option<String>,
+
+    /// Read the --rules from this file, rather than the command line. The
+    /// rules may span several lines, and may contain `#` comments.
+    #[structopt(long, conflicts_with = "rules")]
+    rules_file: Option<PathBuf>,
 
     /// The maximum time to wait for a server process to become ready
     #[structopt(short = "t", long)]
     ready_timeout: Option<ParsableDuration>,
 
+    /// The maximum time to wait for the server to become ready for the first
+    /// time, across every attempt. If it hasn't been ready by then,
+    /// defibrillator gives up with exit code 124.
+    #[structopt(long)]
+    total_timeout: Option<ParsableDuration>,
+
+    /// How often to poll rules like `tcp` and `http` that don't specify their
+    /// own interval with `every`
+    #[structopt(long, default_value = "1s")]
+    poll_interval: ParsableDuration,
+
+    /// How long a single probe, like an HTTP request, a connection attempt, or
+    /// a `cmd` command, may take before it's considered a failure, for rules
+    /// that don't specify their own with `timeout`
+    #[structopt(long, default_value = "10s")]
+    probe_timeout: ParsableDuration,
+
+    /// Send probes to loopback addresses through the proxy set in the
+    /// environment (`HTTP_PROXY` and friends), rather than directly. Rules
+    /// with their own TLS settings (`insecure` or `ca`) always send them
+    /// directly.
+    #[structopt(long)]
+    probe_proxy: bool,
+
     /// The maximum number of times to re-launch a crashed server if it never
-    /// becomes ready
+    /// becomes ready (or, with --min-uptime, doesn't stay ready for long enough)
     #[structopt(short = "R", long)]
     retries: Option<u64>,
 
-    /// The command to run
+    /// Only reset the retry counter if the server stayed ready for at least
+    /// this long before exiting or failing its liveness rules. Shorter runs
+    /// count as failed attempts.
+    #[structopt(long)]
+    min_uptime: Option<ParsableDuration>,
+
+    /// The maximum number of times the server may be restarted within the
+    /// --restart-window, whether or not it became ready. If it's restarted
+    /// more often than this, defibrillator gives up.
+    #[structopt(long)]
+    max_restarts: Option<usize>,
+
+    /// The window over which restarts are counted for --max-restarts
+    #[structopt(long, default_value = "1m")]
+    restart_window: ParsableDuration,
+
+    /// Only restart the server when it exits by itself with one of these
+    /// codes, given as a comma-separated list of codes and ranges, like
+    /// `1,3-5`. Signal names, like SIGKILL, match a server killed by that
+    /// signal. When it exits any other way, defibrillator exits with the
+    /// server's exit code.
+    #[structopt(long, conflicts_with = "no-restart-on-exit-codes")]
+    restart_on_exit_codes: Option<ExitCodes>,
+
+    /// Don't restart the server when it exits by itself with one of these
+    /// codes, like `0` for a server that's done, or `64` for a configuration
+    /// error. Instead, defibrillator exits with the same code.
+    #[structopt(long)]
+    no_restart_on_exit_codes: Option<ExitCodes>,
+
+    /// A set of rules that are evaluated periodically once the server is
+    /// ready. If they fail too many times in a row, the server is restarted.
+    #[structopt(long)]
+    liveness_rules: Option<OrRules>,
+
+    /// How often to evaluate the liveness rules
+    #[structopt(long, default_value = "10s")]
+    liveness_interval: ParsableDuration,
+
+    /// How long the liveness rules have to succeed each time they're evaluated
+    #[structopt(long, default_value = "5s")]
+    liveness_timeout: ParsableDuration,
+
+    /// The number of consecutive liveness failures after which the server is
+    /// restarted
+    #[structopt(long, default_value = "3")]
+    liveness_failures: u32,
+
+    /// Once the server is ready, restart it if its resident memory exceeds
+    /// this many bytes. Accepts K, M, and G suffixes, like 512M.
+    #[structopt(long)]
+    max_memory: Option<ByteSize>,
+
+    /// Gracefully restart the server once it's been ready for this long. The
+    /// new instance has to satisfy the rules again before it's ready.
+    #[structopt(long)]
+    max_uptime: Option<ParsableDuration>,
+
+    /// Gracefully restart the server when this file changes. The file name
+    /// may contain `*` and `?` wildcards, like `config/*.toml`. May be given
+    /// more than once.
+    #[structopt(long, number_of_values = 1)]
+    watch: Vec<WatchPath>,
+
+    /// After a watched file changes, wait until there have been no changes
+    /// for this long before restarting the server. A burst of changes delays
+    /// the restart by at most 10 times this.
+    #[structopt(long, default_value = "500ms")]
+    watch_debounce: ParsableDuration,
+
+    /// When the server is restarted by a restart command, --max-uptime, or
+    /// --watch, keep the old instance running until the new one is ready.
+    /// The server has to support running two instances at once; for
+    /// instance, by listening with SO_REUSEPORT.
+    #[structopt(long)]
+    overlap_restarts: bool,
+
+    /// How often to sample the server's memory usage for --max-memory
+    #[structopt(long, default_value = "5s")]
+    memory_interval: ParsableDuration,
+
+    /// When defibrillator stops the server, it sends a signal (the
+    /// --stop-signal, or the one defibrillator itself received), then waits
+    /// this long for the server to exit before killing it with SIGKILL
+    #[structopt(long, default_value = "10s")]
+    stop_timeout: ParsableDuration,
+
+    /// The signal used to stop the server when it times out, fails, or is
+    /// restarted. May be a name, like TERM or SIGTERM, or a number.
+    #[structopt(long, default_value = "TERM")]
+    stop_signal: SignalArg,
+
+    /// Don't run the server in its own process group. By default, signals
+    /// are sent to the server's entire process group, so that processes it
+    /// spawned (such as those run by a wrapper script) are stopped too.
+    #[structopt(long)]
+    no_process_group: bool,
+
+    /// Run the command through a shell, with `--shell-path -c` (or
+    /// `--shell-path /C` on Windows), so that it can be a pipeline or use
+    /// other shell syntax. Multiple arguments are joined with spaces. The
+    /// shell and everything it runs share a process group, so a whole
+    /// pipeline is stopped together.
+    #[structopt(long, conflicts_with = "no-process-group")]
+    shell: bool,
+
+    /// The shell to run the command with, with --shell. Defaults to /bin/sh,
+    /// or cmd on Windows.
+    #[structopt(long, requires = "shell")]
+    shell_path: Option<String>,
+
+    /// Reap orphaned processes left behind by the server, for use as PID 1 in
+    /// a container. When not running as PID 1, defibrillator registers itself
+    /// as a subreaper so that orphans are still reparented to it.
+    #[structopt(long, conflicts_with = "no-process-group")]
+    init: bool,
+
+    /// The exit code to use when defibrillator gives up on the server after
+    /// exhausting its retries or exceeding --max-restarts. Defaults to the exit
+    /// code of the last attempt.
+    #[structopt(long)]
+    give_up_exit_code: Option<i32>,
+
+    /// A shell command to run once the server is ready. When this command
+    /// finishes, defibrillator stops the server and exits with the command's
+    /// exit code.
+    #[structopt(long)]
+    exec: Option<String>,
+
+    /// A shell command to run alongside each instance of the server. It's
+    /// started just after the server, and stopped whenever the server exits or
+    /// is restarted. Its output is handled just like the server's. May be
+    /// given more than once.
+    #[structopt(long, number_of_values = 1)]
+    sidecar: Vec<String>,
+
+    /// A shell command to run each time the server becomes ready
+    #[structopt(long)]
+    on_ready: Option<String>,
+
+    /// A URL to POST a JSON description of the attempt to each time the
+    /// server becomes ready
+    #[structopt(long)]
+    on_ready_url: Option<Url>,
+
+    /// A shell command to run each time the server exits
+    #[structopt(long)]
+    on_exit: Option<String>,
+
+    /// A URL to POST a JSON description of the attempt to each time the
+    /// server exits
+    #[structopt(long)]
+    on_exit_url: Option<Url>,
+
+    /// A shell command to run each time the server exits, times out, or
+    /// satisfies its `fails-if` rules before becoming ready, for gathering
+    /// diagnostics before the next attempt. It's
+    /// given the same DEFIBRILLATOR_* variables as the other hooks, with the
+    /// server's exit code in DEFIBRILLATOR_EXIT_CODE, and the --crash-context
+    /// lines, if any, on its stdin. The next attempt waits for it to finish.
+    #[structopt(long)]
+    on_failure: Option<String>,
+
+    /// A shell command to run each time the server is about to be restarted
+    #[structopt(long)]
+    on_restart: Option<String>,
+
+    /// A URL to POST a JSON description of the attempt to each time the
+    /// server is about to be restarted
+    #[structopt(long)]
+    on_restart_url: Option<Url>,
+
+    /// A URL to POST to when the server becomes ready, crashes, or is given up
+    /// on, for chat or paging webhooks. The body is a JSON object describing
+    /// the event, unless --notify-template is given.
+    #[structopt(long)]
+    notify_url: Option<Url>,
+
+    /// The body of each --notify-url request, with ${event} (ready, crash, or
+    /// give-up), ${attempt}, ${pid}, ${exit_code}, ${command}, and ${message}
+    /// replaced. Values are escaped for use in JSON strings, and missing ones
+    /// are null. For example, '{"text": "${message}"}' for Slack.
+    #[structopt(long, requires = "notify-url")]
+    notify_template: Option<String>,
+
+    /// How long to wait for each --notify-url request
+    #[structopt(long, default_value = "10s")]
+    notify_timeout: ParsableDuration,
+
+    /// How many times to retry a failed --notify-url request
+    #[structopt(long, default_value = "3")]
+    notify_retries: u32,
+
+    /// Serve Prometheus metrics describing the server at `/metrics` on this
+    /// address
+    #[structopt(long)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Serve the server's health on this address, for load balancers and
+    /// orchestrators: `/ready` responds 200 once the rules have been satisfied,
+    /// and 503 otherwise, and `/status` responds with the JSON status, as
+    /// reported by the control socket
+    #[structopt(long)]
+    health_addr: Option<SocketAddr>,
+
+    /// Find a free port, and make it available as NAME: it's exported to the
+    /// command as $NAME, substituted for $NAME or ${NAME} in the command's
+    /// arguments, and usable in rules, as in `http port $NAME ready`. The port
+    /// stays the same across restarts. May be given more than once.
+    #[structopt(long, number_of_values = 1, value_name = "NAME")]
+    alloc_port: Vec<PortName>,
+
+    /// Create a listening socket, given as tcp:ADDRESS:PORT or unix:PATH, and
+    /// pass it to each instance of the server with the systemd socket
+    /// activation protocol (LISTEN_FDS and LISTEN_PID). The socket outlives
+    /// the server, so connections made while it restarts aren't refused. May
+    /// be given more than once; the sockets are passed in order, starting at
+    /// file descriptor 3.
+    #[structopt(long, number_of_values = 1)]
+    bind: Vec<BindAddr>,
+
+    /// Listen on a port, given as LISTEN:TARGET, and forward connections to
+    /// the server's TARGET port on localhost. Connections are accepted even
+    /// while the server is starting or restarting, and forwarded once it's
+    /// ready. May be given more than once.
+    #[structopt(long, number_of_values = 1)]
+    proxy: Vec<ProxySpec>,
+
+    /// What the --proxy does with connections that arrive while the server
+    /// isn't ready: `hold` them until it is, or `refuse` them
+    #[structopt(long, default_value = "hold")]
+    proxy_while_starting: ProxyMode,
+
+    /// Listen for commands on a Unix socket at this path. Each line sent to
+    /// the socket is a command: `status` reports the server's state as JSON,
+    /// `restart` gracefully restarts the server, and `stop` gracefully stops
+    /// it and exits.
+    #[structopt(long)]
+    control_socket: Option<PathBuf>,
+
+    /// Write `READY` followed by a newline to this inherited file descriptor
+    /// once the server is first ready, then close it, for supervisors like s6
+    /// that use readiness notification fds
+    #[structopt(long)]
+    ready_fd: Option<i32>,
+
+    /// Write the server's PID to this file each time it's launched, and
+    /// remove it when it exits
+    #[structopt(long)]
+    pid_file: Option<PathBuf>,
+
+    /// Write defibrillator's own PID to this file, and remove it when
+    /// defibrillator exits
+    #[structopt(long)]
+    supervisor_pid_file: Option<PathBuf>,
+
+    /// Take an exclusive lock on this file before doing anything else, and
+    /// exit with an error if another defibrillator already holds it, so that
+    /// two instances can't supervise the same service
+    #[structopt(long)]
+    lock_file: Option<PathBuf>,
+
+    /// Create this file each time the server becomes ready, and remove it
+    /// when the server exits or is restarted
+    #[structopt(long)]
+    ready_file: Option<PathBuf>,
+
+    /// Write a newline-delimited JSON record of each state transition
+    /// (spawned, ready, exited, timed-out, restarting, gave-up) to this path,
+    /// inherited file descriptor number, or `-` for stdout
+    #[structopt(long)]
+    events: Option<EventTarget>,
+
+    /// Don't run a command; just wait for the rules to be satisfied, then exit
+    #[structopt(long, conflicts_with = "command")]
+    wait_only: bool,
+
+    /// Don't run anything; just print the parsed rules, with their defaults
+    /// filled in, then exit
+    #[structopt(long)]
+    explain: bool,
+
+    /// The command to run. `${NAME}` and `${NAME:-default}` in its arguments
+    /// are replaced with variables from the server's environment, as set up
+    /// by --env, --env-file, and --clear-env. Each instance is also given
+    /// $DEFIBRILLATOR_ATTEMPT, $DEFIBRILLATOR_STARTED_AT (in seconds since the
+    /// Unix epoch), and, after the first exit, $DEFIBRILLATOR_LAST_EXIT_CODE,
+    /// which can be used in the arguments too, since they're expanded again
+    /// for each instance.
+    #[structopt(required_unless_one = &["wait-only", "explain"])]
     command: Vec<String>,
 
+    /// The working directory to run the command in
+    #[structopt(short = "C", long)]
+    workdir: Option<PathBuf>,
+
+    /// Run the command as this user, given as a name or numeric ID. Its
+    /// supplementary groups, $HOME, $USER, and $LOGNAME are set to match.
+    #[structopt(short, long)]
+    user: Option<String>,
+
+    /// Run the command with this group, given as a name or numeric ID.
+    /// Defaults to the primary group of the --user.
+    #[structopt(short, long)]
+    group: Option<String>,
+
+    /// Limit the number of file descriptors the command may open. Takes a
+    /// number, `unlimited`, or separate soft and hard limits as SOFT:HARD.
+    #[structopt(long)]
+    limit_nofile: Option<Limit>,
+
+    /// Limit the size of core dumps the command may produce, in bytes
+    #[structopt(long)]
+    limit_core: Option<Limit>,
+
+    /// Limit the size of the command's address space, in bytes
+    #[structopt(long)]
+    limit_as: Option<Limit>,
+
+    /// Set an environment variable for the command, as KEY=VALUE. May be
+    /// given more than once, and takes precedence over --env-file.
+    #[structopt(short, long = "env", number_of_values = 1)]
+    env: Vec<EnvVar>,
+
+    /// Read environment variables for the command from a file of KEY=VALUE
+    /// lines. May be given more than once.
+    #[structopt(long, number_of_values = 1)]
+    env_file: Vec<PathBuf>,
+
+    /// Don't pass defibrillator's own environment to the command; it only
+    /// gets the variables from --env and --env-file
+    #[structopt(long)]
+    clear_env: bool,
+
     /// Filter directives to pass to the logger
     #[structopt(short, long)]
     log_filters: Option<String>,
+
+    /// Append the command's output, from both stdout and stderr, to this file,
+    /// in addition to forwarding it
+    #[structopt(long)]
+    log_file: Option<PathBuf>,
+
+    /// Rotate the --log-file before it grows past this many bytes. Accepts
+    /// K, M, and G suffixes, like 100M.
+    #[structopt(long, requires = "log-file")]
+    log_max_size: Option<ByteSize>,
+
+    /// Rotate the --log-file once it's been written to for this long
+    #[structopt(long, requires = "log-file")]
+    log_max_age: Option<ParsableDuration>,
+
+    /// The number of rotated log files to keep, as <log-file>.1 (the newest)
+    /// through <log-file>.N
+    #[structopt(long, default_value = "5")]
+    log_keep: usize,
+
+    /// The number of the command's output lines that can be buffered for
+    /// subscribers like `matches` rules and output forwarding, before the
+    /// --lag-policy applies. It's rounded up to a power of two.
+    #[structopt(long, default_value = "100")]
+    log_buffer: NonZeroUsize,
+
+    /// What to do when the --log-buffer is full: `drop` the oldest lines, so
+    /// that subscribers that are behind miss them; `block` reading the
+    /// command's output until every subscriber catches up; or `fail-attempt`
+    /// if the command isn't ready yet, since a `matches` rule may have missed
+    /// its line
+    #[structopt(long, default_value = "drop")]
+    lag_policy: LagPolicy,
+
+    /// Split lines of the command's output that are longer than this many
+    /// bytes, so that output without newlines can't use unbounded memory.
+    /// Each piece is forwarded and matched as though it were its own line.
+    /// Accepts K, M, and G suffixes, like 64K.
+    #[structopt(long)]
+    max_line_length: Option<ByteSize>,
+
+    /// Test `matches` rules against the command's output exactly as it was
+    /// written. By default, ANSI escape sequences, like colors, are removed
+    /// before matching, though they're still forwarded.
+    #[structopt(long)]
+    match_ansi: bool,
+
+    /// Keep the last N lines of the command's output, and log them if it
+    /// exits, times out, or satisfies its `fails-if` rules before becoming
+    /// ready
+    #[structopt(long)]
+    crash_context: Option<usize>,
+
+    /// What to connect the command's stdin to: `null`; `inherit`, to share
+    /// defibrillator's stdin; or `pipe`, to read defibrillator's stdin and
+    /// pass it on to each new attempt in turn. When stdin is a terminal,
+    /// `inherit` requires --no-process-group, because a process outside the
+    /// terminal's foreground group is stopped when it reads from it.
+    #[structopt(long, default_value = "null")]
+    stdin: StdinMode,
+
+    /// What to do when defibrillator receives SIGHUP: `stop`, to stop the
+    /// server and exit; `forward`, to re-read the --rules-file and pass the
+    /// signal on to the server; or `restart`, to re-read the --rules-file and
+    /// restart the server. Reloaded rules apply from the next attempt.
+    #[structopt(long, default_value = "stop")]
+    sighup: HangupAction,
+
+    /// Run the command with its stdout and stderr connected to
+    /// pseudo-terminals, rather than pipes, for programs that buffer their
+    /// output or disable features when they aren't writing to a terminal
+    #[structopt(long)]
+    pty: bool,
+
+    /// The format of defibrillator's own logs: full, compact, pretty, or json
+    #[structopt(long, default_value = "full")]
+    log_format: LogFormat,
+}
+
+/// The first of the given options that rely on Linux, if any. They're
+/// rejected before anything is started.
+#[cfg(windows)]
+fn unsupported_option(args: &Args) -> Option<&'static str> {
+    [
+        ("--init", args.init),
+        ("--pty", args.pty),
+        ("--user", args.user.is_some()),
+        ("--group", args.group.is_some()),
+        ("--limit-nofile", args.limit_nofile.is_some()),
+        ("--limit-core", args.limit_core.is_some()),
+        ("--limit-as", args.limit_as.is_some()),
+        ("--watch", !args.watch.is_empty()),
+        ("--bind", !args.bind.is_empty()),
+        ("--control-socket", args.control_socket.is_some()),
+        ("--ready-fd", args.ready_fd.is_some()),
+        ("--sighup", args.sighup != HangupAction::Stop),
+    ]
+    .iter()
+    .find(|&&(_, given)| given)
+    .map(|&(option, _)| option)
 }
 
 #[tokio::main]
 #[tracing::instrument]
 async fn main() {
+    // The main arguments end with an arbitrary command, so `check`, `multi`,
+    // and `probe` can't be ordinary subcommands
+    if std::env::args_os().nth(1).is_some_and(|arg| arg == "check") {
+        let args = CheckArgs::from_iter(std::env::args_os().skip(1));
+
+        logging::init(
+            LogFormat::Full,
+            EnvFilter::try_new(&args.log_filters).expect("Failed to create env filter"),
+        );
+
+        exit(check(args).await);
+    }
+
+    #[cfg(unix)]
+    if std::env::args_os().nth(1).is_some_and(|arg| arg == "multi") {
+        let args = MultiArgs::from_iter(std::env::args_os().skip(1));
+
+        logging::init(
+            LogFormat::Full,
+            EnvFilter::try_new(&args.log_filters).expect("Failed to create env filter"),
+        );
+
+        exit(multi(args).await);
+    }
+
+    // Each process reports that it's ready on an inherited pipe, which
+    // Windows has no way to pass on
+    #[cfg(windows)]
+    if std::env::args_os().nth(1).is_some_and(|arg| arg == "multi") {
+        eprintln!("defibrillator multi isn't supported on Windows");
+        exit(1);
+    }
+
+    if std::env::args_os().nth(1).is_some_and(|arg| arg == "probe") {
+        let args = ProbeArgs::from_iter(std::env::args_os().skip(1));
+
+        logging::init(
+            LogFormat::Full,
+            EnvFilter::try_new(&args.log_filters).expect("Failed to create env filter"),
+        );
+
+        exit(probe(args).await);
+    }
+
     let args: Args = Args::from_args();
 
-    FmtSubscriber::builder()
-        .with_env_filter(
-            EnvFilter::try_new(args.log_filters.as_deref().unwrap_or(""))
-                .expect("Failed to create env filter"),
-        )
-        .init();
-
-    let client = match Client::builder()
-        .user_agent(concat!(
-            env!("CARGO_PKG_NAME"),
-            "/",
-            env!("CARGO_PKG_VERSION")
-        ))
-        .build()
+    logging::init(
+        args.log_format,
+        EnvFilter::try_new(args.log_filters.as_deref().unwrap_or(""))
+            .expect("Failed to create env filter"),
+    );
+
+    #[cfg(windows)]
+    if let Some(option) = unsupported_option(&args) {
+        event!(Level::ERROR, "{} isn't supported on Windows", option);
+        exit(1);
+    }
+
+    // Reading the terminal from a background process group stops the reader
+    // with SIGTTIN, so the server would hang as soon as it read its stdin.
+    // Windows has no such thing.
+    #[cfg(unix)]
+    if args.stdin == StdinMode::Inherit
+        && !args.no_process_group
+        // Safety: isatty has no memory safety implications
+        && unsafe { libc::isatty(libc::STDIN_FILENO) } == 1
     {
+        event!(
+            Level::ERROR,
+            "--stdin inherit requires --no-process-group when stdin is a terminal"
+        );
+        exit(1);
+    }
+
+    // This is held until defibrillator exits
+    let _lock = match args.lock_file.as_deref().map(LockFile::acquire).transpose() {
+        Ok(lock) => lock,
+        Err(err) => {
+            let err: &dyn Error = &err;
+            event!(Level::ERROR, error = err, "Failed to lock the --lock-file");
+            exit(1);
+        }
+    };
+
+    let client = match client_builder(args.probe_proxy).build() {
         Ok(client) => client,
         Err(err) => {
             let err: &dyn Error = &err;
@@ -87,203 +717,1845 @@ async fn main() {
         }
     };
 
-    // Unwrap safety: Structopt enforces at least one argument here
-    let program = &args.command[0];
-    let program_args = &args.command[1..];
-
-    let mut command_builder = Command::new(program);
+    let mut signals = match TerminationSignals::new(args.sighup == HangupAction::Stop) {
+        Ok(signals) => signals,
+        Err(err) => {
+            let err: &dyn Error = &err;
+            event!(
+                Level::ERROR,
+                error = err,
+                "Failed to install signal handlers"
+            );
+            exit(1);
+        }
+    };
 
-    command_builder
-        .args(program_args)
-        .stdin(Stdio::null())
-        .stderr(Stdio::inherit())
-        .stdout(Stdio::piped())
-        .kill_on_drop(true);
+    let notifier = match Notifier::from_env() {
+        Ok(notifier) => notifier,
+        Err(err) => {
+            let err: &dyn Error = &err;
+            event!(
+                Level::ERROR,
+                error = err,
+                "Failed to open the sd_notify socket"
+            );
+            exit(1);
+        }
+    };
 
-    let mut attempts: u64 = 0;
+    let ready_fd = match args.ready_fd.map(ReadyFd::new).transpose() {
+        Ok(ready_fd) => ready_fd,
+        Err(err) => {
+            let err: &dyn Error = &err;
+            event!(Level::ERROR, error = err, "Failed to open the readiness fd");
+            exit(1);
+        }
+    };
 
-    loop {
-        let outcome = async {
-            event!(Level::INFO, attempt = attempts + 1);
-            run_server(
-                &mut command_builder,
-                &args.rules,
-                args.ready_timeout.map(|duration| duration.get()),
-                &client,
-            )
-            .await
+    let event_log = match args.events.as_ref().map(EventLog::open).transpose() {
+        Ok(event_log) => event_log.map(Arc::new),
+        Err(err) => {
+            let err: &dyn Error = &err;
+            event!(Level::ERROR, error = err, "Failed to open the event stream");
+            exit(1);
         }
-        .instrument(span!(Level::INFO, "running command"))
-        .await;
+    };
 
-        let _err = match outcome {
-            RunServerOutcome::DidntSpawn(err) => {
-                attempts += 1;
-                Some(err)
-            }
-            RunServerOutcome::ExitedWhileStarting => {
-                attempts += 1;
-                None
-            }
-            RunServerOutcome::TimedOutWhileStarting => {
-                attempts += 1;
-                None
-            }
-            RunServerOutcome::ExitedWhileReady => {
-                attempts = 0;
-                None
+    let log_file = match args.log_file {
+        None => None,
+        Some(ref path) => {
+            let rotation = Rotation {
+                max_size: args.log_max_size.map(|size| size.0),
+                max_age: args.log_max_age.map(|age| age.get()),
+                keep: args.log_keep,
+            };
+
+            match LogFile::open(path.clone(), rotation).await {
+                Ok(log_file) => Some(Arc::new(AsyncMutex::new(log_file))),
+                Err(err) => {
+                    let err: &dyn Error = &err;
+                    event!(Level::ERROR, error = err, "Failed to open the log file");
+                    exit(1);
+                }
             }
-        };
+        }
+    };
 
-        if let Some(retries) = args.retries {
-            if attempts >= retries {
-                event!(Level::ERROR, attempts = attempts, "command failed to start");
-                return;
+    let mut env_file_vars = Vec::new();
+
+    for path in &args.env_file {
+        match read_env_file(path) {
+            Ok(vars) => env_file_vars.extend(vars),
+            Err(err) => {
+                let err: &dyn Error = &err;
+                event!(
+                    Level::ERROR,
+                    error = err,
+                    path = %path.display(),
+                    "Failed to read the env file"
+                );
+                exit(1);
             }
         }
     }
-}
 
-enum RunServerOutcome {
-    DidntSpawn(io::Error),
-    ExitedWhileStarting,
-    TimedOutWhileStarting,
-    ExitedWhileReady,
-}
+    let ports = match alloc_ports(&args.alloc_port) {
+        Ok(ports) => ports,
+        Err(err) => {
+            let err: &dyn Error = &err;
+            event!(Level::ERROR, error = err, "Failed to allocate a port");
+            exit(1);
+        }
+    };
 
-pub async fn handle_stdout<T: Unpin + AsyncRead>(
-    mut pipe: T,
-    broadcast: Sender<Bytes>,
-) -> io::Result<()> {
-    let mut lines = broadcast.subscribe();
+    for port in &ports {
+        event!(
+            Level::INFO,
+            name = port.name.as_str(),
+            port = port.port,
+            "allocated port"
+        );
+    }
 
-    let stdout_task = async move {
-        let mut stdout = tokio::io::stdout();
+    // The variables that can be used as ${NAME} in the rules and the command
+    let mut template_vars = TemplateVars::from_env(args.clear_env);
 
-        loop {
-            match lines.recv().await {
-                Ok(mut line) => stdout.write_all_buf(&mut line).await?,
-                Err(err) => match err {
-                    RecvError::Closed => return stdout.flush().await,
+    for var in env_file_vars.iter().chain(&args.env) {
+        template_vars.set(&var.key, &var.value);
+    }
 
-                    // TODO: log here
-                    RecvError::Lagged(_) => {}
-                },
+    for port in &ports {
+        template_vars.set(port.name.as_str(), port.port.to_string());
+    }
+
+    // The rules are expanded again for each attempt; they're checked as the
+    // first attempt would see them
+    let first_attempt_vars = template_vars.for_attempt(1, unix_time(), None);
+
+    let rules = match args.rules_file {
+        Some(ref path) => match read_rules_file(path, &first_attempt_vars) {
+            Ok(rules) => rules,
+            Err(err) => {
+                let err: &dyn Error = &err;
+                event!(
+                    Level::ERROR,
+                    error = err,
+                    path = %path.display(),
+                    "Failed to read the rules file"
+                );
+                exit(1);
             }
-        }
+        },
+        // Unwrap safety: Structopt requires --rules without --rules-file
+        None => match RulesTemplate::parse(args.rules.clone().unwrap(), &first_attempt_vars) {
+            Ok(rules) => rules,
+            Err(err) => {
+                let err: &dyn Error = &err;
+                event!(Level::ERROR, error = err, "Failed to parse the rules");
+                exit(1);
+            }
+        },
     };
 
-    let read_task = async move {
-        let mut buffer = BytesMut::with_capacity(4096);
-        let mut count: usize = 0;
+    if args.explain {
+        print!("{}", rules.rules());
 
-        loop {
-            let n = match pipe.read_buf(&mut buffer).await {
-                Ok(0) => return Ok(()),
-                Ok(n) => n,
-                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
-                Err(err) => return Err(err),
-            };
+        if let Some(ref liveness_rules) = args.liveness_rules {
+            println!("liveness:");
 
-            if buffer.capacity() == 0 {
-                buffer.reserve(4096);
+            for line in liveness_rules.to_string().lines() {
+                println!("  {}", line);
             }
+        }
 
-            let new_bytes = &buffer[count..n];
-            count += n;
+        exit(0);
+    }
 
-            let line_length = match memchr(b'\n', new_bytes) {
-                None => continue,
-                Some(idx) => count - n + idx + 1,
-            };
+    let uses_notify = rules.rules().uses_notify()
+        || args
+            .liveness_rules
+            .as_ref()
+            .is_some_and(|rules| rules.uses_notify());
 
-            let line = buffer.split_to(line_length).freeze();
-            count -= line.len();
-            broadcast.send(line);
+    let notify_listener = match uses_notify.then(NotifyListener::bind).transpose() {
+        Ok(listener) => listener.map(Arc::new),
+        Err(err) => {
+            let err: &dyn Error = &err;
+            event!(
+                Level::ERROR,
+                error = err,
+                "Failed to create a notification socket"
+            );
+            exit(1);
         }
     };
 
-    let (read_result, stdout_result) = join(read_task, stdout_task).await;
+    let metrics = Arc::new(Metrics::new());
 
-    read_result?;
-    stdout_result?;
-
-    Ok(())
-}
+    if let Some(addr) = args.metrics_addr {
+        match TcpListener::bind(addr).await {
+            Ok(listener) => {
+                tokio::spawn(metrics::serve(listener, Arc::clone(&metrics)));
+            }
+            Err(err) => {
+                let err: &dyn Error = &err;
+                event!(
+                    Level::ERROR,
+                    error = err,
+                    %addr,
+                    "Failed to bind the metrics server"
+                );
+                exit(1);
+            }
+        }
+    }
 
-/// Run a single instance of the server, managing its lifecycle
-#[tracing::instrument]
-async fn run_server(
-    builder: &mut Command,
-    rules: &OrRules,
-    starting_timeout: Option<Duration>,
-    client: &Client,
-) -> RunServerOutcome {
-    let (stdout_task, mut child) = {
-        let log_lines = {
-            let (log_lines, _) = broadcast::channel(100);
-            log_lines
-        };
+    let status = Arc::new(Status::new());
 
-        let rules = rules
-            .build(client, &log_lines)
-            .wait()
-            .instrument(span!(Level::TRACE, "rules"))
-            .fuse();
-        pin_mut!(rules);
+    if let Some(addr) = args.health_addr {
+        match TcpListener::bind(addr).await {
+            Ok(listener) => {
+                tokio::spawn(health::serve(listener, Arc::clone(&status)));
+            }
+            Err(err) => {
+                let err: &dyn Error = &err;
+                event!(
+                    Level::ERROR,
+                    error = err,
+                    %addr,
+                    "Failed to bind the health server"
+                );
+                exit(1);
+            }
+        }
+    }
 
-        event!(Level::INFO, "spawning command");
+    for proxy in &args.proxy {
+        let addr = proxy.listen_addr();
 
-        let mut child = match builder.spawn() {
-            Ok(child) => child,
+        match TcpListener::bind(addr).await {
+            Ok(listener) => {
+                tokio::spawn(proxy::serve(
+                    listener,
+                    proxy.target_addr(),
+                    args.proxy_while_starting,
+                    Arc::clone(&status),
+                ));
+            }
             Err(err) => {
-                let dyn_err: &dyn Error = &err;
-                event!(Level::ERROR, error = dyn_err, "command failed to spawn");
-                return RunServerOutcome::DidntSpawn(err);
+                let err: &dyn Error = &err;
+                event!(
+                    Level::ERROR,
+                    error = err,
+                    %addr,
+                    "Failed to bind the proxy"
+                );
+                exit(1);
             }
-        };
+        }
+    }
 
-        // TODO: Create signal handlers here to kill the child if we get a sigkill, sighup, etc
+    if args.init {
+        if process::id() != 1 {
+            if let Err(err) = become_subreaper() {
+                let err: &dyn Error = &err;
+                event!(
+                    Level::WARN,
+                    error = err,
+                    "Failed to become a subreaper; orphans may not be reaped"
+                );
+            }
+        }
 
-        let child_stdout = child.stdout.take().unwrap();
+        match Reaper::new() {
+            Ok(reaper) => {
+                tokio::spawn(reaper.run());
+            }
+            Err(err) => {
+                let err: &dyn Error = &err;
+                event!(
+                    Level::ERROR,
+                    error = err,
+                    "Failed to install the SIGCHLD handler"
+                );
+                exit(1);
+            }
+        }
+    }
 
-        let stdout_task = ScopedTask::new(tokio::spawn(handle_stdout(child_stdout, log_lines)));
+    // The sender is kept alive even without a control socket, so that
+    // `commands` simply never produces anything
+    let (command_sender, mut commands) = Commands::channel();
 
-        let starting_timeout = match starting_timeout {
-            Some(duration) => Either::Left(sleep_until(Instant::now() + duration).fuse()),
-            None => Either::Right(pending()),
-        };
-        pin_mut!(starting_timeout);
+    if let Some(ref path) = args.control_socket {
+        match control::bind(path) {
+            Ok(listener) => {
+                tokio::spawn(control::serve(
+                    listener,
+                    Arc::clone(&status),
+                    command_sender.clone(),
+                ));
+            }
+            Err(err) => {
+                let err: &dyn Error = &err;
+                event!(
+                    Level::ERROR,
+                    error = err,
+                    path = %path.display(),
+                    "Failed to bind the control socket"
+                );
+                exit(1);
+            }
+        }
+    }
+
+    // Changes to watched files restart the server just like a restart
+    // command would
+    if !args.watch.is_empty() {
+        match Watcher::new(&args.watch) {
+            Ok(watcher) => {
+                tokio::spawn(
+                    watcher.restart_on_change(args.watch_debounce.get(), command_sender.clone()),
+                );
+            }
+            Err(err) => {
+                let err: &dyn Error = &err;
+                event!(Level::ERROR, error = err, "Failed to watch files");
+                exit(1);
+            }
+        }
+    }
 
-        // State is now starting. Wait for the rules to signal readiness, or for
-        // a timeout
-        select_biased! {
-            () = rules => {},
-            _res = child.wait().fuse() => {
-                // Server exited cleanly; finish forwarding stdout
-                stdout_task.await;
+    let probes = ProbeConfig {
+        client: &client,
+        poll_interval: args.poll_interval.get(),
+        timeout: args.probe_timeout.get(),
+    };
 
-                return RunServerOutcome::ExitedWhileStarting;
+    let config = ServerConfig {
+        rules: Arc::new(Mutex::new(rules)),
+        template_vars: template_vars.clone(),
+        starting_timeout: args.ready_timeout.map(|duration| duration.get()),
+        stop_timeout: args.stop_timeout.get(),
+        stop_signal: args.stop_signal.get(),
+        process_group: !args.no_process_group,
+        liveness: args.liveness_rules.as_ref().map(|rules| Liveness {
+            rules: rules.clone(),
+            interval: args.liveness_interval.get(),
+            timeout: args.liveness_timeout.get(),
+            failure_threshold: args.liveness_failures,
+        }),
+        memory_limit: args.max_memory.map(|max| MemoryLimit {
+            max,
+            interval: args.memory_interval.get(),
+        }),
+        max_uptime: args.max_uptime.map(|duration| duration.get()),
+        overlap_restarts: args.overlap_restarts,
+        exec: args.exec.clone(),
+        sidecars: args.sidecar.clone(),
+        ports,
+        hooks: Hooks {
+            on_ready: Hook {
+                command: args.on_ready.clone(),
+                url: args.on_ready_url.clone(),
             },
-            () = starting_timeout => {
-                // Server timeed out; kill it and finish stdout
-                child.kill().await;
-                stdout_task.await;
+            on_exit: Hook {
+                command: args.on_exit.clone(),
+                url: args.on_exit_url.clone(),
+            },
+            on_restart: Hook {
+                command: args.on_restart.clone(),
+                url: args.on_restart_url.clone(),
+            },
+        },
+        on_failure: args.on_failure.clone(),
+        webhook: args.notify_url.as_ref().map(|url| {
+            Arc::new(Webhook {
+                url: url.clone(),
+                template: args.notify_template.clone(),
+                command: args.command.join(" "),
+                timeout: args.notify_timeout.get(),
+                retries: args.notify_retries,
+            })
+        }),
+        notifier,
+        ready_fd,
+        state_files: StateFiles {
+            pid_file: args.pid_file.clone(),
+            supervisor_pid_file: args.supervisor_pid_file.clone(),
+            ready_file: args.ready_file.clone(),
+        },
+        event_log,
+        log_file,
+        crash_context: args.crash_context,
+        log_buffer: args.log_buffer.get(),
+        lag_policy: args.lag_policy,
+        max_line_length: args
+            .max_line_length
+            .map(|size| size.0.clamp(1, usize::MAX as u64) as usize),
+        strip_ansi: !args.match_ansi,
+        count_lines: args.metrics_addr.is_some(),
+        stdin: (args.stdin == StdinMode::Pipe).then(StdinForwarder::spawn),
+        pty: args.pty,
+        notify_listener,
+        metrics,
+        status,
+        summary: Summary::default(),
+    };
 
-                return RunServerOutcome::TimedOutWhileStarting;
-            }
-        };
+    #[cfg(unix)]
+    match signal(SignalKind::user_defined1()) {
+        Ok(signals) => {
+            tokio::spawn(
+                Arc::clone(&config.status).report_on_signal(signals, config.event_log.clone()),
+            );
+        }
+        Err(err) => {
+            let err: &dyn Error = &err;
+            event!(
+                Level::ERROR,
+                error = err,
+                "Failed to install signal handlers"
+            );
+            exit(1);
+        }
+    }
 
-        (stdout_task, child)
-    };
+    #[cfg(unix)]
+    if args.sighup != HangupAction::Stop {
+        match signal(SignalKind::hangup()) {
+            Ok(hangups) => {
+                let reloader = Reloader {
+                    action: args.sighup,
+                    rules_file: args.rules_file.clone(),
+                    template_vars: first_attempt_vars,
+                    rules: Arc::clone(&config.rules),
+                    notify_available: config.notify_listener.is_some(),
+                    status: Arc::clone(&config.status),
+                    process_group: config.process_group,
+                    commands: command_sender.clone(),
+                };
 
-    event!(Level::INFO, "server is now ready");
+                tokio::spawn(reloader.run(hangups));
+            }
+            Err(err) => {
+                let err: &dyn Error = &err;
+                event!(
+                    Level::ERROR,
+                    error = err,
+                    "Failed to install signal handlers"
+                );
+                exit(1);
+            }
+        }
+    }
 
-    // State is now started!
-    let res = child.wait().await;
+    if args.wait_only {
+        let code = wait_only(&config, probes, &mut signals).await;
 
-    // Child exited cleanly; finish forwarding stdout
-    stdout_task.await;
+        if let Some(ref path) = args.control_socket {
+            control::unbind(path);
+        }
+        exit(code);
+    }
+
+    let sockets = match args.bind.is_empty() {
+        true => None,
+        false => match ListenSockets::bind(&args.bind) {
+            Ok(sockets) => Some(sockets),
+            Err(err) => {
+                let err: &dyn Error = &err;
+                event!(
+                    Level::ERROR,
+                    error = err,
+                    "Failed to create a listening socket"
+                );
+                exit(1);
+            }
+        },
+    };
+
+    if let Some(ref workdir) = args.workdir {
+        match fs::metadata(workdir) {
+            Ok(metadata) if metadata.is_dir() => {}
+            Ok(..) => {
+                event!(
+                    Level::ERROR,
+                    workdir = %workdir.display(),
+                    "The working directory is not a directory"
+                );
+                exit(1);
+            }
+            Err(err) => {
+                let err: &dyn Error = &err;
+                event!(
+                    Level::ERROR,
+                    error = err,
+                    workdir = %workdir.display(),
+                    "Failed to access the working directory"
+                );
+                exit(1);
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    let limits = ResourceLimits::default()
+        .with(Resource::Nofile, args.limit_nofile)
+        .with(Resource::Core, args.limit_core)
+        .with(Resource::As, args.limit_as);
+
+    #[cfg(unix)]
+    let credentials = match Credentials::resolve(args.user.as_deref(), args.group.as_deref()) {
+        Ok(credentials) => credentials,
+        Err(err) => {
+            let err: &dyn Error = &err;
+            event!(
+                Level::ERROR,
+                error = err,
+                "Failed to look up the user or group"
+            );
+            exit(1);
+        }
+    };
+
+    // The command is built again for each attempt, since its arguments are
+    // expanded with that attempt's variables
+    let build_command = |vars: &TemplateVars| {
+        // Unwrap safety: Structopt enforces at least one argument here
+        let command: Vec<String> = args
+            .command
+            .iter()
+            .map(|arg| {
+                vars.expand(arg, |name| config.port(name).map(|port| port.to_string()))
+                    .into_owned()
+            })
+            .collect();
+
+        let program = match args.shell {
+            true => args.shell_path.as_deref().unwrap_or(DEFAULT_SHELL),
+            false => &command[0],
+        };
+
+        let mut command_builder = match sockets {
+            Some(ref sockets) => sockets.command(program),
+            None => Command::new(program),
+        };
+
+        match args.shell {
+            true => shell_args(&mut command_builder, &command.join(" ")),
+            false => command_builder.args(&command[1..]),
+        };
+
+        command_builder.stdin(args.stdin.stdio()).kill_on_drop(true);
+
+        // On Windows, every child gets a console process group of its own
+        #[cfg(unix)]
+        if config.process_group {
+            command_builder.process_group(0);
+        }
+
+        if let Some(ref workdir) = args.workdir {
+            command_builder.current_dir(workdir);
+        }
+
+        if args.clear_env {
+            command_builder.env_clear();
+        }
+
+        // Limits are applied before switching users, so that an unprivileged
+        // user can be given a higher hard limit
+        #[cfg(unix)]
+        if !limits.is_empty() {
+            let limits = limits.clone();
+
+            // Safety: `apply` only makes async-signal-safe system calls
+            unsafe {
+                command_builder.pre_exec(move || limits.apply());
+            }
+        }
+
+        #[cfg(unix)]
+        if let Some(ref credentials) = credentials {
+            if let Some(ref user) = credentials.user {
+                let name = OsStr::from_bytes(user.name.as_bytes());
+
+                command_builder
+                    .env("HOME", OsStr::from_bytes(user.home.as_bytes()))
+                    .env("USER", name)
+                    .env("LOGNAME", name);
+            }
+
+            let credentials = credentials.clone();
+
+            // Safety: `apply` only makes async-signal-safe system calls
+            unsafe {
+                command_builder.pre_exec(move || credentials.apply());
+            }
+        }
+
+        command_builder.envs(env_file_vars.iter().map(|var| (&var.key, &var.value)));
+        command_builder.envs(args.env.iter().map(|var| (&var.key, &var.value)));
+
+        command_builder.envs(
+            config
+                .ports
+                .iter()
+                .map(|port| (port.name.as_str(), port.port.to_string())),
+        );
+
+        // Defibrillator reports the server's state to systemd itself, so the
+        // server should only see our own socket, if any
+        if let Some(ref listener) = config.notify_listener {
+            command_builder.env(NOTIFY_SOCKET, listener.address());
+        } else if config.notifier.is_some() {
+            command_builder.env_remove(NOTIFY_SOCKET);
+        }
+
+        command_builder
+    };
+
+    let mut attempts: u64 = 0;
+    let mut ever_ready = false;
+    let mut previous = None;
+    let mut last_exit_code = None;
+    let ready_deadline = args
+        .total_timeout
+        .map(|total_timeout| Instant::now() + total_timeout.get());
+    let exit_code_policy = match (&args.restart_on_exit_codes, &args.no_restart_on_exit_codes) {
+        (Some(codes), _) => Some(ExitCodePolicy::Only(codes.clone())),
+        (None, Some(codes)) => Some(ExitCodePolicy::Except(codes.clone())),
+        (None, None) => None,
+    };
+    let mut restart_limit = args
+        .max_restarts
+        .map(|max_restarts| RestartLimit::new(max_restarts, args.restart_window.get()));
+
+    if let Err(err) = config.state_files.write_supervisor_pid() {
+        let err: &dyn Error = &err;
+        event!(
+            Level::ERROR,
+            error = err,
+            "Failed to write the --supervisor-pid-file"
+        );
+        exit(1);
+    }
+
+    let code = loop {
+        let started_at = unix_time();
+        let vars = config
+            .template_vars
+            .for_attempt(attempts + 1, started_at, last_exit_code);
+        let mut command_builder = build_command(&vars);
+
+        let outcome = async {
+            event!(Level::INFO, attempt = attempts + 1);
+            run_server(
+                &mut command_builder,
+                &config,
+                probes,
+                &mut signals,
+                &mut commands,
+                Attempt {
+                    number: attempts + 1,
+                    started_at,
+                    vars,
+                    ready_deadline: ready_deadline.filter(|_| !ever_ready),
+                    previous: previous.take(),
+                    last_exit_code,
+                },
+            )
+            .await
+        }
+        .instrument(span!(Level::INFO, "running command"))
+        .await;
+
+        // The attempt that just finished; `attempts` is updated below
+        let attempt = attempts + 1;
+        let ready_for = config.status.ready_for();
+        ever_ready |= ready_for.is_some();
+
+        // A server that's been handed off is still running, and is still
+        // reported as ready, until its replacement takes over
+        if !matches!(outcome, RunServerOutcome::HandedOff(..)) {
+            config.metrics.set_state(State::Stopped);
+            config.status.stopped();
+            config.state_files.stopped();
+        }
+
+        let exited_by_itself = matches!(
+            outcome,
+            RunServerOutcome::ExitedWhileStarting(..) | RunServerOutcome::ExitedWhileReady(..)
+        );
+
+        if let Some(status) = outcome.status() {
+            last_exit_code = Some(exit_code(status));
+            log_exit(status, exited_by_itself);
+            config
+                .metrics
+                .record_exit(exit_code(status), exit_signal(status));
+            config.record(
+                attempt,
+                Transition::Exited {
+                    exit_code: exit_code(status),
+                    signal: exit_signal(status),
+                },
+            );
+
+            let context = HookContext {
+                event: Event::Exit,
+                attempt: attempts + 1,
+                pid: None,
+                exit_code: Some(exit_code(status)),
+            };
+            config.hooks.on_exit.run(&client, context).await;
+
+            if exited_by_itself {
+                config.send_webhook(
+                    &client,
+                    HookContext {
+                        event: Event::Crash,
+                        ..context
+                    },
+                );
+            }
+        }
+
+        // The server is being replaced, and is still running, so there's no
+        // exit to report yet
+        if let RunServerOutcome::HandedOff(server) = outcome {
+            previous = server.map(|server| *server);
+            attempts = 0;
+
+            let context = HookContext {
+                event: Event::Restart,
+                attempt: attempts + 1,
+                pid: None,
+                exit_code: None,
+            };
+            config.record(attempts + 1, Transition::Restarting);
+            config.summary.record_restart();
+            config.hooks.on_restart.run(&client, context).await;
+            config.notify("RELOADING=1");
+            continue;
+        }
+
+        let restart_requested = matches!(outcome, RunServerOutcome::Restarted(..));
+        let signal = outcome.status().and_then(exit_signal);
+
+        let last_exit_code = match outcome {
+            RunServerOutcome::DidntSpawn(err) => {
+                attempts += 1;
+
+                // These match the shell conventions for commands that couldn't
+                // be found or executed
+                match err.kind() {
+                    io::ErrorKind::NotFound => 127,
+                    _ => 126,
+                }
+            }
+            RunServerOutcome::ExitedWhileStarting(status) => {
+                attempts += 1;
+                exit_code(&status)
+            }
+            RunServerOutcome::TimedOutWhileStarting(status)
+            | RunServerOutcome::FailedWhileStarting(status) => {
+                attempts += 1;
+                exit_code(&status)
+            }
+            RunServerOutcome::ExitedWhileReady(status)
+            | RunServerOutcome::FailedLiveness(status)
+            | RunServerOutcome::ExceededMemory(status)
+                if config.exec.is_some() =>
+            {
+                event!(
+                    Level::ERROR,
+                    "server stopped before the --exec command finished"
+                );
+                config.notify("STOPPING=1");
+                break exit_code(&status);
+            }
+            RunServerOutcome::ExitedWhileReady(status)
+            | RunServerOutcome::FailedLiveness(status)
+            | RunServerOutcome::ExceededMemory(status) => {
+                match (args.min_uptime, ready_for) {
+                    (Some(min_uptime), Some(ready_for)) if ready_for < min_uptime.get() => {
+                        event!(
+                            Level::WARN,
+                            ?ready_for,
+                            "server stopped before reaching --min-uptime"
+                        );
+                        attempts += 1;
+                    }
+                    _ => attempts = 0,
+                }
+                exit_code(&status)
+            }
+            RunServerOutcome::ExecFinished(status) => {
+                let code = exit_code(&status);
+                event!(Level::INFO, exit_code = code, "--exec command finished");
+                break code;
+            }
+            RunServerOutcome::Terminated(signal, _) => {
+                event!(Level::INFO, signal, "shutting down");
+                break 128 + signal;
+            }
+            RunServerOutcome::Restarted(status) => {
+                attempts = 0;
+                exit_code(&status)
+            }
+            RunServerOutcome::Stopped(..) => {
+                event!(Level::INFO, "stopped by control command; shutting down");
+                break 0;
+            }
+            RunServerOutcome::HandedOff(..) => unreachable!(),
+        };
+
+        if let (Some(policy), true) = (&exit_code_policy, exited_by_itself) {
+            if !policy.restarts(last_exit_code, signal) {
+                event!(
+                    Level::INFO,
+                    exit_code = last_exit_code,
+                    "server exited with a code that doesn't restart it; shutting down"
+                );
+                config.give_up(&client, attempt, last_exit_code).await;
+                break last_exit_code;
+            }
+        }
+
+        if let (Some(deadline), false) = (ready_deadline, ever_ready) {
+            if Instant::now() >= deadline {
+                // This matches the exit code of timeout(1)
+                let code = 124;
+                event!(
+                    Level::ERROR,
+                    attempts = attempts,
+                    exit_code = code,
+                    "server didn't become ready within --total-timeout"
+                );
+                config.give_up(&client, attempt, code).await;
+                break code;
+            }
+        }
+
+        if let (Some(retries), false) = (args.retries, restart_requested) {
+            if attempts >= retries {
+                let code = args.give_up_exit_code.unwrap_or(last_exit_code);
+                event!(
+                    Level::ERROR,
+                    attempts = attempts,
+                    exit_code = code,
+                    "command failed to start"
+                );
+                config.give_up(&client, attempt, code).await;
+                break code;
+            }
+        }
+
+        if let (Some(limit), false) = (restart_limit.as_mut(), restart_requested) {
+            if !limit.record() {
+                let code = args.give_up_exit_code.unwrap_or(last_exit_code);
+                event!(
+                    Level::ERROR,
+                    max_restarts = limit.max_restarts,
+                    window = ?limit.window,
+                    exit_code = code,
+                    "server is restarting too often"
+                );
+                config.give_up(&client, attempt, code).await;
+                break code;
+            }
+        }
+
+        let context = HookContext {
+            event: Event::Restart,
+            attempt: attempts + 1,
+            pid: None,
+            exit_code: Some(last_exit_code),
+        };
+        config.record(attempts + 1, Transition::Restarting);
+        config.summary.record_restart();
+        config.hooks.on_restart.run(&client, context).await;
+        config.notify("RELOADING=1");
+    };
+
+    config.summary.log();
+    config.state_files.remove_all();
+
+    if let Some(ref path) = args.control_socket {
+        control::unbind(path);
+    }
+    exit(code);
+}
+
+enum RunServerOutcome {
+    DidntSpawn(io::Error),
+    ExitedWhileStarting(io::Result<ExitStatus>),
+    TimedOutWhileStarting(io::Result<ExitStatus>),
+    FailedWhileStarting(io::Result<ExitStatus>),
+    ExitedWhileReady(io::Result<ExitStatus>),
+    FailedLiveness(io::Result<ExitStatus>),
+    ExceededMemory(io::Result<ExitStatus>),
+    ExecFinished(io::Result<ExitStatus>),
+    Terminated(c_int, io::Result<ExitStatus>),
+    Restarted(io::Result<ExitStatus>),
+    Stopped(io::Result<ExitStatus>),
+
+    /// With --overlap-restarts, the server is being restarted, but is left
+    /// running until the next attempt is ready. This is only `None` within
+    /// `run_server`.
+    HandedOff(Option<Box<PreviousServer>>),
+}
+
+impl RunServerOutcome {
+    /// The outcome when the server was stopped in response to a control
+    /// command
+    fn from_command(command: ControlCommand, status: io::Result<ExitStatus>) -> Self {
+        match command {
+            ControlCommand::Restart => RunServerOutcome::Restarted(status),
+            ControlCommand::Stop => RunServerOutcome::Stopped(status),
+        }
+    }
+
+    /// The exit status of the server, if it was spawned and then reaped
+    fn status(&self) -> Option<&io::Result<ExitStatus>> {
+        match self {
+            RunServerOutcome::DidntSpawn(..) | RunServerOutcome::HandedOff(..) => None,
+            RunServerOutcome::ExitedWhileStarting(status)
+            | RunServerOutcome::TimedOutWhileStarting(status)
+            | RunServerOutcome::FailedWhileStarting(status)
+            | RunServerOutcome::ExitedWhileReady(status)
+            | RunServerOutcome::FailedLiveness(status)
+            | RunServerOutcome::ExceededMemory(status)
+            | RunServerOutcome::ExecFinished(status)
+            | RunServerOutcome::Terminated(_, status)
+            | RunServerOutcome::Restarted(status)
+            | RunServerOutcome::Stopped(status) => Some(status),
+        }
+    }
+}
+
+/// A single attempt to run the server
+struct Attempt {
+    number: u64,
+
+    /// When the attempt started, in seconds since the Unix epoch
+    started_at: u64,
+
+    /// The variables that the rules are expanded with
+    vars: TemplateVars,
+
+    /// When to give up if the server isn't ready, for --total-timeout
+    ready_deadline: Option<Instant>,
+
+    /// The instance that this attempt is replacing, with --overlap-restarts
+    previous: Option<PreviousServer>,
+
+    /// The exit code of the last instance to exit, if any has
+    last_exit_code: Option<i32>,
+}
+
+/// The results of forwarding the server's stdout and stderr, for the streams
+/// that were read
+type OutputResults = (Option<io::Result<()>>, Option<io::Result<()>>);
+
+/// An instance of the server that's being replaced, with --overlap-restarts.
+/// It keeps running, and its output is still forwarded, until it's stopped.
+struct PreviousServer {
+    attempt: u64,
+    child: Child,
+    /// Only held to keep the reaper away from the child
+    _tracked: Tracked,
+    group: Option<pid_t>,
+    sidecars: Vec<Sidecar>,
+    output_task: ScopedTask<OutputResults>,
+    log_file_task: Option<ScopedTask<()>>,
+}
+
+impl PreviousServer {
+    /// Stop the instance, if it hasn't already exited, and finish forwarding
+    /// its output
+    async fn stop(mut self, config: &ServerConfig) {
+        let status = match self.child.try_wait() {
+            Ok(Some(status)) => Ok(status),
+            _ => {
+                stop_child(
+                    &mut self.child,
+                    self.group,
+                    config.stop_signal,
+                    config.stop_timeout,
+                )
+                .await
+            }
+        };
+
+        let code = exit_code(&status);
+        event!(
+            Level::INFO,
+            attempt = self.attempt,
+            exit_code = code,
+            "stopped the previous instance"
+        );
+        config.metrics.record_exit(code, exit_signal(&status));
+        config.record(
+            self.attempt,
+            Transition::Exited {
+                exit_code: code,
+                signal: exit_signal(&status),
+            },
+        );
+
+        if let Some(group) = self.group {
+            kill_group(group);
+        }
+
+        stop_sidecars(self.sidecars, config.stop_signal, config.stop_timeout).await;
+        let _ = self.output_task.await;
+
+        if let Some(log_file_task) = self.log_file_task {
+            let _ = log_file_task.await;
+        }
+    }
+}
+
+/// Configuration for running the server, shared by every attempt
+#[derive(Debug)]
+struct ServerConfig {
+    /// The rules for the next attempt. These are replaced when the
+    /// --rules-file is reloaded.
+    rules: Arc<Mutex<RulesTemplate>>,
+    /// The variables for the command and the rules, before each attempt's
+    /// own are added
+    template_vars: TemplateVars,
+    starting_timeout: Option<Duration>,
+    stop_timeout: Duration,
+    stop_signal: c_int,
+    process_group: bool,
+    liveness: Option<Liveness>,
+    memory_limit: Option<MemoryLimit>,
+    max_uptime: Option<Duration>,
+    overlap_restarts: bool,
+    exec: Option<String>,
+    sidecars: Vec<String>,
+    ports: Vec<AllocatedPort>,
+    hooks: Hooks,
+    on_failure: Option<String>,
+    webhook: Option<Arc<Webhook>>,
+    notifier: Option<Notifier>,
+    ready_fd: Option<ReadyFd>,
+    state_files: StateFiles,
+    event_log: Option<Arc<EventLog>>,
+    log_file: Option<Arc<AsyncMutex<LogFile>>>,
+    crash_context: Option<usize>,
+    log_buffer: usize,
+    lag_policy: LagPolicy,
+    max_line_length: Option<usize>,
+    strip_ansi: bool,
+    count_lines: bool,
+    stdin: Option<StdinForwarder>,
+    pty: bool,
+    notify_listener: Option<Arc<NotifyListener>>,
+    metrics: Arc<Metrics>,
+    status: Arc<Status>,
+    summary: Summary,
+}
+
+impl ServerConfig {
+    /// Report a state change to systemd, if we're running under it
+    fn notify(&self, state: &str) {
+        if let Some(ref notifier) = self.notifier {
+            notifier.notify(state);
+        }
+    }
+
+    /// The port allocated with --alloc-port as `name`, if there is one
+    fn port(&self, name: &str) -> Option<u16> {
+        self.ports
+            .iter()
+            .find(|port| port.name.as_str() == name)
+            .map(|port| port.port)
+    }
+
+    /// Make each --alloc-port available to the rules as a `$NAME` variable
+    fn set_port_variables(&self, variables: &Variables) {
+        for port in &self.ports {
+            variables.set(port.name.as_str().to_owned(), port.port.to_string());
+        }
+    }
+
+    /// The current rules
+    fn rules(&self) -> Rules {
+        self.rules.lock().unwrap().rules().clone()
+    }
+
+    /// The current rules, expanded with an attempt's variables
+    fn rules_for(&self, vars: &TemplateVars) -> Rules {
+        self.rules.lock().unwrap().expand(vars)
+    }
+
+    /// Record a state transition to the --events stream, if there is one
+    fn record(&self, attempt: u64, transition: Transition) {
+        if let Some(ref event_log) = self.event_log {
+            event_log.record(attempt, transition);
+        }
+    }
+
+    /// Send the --notify-url webhook in the background, so that it doesn't
+    /// delay restarting the server
+    fn send_webhook(&self, client: &Client, context: HookContext) {
+        if let Some(ref webhook) = self.webhook {
+            let webhook = Arc::clone(webhook);
+            let client = client.clone();
+            tokio::spawn(async move { webhook.send(&client, context).await });
+        }
+    }
+
+    /// Report that defibrillator is giving up on the server, before it exits
+    /// with `exit_code`. Unlike the other webhooks, this one is sent before
+    /// returning, since it would otherwise be lost when defibrillator exits.
+    async fn give_up(&self, client: &Client, attempt: u64, exit_code: i32) {
+        self.record(attempt, Transition::GaveUp { exit_code });
+
+        if let Some(ref webhook) = self.webhook {
+            let context = HookContext {
+                event: Event::GiveUp,
+                attempt,
+                pid: None,
+                exit_code: Some(exit_code),
+            };
+            webhook.send(client, context).await;
+        }
+
+        self.notify("STOPPING=1");
+    }
+
+    /// Check if anything needs to see the server's output on `stream`. If
+    /// nothing does, the server writes to our own stdout or stderr directly,
+    /// rather than each line being read and copied.
+    fn uses_output(&self, stream: Stream) -> bool {
+        self.rules.lock().unwrap().rules().uses_output(stream)
+            || self
+                .liveness
+                .as_ref()
+                .is_some_and(|liveness| liveness.rules.uses_output(stream))
+            || self.log_file.is_some()
+            || self.crash_context.is_some()
+            || self.count_lines
+            || !self.sidecars.is_empty()
+    }
+
+    /// Report readiness to systemd and the --ready-fd, if there are either
+    fn notify_ready(&self) {
+        self.notify("READY=1");
+
+        if let Some(ref ready_fd) = self.ready_fd {
+            ready_fd.notify();
+        }
+    }
+}
+
+/// Our end of one of the server's output streams: a pseudo-terminal, with
+/// --pty, or otherwise a pipe, if it was piped
+fn output_reader(
+    pty: Option<PtyMaster>,
+    pipe: Option<impl AsyncRead + Unpin + Send + 'static>,
+) -> Option<Box<dyn AsyncRead + Unpin + Send>> {
+    match pty {
+        Some(pty) => Some(Box::new(pty)),
+        None => pipe.map(|pipe| Box::new(pipe) as _),
+    }
+}
+
+/// Log how the server exited: with an exit code, or killed by a signal. Unless
+/// defibrillator stopped it, a signal usually means that it crashed, or was
+/// killed by the OOM killer.
+fn log_exit(status: &io::Result<ExitStatus>, exited_by_itself: bool) {
+    match (status, exit_signal(status)) {
+        (Err(err), _) => {
+            let err: &dyn Error = err;
+            event!(
+                Level::WARN,
+                error = err,
+                "failed to get the server's exit status"
+            );
+        }
+        (Ok(..), Some(signal)) if exited_by_itself => event!(
+            Level::WARN,
+            signal = %signal_name(signal),
+            exit_code = exit_code(status),
+            "server was killed by a signal"
+        ),
+        (Ok(..), Some(signal)) => event!(
+            Level::INFO,
+            signal = %signal_name(signal),
+            exit_code = exit_code(status),
+            "server was stopped by a signal"
+        ),
+        (Ok(..), None) => event!(Level::INFO, exit_code = exit_code(status), "server exited"),
+    }
+}
+
+/// The current time, in seconds since the Unix epoch
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+/// The signal that killed the child, if it was killed by one
+#[cfg(unix)]
+fn exit_signal(status: &io::Result<ExitStatus>) -> Option<c_int> {
+    status.as_ref().ok().and_then(|status| status.signal())
+}
+
+/// Processes on Windows always exit with a code, even when they're killed
+#[cfg(windows)]
+fn exit_signal(_status: &io::Result<ExitStatus>) -> Option<c_int> {
+    None
+}
+
+/// Convert the child's exit status into an exit code for defibrillator. A
+/// child killed by a signal is reported as 128 + the signal number, following
+/// the shell convention.
+fn exit_code(status: &io::Result<ExitStatus>) -> i32 {
+    let code = status.as_ref().ok().and_then(ExitStatus::code);
+
+    match (code, exit_signal(status)) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 1,
+    }
+}
+
+/// Wait for the rules to be satisfied without running anything, returning an
+/// exit code for defibrillator
+#[tracing::instrument(skip_all)]
+async fn wait_only(
+    config: &ServerConfig,
+    probes: ProbeConfig<'_>,
+    signals: &mut TerminationSignals,
+) -> i32 {
+    // There's no process, so there are no events for `matches` or `notify`
+    // rules
+    let events = ServerEvents::new(1);
+    config.set_port_variables(&events.variables);
+
+    let current_rules = config.rules();
+
+    let rules = current_rules
+        .ready()
+        .build(probes, &events)
+        .wait()
+        .instrument(span!(Level::TRACE, "rules"))
+        .fuse();
+    pin_mut!(rules);
+
+    let failure = fails_if(&current_rules, probes, &events);
+    pin_mut!(failure);
+
+    let timeout = match config.starting_timeout {
+        Some(duration) => Either::Left(sleep_until(Instant::now() + duration).fuse()),
+        None => Either::Right(pending()),
+    };
+    pin_mut!(timeout);
+
+    select_biased! {
+        signal = signals.recv().fuse() => 128 + signal,
+        result = rules => match result {
+            Ok(satisfied) => {
+                event!(Level::INFO, group = satisfied.group, "rules are satisfied");
+                0
+            }
+            Err(Unsatisfiable) => {
+                event!(Level::ERROR, "rules can no longer be satisfied");
+                1
+            }
+        },
+        () = failure => {
+            event!(Level::ERROR, "fails-if rules are satisfied");
+            1
+        },
+        () = timeout => {
+            event!(Level::ERROR, "timed out waiting for rules");
+            1
+        },
+    }
+}
+
+/// Build a future that resolves once the `fails-if` rules are satisfied. If
+/// there are no such rules, or they become unsatisfiable, it never resolves.
+fn fails_if<'a>(
+    rules: &Rules,
+    probes: ProbeConfig<'a>,
+    events: &ServerEvents,
+) -> impl FusedFuture<Output = ()> + 'a {
+    // These rules aren't listed with the readiness rules that are pending
+    let events = ServerEvents {
+        pending: PendingRules::default(),
+        ..events.clone()
+    };
+
+    let failure = rules.fails_if().map(|rules| {
+        rules
+            .build(probes, &events)
+            .wait()
+            .instrument(span!(Level::TRACE, "fails-if rules"))
+    });
+
+    async move {
+        if let Some(failure) = failure {
+            if failure.await.is_ok() {
+                return;
+            }
+        }
+
+        pending().await
+    }
+    .fuse()
+}
+
+/// Run the `--exec` command through the shell and wait for it to finish
+#[tracing::instrument(name = "exec")]
+async fn run_exec(command: &str) -> io::Result<ExitStatus> {
+    event!(Level::INFO, "server is ready; running --exec command");
+
+    shell(command).kill_on_drop(true).status().await
+}
+
+/// The shell that commands are run with, unless --shell-path says otherwise
+#[cfg(unix)]
+const DEFAULT_SHELL: &str = "/bin/sh";
+
+#[cfg(windows)]
+const DEFAULT_SHELL: &str = "cmd";
+
+/// Add the arguments that make the shell run `command`
+#[cfg(unix)]
+fn shell_args<'a>(builder: &'a mut Command, command: &str) -> &'a mut Command {
+    builder.arg("-c").arg(command)
+}
+
+/// Add the arguments that make cmd run `command`. It's passed along as is,
+/// since cmd doesn't follow the usual rules for quoting arguments.
+#[cfg(windows)]
+fn shell_args<'a>(builder: &'a mut Command, command: &str) -> &'a mut Command {
+    builder.arg("/C").raw_arg(command)
+}
+
+/// Create a command that runs `command` with the default shell
+fn shell(command: &str) -> Command {
+    let mut builder = Command::new(DEFAULT_SHELL);
+    shell_args(&mut builder, command);
+    builder
+}
+
+/// Run a single instance of the server, managing its lifecycle
+#[tracing::instrument(
+    skip(config, probes, signals, commands, attempt),
+    fields(attempt = attempt.number)
+)]
+async fn run_server(
+    builder: &mut Command,
+    config: &ServerConfig,
+    probes: ProbeConfig<'_>,
+    signals: &mut TerminationSignals,
+    commands: &mut Commands,
+    attempt: Attempt,
+) -> RunServerOutcome {
+    let Attempt {
+        number: attempt,
+        started_at,
+        vars,
+        ready_deadline,
+        mut previous,
+        last_exit_code,
+    } = attempt;
+    let stop_timeout = config.stop_timeout;
+    let stop_signal = config.stop_signal;
+    let log_buffer = LogBuffer::new(config.log_buffer, config.lag_policy);
+    let events = ServerEvents {
+        strip_ansi: config.strip_ansi,
+        pending: config.status.pending().clone(),
+        ..ServerEvents::new(log_buffer.capacity)
+    };
+    config.set_port_variables(&events.variables);
+
+    // Forward notifications from the server to the `notify` rules, ignoring
+    // any left over from a previous attempt
+    let _notify_task = config.notify_listener.as_ref().map(|listener| {
+        listener.drain();
+
+        let listener = Arc::clone(listener);
+        let notifications = events.notifications.clone();
+        ScopedTask::new(tokio::spawn(async move {
+            listener.forward(notifications).await
+        }))
+    });
+
+    let current_rules = config.rules_for(&vars);
+
+    let rules = current_rules
+        .ready()
+        .build(probes, &events)
+        .wait()
+        .instrument(span!(Level::TRACE, "rules"))
+        .fuse();
+
+    let failure = fails_if(&current_rules, probes, &events);
+
+    // With --pty, the output always goes through the terminals, even if
+    // nothing else needs to see it
+    let (stdout_pty, stderr_pty) = match config.pty {
+        true => match pty::open().and_then(|stdout| Ok((stdout, pty::open()?))) {
+            Ok(((stdout_pty, stdout), (stderr_pty, stderr))) => {
+                builder.stdout(stdout).stderr(stderr);
+                (Some(stdout_pty), Some(stderr_pty))
+            }
+            Err(err) => {
+                let dyn_err: &dyn Error = &err;
+                event!(
+                    Level::ERROR,
+                    error = dyn_err,
+                    "failed to open a pseudo-terminal"
+                );
+
+                if let Some(previous) = previous {
+                    previous.stop(config).await;
+                }
+
+                return RunServerOutcome::DidntSpawn(err);
+            }
+        },
+        false => {
+            let output = |stream| match config.uses_output(stream) {
+                true => Stdio::piped(),
+                false => Stdio::inherit(),
+            };
+
+            builder
+                .stdout(output(Stream::Stdout))
+                .stderr(output(Stream::Stderr));
+
+            (None, None)
+        }
+    };
+
+    // Let the server know where it is in the supervision, so that it can
+    // behave differently when it's being retried
+    builder
+        .env("DEFIBRILLATOR_ATTEMPT", attempt.to_string())
+        .env("DEFIBRILLATOR_STARTED_AT", started_at.to_string());
+
+    match last_exit_code {
+        Some(code) => builder.env("DEFIBRILLATOR_LAST_EXIT_CODE", code.to_string()),
+        None => builder.env_remove("DEFIBRILLATOR_LAST_EXIT_CODE"),
+    };
+
+    event!(Level::INFO, "spawning command");
+    config.metrics.record_attempt();
+    config.summary.record_attempt();
+
+    let spawned = spawn_tracked(builder);
+
+    // Drop the builder's copies of the server's ends of the terminals;
+    // otherwise, reading our ends would never finish
+    if config.pty {
+        builder.stdout(Stdio::null()).stderr(Stdio::null());
+    }
+
+    let (mut child, tracked) = match spawned {
+        Ok(spawned) => spawned,
+        Err(err) => {
+            let dyn_err: &dyn Error = &err;
+            event!(Level::ERROR, error = dyn_err, "command failed to spawn");
+
+            if let Some(previous) = previous {
+                previous.stop(config).await;
+            }
+
+            return RunServerOutcome::DidntSpawn(err);
+        }
+    };
+
+    config.record(attempt, Transition::Spawned { pid: child.id() });
+
+    let _stdin_task = config
+        .stdin
+        .clone()
+        .zip(child.stdin.take())
+        .map(|(stdin, pipe)| ScopedTask::new(tokio::spawn(stdin.forward(pipe))));
+
+    // The child leads its own process group, so the whole group can be
+    // signalled, even after the child itself has exited
+    let group = match config.process_group {
+        true => child.id().map(|pid| pid as pid_t),
+        false => None,
+    };
+
+    // While the instance that this one replaces is still serving, it's the
+    // one that's reported; this one takes over once it's ready
+    let started = Instant::now();
+    if previous.is_none() {
+        config.metrics.set_state(State::Starting);
+        config.status.starting(attempt, child.id());
+        config.state_files.spawned(child.id());
+    }
+
+    // These are only present for the streams that we're reading
+    let child_stdout = output_reader(stdout_pty, child.stdout.take());
+    let child_stderr = output_reader(stderr_pty, child.stderr.take());
+
+    // Subscribe now, so that the log file doesn't miss any lines
+    let log_file_task = config.log_file.as_ref().map(|log_file| {
+        let log_file = Arc::clone(log_file);
+        let lines = events.log_lines.subscribe();
+
+        ScopedTask::new(tokio::spawn(async move {
+            LogFile::write_lines(&log_file, lines).await
+        }))
+    });
+
+    let crash_context_task = config.crash_context.map(|capacity| {
+        ScopedTask::new(tokio::spawn(RecentLines::collect(
+            capacity,
+            events.log_lines.subscribe(),
+        )))
+    });
+
+    let output_task = ScopedTask::new(tokio::spawn(join(
+        OptionFuture::from(child_stdout.map(|pipe| {
+            handle_output(
+                pipe,
+                tokio::io::stdout(),
+                Stream::Stdout,
+                events.log_lines.clone(),
+                log_buffer.clone(),
+                config.max_line_length,
+                config.metrics.log_lines(Stream::Stdout),
+            )
+        })),
+        OptionFuture::from(child_stderr.map(|pipe| {
+            handle_output(
+                pipe,
+                tokio::io::stderr(),
+                Stream::Stderr,
+                events.log_lines.clone(),
+                log_buffer.clone(),
+                config.max_line_length,
+                config.metrics.log_lines(Stream::Stderr),
+            )
+        })),
+    )));
+
+    let sidecar_output = SidecarOutput {
+        log_lines: events.log_lines.clone(),
+        log_buffer: log_buffer.clone(),
+        max_line_length: config.max_line_length,
+    };
+
+    let sidecars: Vec<Sidecar> = config
+        .sidecars
+        .iter()
+        .filter_map(|command| match Sidecar::spawn(command, &sidecar_output) {
+            Ok(sidecar) => Some(sidecar),
+            Err(err) => {
+                let err: &dyn Error = &err;
+                event!(Level::WARN, error = err, command = %command, "sidecar failed to spawn");
+                None
+            }
+        })
+        .collect();
+
+    drop(sidecar_output);
+
+    let outcome = async {
+        // The attempt times out at the --ready-timeout, or the end of the
+        // --total-timeout, whichever comes first
+        let starting_deadline = config
+            .starting_timeout
+            .map(|duration| Instant::now() + duration)
+            .into_iter()
+            .chain(ready_deadline)
+            .min();
+
+        let starting_timeout = match starting_deadline {
+            Some(deadline) => Either::Left(sleep_until(deadline).fuse()),
+            None => Either::Right(pending()),
+        };
+        pin_mut!(starting_timeout);
+
+        // The startup rules are boxed, so that they can be dropped once the
+        // server is ready. Otherwise, their `matches` subscriptions would fall
+        // behind, which stalls the output with `--lag-policy block`.
+        let mut rules = Box::pin(rules);
+        let mut failure = Box::pin(failure);
+
+        // State is now starting. Wait for the rules to signal readiness, or
+        // for a timeout.
+        let satisfied = select_biased! {
+            signal = signals.recv().fuse() => {
+                // We're shutting down; pass the signal along to the server
+                config.notify("STOPPING=1");
+                let status = stop_child(&mut child, group, signal, stop_timeout).await;
+                return RunServerOutcome::Terminated(signal, status);
+            },
+            command = commands.recv().fuse() => {
+                if command == ControlCommand::Stop {
+                    config.notify("STOPPING=1");
+                }
+
+                let status = stop_child(&mut child, group, stop_signal, stop_timeout).await;
+                return RunServerOutcome::from_command(command, status);
+            },
+            result = rules => match result {
+                Ok(satisfied) => satisfied,
+                Err(Unsatisfiable) => {
+                    // The rules will never be satisfied, so there's no point
+                    // in waiting for the timeout
+                    event!(Level::WARN, "rules can no longer be satisfied; stopping server");
+                    let status = stop_child(&mut child, group, stop_signal, stop_timeout).await;
+                    return RunServerOutcome::FailedWhileStarting(status);
+                }
+            },
+            () = failure => {
+                // The server reported a failure; don't wait for the timeout
+                event!(Level::WARN, "fails-if rules are satisfied; stopping server");
+                let status = stop_child(&mut child, group, stop_signal, stop_timeout).await;
+                return RunServerOutcome::FailedWhileStarting(status);
+            },
+            () = log_buffer.overflowed().fuse() => {
+                event!(
+                    Level::WARN,
+                    "log buffer overflowed before the server was ready; stopping server"
+                );
+                let status = stop_child(&mut child, group, stop_signal, stop_timeout).await;
+                return RunServerOutcome::FailedWhileStarting(status);
+            },
+            status = child.wait().fuse() => {
+                return RunServerOutcome::ExitedWhileStarting(status);
+            },
+            () = starting_timeout => {
+                config.record(attempt, Transition::TimedOut);
+                let status = stop_child(&mut child, group, stop_signal, stop_timeout).await;
+                return RunServerOutcome::TimedOutWhileStarting(status);
+            }
+        };
+
+        drop(rules);
+        drop(failure);
+
+        let time_to_ready = started.elapsed();
+        config.metrics.record_time_to_ready(time_to_ready);
+        config.summary.record_ready(time_to_ready);
+
+        let rules: Vec<(String, Duration)> = satisfied
+            .rules
+            .into_iter()
+            .map(|rule| (rule.rule, rule.at.saturating_duration_since(started)))
+            .collect();
+
+        for (rule, elapsed) in &rules {
+            event!(Level::INFO, ?elapsed, "rule {:?} satisfied", rule);
+        }
+
+        event!(
+            Level::INFO,
+            group = satisfied.group,
+            ?time_to_ready,
+            "server is now ready"
+        );
+        if previous.is_some() {
+            config.status.starting(attempt, child.id());
+            config.state_files.spawned(child.id());
+        }
+        config.metrics.set_state(State::Ready);
+        config.status.ready();
+        config.state_files.ready();
+        config.notify_ready();
+        config.record(
+            attempt,
+            Transition::Ready {
+                group: satisfied.group,
+                rules,
+            },
+        );
+        config.send_webhook(
+            probes.client,
+            HookContext {
+                event: Event::Ready,
+                attempt,
+                pid: child.id(),
+                exit_code: None,
+            },
+        );
+
+        // With --overlap-restarts, the instance that this one replaces is
+        // only stopped once this one is ready
+        if let Some(previous) = previous.take() {
+            previous.stop(config).await;
+        }
+
+        // State is now started! Wait for the server to exit, or for it to
+        // fail its liveness checks.
+        let liveness = match config.liveness {
+            Some(ref liveness) => Either::Left(liveness.monitor(probes, events.clone()).fuse()),
+            None => Either::Right(pending()),
+        };
+        pin_mut!(liveness);
+
+        let memory = match (&config.memory_limit, child.id()) {
+            (Some(limit), Some(pid)) => Either::Left(limit.monitor(pid).fuse()),
+            _ => Either::Right(pending()),
+        };
+        pin_mut!(memory);
+
+        let max_uptime = match config.max_uptime {
+            Some(duration) => Either::Left(sleep_until(Instant::now() + duration).fuse()),
+            None => Either::Right(pending()),
+        };
+        pin_mut!(max_uptime);
+
+        let exec = match config.exec {
+            Some(ref exec) => Either::Left(run_exec(exec).fuse()),
+            None => Either::Right(pending()),
+        };
+        pin_mut!(exec);
+
+        let on_ready = config
+            .hooks
+            .on_ready
+            .run(
+                probes.client,
+                HookContext {
+                    event: Event::Ready,
+                    attempt,
+                    pid: child.id(),
+                    exit_code: None,
+                },
+            )
+            .fuse();
+        pin_mut!(on_ready);
+
+        // The ready hook runs alongside everything else, so that a slow hook
+        // doesn't delay noticing that the server exited
+        loop {
+            break select_biased! {
+                signal = signals.recv().fuse() => {
+                    config.notify("STOPPING=1");
+                    let status = stop_child(&mut child, group, signal, stop_timeout).await;
+                    RunServerOutcome::Terminated(signal, status)
+                },
+                command = commands.recv().fuse() => match command {
+                    ControlCommand::Restart if config.overlap_restarts => {
+                        event!(Level::INFO, "starting a new instance before stopping this one");
+                        RunServerOutcome::HandedOff(None)
+                    }
+                    command => {
+                        if command == ControlCommand::Stop {
+                            config.notify("STOPPING=1");
+                        }
+
+                        let status = stop_child(&mut child, group, stop_signal, stop_timeout).await;
+                        RunServerOutcome::from_command(command, status)
+                    }
+                },
+                () = liveness => {
+                    event!(Level::WARN, "server failed its liveness checks; stopping it");
+                    let status = stop_child(&mut child, group, stop_signal, stop_timeout).await;
+                    RunServerOutcome::FailedLiveness(status)
+                },
+                () = memory => {
+                    config.metrics.record_memory_restart();
+                    let status = stop_child(&mut child, group, stop_signal, stop_timeout).await;
+                    RunServerOutcome::ExceededMemory(status)
+                },
+                () = max_uptime => {
+                    // This is a planned restart, so it's treated like a
+                    // restart command, rather than a failure
+                    event!(Level::INFO, "server reached --max-uptime; restarting it");
+
+                    if config.overlap_restarts {
+                        break RunServerOutcome::HandedOff(None);
+                    }
+
+                    let status = stop_child(&mut child, group, stop_signal, stop_timeout).await;
+                    RunServerOutcome::Restarted(status)
+                },
+                status = child.wait().fuse() => RunServerOutcome::ExitedWhileReady(status),
+                status = exec => {
+                    // The follow-up command is done, so we're done with the server
+                    config.notify("STOPPING=1");
+                    let _ = stop_child(&mut child, group, stop_signal, stop_timeout).await;
+                    RunServerOutcome::ExecFinished(status)
+                },
+                () = on_ready => continue,
+            };
+        }
+    }
+    .await;
+
+    // The server keeps running until its replacement is ready
+    if let RunServerOutcome::HandedOff(None) = outcome {
+        return RunServerOutcome::HandedOff(Some(Box::new(PreviousServer {
+            attempt,
+            child,
+            _tracked: tracked,
+            group,
+            sidecars,
+            output_task,
+            log_file_task,
+        })));
+    }
+
+    // If this instance never became ready, the one that it was replacing is
+    // stopped along with it
+    if let Some(previous) = previous {
+        previous.stop(config).await;
+    }
+
+    // The server has exited. Anything it left behind in its process group
+    // would keep its output pipes open, so kill it.
+    if let Some(group) = group {
+        kill_group(group);
+    }
+
+    // Finish forwarding output. Note that the output tasks won't finish until
+    // every log line sender, including the one in `events`, has been dropped.
+    drop(events);
+
+    // The sidecars only run for as long as the server does
+    stop_sidecars(sidecars, stop_signal, stop_timeout).await;
+    let _ = output_task.await;
+
+    if let Some(log_file_task) = log_file_task {
+        let _ = log_file_task.await;
+    }
+
+    let recent = match crash_context_task {
+        Some(task) => task.await.ok(),
+        None => None,
+    };
+
+    if let RunServerOutcome::ExitedWhileStarting(status)
+    | RunServerOutcome::TimedOutWhileStarting(status)
+    | RunServerOutcome::FailedWhileStarting(status) = &outcome
+    {
+        if let Some(ref recent) = recent {
+            recent.dump();
+        }
+
+        if let Some(ref command) = config.on_failure {
+            let context = HookContext {
+                event: Event::Failure,
+                attempt,
+                pid: None,
+                exit_code: Some(exit_code(status)),
+            };
+            let output = recent.map(|recent| recent.contents()).unwrap_or_default();
+            run_failure_command(command, context, &output).await;
+        }
+    }
 
-    RunServerOutcome::ExitedWhileReady
+    outcome
 }
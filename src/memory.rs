@@ -0,0 +1,100 @@
+#[cfg(unix)]
+use std::fs;
+use std::{error::Error, fmt, io, str::FromStr, time::Duration};
+
+use tokio::time::sleep;
+use tracing::{debug, warn};
+
+/// A number of bytes, parsed from an integer with an optional `K`, `M`, or
+/// `G` suffix (powers of 1024; `KiB` and `KB` are also accepted).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ByteSize(pub u64);
+
+#[derive(Debug, Clone)]
+pub struct InvalidByteSize(String);
+
+impl fmt::Display for InvalidByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid size {:?}; expected a number of bytes, like 512M",
+            self.0
+        )
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = InvalidByteSize;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (count, suffix) = s.split_at(split);
+
+        let multiplier: u64 = match suffix.trim_start().to_ascii_uppercase().as_str() {
+            "" | "B" => 1,
+            "K" | "KB" | "KIB" => 1 << 10,
+            "M" | "MB" | "MIB" => 1 << 20,
+            "G" | "GB" | "GIB" => 1 << 30,
+            _ => return Err(InvalidByteSize(s.to_owned())),
+        };
+
+        count
+            .parse::<u64>()
+            .ok()
+            .and_then(|count| count.checked_mul(multiplier))
+            .map(ByteSize)
+            .ok_or_else(|| InvalidByteSize(s.to_owned()))
+    }
+}
+
+/// Read the resident set size of a process, in bytes
+#[cfg(unix)]
+fn read_rss(pid: u32) -> io::Result<u64> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid))?;
+
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))
+        .and_then(|value| value.trim().strip_suffix("kB"))
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(|kilobytes| kilobytes * 1024)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no VmRSS in process status"))
+}
+
+#[cfg(windows)]
+fn read_rss(pid: u32) -> io::Result<u64> {
+    defibrillator::windows::working_set(pid)
+}
+
+/// A limit on the memory used by the server once it's ready
+#[derive(Debug)]
+pub struct MemoryLimit {
+    /// The maximum resident set size
+    pub max: ByteSize,
+
+    /// How often to sample the server's memory usage
+    pub interval: Duration,
+}
+
+impl MemoryLimit {
+    /// Sample the memory usage of the process `pid` forever, returning only
+    /// once it has exceeded the limit.
+    #[tracing::instrument(name = "memory", skip(self))]
+    pub async fn monitor(&self, pid: u32) {
+        loop {
+            sleep(self.interval).await;
+
+            match read_rss(pid) {
+                Ok(rss) if rss > self.max.0 => {
+                    warn!(rss, max = self.max.0, "server exceeded its memory limit");
+                    return;
+                }
+                Ok(rss) => debug!(rss, "sampled memory usage"),
+                Err(err) => {
+                    let err: &dyn Error = &err;
+                    debug!(error = err, "failed to sample memory usage");
+                }
+            }
+        }
+    }
+}
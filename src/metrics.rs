@@ -0,0 +1,259 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, AtomicU8, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use libc::c_int;
+use tokio::net::TcpListener;
+
+use defibrillator::output::Stream;
+
+use crate::http_server::{self, Response};
+use crate::signals::signal_name;
+
+/// The upper bounds of the time-to-ready histogram buckets, in seconds
+const TIME_TO_READY_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+/// The lifecycle state of the server, as reported by the `state` gauge
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum State {
+    Stopped,
+    Starting,
+    Ready,
+}
+
+impl State {
+    const ALL: [State; 3] = [State::Stopped, State::Starting, State::Ready];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            State::Stopped => "stopped",
+            State::Starting => "starting",
+            State::Ready => "ready",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [u64; TIME_TO_READY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+/// Counters and gauges describing the supervised server, rendered in the
+/// Prometheus text exposition format
+#[derive(Debug)]
+pub struct Metrics {
+    attempts: AtomicU64,
+    state: AtomicU8,
+    time_to_ready: Mutex<Histogram>,
+    exit_codes: Mutex<BTreeMap<i32, u64>>,
+    exit_signals: Mutex<BTreeMap<c_int, u64>>,
+    memory_restarts: AtomicU64,
+    stdout_lines: Arc<AtomicU64>,
+    stderr_lines: Arc<AtomicU64>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            attempts: AtomicU64::new(0),
+            state: AtomicU8::new(State::Stopped as u8),
+            time_to_ready: Mutex::default(),
+            exit_codes: Mutex::default(),
+            exit_signals: Mutex::default(),
+            memory_restarts: AtomicU64::new(0),
+            stdout_lines: Arc::default(),
+            stderr_lines: Arc::default(),
+        }
+    }
+
+    pub fn record_attempt(&self) {
+        self.attempts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_state(&self, state: State) {
+        self.state.store(state as u8, Ordering::Relaxed);
+    }
+
+    pub fn record_time_to_ready(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let mut histogram = self.time_to_ready.lock().unwrap();
+
+        for (bucket, &bound) in histogram.buckets.iter_mut().zip(TIME_TO_READY_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+
+    /// Record an exit of the server, with the signal that killed it, if it
+    /// was killed by one
+    pub fn record_exit(&self, exit_code: i32, signal: Option<c_int>) {
+        *self
+            .exit_codes
+            .lock()
+            .unwrap()
+            .entry(exit_code)
+            .or_insert(0) += 1;
+
+        if let Some(signal) = signal {
+            *self.exit_signals.lock().unwrap().entry(signal).or_insert(0) += 1;
+        }
+    }
+
+    pub fn record_memory_restart(&self) {
+        self.memory_restarts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The counter of lines forwarded from the given stream, to be passed
+    /// to `handle_output`
+    pub fn log_lines(&self, stream: Stream) -> Arc<AtomicU64> {
+        Arc::clone(match stream {
+            Stream::Stdout => &self.stdout_lines,
+            Stream::Stderr => &self.stderr_lines,
+        })
+    }
+}
+
+/// Renders every metric in the Prometheus text exposition format
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "# HELP defibrillator_attempts_total Number of times the server has been launched"
+        )?;
+        writeln!(f, "# TYPE defibrillator_attempts_total counter")?;
+        writeln!(
+            f,
+            "defibrillator_attempts_total {}",
+            self.attempts.load(Ordering::Relaxed)
+        )?;
+
+        let current = self.state.load(Ordering::Relaxed);
+        writeln!(
+            f,
+            "# HELP defibrillator_state Whether the server is in each lifecycle state"
+        )?;
+        writeln!(f, "# TYPE defibrillator_state gauge")?;
+        for &state in State::ALL.iter() {
+            writeln!(
+                f,
+                "defibrillator_state{{state=\"{}\"}} {}",
+                state.as_str(),
+                (state as u8 == current) as u8
+            )?;
+        }
+
+        {
+            let histogram = self.time_to_ready.lock().unwrap();
+            writeln!(
+                f,
+                "# HELP defibrillator_time_to_ready_seconds Time from launching the server until its rules were satisfied"
+            )?;
+            writeln!(f, "# TYPE defibrillator_time_to_ready_seconds histogram")?;
+            for (count, bound) in histogram.buckets.iter().zip(TIME_TO_READY_BUCKETS) {
+                writeln!(
+                    f,
+                    "defibrillator_time_to_ready_seconds_bucket{{le=\"{}\"}} {}",
+                    bound, count
+                )?;
+            }
+            writeln!(
+                f,
+                "defibrillator_time_to_ready_seconds_bucket{{le=\"+Inf\"}} {}",
+                histogram.count
+            )?;
+            writeln!(
+                f,
+                "defibrillator_time_to_ready_seconds_sum {}",
+                histogram.sum
+            )?;
+            writeln!(
+                f,
+                "defibrillator_time_to_ready_seconds_count {}",
+                histogram.count
+            )?;
+        }
+
+        writeln!(
+            f,
+            "# HELP defibrillator_exits_total Number of times the server has exited, by exit code"
+        )?;
+        writeln!(f, "# TYPE defibrillator_exits_total counter")?;
+        for (code, count) in self.exit_codes.lock().unwrap().iter() {
+            writeln!(
+                f,
+                "defibrillator_exits_total{{code=\"{}\"}} {}",
+                code, count
+            )?;
+        }
+
+        writeln!(
+            f,
+            "# HELP defibrillator_signal_exits_total Number of times the server has been killed by a signal, by signal"
+        )?;
+        writeln!(f, "# TYPE defibrillator_signal_exits_total counter")?;
+        for (&signal, count) in self.exit_signals.lock().unwrap().iter() {
+            writeln!(
+                f,
+                "defibrillator_signal_exits_total{{signal=\"{}\"}} {}",
+                signal_name(signal),
+                count
+            )?;
+        }
+
+        writeln!(
+            f,
+            "# HELP defibrillator_memory_restarts_total Number of times the server was restarted for exceeding --max-memory"
+        )?;
+        writeln!(f, "# TYPE defibrillator_memory_restarts_total counter")?;
+        writeln!(
+            f,
+            "defibrillator_memory_restarts_total {}",
+            self.memory_restarts.load(Ordering::Relaxed)
+        )?;
+
+        writeln!(
+            f,
+            "# HELP defibrillator_log_lines_total Number of lines of output forwarded from the server"
+        )?;
+        writeln!(f, "# TYPE defibrillator_log_lines_total counter")?;
+        writeln!(
+            f,
+            "defibrillator_log_lines_total{{stream=\"stdout\"}} {}",
+            self.stdout_lines.load(Ordering::Relaxed)
+        )?;
+        writeln!(
+            f,
+            "defibrillator_log_lines_total{{stream=\"stderr\"}} {}",
+            self.stderr_lines.load(Ordering::Relaxed)
+        )?;
+
+        Ok(())
+    }
+}
+
+/// Serve the metrics over HTTP on `listener` forever. This answers
+/// `GET /metrics` and nothing else.
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) {
+    http_server::serve(listener, "metrics", move |path| match path {
+        b"/metrics" => Some(Response::new(
+            "200 OK",
+            "text/plain; version=0.0.4",
+            metrics.to_string(),
+        )),
+        _ => None,
+    })
+    .await
+}
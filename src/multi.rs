@@ -0,0 +1,526 @@
+use std::{
+    collections::HashMap,
+    env,
+    error::Error,
+    fs,
+    io::{self, Write},
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd},
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
+};
+
+use futures::{select_biased, FutureExt};
+use libc::c_int;
+use structopt::StructOpt;
+use tokio::{
+    fs::File,
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader},
+    process::Command,
+    sync::mpsc,
+};
+use tracing::{error, info, warn};
+
+use crate::exit_code;
+use crate::signals::TerminationSignals;
+
+/// Run several servers, each supervised by its own defibrillator, starting
+/// each one only once the ones it depends on are ready. If any of them exits,
+/// the rest are stopped.
+#[derive(StructOpt)]
+#[structopt(name = "defibrillator multi")]
+pub struct MultiArgs {
+    /// The file describing the servers. Each line looks like
+    /// `name: <arguments>` or `name (after a, b): <arguments>`, where the
+    /// arguments are the defibrillator arguments for that server, quoted as
+    /// in a shell, like `-l info -r "tcp port 80 ready" -- ./server`. Lines
+    /// starting with `#` are comments.
+    file: PathBuf,
+
+    /// Filter directives to pass to the logger
+    #[structopt(short, long, default_value = "info")]
+    pub log_filters: String,
+}
+
+/// One of the servers in a process file
+#[derive(Debug, Clone)]
+struct Process {
+    name: String,
+
+    /// The names of the processes that have to be ready before this one is
+    /// started
+    after: Vec<String>,
+
+    /// The defibrillator arguments for this process
+    args: Vec<String>,
+}
+
+fn invalid(line: usize, message: impl Into<String>) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("line {}: {}", line, message.into()),
+    )
+}
+
+fn is_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Split a line into words, like a shell would: words are separated by
+/// whitespace, and may be quoted with `'` or `"`. Backslashes escape the next
+/// character, except inside single quotes.
+fn split_words(line: &str) -> Result<Vec<String>, &'static str> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err("unterminated single quote"),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\')) => word.push(c),
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => return Err("unterminated double quote"),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err("unterminated double quote"),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => return Err("trailing backslash"),
+            },
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    words.extend(word);
+    Ok(words)
+}
+
+fn parse_process(line: usize, content: &str) -> io::Result<Process> {
+    let (head, args) = content
+        .split_once(':')
+        .ok_or_else(|| invalid(line, "expected `name: <arguments>`"))?;
+
+    let head = head.trim();
+    let (name, after) = match head.split_once('(') {
+        None => (head, Vec::new()),
+        Some((name, after)) => {
+            let after = after
+                .trim()
+                .strip_suffix(')')
+                .and_then(|after| after.strip_prefix("after "))
+                .ok_or_else(|| invalid(line, "expected `(after <names>)`"))?;
+
+            (
+                name.trim_end(),
+                after
+                    .split(',')
+                    .map(|name| name.trim().to_owned())
+                    .collect(),
+            )
+        }
+    };
+
+    if let Some(name) = Some(name)
+        .into_iter()
+        .chain(after.iter().map(String::as_str))
+        .find(|name| !is_name(name))
+    {
+        return Err(invalid(line, format!("invalid process name {:?}", name)));
+    }
+
+    let args = split_words(args).map_err(|message| invalid(line, message))?;
+
+    if args.is_empty() {
+        return Err(invalid(line, "expected defibrillator arguments"));
+    }
+
+    Ok(Process {
+        name: name.to_owned(),
+        after,
+        args,
+    })
+}
+
+/// Check that the dependencies between processes can be satisfied: each one
+/// has to exist, and there can't be a cycle
+fn check_dependencies(processes: &[Process]) -> Result<(), String> {
+    let indexes: HashMap<&str, usize> = processes
+        .iter()
+        .enumerate()
+        .map(|(index, process)| (process.name.as_str(), index))
+        .collect();
+
+    for process in processes {
+        if let Some(name) = process
+            .after
+            .iter()
+            .find(|name| !indexes.contains_key(name.as_str()))
+        {
+            return Err(format!(
+                "{:?} depends on unknown process {:?}",
+                process.name, name
+            ));
+        }
+    }
+
+    // 0 is unvisited, 1 is being visited, and 2 is done
+    fn visit(
+        index: usize,
+        processes: &[Process],
+        indexes: &HashMap<&str, usize>,
+        states: &mut [u8],
+    ) -> Result<(), String> {
+        match states[index] {
+            1 => {
+                return Err(format!(
+                    "{:?} is part of a dependency cycle",
+                    processes[index].name
+                ))
+            }
+            2 => return Ok(()),
+            _ => states[index] = 1,
+        }
+
+        for name in &processes[index].after {
+            visit(indexes[name.as_str()], processes, indexes, states)?;
+        }
+
+        states[index] = 2;
+        Ok(())
+    }
+
+    let mut states = vec![0; processes.len()];
+    (0..processes.len()).try_for_each(|index| visit(index, processes, &indexes, &mut states))
+}
+
+/// Read the processes from a process file
+fn read_process_file(path: &Path) -> io::Result<Vec<Process>> {
+    let processes = fs::read_to_string(path)?
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(line, content)| parse_process(line, content))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    if processes.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "no processes"));
+    }
+
+    for (index, process) in processes.iter().enumerate() {
+        if processes[..index]
+            .iter()
+            .any(|other| other.name == process.name)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("process {:?} is defined more than once", process.name),
+            ));
+        }
+    }
+
+    check_dependencies(&processes)
+        .map_err(|message| io::Error::new(io::ErrorKind::InvalidData, message))?;
+
+    Ok(processes)
+}
+
+/// Something that happened to one of the processes, by index
+#[derive(Debug)]
+enum ProcessEvent {
+    Ready(usize),
+    Exited(usize, io::Result<ExitStatus>),
+}
+
+/// The descriptor that each process's defibrillator reports readiness on,
+/// with --ready-fd
+const READY_FD: c_int = 3;
+
+/// Create a pipe, returning the read and write ends. Both are close-on-exec.
+fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+
+    // Safety: pipe2 only writes the two descriptors
+    match unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } {
+        -1 => Err(io::Error::last_os_error()),
+        // Safety: the descriptors were just created, and nothing else owns
+        // them
+        _ => Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) }),
+    }
+}
+
+/// Copy lines from one of a process's output streams to our own, prefixed
+/// with the process's name
+async fn forward_output(
+    pipe: impl AsyncRead + Unpin,
+    prefix: String,
+    mut output: impl Write,
+) -> io::Result<()> {
+    let mut pipe = BufReader::new(pipe);
+    let mut line = Vec::new();
+
+    loop {
+        line.clear();
+
+        if pipe.read_until(b'\n', &mut line).await? == 0 {
+            return Ok(());
+        }
+
+        if !line.ends_with(b"\n") {
+            line.push(b'\n');
+        }
+
+        let mut prefixed = prefix.clone().into_bytes();
+        prefixed.extend_from_slice(&line);
+        output.write_all(&prefixed)?;
+        output.flush()?;
+    }
+}
+
+/// Launch a process's defibrillator, reporting when it's ready and when it
+/// exits. Returns its PID.
+fn spawn_process(
+    index: usize,
+    process: &Process,
+    prefix: String,
+    events: mpsc::UnboundedSender<ProcessEvent>,
+) -> io::Result<u32> {
+    let (ready_reader, ready_writer) = pipe()?;
+    let ready_writer_fd = ready_writer.as_raw_fd();
+
+    let mut command = Command::new(env::current_exe()?);
+    command
+        .arg("--ready-fd")
+        .arg(READY_FD.to_string())
+        .args(&process.args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        // Signals from the terminal go to us, and we pass them on in order
+        .process_group(0);
+
+    // Safety: dup2 and fcntl are async-signal-safe
+    unsafe {
+        command.pre_exec(move || {
+            match ready_writer_fd {
+                // dup2 does nothing if the descriptors are the same, so the
+                // flag has to be cleared separately
+                READY_FD => libc::fcntl(READY_FD, libc::F_SETFD, 0),
+                fd => libc::dup2(fd, READY_FD),
+            };
+
+            Ok(())
+        });
+    }
+
+    let mut child = command.spawn()?;
+    drop(ready_writer);
+
+    // Unwrap safety: the child hasn't been waited for, so it has a PID
+    let pid = child.id().unwrap();
+
+    let stdout = child
+        .stdout
+        .take()
+        .map(|stdout| tokio::spawn(forward_output(stdout, prefix.clone(), io::stdout())));
+    let stderr = child
+        .stderr
+        .take()
+        .map(|stderr| tokio::spawn(forward_output(stderr, prefix, io::stderr())));
+
+    let ready_events = events.clone();
+    tokio::spawn(async move {
+        let mut ready = File::from_std(ready_reader.into());
+        let mut content = Vec::new();
+
+        if ready.read_to_end(&mut content).await.is_ok() && content.starts_with(b"READY") {
+            let _ = ready_events.send(ProcessEvent::Ready(index));
+        }
+    });
+
+    tokio::spawn(async move {
+        let status = child.wait().await;
+
+        // Report the exit only once all of its output has been forwarded
+        for task in stdout.into_iter().chain(stderr) {
+            let _ = task.await;
+        }
+
+        let _ = events.send(ProcessEvent::Exited(index, status));
+    });
+
+    Ok(pid)
+}
+
+/// The state of the processes while they're being supervised
+struct Supervisor {
+    processes: Vec<Process>,
+    events: mpsc::UnboundedReceiver<ProcessEvent>,
+    sender: mpsc::UnboundedSender<ProcessEvent>,
+
+    /// The width of the longest name, for aligning the output
+    width: usize,
+
+    ready: Vec<bool>,
+
+    /// The PIDs of the processes that are running, in the order that they
+    /// were started
+    running: Vec<(usize, u32)>,
+}
+
+impl Supervisor {
+    /// Start each process that hasn't been started yet, and whose
+    /// dependencies are all ready
+    fn start_ready_processes(&mut self) -> io::Result<()> {
+        for index in 0..self.processes.len() {
+            let process = &self.processes[index];
+
+            let started =
+                self.ready[index] || self.running.iter().any(|&(running, _)| running == index);
+            let unblocked = process.after.iter().all(|name| {
+                self.processes
+                    .iter()
+                    .position(|process| process.name == *name)
+                    .is_some_and(|index| self.ready[index])
+            });
+
+            if started || !unblocked {
+                continue;
+            }
+
+            let prefix = format!("{:width$} | ", process.name, width = self.width);
+            let pid = spawn_process(index, process, prefix, self.sender.clone())?;
+            info!(process = %process.name, pid, "started process");
+            self.running.push((index, pid));
+        }
+
+        Ok(())
+    }
+
+    /// Stop every running process with `signal`, most recently started first,
+    /// so that each one is stopped before the ones it depends on
+    async fn stop_all(&mut self, signal: c_int) {
+        while let Some((index, pid)) = self.running.pop() {
+            info!(process = %self.processes[index].name, signal, "stopping process");
+
+            // Safety: kill has no memory safety implications
+            unsafe { libc::kill(pid as libc::pid_t, signal) };
+
+            while let Some(event) = self.events.recv().await {
+                match event {
+                    ProcessEvent::Exited(exited, _) if exited == index => break,
+                    ProcessEvent::Exited(exited, _) => {
+                        self.running.retain(|&(running, _)| running != exited)
+                    }
+                    ProcessEvent::Ready(..) => {}
+                }
+            }
+        }
+    }
+}
+
+/// Supervise the processes, returning an exit code for defibrillator
+pub async fn multi(args: MultiArgs) -> i32 {
+    let processes = match read_process_file(&args.file) {
+        Ok(processes) => processes,
+        Err(err) => {
+            let err: &dyn Error = &err;
+            error!(error = err, path = %args.file.display(), "Failed to read the process file");
+            return 1;
+        }
+    };
+
+    let mut signals = match TerminationSignals::new(true) {
+        Ok(signals) => signals,
+        Err(err) => {
+            let err: &dyn Error = &err;
+            error!(error = err, "Failed to install signal handlers");
+            return 1;
+        }
+    };
+
+    let (sender, events) = mpsc::unbounded_channel();
+
+    let mut supervisor = Supervisor {
+        width: processes
+            .iter()
+            .map(|process| process.name.len())
+            .max()
+            .unwrap_or(0),
+        ready: vec![false; processes.len()],
+        running: Vec::new(),
+        processes,
+        events,
+        sender,
+    };
+
+    if let Err(err) = supervisor.start_ready_processes() {
+        let err: &dyn Error = &err;
+        error!(error = err, "Failed to start a process");
+        supervisor.stop_all(libc::SIGTERM).await;
+        return 1;
+    }
+
+    loop {
+        select_biased! {
+            signal = signals.recv().fuse() => {
+                info!(signal, "shutting down");
+                supervisor.stop_all(signal).await;
+                return 128 + signal;
+            }
+            // Unwrap safety: the supervisor keeps a sender
+            event = supervisor.events.recv().fuse() => match event.unwrap() {
+                ProcessEvent::Ready(index) => {
+                    info!(process = %supervisor.processes[index].name, "process is ready");
+                    supervisor.ready[index] = true;
+
+                    if let Err(err) = supervisor.start_ready_processes() {
+                        let err: &dyn Error = &err;
+                        error!(error = err, "Failed to start a process");
+                        supervisor.stop_all(libc::SIGTERM).await;
+                        return 1;
+                    }
+                }
+                ProcessEvent::Exited(index, status) => {
+                    let code = exit_code(&status);
+                    warn!(
+                        process = %supervisor.processes[index].name,
+                        exit_code = code,
+                        "process exited; stopping the others"
+                    );
+
+                    supervisor.running.retain(|&(running, _)| running != index);
+                    supervisor.stop_all(libc::SIGTERM).await;
+                    return code;
+                }
+            }
+        }
+    }
+}
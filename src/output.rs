@@ -0,0 +1,329 @@
+use std::{
+    borrow::Cow,
+    fmt, io,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, LazyLock,
+    },
+    time::Duration,
+};
+
+use bytes::{Bytes, BytesMut};
+use futures::future::join;
+use memchr::memchr;
+use regex::bytes::Regex;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{
+        broadcast::{error::RecvError, Sender},
+        Notify,
+    },
+    task::yield_now,
+    time::sleep,
+};
+use tracing::warn;
+
+/// Which of the child's output pipes a line was read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// A single line of output from the child process
+#[derive(Debug, Clone)]
+pub struct LogLine {
+    pub stream: Stream,
+    pub content: Bytes,
+}
+
+/// ANSI escape sequences: CSI sequences, like colors and cursor movement; OSC
+/// sequences, like window titles and hyperlinks; and two-character escapes
+static ANSI_ESCAPE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\x1b(?:\[[0-?]*[ -/]*[@-~]|\][^\x07\x1b]*(?:\x07|\x1b\\)|[@-_])").unwrap()
+});
+
+/// Remove ANSI escape sequences from a line of output, so that it can be
+/// matched as plain text
+pub fn strip_ansi(content: &[u8]) -> Cow<'_, [u8]> {
+    match memchr(b'\x1b', content) {
+        None => Cow::Borrowed(content),
+        Some(..) => ANSI_ESCAPE.replace_all(content, &b""[..]),
+    }
+}
+
+/// What to do when the log line channel is full, meaning that sending another
+/// line would push out the oldest one before every subscriber has seen it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LagPolicy {
+    /// Send the line anyway; subscribers that are behind miss lines
+    Drop,
+
+    /// Stop reading from the server until every subscriber catches up, which
+    /// applies backpressure to the server's output
+    Block,
+
+    /// Send the line anyway, but report the overflow, so that the attempt
+    /// can be failed
+    Fail,
+}
+
+#[derive(Debug, Clone)]
+pub struct InvalidLagPolicy(String);
+
+impl fmt::Display for InvalidLagPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown lag policy {:?}; expected drop, block, or fail-attempt",
+            self.0
+        )
+    }
+}
+
+impl FromStr for LagPolicy {
+    type Err = InvalidLagPolicy;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(LagPolicy::Drop),
+            "block" => Ok(LagPolicy::Block),
+            "fail-attempt" => Ok(LagPolicy::Fail),
+            _ => Err(InvalidLagPolicy(s.to_owned())),
+        }
+    }
+}
+
+/// The size of the log line channel, and how to handle it filling up
+#[derive(Debug, Clone)]
+pub struct LogBuffer {
+    pub capacity: usize,
+    pub policy: LagPolicy,
+
+    /// Notified when a line is sent while the channel is full, with
+    /// `LagPolicy::Fail`
+    overflowed: Arc<Notify>,
+}
+
+impl LogBuffer {
+    pub fn new(capacity: usize, policy: LagPolicy) -> Self {
+        Self {
+            // Broadcast channels round their capacity up to a power of two,
+            // and only lag once that many lines are unread, so round up here
+            // too, so that `send` sees the channel's real capacity.
+            capacity: capacity.max(1).next_power_of_two(),
+            policy,
+            overflowed: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Wait until a line is sent while the channel is full. This only
+    /// happens with `LagPolicy::Fail`.
+    pub async fn overflowed(&self) {
+        self.overflowed.notified().await
+    }
+
+    /// Publish a line, applying the lag policy if the channel is full
+    async fn send(&self, broadcast: &Sender<LogLine>, line: LogLine) {
+        if broadcast.len() >= self.capacity {
+            match self.policy {
+                LagPolicy::Drop => {}
+                LagPolicy::Block => {
+                    // Broadcast channels don't notify senders when lines are
+                    // received, so poll until there's room. Subscribers that
+                    // are keeping up usually catch up after a few yields;
+                    // beyond that, back off so that a stalled one doesn't
+                    // cost a busy loop.
+                    let mut polls: u32 = 0;
+                    while broadcast.len() >= self.capacity {
+                        match polls {
+                            0..=15 => yield_now().await,
+                            _ => sleep(Duration::from_millis(1)).await,
+                        }
+                        polls += 1;
+                    }
+                }
+                LagPolicy::Fail => self.overflowed.notify_one(),
+            }
+        }
+
+        let _ = broadcast.send(line);
+    }
+}
+
+/// Read lines from `pipe`, publish them to `broadcast`, and forward them to
+/// `output`. Several pipes may share a single broadcast channel; each one only
+/// forwards the lines that originated from its own `stream`. `forwarded` is
+/// incremented for each line written to `output`. `log_buffer` describes
+/// the channel, which should have been created with its capacity. Lines
+/// longer than `max_line_length` are split into chunks of that length, so
+/// that output without newlines can't grow the buffer without limit.
+pub async fn handle_output<T, W>(
+    mut pipe: T,
+    mut output: W,
+    stream: Stream,
+    broadcast: Sender<LogLine>,
+    log_buffer: LogBuffer,
+    max_line_length: Option<usize>,
+    forwarded: Arc<AtomicU64>,
+) -> io::Result<()>
+where
+    T: Unpin + AsyncRead,
+    W: Unpin + AsyncWrite,
+{
+    let mut lines = broadcast.subscribe();
+
+    let output_task = async move {
+        loop {
+            match lines.recv().await {
+                Ok(line) if line.stream == stream => {
+                    let mut content = line.content;
+                    output.write_all_buf(&mut content).await?;
+                    forwarded.fetch_add(1, Ordering::Relaxed);
+                }
+                Ok(_) => {}
+                Err(err) => match err {
+                    RecvError::Closed => return output.flush().await,
+                    RecvError::Lagged(lines) => {
+                        warn!(missed = lines, ?stream, "output forwarding lagged")
+                    }
+                },
+            }
+        }
+    };
+
+    let read_task = async move {
+        let mut buffer = BytesMut::with_capacity(4096);
+
+        // The number of bytes at the front of the buffer that are already
+        // known not to contain a newline
+        let mut scanned: usize = 0;
+
+        loop {
+            buffer.reserve(4096);
+
+            match pipe.read_buf(&mut buffer).await {
+                Ok(0) => {
+                    // The last line might not have ended with a newline
+                    if !buffer.is_empty() {
+                        let content = buffer.split().freeze();
+                        log_buffer
+                            .send(&broadcast, LogLine { stream, content })
+                            .await;
+                    }
+
+                    return Ok(());
+                }
+                Ok(..) => {}
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+
+            // A single read may contain any number of lines; send all of the
+            // complete ones and keep the remainder for the next read
+            while let Some(idx) = memchr(b'\n', &buffer[scanned..]) {
+                let mut end = scanned + idx + 1;
+                scanned = 0;
+
+                // Complete lines are split the same way as the remainder
+                if let Some(max_line_length) = max_line_length {
+                    while end > max_line_length {
+                        let content = buffer.split_to(max_line_length).freeze();
+                        end -= max_line_length;
+                        log_buffer
+                            .send(&broadcast, LogLine { stream, content })
+                            .await;
+                    }
+                }
+
+                let content = buffer.split_to(end).freeze();
+                log_buffer
+                    .send(&broadcast, LogLine { stream, content })
+                    .await;
+            }
+
+            // Whatever's left has no newline; if it's too long, send it in
+            // pieces rather than waiting for the rest of the line
+            if let Some(max_line_length) = max_line_length {
+                while buffer.len() >= max_line_length {
+                    let content = buffer.split_to(max_line_length).freeze();
+                    log_buffer
+                        .send(&broadcast, LogLine { stream, content })
+                        .await;
+                }
+            }
+
+            scanned = buffer.len();
+        }
+    };
+
+    let (read_result, output_result) = join(read_task, output_task).await;
+
+    read_result?;
+    output_result?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::sync::broadcast::channel;
+
+    /// Run `handle_output` over `pipe` to completion, and collect the lines
+    /// that it published
+    async fn lines(pipe: impl Unpin + AsyncRead, max_line_length: Option<usize>) -> Vec<Bytes> {
+        let (broadcast, mut received) = channel(16);
+
+        handle_output(
+            pipe,
+            tokio::io::sink(),
+            Stream::Stdout,
+            broadcast,
+            LogBuffer::new(16, LagPolicy::Drop),
+            max_line_length,
+            Arc::new(AtomicU64::new(0)),
+        )
+        .await
+        .unwrap();
+
+        let mut lines = Vec::new();
+        while let Ok(line) = received.try_recv() {
+            assert_eq!(line.stream, Stream::Stdout);
+            lines.push(line.content);
+        }
+        lines
+    }
+
+    #[tokio::test]
+    async fn several_lines_in_one_read() {
+        let lines = lines(&b"one\ntwo\nthree\n"[..], None).await;
+
+        assert_eq!(lines, ["one\n", "two\n", "three\n"]);
+    }
+
+    #[tokio::test]
+    async fn line_split_across_reads() {
+        let pipe = (&b"first half, "[..]).chain(&b"second half\nnext\n"[..]);
+        let lines = lines(pipe, None).await;
+
+        assert_eq!(lines, ["first half, second half\n", "next\n"]);
+    }
+
+    #[tokio::test]
+    async fn trailing_line_without_newline() {
+        let lines = lines(&b"done\nno newline"[..], None).await;
+
+        assert_eq!(lines, ["done\n", "no newline"]);
+    }
+
+    #[tokio::test]
+    async fn long_lines_are_split() {
+        let pipe = (&b"abcdefghij\nabc"[..]).chain(&b"d\nshort\n"[..]);
+        let lines = lines(pipe, Some(4)).await;
+
+        assert_eq!(lines, ["abcd", "efgh", "ij\n", "abcd", "\n", "shor", "t\n"]);
+    }
+}
@@ -0,0 +1,69 @@
+use std::{
+    fmt, io,
+    net::{Ipv4Addr, TcpListener},
+    str::FromStr,
+};
+
+/// The name of a port for --alloc-port. It's used as an environment variable
+/// name, and as a `$NAME` variable in the command and the rules, so it may
+/// only contain letters, digits, and underscores.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortName(String);
+
+impl PortName {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InvalidPortName(String);
+
+impl fmt::Display for InvalidPortName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid port name {:?}; expected letters, digits, and underscores",
+            self.0
+        )
+    }
+}
+
+impl FromStr for PortName {
+    type Err = InvalidPortName;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            true => Ok(PortName(s.to_owned())),
+            false => Err(InvalidPortName(s.to_owned())),
+        }
+    }
+}
+
+/// A port allocated with --alloc-port
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllocatedPort {
+    pub name: PortName,
+    pub port: u16,
+}
+
+/// Find a free port for each name, by having the kernel pick an ephemeral
+/// port. The ports are all bound at once, so that they're distinct, then
+/// released for the server to bind.
+pub fn alloc_ports(names: &[PortName]) -> io::Result<Vec<AllocatedPort>> {
+    let listeners = names
+        .iter()
+        .map(|_| TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0)))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    names
+        .iter()
+        .zip(&listeners)
+        .map(|(name, listener)| {
+            Ok(AllocatedPort {
+                name: name.clone(),
+                port: listener.local_addr()?.port(),
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,111 @@
+use std::{
+    error::Error,
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+#[cfg(windows)]
+use defibrillator::windows;
+use defibrillator::{duration::Duration as ParsableDuration, rules::client_builder};
+use structopt::StructOpt;
+use tokio::time::timeout;
+#[cfg(unix)]
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::UnixStream,
+};
+use tracing::{event, Level};
+
+/// Ask a running defibrillator whether its server is ready, through its
+/// --control-socket or --health-addr, for use as a Docker `HEALTHCHECK CMD`.
+/// Exits with 0 if the server is ready, or 1 if it isn't, or if defibrillator
+/// couldn't be reached.
+#[derive(StructOpt)]
+#[structopt(name = "defibrillator probe")]
+pub struct ProbeArgs {
+    /// The --control-socket of the defibrillator to ask
+    #[structopt(long, required_unless = "health-addr", conflicts_with = "health-addr")]
+    control_socket: Option<PathBuf>,
+
+    /// The --health-addr of the defibrillator to ask
+    #[structopt(long)]
+    health_addr: Option<SocketAddr>,
+
+    /// How long to wait for a response
+    #[structopt(short = "t", long, default_value = "5s")]
+    timeout: ParsableDuration,
+
+    /// Filter directives to pass to the logger. Only problems are logged by
+    /// default, so that the output is just the result.
+    #[structopt(short, long, default_value = "warn")]
+    pub log_filters: String,
+}
+
+/// Ask the control socket for the server's status, and check its state
+#[cfg(unix)]
+async fn probe_control_socket(path: &Path) -> io::Result<bool> {
+    let stream = UnixStream::connect(path).await?;
+    let (reader, mut writer) = stream.into_split();
+
+    writer.write_all(b"status\n").await?;
+
+    let line = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "no response"))?;
+
+    let status: serde_json::Value = serde_json::from_str(&line)?;
+    Ok(status["state"] == "ready")
+}
+
+/// The control socket is a Unix socket, which Windows doesn't have
+#[cfg(windows)]
+async fn probe_control_socket(_path: &Path) -> io::Result<bool> {
+    Err(windows::unsupported("--control-socket"))
+}
+
+async fn probe_health_addr(addr: SocketAddr) -> Result<bool, reqwest::Error> {
+    let client = client_builder(false).build()?;
+    let response = client.get(format!("http://{}/ready", addr)).send().await?;
+
+    Ok(response.status().is_success())
+}
+
+/// Probe defibrillator, returning an exit code
+pub async fn probe(args: ProbeArgs) -> i32 {
+    let result = timeout(args.timeout.get(), async {
+        match (&args.control_socket, args.health_addr) {
+            (Some(path), _) => probe_control_socket(path)
+                .await
+                .map_err(|err| Box::new(err) as Box<dyn Error>),
+            (None, Some(addr)) => probe_health_addr(addr)
+                .await
+                .map_err(|err| Box::new(err) as Box<dyn Error>),
+            // Structopt requires one or the other
+            (None, None) => unreachable!(),
+        }
+    })
+    .await;
+
+    match result {
+        Ok(Ok(true)) => {
+            event!(Level::INFO, "server is ready");
+            0
+        }
+        Ok(Ok(false)) => {
+            event!(Level::ERROR, "server isn't ready");
+            1
+        }
+        Ok(Err(err)) => {
+            let err: &dyn Error = &*err;
+            event!(Level::ERROR, error = err, "Failed to reach defibrillator");
+            1
+        }
+        Err(..) => {
+            event!(Level::ERROR, "timed out waiting for defibrillator");
+            1
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use std::{
+    error::Error,
+    fmt,
+    net::{Ipv4Addr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    io::copy_bidirectional,
+    net::{TcpListener, TcpStream},
+    time::sleep,
+};
+use tracing::{debug, warn};
+
+use crate::control::Status;
+
+/// A port to forward with --proxy, given as LISTEN:TARGET
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxySpec {
+    /// The port that defibrillator listens on, on every interface
+    pub listen: u16,
+
+    /// The port that the server listens on, on localhost
+    pub target: u16,
+}
+
+#[derive(Debug, Clone)]
+pub struct InvalidProxySpec(String);
+
+impl fmt::Display for InvalidProxySpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid proxy {:?}; expected LISTEN:TARGET, where both are port numbers",
+            self.0
+        )
+    }
+}
+
+impl FromStr for ProxySpec {
+    type Err = InvalidProxySpec;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidProxySpec(s.to_owned());
+        let (listen, target) = s.split_once(':').ok_or_else(invalid)?;
+
+        Ok(Self {
+            listen: listen.parse().map_err(|_| invalid())?,
+            target: target.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl ProxySpec {
+    pub fn listen_addr(&self) -> SocketAddr {
+        (Ipv4Addr::UNSPECIFIED, self.listen).into()
+    }
+
+    pub fn target_addr(&self) -> SocketAddr {
+        (Ipv4Addr::LOCALHOST, self.target).into()
+    }
+}
+
+/// What the --proxy does with connections that arrive while the server isn't
+/// ready
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyMode {
+    /// Keep the connection open until the server is ready, then forward it
+    Hold,
+
+    /// Reset the connection immediately
+    Refuse,
+}
+
+#[derive(Debug, Clone)]
+pub struct InvalidProxyMode(String);
+
+impl fmt::Display for InvalidProxyMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown proxy mode {:?}; expected hold or refuse",
+            self.0
+        )
+    }
+}
+
+impl FromStr for ProxyMode {
+    type Err = InvalidProxyMode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hold" => Ok(ProxyMode::Hold),
+            "refuse" => Ok(ProxyMode::Refuse),
+            _ => Err(InvalidProxyMode(s.to_owned())),
+        }
+    }
+}
+
+/// Accept connections on `listener` for as long as defibrillator runs,
+/// forwarding each one to the server once it's ready. Since the listener is
+/// ours, rather than the server's, clients can connect while the server is
+/// starting or restarting.
+pub async fn serve(
+    listener: TcpListener,
+    target: SocketAddr,
+    mode: ProxyMode,
+    status: Arc<Status>,
+) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(err) => {
+                {
+                    let err: &dyn Error = &err;
+                    warn!(error = err, "failed to accept proxy connection");
+                }
+
+                // Running out of file descriptors is the usual cause, and
+                // retrying immediately would just fail again
+                sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        if mode == ProxyMode::Refuse && !status.is_ready() {
+            debug!(%peer, "server isn't ready; refusing proxy connection");
+
+            // Closing with a zero linger sends a reset, rather than a
+            // graceful shutdown, so the client sees the connection refused
+            let _ = stream.set_zero_linger();
+            continue;
+        }
+
+        let status = Arc::clone(&status);
+        tokio::spawn(async move {
+            if let Err(err) = forward(stream, target, &status).await {
+                let err: &dyn Error = &err;
+                debug!(error = err, %peer, "failed to proxy connection");
+            }
+        });
+    }
+}
+
+async fn forward(
+    mut client: TcpStream,
+    target: SocketAddr,
+    status: &Status,
+) -> std::io::Result<()> {
+    status.wait_until_ready().await;
+
+    let mut server = TcpStream::connect(target).await?;
+    let _ = client.set_nodelay(true);
+    let _ = server.set_nodelay(true);
+
+    copy_bidirectional(&mut client, &mut server).await?;
+    Ok(())
+}
@@ -0,0 +1,151 @@
+#[cfg(unix)]
+use std::{
+    fs::File,
+    io::Read,
+    os::unix::io::{AsRawFd, FromRawFd, RawFd},
+    ptr,
+    task::ready,
+};
+use std::{
+    io,
+    pin::Pin,
+    process::Stdio,
+    task::{Context, Poll},
+};
+
+#[cfg(windows)]
+use defibrillator::windows;
+#[cfg(unix)]
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// Our end of a pseudo-terminal. Reading it yields whatever the server writes
+/// to its end, and ends once every copy of the server's end has been closed.
+/// It's non-blocking, and polled through the runtime's reactor, so reading it
+/// doesn't tie up a blocking thread for as long as the server is quiet.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct PtyMaster {
+    fd: AsyncFd<File>,
+}
+
+#[cfg(unix)]
+impl AsyncRead for PtyMaster {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.fd.poll_read_ready(cx))?;
+            let unfilled = buf.initialize_unfilled();
+
+            match guard.try_io(|fd| fd.get_ref().read(unfilled)) {
+                Ok(Ok(len)) => {
+                    buf.advance(len);
+                    return Poll::Ready(Ok(()));
+                }
+                // On Linux, reading the master fails with EIO, rather than
+                // reaching EOF, once the other end is closed
+                Ok(Err(err)) if err.raw_os_error() == Some(libc::EIO) => {
+                    return Poll::Ready(Ok(()))
+                }
+                Ok(Err(err)) => return Poll::Ready(Err(err)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn cvt(result: libc::c_int) -> io::Result<libc::c_int> {
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        result => Ok(result),
+    }
+}
+
+#[cfg(unix)]
+fn set_cloexec(fd: RawFd) -> io::Result<()> {
+    // Safety: fcntl doesn't touch memory
+    let flags = cvt(unsafe { libc::fcntl(fd, libc::F_GETFD) })?;
+    cvt(unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) })?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    // Safety: fcntl doesn't touch memory
+    let flags = cvt(unsafe { libc::fcntl(fd, libc::F_GETFL) })?;
+    cvt(unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) })?;
+    Ok(())
+}
+
+/// Open a new pseudo-terminal, returning our end, and the server's end to be
+/// used as one of its output streams. Output processing is disabled, so that
+/// lines end in `\n` rather than `\r\n`, just like they would in a pipe.
+#[cfg(unix)]
+pub fn open() -> io::Result<(PtyMaster, Stdio)> {
+    let mut master: RawFd = -1;
+    let mut slave: RawFd = -1;
+
+    // Some programs behave strangely with a zero-sized terminal
+    let size = libc::winsize {
+        ws_row: 24,
+        ws_col: 80,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+
+    // Safety: openpty only writes the two descriptors, and only reads the
+    // window size
+    cvt(unsafe { libc::openpty(&mut master, &mut slave, ptr::null_mut(), ptr::null(), &size) })?;
+
+    // Safety: openpty succeeded, so both descriptors are open, and nothing
+    // else owns them
+    let (master, slave) = unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) };
+
+    // The server should only get its own end, which is duplicated onto its
+    // stdout or stderr when it's spawned
+    set_cloexec(master.as_raw_fd())?;
+    set_cloexec(slave.as_raw_fd())?;
+
+    // Only our end is non-blocking; the server's end behaves like any other
+    // terminal
+    set_nonblocking(master.as_raw_fd())?;
+
+    // Safety: termios is plain data, which tcgetattr fills in
+    let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+    cvt(unsafe { libc::tcgetattr(slave.as_raw_fd(), &mut termios) })?;
+    termios.c_oflag &= !libc::OPOST;
+    cvt(unsafe { libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) })?;
+
+    Ok((
+        PtyMaster {
+            fd: AsyncFd::new(master)?,
+        },
+        slave.into(),
+    ))
+}
+
+/// Windows has pseudo consoles rather than pseudo-terminals, and they can't
+/// stand in for a single output stream
+#[cfg(windows)]
+#[derive(Debug)]
+pub enum PtyMaster {}
+
+#[cfg(windows)]
+impl AsyncRead for PtyMaster {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        _buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match *self {}
+    }
+}
+
+#[cfg(windows)]
+pub fn open() -> io::Result<(PtyMaster, Stdio)> {
+    Err(windows::unsupported("--pty"))
+}
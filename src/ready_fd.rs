@@ -0,0 +1,79 @@
+#[cfg(unix)]
+use std::os::unix::io::FromRawFd;
+#[cfg(unix)]
+pub use std::os::unix::io::RawFd;
+use std::{
+    error::Error,
+    fs::File,
+    io::{self, Write},
+    sync::Mutex,
+};
+
+#[cfg(windows)]
+use defibrillator::windows;
+use tracing::{debug, warn};
+
+/// Windows processes inherit handles rather than file descriptors, but the
+/// standard streams can still be named by their numbers
+#[cfg(windows)]
+pub type RawFd = i32;
+
+/// Take ownership of an open file descriptor inherited from our parent,
+/// marking it close-on-exec so that the server doesn't inherit it in turn
+#[cfg(unix)]
+pub fn inherit_fd(fd: RawFd) -> io::Result<File> {
+    // Safety: fcntl doesn't touch memory; it fails with EBADF if the
+    // descriptor isn't open
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags == -1 || unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Safety: the descriptor is open, and nothing else in defibrillator owns
+    // it
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(windows)]
+pub fn inherit_fd(_fd: RawFd) -> io::Result<File> {
+    Err(windows::unsupported("inheriting file descriptors"))
+}
+
+/// A file descriptor, inherited from an outer supervisor, that's used to
+/// report readiness in the style of s6: a single line is written once the
+/// server is first ready, then the descriptor is closed.
+#[derive(Debug)]
+pub struct ReadyFd {
+    file: Mutex<Option<File>>,
+}
+
+impl ReadyFd {
+    /// Take ownership of `fd`, which must be open. It's marked close-on-exec,
+    /// so that the server doesn't keep it open after it's been closed here.
+    pub fn new(fd: RawFd) -> io::Result<Self> {
+        if fd <= 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the readiness fd can't be stdin, stdout, or stderr",
+            ));
+        }
+
+        Ok(Self {
+            file: Mutex::new(Some(inherit_fd(fd)?)),
+        })
+    }
+
+    /// Write `READY` to the descriptor and close it. Only the first call has
+    /// any effect.
+    pub fn notify(&self) {
+        if let Some(mut file) = self.file.lock().unwrap().take() {
+            match file.write_all(b"READY\n") {
+                Ok(()) => debug!("wrote readiness notification"),
+                Err(err) => {
+                    let err: &dyn Error = &err;
+                    warn!(error = err, "failed to write readiness notification");
+                }
+            }
+        }
+    }
+}
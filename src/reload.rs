@@ -0,0 +1,156 @@
+#[cfg(unix)]
+use std::{
+    error::Error,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use std::{fmt, str::FromStr};
+
+#[cfg(unix)]
+use libc::pid_t;
+#[cfg(unix)]
+use tokio::{signal::unix::Signal, sync::mpsc};
+#[cfg(unix)]
+use tracing::{debug, info, warn};
+
+#[cfg(unix)]
+use crate::control::{Command, Status};
+#[cfg(unix)]
+use crate::rules_file::{read_rules_file, RulesTemplate};
+#[cfg(unix)]
+use crate::template::TemplateVars;
+
+/// What defibrillator does when it receives SIGHUP
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HangupAction {
+    /// Stop the server and exit, like SIGINT or SIGTERM
+    Stop,
+
+    /// Reload the rules, and pass the signal along to the server
+    Forward,
+
+    /// Reload the rules, and restart the server
+    Restart,
+}
+
+#[derive(Debug, Clone)]
+pub struct InvalidHangupAction(String);
+
+impl fmt::Display for InvalidHangupAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown SIGHUP action {:?}; expected stop, forward, or restart",
+            self.0
+        )
+    }
+}
+
+impl FromStr for HangupAction {
+    type Err = InvalidHangupAction;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stop" => Ok(HangupAction::Stop),
+            "forward" => Ok(HangupAction::Forward),
+            "restart" => Ok(HangupAction::Restart),
+            _ => Err(InvalidHangupAction(s.to_owned())),
+        }
+    }
+}
+
+/// Handles SIGHUP when it doesn't stop defibrillator: the --rules-file is
+/// re-read, so that later attempts use the new rules, and then the server is
+/// signalled or restarted. Windows has no SIGHUP, so only `stop` is
+/// available there.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct Reloader {
+    pub action: HangupAction,
+    pub rules_file: Option<PathBuf>,
+
+    /// The variables to check the reloaded rules with, as the first attempt
+    /// would see them. They're expanded again for each attempt.
+    pub template_vars: TemplateVars,
+    pub rules: Arc<Mutex<RulesTemplate>>,
+
+    /// Whether the server was given a notification socket. If it wasn't,
+    /// rules that use `notify` can't be loaded.
+    pub notify_available: bool,
+
+    pub status: Arc<Status>,
+    pub process_group: bool,
+    pub commands: mpsc::Sender<Command>,
+}
+
+#[cfg(unix)]
+impl Reloader {
+    fn reload_rules(&self) {
+        let path = match self.rules_file {
+            Some(ref path) => path,
+            None => return,
+        };
+
+        match read_rules_file(path, &self.template_vars) {
+            Ok(rules) if rules.rules().uses_notify() && !self.notify_available => warn!(
+                path = %path.display(),
+                "can't reload rules that use `notify` without restarting defibrillator; \
+                keeping the current rules"
+            ),
+            Ok(rules) => {
+                *self.rules.lock().unwrap() = rules;
+                info!(path = %path.display(), "reloaded the rules file");
+            }
+            Err(err) => {
+                let err: &dyn Error = &err;
+                warn!(
+                    error = err,
+                    path = %path.display(),
+                    "failed to reload the rules file; keeping the current rules"
+                );
+            }
+        }
+    }
+
+    /// Pass SIGHUP along to the server, or its process group, if it's running
+    fn forward(&self) {
+        let pid = match self.status.pid() {
+            Some(pid) => pid as pid_t,
+            None => {
+                debug!("server isn't running; not forwarding SIGHUP");
+                return;
+            }
+        };
+
+        let target = match self.process_group {
+            true => -pid,
+            false => pid,
+        };
+
+        // Safety: kill has no memory safety implications
+        if unsafe { libc::kill(target, libc::SIGHUP) } != 0 {
+            let err = std::io::Error::last_os_error();
+            let err: &dyn Error = &err;
+            warn!(error = err, "failed to forward SIGHUP to the server");
+        }
+    }
+
+    /// Handle each SIGHUP, until the supervision loop stops listening for
+    /// commands
+    pub async fn run(self, mut hangups: Signal) {
+        while hangups.recv().await.is_some() {
+            info!("received SIGHUP");
+            self.reload_rules();
+
+            match self.action {
+                HangupAction::Stop => {}
+                HangupAction::Forward => self.forward(),
+                HangupAction::Restart => {
+                    if self.commands.send(Command::Restart).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use std::{collections::VecDeque, fmt, ops::RangeInclusive, str::FromStr, time::Duration};
+
+use libc::c_int;
+use tokio::time::Instant;
+
+use crate::signals::SignalArg;
+
+/// Tracks recent restarts of the server, to detect when it's crash-looping.
+/// Unlike `--retries`, every restart counts, whether or not the server became
+/// ready first.
+#[derive(Debug)]
+pub struct RestartLimit {
+    /// The maximum number of restarts allowed within `window`
+    pub max_restarts: usize,
+
+    /// How far back to look when counting restarts
+    pub window: Duration,
+
+    history: VecDeque<Instant>,
+}
+
+impl RestartLimit {
+    pub fn new(max_restarts: usize, window: Duration) -> Self {
+        Self {
+            max_restarts,
+            window,
+            history: VecDeque::with_capacity(max_restarts + 1),
+        }
+    }
+
+    /// Record a restart happening now. Returns false if this restart exceeds
+    /// the limit.
+    pub fn record(&mut self) -> bool {
+        let now = Instant::now();
+
+        while let Some(&oldest) = self.history.front() {
+            if now.duration_since(oldest) < self.window {
+                break;
+            }
+
+            self.history.pop_front();
+        }
+
+        self.history.push_back(now);
+        self.history.len() <= self.max_restarts
+    }
+}
+
+/// A set of exit codes, given as a comma-separated list of codes, ranges, and
+/// signal names, like `1,3-5,SIGKILL`. A signal name matches a server that
+/// was killed by that signal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExitCodes {
+    ranges: Vec<RangeInclusive<i32>>,
+    signals: Vec<c_int>,
+}
+
+impl ExitCodes {
+    pub fn contains(&self, code: i32, signal: Option<c_int>) -> bool {
+        self.ranges.iter().any(|range| range.contains(&code))
+            || signal.is_some_and(|signal| self.signals.contains(&signal))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InvalidExitCodes(String);
+
+impl fmt::Display for InvalidExitCodes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid exit codes {:?}; expected a comma-separated list of codes, ranges, and signals, like 1,3-5,SIGKILL",
+            self.0
+        )
+    }
+}
+
+impl FromStr for ExitCodes {
+    type Err = InvalidExitCodes;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidExitCodes(s.to_owned());
+
+        let mut codes = Self {
+            ranges: Vec::new(),
+            signals: Vec::new(),
+        };
+
+        for item in s.split(',').map(str::trim) {
+            // Signals are only accepted by name, since a number is an exit
+            // code
+            if item.starts_with(|c: char| c.is_ascii_alphabetic()) {
+                let signal: SignalArg = item.parse().map_err(|_| invalid())?;
+                codes.signals.push(signal.get());
+                continue;
+            }
+
+            let (start, end) = item.split_once('-').unwrap_or((item, item));
+            let start: i32 = start.trim().parse().map_err(|_| invalid())?;
+            let end: i32 = end.trim().parse().map_err(|_| invalid())?;
+
+            match start <= end {
+                true => codes.ranges.push(start..=end),
+                false => return Err(invalid()),
+            }
+        }
+
+        Ok(codes)
+    }
+}
+
+/// Which exit codes cause the server to be restarted when it exits by itself
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExitCodePolicy {
+    /// Only these codes restart the server, for --restart-on-exit-codes
+    Only(ExitCodes),
+
+    /// Every code except these restarts the server, for
+    /// --no-restart-on-exit-codes
+    Except(ExitCodes),
+}
+
+impl ExitCodePolicy {
+    pub fn restarts(&self, code: i32, signal: Option<c_int>) -> bool {
+        match self {
+            ExitCodePolicy::Only(codes) => codes.contains(code, signal),
+            ExitCodePolicy::Except(codes) => !codes.contains(code, signal),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_codes_from_str() {
+        let codes: ExitCodes = "1, 3-5,7".parse().unwrap();
+
+        assert_eq!(codes.ranges, [1..=1, 3..=5, 7..=7]);
+        assert!(codes.signals.is_empty());
+    }
+
+    #[test]
+    fn exit_codes_invalid() {
+        for codes in ["", "1,", "5-3", "1-", "x", "1.5"] {
+            assert!(codes.parse::<ExitCodes>().is_err(), "{:?} parsed", codes);
+        }
+    }
+
+    #[test]
+    fn exit_codes_contains() {
+        let codes: ExitCodes = "1,3-5".parse().unwrap();
+
+        assert!(codes.contains(1, None));
+        assert!(codes.contains(4, None));
+        assert!(!codes.contains(2, None));
+        assert!(!codes.contains(6, None));
+    }
+
+    #[test]
+    fn exit_code_policy() {
+        let codes: ExitCodes = "0".parse().unwrap();
+
+        assert!(!ExitCodePolicy::Only(codes.clone()).restarts(1, None));
+        assert!(ExitCodePolicy::Only(codes.clone()).restarts(0, None));
+        assert!(ExitCodePolicy::Except(codes.clone()).restarts(1, None));
+        assert!(!ExitCodePolicy::Except(codes).restarts(0, None));
+    }
+
+    #[test]
+    fn exit_codes_signal_names() {
+        let codes: ExitCodes = "1,term,SIGINT".parse().unwrap();
+
+        assert_eq!(codes.ranges, [1..=1]);
+        assert_eq!(codes.signals, [libc::SIGTERM, libc::SIGINT]);
+
+        assert!("SIGNOPE".parse::<ExitCodes>().is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn exit_codes_contains_signals() {
+        let codes: ExitCodes = "1,SIGKILL".parse().unwrap();
+
+        // A signal matches by name, whatever the exit code
+        assert!(codes.contains(137, Some(libc::SIGKILL)));
+        assert!(!codes.contains(143, Some(libc::SIGTERM)));
+        assert!(!codes.contains(137, None));
+    }
+}
@@ -1,5 +1,185 @@
+//! Readiness rules. The types in this module describe rules, and can be
+//! parsed from rule expressions with `FromStr`; their `build` methods create
+//! the corresponding futures from the [`futures`] module, which do the
+//! actual waiting.
+
 mod descriptors;
-mod futures;
+pub mod futures;
 mod parsers;
+mod probes;
+
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use reqwest::{Client, ClientBuilder, NoProxy, Proxy};
+use tokio::sync::broadcast::{self, Sender};
+
+use crate::{output::LogLine, sd_notify::Notification};
+
+pub use descriptors::{
+    After, AndRules, ClusterHealth, Cmd, Consul, Elasticsearch, Etcd, File, Http, HttpOptions,
+    Https, JsonField, Labeled, Ldap, Matches, Mongodb, Mqtt, Notify, OrRules, Pidfile, Port,
+    Postgres, Redis, Rule, Rules, Sequence, Service, Smtp, Ssh, Tcp, Term, Tls, TlsOptions, Udp,
+    VariableError, Within, Zookeeper,
+};
+pub use futures::{Satisfied, SatisfiedRule, Unsatisfiable};
+
+/// Settings shared by every rule that polls for readiness
+#[derive(Debug, Clone, Copy)]
+pub struct ProbeConfig<'a> {
+    pub client: &'a Client,
+
+    /// How often to poll, for rules that don't specify their own interval
+    pub poll_interval: Duration,
+
+    /// How long a single probe, like an HTTP request, a connection, or a `cmd`
+    /// command, may take before it's considered a failure, for rules that
+    /// don't specify their own timeout
+    pub timeout: Duration,
+}
+
+/// Values captured by named groups in `matches` rules, which later rules can
+/// refer to as `$name`. Clones share the same set of values.
+#[derive(Debug, Clone, Default)]
+pub struct Variables {
+    values: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl Variables {
+    pub fn get(&self, name: &str) -> Option<String> {
+        self.values.lock().unwrap().get(name).cloned()
+    }
+
+    pub fn set(&self, name: String, value: String) {
+        self.values.lock().unwrap().insert(name, value);
+    }
+}
+
+/// The descriptions of the rules that are currently being waited for, for
+/// status reports. Clones share the same set.
+#[derive(Debug, Clone, Default)]
+pub struct PendingRules {
+    rules: Arc<Mutex<Vec<String>>>,
+}
+
+impl PendingRules {
+    /// The rules being waited for, in the order that they started
+    pub fn list(&self) -> Vec<String> {
+        self.rules.lock().unwrap().clone()
+    }
+
+    /// Record that `rule` is being waited for, until the returned guard is
+    /// dropped
+    fn start(&self, rule: String) -> PendingRule {
+        self.rules.lock().unwrap().push(rule.clone());
+
+        PendingRule {
+            rules: self.clone(),
+            rule,
+        }
+    }
+}
+
+/// A rule in a `PendingRules`, which is removed when this is dropped
+#[derive(Debug)]
+struct PendingRule {
+    rules: PendingRules,
+    rule: String,
+}
+
+impl Drop for PendingRule {
+    fn drop(&mut self) {
+        let mut rules = self.rules.rules.lock().unwrap();
+
+        if let Some(index) = rules.iter().position(|rule| *rule == self.rule) {
+            rules.remove(index);
+        }
+    }
+}
+
+/// Channels carrying events from a running server, which are observed by
+/// rules like `matches` and `notify`, plus the variables captured from them
+#[derive(Debug, Clone)]
+pub struct ServerEvents {
+    pub log_lines: Sender<LogLine>,
+    pub notifications: Sender<Notification>,
+    pub variables: Variables,
+
+    /// If true, `matches` rules ignore ANSI escape sequences, like colors, in
+    /// log lines
+    pub strip_ansi: bool,
+
+    /// The rules built with these events that haven't been satisfied yet
+    pub pending: PendingRules,
+}
+
+impl ServerEvents {
+    /// Create a new set of channels, each with room for `capacity` events
+    pub fn new(capacity: usize) -> Self {
+        let (log_lines, _) = broadcast::channel(capacity);
+        let (notifications, _) = broadcast::channel(capacity);
+
+        Self {
+            log_lines,
+            notifications,
+            variables: Variables::default(),
+            strip_ansi: true,
+            pending: PendingRules::default(),
+        }
+    }
+}
+
+/// The poll interval used by `OrRules::wait`
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The probe timeout used by `OrRules::wait`
+pub const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The user agent used for HTTP readiness probes
+pub const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// Hosts that probes reach directly, even if a proxy is set in the
+/// environment, in the format of `NO_PROXY`
+const LOOPBACK: &str = "localhost, 127.0.0.0/8, ::1";
+
+/// Start building an HTTP client for readiness probes. Proxies are taken from
+/// the environment (`HTTP_PROXY`, `HTTPS_PROXY`, `ALL_PROXY`, and `NO_PROXY`)
+/// as usual, except that requests to loopback addresses, which are nearly
+/// always the server being supervised, never use them, unless
+/// `proxy_loopback` is set.
+pub fn client_builder(proxy_loopback: bool) -> ClientBuilder {
+    let builder = Client::builder().user_agent(USER_AGENT);
+
+    if proxy_loopback {
+        return builder;
+    }
+
+    let var = |upper, lower| env::var(upper).or_else(|_| env::var(lower)).ok();
+
+    let no_proxy = match var("NO_PROXY", "no_proxy") {
+        Some(list) => format!("{}, {}", LOOPBACK, list),
+        None => LOOPBACK.to_owned(),
+    };
+
+    // Scheme-specific proxies come first, so that they take precedence
+    let proxies = [
+        (
+            var("HTTP_PROXY", "http_proxy"),
+            Proxy::http as fn(String) -> reqwest::Result<Proxy>,
+        ),
+        (var("HTTPS_PROXY", "https_proxy"), Proxy::https),
+        (var("ALL_PROXY", "all_proxy"), Proxy::all),
+    ];
 
-pub use descriptors::OrRules;
+    // Like reqwest, ignore any proxy URLs that are invalid
+    proxies
+        .iter()
+        .filter_map(|(url, proxy)| proxy(url.clone()?).ok())
+        .fold(builder.no_proxy(), |builder, proxy| {
+            builder.proxy(proxy.no_proxy(NoProxy::from_string(&no_proxy)))
+        })
+}
@@ -1,12 +1,34 @@
-use std::{num::NonZeroU16, time::Duration};
+use std::{
+    borrow::Cow,
+    error::Error,
+    fmt,
+    num::{NonZeroU16, NonZeroU32, NonZeroUsize},
+    ops::RangeInclusive,
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
 
-use bytes::Bytes;
-use regex::bytes::Regex;
-use reqwest::Client;
-use tokio::sync::broadcast::{Receiver, Sender};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use native_tls::TlsConnector;
+use regex::bytes::{Regex, RegexBuilder};
+use reqwest::{
+    header::{HeaderName, HeaderValue, AUTHORIZATION},
+    Certificate, Client, Method,
+};
+use serde_json::Value;
+use tokio::sync::broadcast::{self, Receiver, Sender};
 
-use super::futures as rule_futures;
+use super::{
+    client_builder, futures as rule_futures, PendingRules, ProbeConfig, ServerEvents, Variables,
+    DEFAULT_POLL_INTERVAL, DEFAULT_PROBE_TIMEOUT,
+};
+use crate::{
+    output::{LogLine, Stream},
+    sd_notify::Notification,
+};
 
+/// A rule that's satisfied after a fixed amount of time (`after 5s`)
 #[derive(Debug, Clone, Copy)]
 pub struct After {
     duration: Duration,
@@ -17,142 +39,2135 @@ impl After {
         Self { duration }
     }
 
+    /// Create the future that waits for this rule to be satisfied
     pub fn build(&self) -> rule_futures::After {
         rule_futures::After::new(self.duration)
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+/// The host probed by network rules that don't specify one
+const DEFAULT_HOST: &str = "127.0.0.1";
+
+/// Error resolving a `$name` variable when building a rule
+#[derive(Debug, Clone)]
+pub enum VariableError {
+    /// No `matches` rule has captured the variable yet
+    Unset(String),
+
+    /// The variable was captured, but isn't a valid port number
+    InvalidPort { name: String, value: String },
+}
+
+impl fmt::Display for VariableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VariableError::Unset(name) => write!(f, "${} hasn't been captured", name),
+            VariableError::InvalidPort { name, value } => {
+                write!(f, "${} is {:?}, which isn't a valid port", name, value)
+            }
+        }
+    }
+}
+
+impl std::error::Error for VariableError {}
+
+/// The port probed by a network rule: either a number, or a `$name` variable
+/// captured by an earlier `matches` rule (`http port $port ready`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Port {
+    Number(NonZeroU16),
+    Variable(String),
+}
+
+impl Port {
+    fn resolve(&self, variables: &Variables) -> Result<NonZeroU16, VariableError> {
+        match self {
+            Port::Number(port) => Ok(*port),
+            Port::Variable(name) => match variables.get(name) {
+                None => Err(VariableError::Unset(name.clone())),
+                Some(value) => value.parse().map_err(|_| VariableError::InvalidPort {
+                    name: name.clone(),
+                    value,
+                }),
+            },
+        }
+    }
+
+    /// Resolve an optional port, falling back to a rule's usual port
+    fn resolve_or(
+        port: &Option<Port>,
+        default_port: u16,
+        variables: &Variables,
+    ) -> Result<NonZeroU16, VariableError> {
+        match port {
+            Some(port) => port.resolve(variables),
+            None => Ok(NonZeroU16::new(default_port).unwrap()),
+        }
+    }
+}
+
+impl From<NonZeroU16> for Port {
+    fn from(port: NonZeroU16) -> Self {
+        Port::Number(port)
+    }
+}
+
+/// A condition on a field of a JSON response body (`json /status == "ok"`).
+/// The field is found with a JSON pointer, as in RFC 6901.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonField {
+    pointer: String,
+    value: Value,
+}
+
+impl JsonField {
+    pub fn new(pointer: String, value: Value) -> Self {
+        Self { pointer, value }
+    }
+
+    /// Check whether `body` is JSON, with the field set to the value
+    pub(super) fn matches(&self, body: &[u8]) -> bool {
+        serde_json::from_slice::<Value>(body)
+            .ok()
+            .and_then(|body| {
+                body.pointer(&self.pointer)
+                    .map(|field| *field == self.value)
+            })
+            .unwrap_or(false)
+    }
+}
+
+impl fmt::Display for JsonField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} == {}", self.pointer, self.value)
+    }
+}
+
+/// Options shared by the `http` and `https` rules. These are set with
+/// builder-style methods, starting from `HttpOptions::default()`.
+#[derive(Debug, Clone, Default)]
+pub struct HttpOptions {
+    host: Option<String>,
+    port: Option<Port>,
+    method: Option<Method>,
+    path: Option<String>,
+
+    /// The set of acceptable status codes; if empty, any response is
+    /// accepted, except a 401 when headers are sent, since that means the
+    /// credentials were rejected
+    statuses: Vec<RangeInclusive<u16>>,
+
+    /// Skip certificate verification (https only)
+    insecure: bool,
+
+    /// An additional trusted root certificate (https only)
+    ca: Option<Certificate>,
+
+    interval: Option<Duration>,
+
+    /// How long each request may take
+    timeout: Option<Duration>,
+
+    /// A Unix socket to send requests over (http only)
+    socket: Option<PathBuf>,
+
+    /// A condition on the JSON response body
+    json: Option<JsonField>,
+
+    /// A pattern the response body must match
+    body: Option<Regex>,
+
+    /// Extra request headers, including any credentials
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl HttpOptions {
+    pub fn host(self, host: String) -> Self {
+        Self {
+            host: Some(host),
+            ..self
+        }
+    }
+
+    pub fn port(self, port: impl Into<Port>) -> Self {
+        Self {
+            port: Some(port.into()),
+            ..self
+        }
+    }
+
+    pub fn method(self, method: Method) -> Self {
+        Self {
+            method: Some(method),
+            ..self
+        }
+    }
+
+    pub fn path(self, path: String) -> Self {
+        Self {
+            path: Some(path),
+            ..self
+        }
+    }
+
+    pub fn statuses(mut self, statuses: impl IntoIterator<Item = RangeInclusive<u16>>) -> Self {
+        self.statuses.extend(statuses);
+        self
+    }
+
+    pub fn insecure(self) -> Self {
+        Self {
+            insecure: true,
+            ..self
+        }
+    }
+
+    pub fn ca(self, ca: Certificate) -> Self {
+        Self {
+            ca: Some(ca),
+            ..self
+        }
+    }
+
+    pub fn interval(self, interval: Duration) -> Self {
+        Self {
+            interval: Some(interval),
+            ..self
+        }
+    }
+
+    /// Send requests over a Unix socket, rather than connecting to the host
+    /// and port. The host is still sent in the `Host` header.
+    #[cfg(unix)]
+    pub fn socket(self, socket: PathBuf) -> Self {
+        Self {
+            socket: Some(socket),
+            ..self
+        }
+    }
+
+    /// Require the response body to be JSON, with a field set to a value.
+    /// Unless another method is given, this switches the request to GET, since
+    /// HEAD responses don't have a body.
+    pub fn json(self, json: JsonField) -> Self {
+        Self {
+            json: Some(json),
+            ..self
+        }
+    }
+
+    /// Require the response body to match a pattern. Like `json`, this
+    /// switches the request to GET, unless another method is given.
+    pub fn body(self, body: Regex) -> Self {
+        Self {
+            body: Some(body),
+            ..self
+        }
+    }
+
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+
+    /// Authenticate with HTTP basic auth, by sending an `Authorization` header
+    pub fn basic_auth(self, username: &str, password: Option<&str>) -> Self {
+        let credentials = format!("{}:{}", username, password.unwrap_or(""));
+        let mut value = HeaderValue::from_str(&format!("Basic {}", STANDARD.encode(credentials)))
+            .expect("base64 is always a valid header value");
+        value.set_sensitive(true);
+
+        self.header(AUTHORIZATION, value)
+    }
+
+    fn request_method(&self) -> Method {
+        match self.method {
+            Some(ref method) => method.clone(),
+            None if self.json.is_some() || self.body.is_some() => Method::GET,
+            None => Method::HEAD,
+        }
+    }
+
+    pub fn timeout(self, timeout: Duration) -> Self {
+        Self {
+            timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    fn has_tls_options(&self) -> bool {
+        self.insecure || self.ca.is_some()
+    }
+
+    fn build(
+        &self,
+        default_port: u16,
+        probes: ProbeConfig<'_>,
+        variables: &Variables,
+    ) -> Result<rule_futures::HttpRequest, VariableError> {
+        Ok(rule_futures::HttpRequest {
+            host: self.host.clone().unwrap_or_else(|| DEFAULT_HOST.to_owned()),
+            port: Port::resolve_or(&self.port, default_port, variables)?,
+            method: self.request_method(),
+            path: self.path.clone().unwrap_or_else(|| "/".to_owned()),
+            statuses: self.statuses.clone(),
+            timeout: self.timeout.unwrap_or(probes.timeout),
+            socket: self.socket.clone(),
+            json: self.json.clone(),
+            body: self.body.clone(),
+            headers: self.headers.clone(),
+        })
+    }
+}
+
+/// A rule that's satisfied when an HTTP request to the server succeeds
+/// (`http port 80 ready`)
+#[derive(Debug, Clone)]
 pub struct Http {
-    port: Option<NonZeroU16>,
+    options: Box<HttpOptions>,
 }
 
 impl Http {
-    pub fn new(port: Option<NonZeroU16>) -> Self {
-        Self { port }
+    pub fn new(options: HttpOptions) -> Self {
+        Self {
+            options: Box::new(options),
+        }
     }
 
-    pub fn build<'a>(&self, client: &'a Client) -> rule_futures::Http<'a> {
-        rule_futures::Http::new(self.port.or_else(|| NonZeroU16::new(80)).unwrap(), client)
+    pub fn build<'a>(
+        &self,
+        probes: ProbeConfig<'a>,
+        variables: &Variables,
+    ) -> Result<rule_futures::Http<'a>, VariableError> {
+        Ok(rule_futures::Http::new(
+            self.options.build(80, probes, variables)?,
+            self.options.interval.unwrap_or(probes.poll_interval),
+            probes.client,
+        ))
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+/// A rule that's satisfied when an HTTPS request to the server succeeds
+/// (`https port 443 ready`)
+#[derive(Debug, Clone)]
 pub struct Https {
-    port: Option<NonZeroU16>,
+    options: Box<HttpOptions>,
+
+    /// reqwest only supports TLS configuration per client, so rules with
+    /// custom TLS options get their own client.
+    client: Option<Client>,
 }
 
 impl Https {
-    pub fn new(port: Option<NonZeroU16>) -> Self {
-        Self { port }
+    /// Create the rule. This fails if the options include TLS settings and
+    /// the dedicated client for them can't be created.
+    pub fn new(options: HttpOptions) -> reqwest::Result<Self> {
+        let client = match options.has_tls_options() {
+            false => None,
+            true => {
+                let builder = client_builder(false).danger_accept_invalid_certs(options.insecure);
+
+                let builder = match options.ca {
+                    Some(ref ca) => builder.add_root_certificate(ca.clone()),
+                    None => builder,
+                };
+
+                Some(builder.build()?)
+            }
+        };
+
+        Ok(Self {
+            options: Box::new(options),
+            client,
+        })
     }
 
-    pub fn build<'a>(&self, client: &'a Client) -> rule_futures::Https<'a> {
-        rule_futures::Https::new(self.port.or_else(|| NonZeroU16::new(443)).unwrap(), client)
+    pub fn build<'a>(
+        &self,
+        probes: ProbeConfig<'a>,
+        variables: &Variables,
+    ) -> Result<rule_futures::Https<'a>, VariableError> {
+        let client = match self.client {
+            Some(ref client) => Cow::Owned(client.clone()),
+            None => Cow::Borrowed(probes.client),
+        };
+
+        Ok(rule_futures::Https::new(
+            self.options.build(443, probes, variables)?,
+            self.options.interval.unwrap_or(probes.poll_interval),
+            client,
+        ))
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+/// A rule that's satisfied when a TCP connection to the server succeeds
+/// (`tcp port 5432 ready`). With `send` and `expect` clauses, it also sends
+/// a payload and waits for the server's response to match a pattern, like a
+/// protocol banner.
+#[derive(Debug, Clone)]
 pub struct Tcp {
-    port: NonZeroU16,
+    host: Option<String>,
+    port: Port,
+    payload: Option<Vec<u8>>,
+    expect: Option<Regex>,
+    interval: Option<Duration>,
 }
 
 impl Tcp {
-    pub fn new(port: NonZeroU16) -> Self {
-        Self { port }
+    pub fn new(host: Option<String>, port: impl Into<Port>, interval: Option<Duration>) -> Self {
+        Self {
+            host,
+            port: port.into(),
+            payload: None,
+            expect: None,
+            interval,
+        }
+    }
+
+    /// Send a payload after connecting
+    pub fn send(self, payload: Vec<u8>) -> Self {
+        Self {
+            payload: Some(payload),
+            ..self
+        }
+    }
+
+    /// Wait for the data received from the server to match a pattern
+    pub fn expect(self, expect: Regex) -> Self {
+        Self {
+            expect: Some(expect),
+            ..self
+        }
+    }
+
+    pub fn build(
+        &self,
+        probes: ProbeConfig<'_>,
+        variables: &Variables,
+    ) -> Result<rule_futures::Tcp, VariableError> {
+        Ok(rule_futures::Tcp::new(
+            self.host.clone().unwrap_or_else(|| DEFAULT_HOST.to_owned()),
+            self.port.resolve(variables)?,
+            self.payload.clone(),
+            self.expect.clone(),
+            self.interval.unwrap_or(probes.poll_interval),
+            probes.timeout,
+        ))
+    }
+}
+
+/// TLS settings for the `tls` rule. These are set with builder-style
+/// methods, starting from `TlsOptions::default()`.
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    /// The name sent with SNI and verified against the server's certificate;
+    /// defaults to the host
+    name: Option<String>,
+
+    /// Skip certificate verification
+    insecure: bool,
+
+    /// An additional trusted root certificate, in PEM format
+    ca: Option<Vec<u8>>,
+}
+
+impl TlsOptions {
+    pub fn name(self, name: String) -> Self {
+        Self {
+            name: Some(name),
+            ..self
+        }
+    }
+
+    pub fn insecure(self) -> Self {
+        Self {
+            insecure: true,
+            ..self
+        }
     }
 
-    pub fn build(&self) -> rule_futures::Tcp {
-        rule_futures::Tcp::new(self.port)
+    pub fn ca(self, pem: Vec<u8>) -> Self {
+        Self {
+            ca: Some(pem),
+            ..self
+        }
     }
 }
 
+/// A rule that's satisfied when a TLS handshake with the server succeeds
+/// (`tls port 636 ready`), for TLS services that don't speak HTTP
 #[derive(Debug, Clone)]
-pub struct Matches {
-    pattern: Regex,
+pub struct Tls {
+    host: Option<String>,
+    port: Port,
+    name: Option<String>,
+    connector: TlsConnector,
+    interval: Option<Duration>,
 }
 
-impl Matches {
-    pub fn new(pattern: Regex) -> Self {
-        Self { pattern }
+impl Tls {
+    /// Create the rule. This fails if the CA certificate is invalid, or the
+    /// TLS connector can't be created.
+    pub fn new(
+        host: Option<String>,
+        port: impl Into<Port>,
+        options: TlsOptions,
+        interval: Option<Duration>,
+    ) -> native_tls::Result<Self> {
+        let mut builder = TlsConnector::builder();
+
+        builder
+            .danger_accept_invalid_certs(options.insecure)
+            .danger_accept_invalid_hostnames(options.insecure);
+
+        if let Some(ref pem) = options.ca {
+            builder.add_root_certificate(native_tls::Certificate::from_pem(pem)?);
+        }
+
+        Ok(Self {
+            host,
+            port: port.into(),
+            name: options.name,
+            connector: builder.build()?,
+            interval,
+        })
+    }
+
+    pub fn build(
+        &self,
+        probes: ProbeConfig<'_>,
+        variables: &Variables,
+    ) -> Result<rule_futures::Tls, VariableError> {
+        let host = self.host.clone().unwrap_or_else(|| DEFAULT_HOST.to_owned());
+
+        Ok(rule_futures::Tls::new(
+            self.name.clone().unwrap_or_else(|| host.clone()),
+            host,
+            self.port.resolve(variables)?,
+            self.connector.clone().into(),
+            self.interval.unwrap_or(probes.poll_interval),
+            probes.timeout,
+        ))
+    }
+}
+
+/// The address and poll interval of a rule that probes a network service,
+/// like `postgres`. The host defaults to localhost, and the port to the
+/// service's usual port.
+#[derive(Debug, Clone, Default)]
+pub struct Service {
+    host: Option<String>,
+    port: Option<Port>,
+    interval: Option<Duration>,
+}
+
+impl Service {
+    pub fn new(host: Option<String>, port: Option<Port>, interval: Option<Duration>) -> Self {
+        Self {
+            host,
+            port,
+            interval,
+        }
+    }
+
+    fn host(&self) -> String {
+        self.host.clone().unwrap_or_else(|| DEFAULT_HOST.to_owned())
+    }
+
+    fn port(&self, default_port: u16, variables: &Variables) -> Result<NonZeroU16, VariableError> {
+        Port::resolve_or(&self.port, default_port, variables)
     }
 
-    pub fn build(&self, log_lines: Receiver<Bytes>) -> rule_futures::Matches {
-        rule_futures::Matches::new(self.pattern.clone(), log_lines)
+    fn interval(&self, probes: ProbeConfig<'_>) -> Duration {
+        self.interval.unwrap_or(probes.poll_interval)
     }
 }
 
+/// A rule that's satisfied when a PostgreSQL server accepts connections
+/// (`postgres port 5432 ready`). Like `pg_isready`, it doesn't need to
+/// authenticate.
 #[derive(Debug, Clone)]
-pub enum Rule {
-    After(After),
-    Tcp(Tcp),
-    Http(Http),
-    Https(Https),
-    Matches(Matches),
+pub struct Postgres {
+    service: Service,
+
+    /// The user to connect as; defaults to `postgres`
+    user: Option<String>,
+    database: Option<String>,
 }
 
-impl Rule {
-    pub fn build<'a>(
+impl Postgres {
+    pub fn new(service: Service, user: Option<String>, database: Option<String>) -> Self {
+        Self {
+            service,
+            user,
+            database,
+        }
+    }
+
+    pub fn build(
         &self,
-        client: &'a Client,
-        log_lines: &Sender<Bytes>,
-    ) -> rule_futures::Rule<'a> {
-        match self {
-            Rule::After(after) => rule_futures::Rule::After(after.build()),
-            Rule::Tcp(tcp) => rule_futures::Rule::Tcp(tcp.build()),
-            Rule::Http(http) => rule_futures::Rule::Http(http.build(client)),
-            Rule::Https(https) => rule_futures::Rule::Https(https.build(client)),
-            Rule::Matches(matches) => {
-                rule_futures::Rule::Matches(matches.build(log_lines.subscribe()))
-            }
+        probes: ProbeConfig<'_>,
+        variables: &Variables,
+    ) -> Result<rule_futures::Postgres, VariableError> {
+        Ok(rule_futures::Postgres::new(
+            self.service.host(),
+            self.service.port(5432, variables)?,
+            self.user.clone().unwrap_or_else(|| "postgres".to_owned()),
+            self.database.clone(),
+            self.service.interval(probes),
+            probes.timeout,
+        ))
+    }
+}
+
+/// A rule that's satisfied when a Redis server answers `PING`
+/// (`redis port 6379 ready`), optionally after authenticating with
+/// `auth <password>`
+#[derive(Debug, Clone)]
+pub struct Redis {
+    service: Service,
+    user: Option<String>,
+    password: Option<String>,
+}
+
+impl Redis {
+    pub fn new(service: Service, user: Option<String>, password: Option<String>) -> Self {
+        Self {
+            service,
+            user,
+            password,
         }
     }
+
+    pub fn build(
+        &self,
+        probes: ProbeConfig<'_>,
+        variables: &Variables,
+    ) -> Result<rule_futures::Redis, VariableError> {
+        Ok(rule_futures::Redis::new(
+            self.service.host(),
+            self.service.port(6379, variables)?,
+            self.user.clone(),
+            self.password.clone(),
+            self.service.interval(probes),
+            probes.timeout,
+        ))
+    }
 }
 
+/// A rule that's satisfied when a MongoDB server reports that it's a primary
+/// or a secondary (`mongodb port 27017 ready`). Connections are accepted
+/// while it's still recovering its journal, so a `tcp` rule isn't enough.
 #[derive(Debug, Clone)]
-pub struct AndRules {
-    rules: Vec<Rule>,
+pub struct Mongodb {
+    service: Service,
 }
 
-impl AndRules {
-    pub fn new(rules: Vec<Rule>) -> Self {
-        Self { rules }
+impl Mongodb {
+    pub fn new(service: Service) -> Self {
+        Self { service }
     }
 
-    pub fn build<'a>(
+    pub fn build(
         &self,
-        client: &'a Client,
-        log_lines: &Sender<Bytes>,
-    ) -> rule_futures::AndRules<'a> {
-        rule_futures::AndRules::new(
-            self.rules
-                .iter()
-                .map(|rule| rule.build(client, log_lines))
-                .collect(),
-        )
+        probes: ProbeConfig<'_>,
+        variables: &Variables,
+    ) -> Result<rule_futures::Mongodb, VariableError> {
+        Ok(rule_futures::Mongodb::new(
+            self.service.host(),
+            self.service.port(27017, variables)?,
+            self.service.interval(probes),
+            probes.timeout,
+        ))
     }
 }
 
+/// A rule that's satisfied when an SMTP server greets clients with `220`
+/// (`smtp port 25 ready`), and, with `ehlo <domain>`, accepts `EHLO`
 #[derive(Debug, Clone)]
-pub struct OrRules {
-    rules: Vec<AndRules>,
+pub struct Smtp {
+    service: Service,
+    ehlo: Option<String>,
 }
 
-impl OrRules {
-    pub fn new(rules: Vec<AndRules>) -> Self {
-        Self { rules }
+impl Smtp {
+    pub fn new(service: Service, ehlo: Option<String>) -> Self {
+        Self { service, ehlo }
     }
 
-    pub fn build<'a>(
+    pub fn build(
         &self,
-        client: &'a Client,
-        log_lines: &Sender<Bytes>,
-    ) -> rule_futures::OrRules<'a> {
-        rule_futures::OrRules::new(
-            self.rules
-                .iter()
-                .map(|rule| rule.build(client, log_lines))
-                .collect(),
+        probes: ProbeConfig<'_>,
+        variables: &Variables,
+    ) -> Result<rule_futures::Smtp, VariableError> {
+        Ok(rule_futures::Smtp::new(
+            self.service.host(),
+            self.service.port(25, variables)?,
+            self.ehlo.clone(),
+            self.service.interval(probes),
+            probes.timeout,
+        ))
+    }
+}
+
+/// A rule that's satisfied when an SSH server sends its version banner (`ssh
+/// port 22 ready`). With socket activation, connections are accepted before
+/// sshd has loaded its host keys, so a `tcp` rule isn't enough.
+#[derive(Debug, Clone)]
+pub struct Ssh {
+    service: Service,
+}
+
+impl Ssh {
+    pub fn new(service: Service) -> Self {
+        Self { service }
+    }
+
+    pub fn build(
+        &self,
+        probes: ProbeConfig<'_>,
+        variables: &Variables,
+    ) -> Result<rule_futures::Ssh, VariableError> {
+        Ok(rule_futures::Ssh::new(
+            self.service.host(),
+            self.service.port(22, variables)?,
+            self.service.interval(probes),
+            probes.timeout,
+        ))
+    }
+}
+
+/// A rule that's satisfied when an LDAP server answers an anonymous bind
+/// (`ldap port 389 ready`). Directory servers accept connections while
+/// they're rebuilding their indexes, but report that they're busy or
+/// unavailable, so a `tcp` rule isn't enough.
+#[derive(Debug, Clone)]
+pub struct Ldap {
+    service: Service,
+}
+
+impl Ldap {
+    pub fn new(service: Service) -> Self {
+        Self { service }
+    }
+
+    pub fn build(
+        &self,
+        probes: ProbeConfig<'_>,
+        variables: &Variables,
+    ) -> Result<rule_futures::Ldap, VariableError> {
+        Ok(rule_futures::Ldap::new(
+            self.service.host(),
+            self.service.port(389, variables)?,
+            self.service.interval(probes),
+            probes.timeout,
+        ))
+    }
+}
+
+/// A rule that's satisfied when a ZooKeeper server is serving requests
+/// (`zookeeper port 2181 ready`). It listens on its port while the quorum is
+/// still being established, so a `tcp` rule isn't enough.
+#[derive(Debug, Clone)]
+pub struct Zookeeper {
+    service: Service,
+}
+
+impl Zookeeper {
+    pub fn new(service: Service) -> Self {
+        Self { service }
+    }
+
+    pub fn build(
+        &self,
+        probes: ProbeConfig<'_>,
+        variables: &Variables,
+    ) -> Result<rule_futures::Zookeeper, VariableError> {
+        Ok(rule_futures::Zookeeper::new(
+            self.service.host(),
+            self.service.port(2181, variables)?,
+            self.service.interval(probes),
+            probes.timeout,
+        ))
+    }
+}
+
+/// A rule that's satisfied when an MQTT broker accepts a session (`mqtt port
+/// 1883 ready`), optionally with credentials given by `user <name>` and
+/// `auth <password>`
+#[derive(Debug, Clone)]
+pub struct Mqtt {
+    service: Service,
+    user: Option<String>,
+    password: Option<String>,
+}
+
+impl Mqtt {
+    pub fn new(
+        service: Service,
+        user: Option<String>,
+        password: Option<String>,
+    ) -> Result<Self, PasswordWithoutUser> {
+        // MQTT 3.1.1 only allows a password alongside a user name
+        if password.is_some() && user.is_none() {
+            return Err(PasswordWithoutUser);
+        }
+
+        Ok(Self {
+            service,
+            user,
+            password,
+        })
+    }
+
+    pub fn build(
+        &self,
+        probes: ProbeConfig<'_>,
+        variables: &Variables,
+    ) -> Result<rule_futures::Mqtt, VariableError> {
+        Ok(rule_futures::Mqtt::new(
+            self.service.host(),
+            self.service.port(1883, variables)?,
+            self.user.clone(),
+            self.password.clone(),
+            self.service.interval(probes),
+            probes.timeout,
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PasswordWithoutUser;
+
+impl fmt::Display for PasswordWithoutUser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`auth` requires a `user` for MQTT brokers")
+    }
+}
+
+impl Error for PasswordWithoutUser {}
+
+/// The health of an Elasticsearch cluster, from worst to best
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ClusterHealth {
+    Red,
+    Yellow,
+    Green,
+}
+
+#[derive(Debug, Clone)]
+pub struct InvalidClusterHealth(String);
+
+impl fmt::Display for InvalidClusterHealth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown cluster health {:?}; expected green, yellow, or red",
+            self.0
         )
     }
 }
+
+impl Error for InvalidClusterHealth {}
+
+impl FromStr for ClusterHealth {
+    type Err = InvalidClusterHealth;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "red" => Ok(ClusterHealth::Red),
+            "yellow" => Ok(ClusterHealth::Yellow),
+            "green" => Ok(ClusterHealth::Green),
+            _ => Err(InvalidClusterHealth(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for ClusterHealth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ClusterHealth::Red => "red",
+            ClusterHealth::Yellow => "yellow",
+            ClusterHealth::Green => "green",
+        })
+    }
+}
+
+/// A rule that's satisfied when an Elasticsearch or OpenSearch cluster's
+/// health, from `_cluster/health`, is at least `status` (`elasticsearch port
+/// 9200 status yellow ready`). The HTTP port answers long before the shards
+/// are allocated, so a plain `http` rule isn't enough. The status defaults to
+/// yellow, since a single node cluster with replicas never becomes green.
+#[derive(Debug, Clone)]
+pub struct Elasticsearch {
+    service: Service,
+    status: Option<ClusterHealth>,
+}
+
+impl Elasticsearch {
+    pub fn new(service: Service, status: Option<ClusterHealth>) -> Self {
+        Self { service, status }
+    }
+
+    pub fn build<'a>(
+        &self,
+        probes: ProbeConfig<'a>,
+        variables: &Variables,
+    ) -> Result<rule_futures::Elasticsearch<'a>, VariableError> {
+        Ok(rule_futures::Elasticsearch::new(
+            self.service.host(),
+            self.service.port(9200, variables)?,
+            self.status.unwrap_or(ClusterHealth::Yellow),
+            self.service.interval(probes),
+            probes.timeout,
+            probes.client,
+        ))
+    }
+}
+
+/// A rule that's satisfied when etcd's `/health` endpoint reports that it's
+/// healthy (`etcd port 2379 ready`), which requires a quorum
+#[derive(Debug, Clone)]
+pub struct Etcd {
+    service: Service,
+}
+
+impl Etcd {
+    pub fn new(service: Service) -> Self {
+        Self { service }
+    }
+
+    pub fn build<'a>(
+        &self,
+        probes: ProbeConfig<'a>,
+        variables: &Variables,
+    ) -> Result<rule_futures::Etcd<'a>, VariableError> {
+        Ok(rule_futures::Etcd::new(
+            self.service.host(),
+            self.service.port(2379, variables)?,
+            self.service.interval(probes),
+            probes.timeout,
+            probes.client,
+        ))
+    }
+}
+
+/// A rule that's satisfied when a Consul cluster has elected a leader
+/// (`consul port 8500 ready`), according to `/v1/status/leader`
+#[derive(Debug, Clone)]
+pub struct Consul {
+    service: Service,
+}
+
+impl Consul {
+    pub fn new(service: Service) -> Self {
+        Self { service }
+    }
+
+    pub fn build<'a>(
+        &self,
+        probes: ProbeConfig<'a>,
+        variables: &Variables,
+    ) -> Result<rule_futures::Consul<'a>, VariableError> {
+        Ok(rule_futures::Consul::new(
+            self.service.host(),
+            self.service.port(8500, variables)?,
+            self.service.interval(probes),
+            probes.timeout,
+            probes.client,
+        ))
+    }
+}
+
+/// A rule that's satisfied when the server responds to a UDP datagram
+/// (`udp port 53 send "..." expect "..."`). Without `expect`, any response is
+/// accepted.
+#[derive(Debug, Clone)]
+pub struct Udp {
+    host: Option<String>,
+    port: Port,
+    payload: Vec<u8>,
+    expect: Option<Regex>,
+    interval: Option<Duration>,
+}
+
+impl Udp {
+    pub fn new(
+        host: Option<String>,
+        port: impl Into<Port>,
+        payload: Vec<u8>,
+        expect: Option<Regex>,
+        interval: Option<Duration>,
+    ) -> Self {
+        Self {
+            host,
+            port: port.into(),
+            payload,
+            expect,
+            interval,
+        }
+    }
+
+    pub fn build(
+        &self,
+        probes: ProbeConfig<'_>,
+        variables: &Variables,
+    ) -> Result<rule_futures::Udp, VariableError> {
+        Ok(rule_futures::Udp::new(
+            self.host.clone().unwrap_or_else(|| DEFAULT_HOST.to_owned()),
+            self.port.resolve(variables)?,
+            self.payload.clone(),
+            self.expect.clone(),
+            self.interval.unwrap_or(probes.poll_interval),
+        ))
+    }
+}
+
+/// A rule that watches a file (`file /run/app.ready exists`, or
+/// `file /var/log/app.log matches "started"`). With a pattern, only lines
+/// written after the rule starts are matched.
+#[derive(Debug, Clone)]
+pub struct File {
+    path: PathBuf,
+    pattern: Option<Regex>,
+    interval: Option<Duration>,
+}
+
+impl File {
+    pub fn new(path: PathBuf, pattern: Option<Regex>, interval: Option<Duration>) -> Self {
+        Self {
+            path,
+            pattern,
+            interval,
+        }
+    }
+
+    pub fn build(&self, probes: ProbeConfig<'_>) -> rule_futures::File {
+        rule_futures::File::new(
+            self.path.clone(),
+            self.pattern.clone(),
+            self.interval.unwrap_or(probes.poll_interval),
+        )
+    }
+}
+
+/// A rule that's satisfied when a PID file exists and names a running
+/// process (`pidfile /run/app.pid ready`), for daemons that fork into the
+/// background
+#[derive(Debug, Clone)]
+pub struct Pidfile {
+    path: PathBuf,
+    interval: Option<Duration>,
+}
+
+impl Pidfile {
+    pub fn new(path: PathBuf, interval: Option<Duration>) -> Self {
+        Self { path, interval }
+    }
+
+    pub fn build(&self, probes: ProbeConfig<'_>) -> rule_futures::Pidfile {
+        rule_futures::Pidfile::new(
+            self.path.clone(),
+            self.interval.unwrap_or(probes.poll_interval),
+        )
+    }
+}
+
+/// A rule that's satisfied when the server prints a line matching a pattern
+/// (`matches "listening on"`), optionally a number of times
+/// (`matches "worker ready" times 4`). Named groups in the pattern capture
+/// variables for later rules (`matches "port (?P<port>[0-9]+)"`). The
+/// pattern can also be a plain string (`contains "[ready]"`), and either can
+/// be case-insensitive (`imatches "ready"`, `contains -i "ready"`). Patterns
+/// that span several lines can be matched against a window of the most recent
+/// lines (`matches-multiline "(?s)endpoints:.*grpc" window 3`).
+#[derive(Debug, Clone)]
+pub struct Matches {
+    pattern: Regex,
+
+    /// The text of a `contains` rule, which the pattern matches literally
+    text: Option<String>,
+
+    /// Whether the pattern ignores case
+    case_insensitive: bool,
+
+    /// The output stream to match against; `None` matches both
+    stream: Option<Stream>,
+
+    /// How many matching lines are needed
+    times: NonZeroU32,
+
+    /// How many of the most recent lines the pattern is matched against,
+    /// joined with newlines
+    window: NonZeroUsize,
+}
+
+impl Matches {
+    pub fn new(pattern: Regex, stream: Option<Stream>) -> Self {
+        Self {
+            pattern,
+            text: None,
+            case_insensitive: false,
+            stream,
+            times: NonZeroU32::new(1).unwrap(),
+            window: NonZeroUsize::new(1).unwrap(),
+        }
+    }
+
+    /// Match lines that contain `text`, rather than a regular expression
+    pub fn contains(text: &str, stream: Option<Stream>) -> Self {
+        // Unwrap safety: an escaped string is always a valid pattern
+        let pattern = Regex::new(&regex::escape(text)).unwrap();
+
+        Self {
+            text: Some(text.to_owned()),
+            ..Self::new(pattern, stream)
+        }
+    }
+
+    /// Ignore case when matching. This fails if the case-insensitive
+    /// pattern is too big to compile.
+    pub fn case_insensitive(self) -> Result<Self, regex::Error> {
+        let pattern = RegexBuilder::new(self.pattern.as_str())
+            .case_insensitive(true)
+            .build()?;
+
+        Ok(Self {
+            pattern,
+            case_insensitive: true,
+            ..self
+        })
+    }
+
+    /// Match lines from this stream; `None` matches both
+    pub fn stream(self, stream: Option<Stream>) -> Self {
+        Self { stream, ..self }
+    }
+
+    /// Require this many matching lines, rather than just one
+    pub fn times(self, times: NonZeroU32) -> Self {
+        Self { times, ..self }
+    }
+
+    /// Match the pattern against this many of the most recent lines at once,
+    /// rather than one line at a time
+    pub fn window(self, window: NonZeroUsize) -> Self {
+        Self { window, ..self }
+    }
+
+    pub fn build(
+        &self,
+        log_lines: Receiver<LogLine>,
+        variables: Variables,
+        strip_ansi: bool,
+    ) -> rule_futures::Matches {
+        rule_futures::Matches::new(
+            self.pattern.clone(),
+            self.stream,
+            self.times,
+            self.window,
+            log_lines,
+            variables,
+            strip_ansi,
+        )
+    }
+
+    fn uses_output(&self, stream: Stream) -> bool {
+        self.stream.is_none_or(|watched| watched == stream)
+    }
+}
+
+/// A rule that's satisfied when a shell command exits successfully
+/// (`cmd "pg_isready"`)
+#[derive(Debug, Clone)]
+pub struct Cmd {
+    command: String,
+    interval: Option<Duration>,
+}
+
+impl Cmd {
+    pub fn new(command: String, interval: Option<Duration>) -> Self {
+        Self { command, interval }
+    }
+
+    pub fn build(&self, probes: ProbeConfig<'_>) -> rule_futures::Cmd {
+        rule_futures::Cmd::new(
+            self.command.clone(),
+            self.interval.unwrap_or(probes.poll_interval),
+            probes.timeout,
+        )
+    }
+}
+
+/// A rule that waits for the server to send `READY=1` to defibrillator's
+/// notification socket
+#[derive(Debug, Clone, Copy)]
+pub struct Notify;
+
+impl Notify {
+    pub fn build(&self, notifications: Receiver<Notification>) -> rule_futures::Notify {
+        rule_futures::Notify::new(notifications)
+    }
+}
+
+/// Any single readiness rule
+#[derive(Debug, Clone)]
+pub enum Rule {
+    After(After),
+    Tcp(Tcp),
+    Postgres(Postgres),
+    Redis(Redis),
+    Mongodb(Mongodb),
+    Mqtt(Mqtt),
+    Zookeeper(Zookeeper),
+    Elasticsearch(Elasticsearch),
+    Etcd(Etcd),
+    Consul(Consul),
+    Smtp(Smtp),
+    Ssh(Ssh),
+    Ldap(Ldap),
+    Udp(Udp),
+    Tls(Tls),
+    Http(Http),
+    Https(Https),
+    Matches(Matches),
+    File(File),
+    Pidfile(Pidfile),
+    Cmd(Cmd),
+    Notify(Notify),
+}
+
+impl Rule {
+    /// Create the future that waits for this rule to be satisfied. This
+    /// fails if the rule refers to a variable that hasn't been captured.
+    pub fn build<'a>(
+        &self,
+        probes: ProbeConfig<'a>,
+        events: &ServerEvents,
+    ) -> Result<rule_futures::Rule<'a>, VariableError> {
+        let variables = &events.variables;
+
+        Ok(match self {
+            Rule::After(after) => rule_futures::Rule::After(after.build()),
+            Rule::Tcp(tcp) => rule_futures::Rule::Tcp(tcp.build(probes, variables)?),
+            Rule::Postgres(postgres) => {
+                rule_futures::Rule::Postgres(postgres.build(probes, variables)?)
+            }
+            Rule::Redis(redis) => rule_futures::Rule::Redis(redis.build(probes, variables)?),
+            Rule::Mongodb(mongodb) => {
+                rule_futures::Rule::Mongodb(mongodb.build(probes, variables)?)
+            }
+            Rule::Mqtt(mqtt) => rule_futures::Rule::Mqtt(mqtt.build(probes, variables)?),
+            Rule::Zookeeper(zookeeper) => {
+                rule_futures::Rule::Zookeeper(zookeeper.build(probes, variables)?)
+            }
+            Rule::Elasticsearch(elasticsearch) => {
+                rule_futures::Rule::Elasticsearch(elasticsearch.build(probes, variables)?)
+            }
+            Rule::Etcd(etcd) => rule_futures::Rule::Etcd(etcd.build(probes, variables)?),
+            Rule::Consul(consul) => rule_futures::Rule::Consul(consul.build(probes, variables)?),
+            Rule::Smtp(smtp) => rule_futures::Rule::Smtp(smtp.build(probes, variables)?),
+            Rule::Ssh(ssh) => rule_futures::Rule::Ssh(ssh.build(probes, variables)?),
+            Rule::Ldap(ldap) => rule_futures::Rule::Ldap(ldap.build(probes, variables)?),
+            Rule::Udp(udp) => rule_futures::Rule::Udp(udp.build(probes, variables)?),
+            Rule::Tls(tls) => rule_futures::Rule::Tls(tls.build(probes, variables)?),
+            Rule::Http(http) => rule_futures::Rule::Http(http.build(probes, variables)?),
+            Rule::Https(https) => rule_futures::Rule::Https(https.build(probes, variables)?),
+            Rule::Matches(matches) => rule_futures::Rule::Matches(matches.build(
+                events.log_lines.subscribe(),
+                variables.clone(),
+                events.strip_ansi,
+            )),
+            Rule::File(file) => rule_futures::Rule::File(file.build(probes)),
+            Rule::Pidfile(pidfile) => rule_futures::Rule::Pidfile(pidfile.build(probes)),
+            Rule::Cmd(cmd) => rule_futures::Rule::Cmd(cmd.build(probes)),
+            Rule::Notify(notify) => {
+                rule_futures::Rule::Notify(notify.build(events.notifications.subscribe()))
+            }
+        })
+    }
+
+    fn uses_notify(&self) -> bool {
+        matches!(self, Rule::Notify(..))
+    }
+
+    fn uses_output(&self, stream: Stream) -> bool {
+        match self {
+            Rule::Matches(matches) => matches.uses_output(stream),
+            _ => false,
+        }
+    }
+}
+
+macro_rules! rule_from {
+    ($($variant:ident)*) => {$(
+        impl From<$variant> for Rule {
+            fn from(rule: $variant) -> Self {
+                Rule::$variant(rule)
+            }
+        }
+    )*};
+}
+
+rule_from! { After Tcp Postgres Redis Mongodb Mqtt Zookeeper Elasticsearch Etcd Consul Smtp Ssh Ldap Udp Tls Http Https Matches File Pidfile Cmd Notify }
+
+/// A single operand of an `and` expression: either a rule, a parenthesized
+/// group of rules, a sequence of terms joined by `then`, a term bounded by
+/// `within`, or a term with a label
+#[derive(Debug, Clone)]
+pub enum Term {
+    Rule(Rule),
+    Group(OrRules),
+    Sequence(Sequence),
+    Within(Within),
+    Labeled(Labeled),
+}
+
+impl Term {
+    pub fn build<'a>(
+        &self,
+        probes: ProbeConfig<'a>,
+        events: &ServerEvents,
+    ) -> rule_futures::Term<'a> {
+        match self {
+            Term::Rule(rule) => match rule.build(probes, events) {
+                Ok(built) => rule_futures::Term::Rule {
+                    rule: built,
+                    description: rule.to_string(),
+                    pending: events.pending.clone(),
+                },
+                Err(err) => rule_futures::Term::Invalid(err),
+            },
+            Term::Group(group) => rule_futures::Term::Group(group.build(probes, events)),
+            Term::Sequence(sequence) => {
+                rule_futures::Term::Sequence(sequence.build(probes, events))
+            }
+            Term::Within(within) => rule_futures::Term::Within(within.build(probes, events)),
+            Term::Labeled(labeled) => rule_futures::Term::Labeled(labeled.build(probes, events)),
+        }
+    }
+
+    fn uses_notify(&self) -> bool {
+        match self {
+            Term::Rule(rule) => rule.uses_notify(),
+            Term::Group(group) => group.uses_notify(),
+            Term::Sequence(sequence) => sequence.uses_notify(),
+            Term::Within(within) => within.term.uses_notify(),
+            Term::Labeled(labeled) => labeled.term.uses_notify(),
+        }
+    }
+
+    fn uses_output(&self, stream: Stream) -> bool {
+        match self {
+            Term::Rule(rule) => rule.uses_output(stream),
+            Term::Group(group) => group.uses_output(stream),
+            Term::Sequence(sequence) => sequence.uses_output(stream),
+            Term::Within(within) => within.term.uses_output(stream),
+            Term::Labeled(labeled) => labeled.term.uses_output(stream),
+        }
+    }
+}
+
+impl<T: Into<Rule>> From<T> for Term {
+    fn from(rule: T) -> Self {
+        Term::Rule(rule.into())
+    }
+}
+
+impl From<OrRules> for Term {
+    fn from(group: OrRules) -> Self {
+        Term::Group(group)
+    }
+}
+
+impl From<Sequence> for Term {
+    fn from(sequence: Sequence) -> Self {
+        Term::Sequence(sequence)
+    }
+}
+
+impl From<Within> for Term {
+    fn from(within: Within) -> Self {
+        Term::Within(within)
+    }
+}
+
+impl From<Labeled> for Term {
+    fn from(labeled: Labeled) -> Self {
+        Term::Labeled(labeled)
+    }
+}
+
+/// A term with a name (`[web] http port 8080 ready`), which is used in place
+/// of its rules when logging and reporting how the rules were satisfied
+#[derive(Debug, Clone)]
+pub struct Labeled {
+    label: String,
+    term: Box<Term>,
+}
+
+impl Labeled {
+    pub fn new(label: impl Into<String>, term: impl Into<Term>) -> Self {
+        Self {
+            label: label.into(),
+            term: Box::new(term.into()),
+        }
+    }
+
+    /// Create the future that waits for the term, in a span that carries the
+    /// label
+    pub fn build<'a>(
+        &self,
+        probes: ProbeConfig<'a>,
+        events: &ServerEvents,
+    ) -> rule_futures::Labeled<'a> {
+        rule_futures::Labeled::new(self.label.clone(), self.term.build(probes, events))
+    }
+}
+
+/// A term that must be satisfied within a time limit (`a within 30s`). If the
+/// limit passes first, the term can never be satisfied, which abandons its
+/// alternative of the enclosing `or`; with `else fail`, the whole set of
+/// rules fails instead.
+#[derive(Debug, Clone)]
+pub struct Within {
+    term: Box<Term>,
+    limit: Duration,
+    fatal: bool,
+}
+
+impl Within {
+    pub fn new(term: impl Into<Term>, limit: Duration) -> Self {
+        Self {
+            term: Box::new(term.into()),
+            limit,
+            fatal: false,
+        }
+    }
+
+    /// Fail the whole set of rules, rather than just this alternative, if the
+    /// limit passes
+    pub fn fatal(self) -> Self {
+        Self {
+            fatal: true,
+            ..self
+        }
+    }
+
+    /// Create the future that waits for the term, up to the limit. The limit
+    /// starts counting when the future is first polled.
+    pub fn build<'a>(
+        &self,
+        probes: ProbeConfig<'a>,
+        events: &ServerEvents,
+    ) -> rule_futures::Within<'a> {
+        rule_futures::Within::new(self.term.build(probes, events), self.limit, self.fatal)
+    }
+}
+
+/// A series of terms that must be satisfied in order (`a then b`). Each term
+/// only starts being evaluated once the one before it is satisfied, so it can
+/// use variables captured by the terms before it.
+#[derive(Debug, Clone)]
+pub struct Sequence {
+    terms: Vec<Term>,
+}
+
+impl Sequence {
+    pub fn new(terms: Vec<Term>) -> Self {
+        Self { terms }
+    }
+
+    /// Add a term to be evaluated after the others are satisfied
+    pub fn then(mut self, term: impl Into<Term>) -> Self {
+        self.terms.push(term.into());
+        self
+    }
+
+    /// Create the future that waits for each term in turn. The terms are
+    /// built as they're reached, rather than up front.
+    pub fn build<'a>(
+        &self,
+        probes: ProbeConfig<'a>,
+        events: &ServerEvents,
+    ) -> rule_futures::Sequence<'a> {
+        rule_futures::Sequence::new(self.terms.clone(), probes, events.clone())
+    }
+
+    fn uses_notify(&self) -> bool {
+        self.terms.iter().any(|term| term.uses_notify())
+    }
+
+    fn uses_output(&self, stream: Stream) -> bool {
+        self.terms.iter().any(|term| term.uses_output(stream))
+    }
+}
+
+/// A set of terms that must all be satisfied (`a and b and not c`)
+#[derive(Debug, Clone)]
+pub struct AndRules {
+    rules: Vec<Term>,
+
+    /// Terms prefixed with `not`; if any of these is satisfied before all of
+    /// `rules` are, this group can never be satisfied.
+    negated: Vec<Term>,
+}
+
+impl AndRules {
+    pub fn new(rules: Vec<Term>, negated: Vec<Term>) -> Self {
+        Self { rules, negated }
+    }
+
+    /// Add a term that must also be satisfied
+    pub fn and(mut self, term: impl Into<Term>) -> Self {
+        self.rules.push(term.into());
+        self
+    }
+
+    /// Add a term that must not be satisfied before the others are
+    pub fn and_not(mut self, term: impl Into<Term>) -> Self {
+        self.negated.push(term.into());
+        self
+    }
+
+    /// Create the future that waits for these rules to be satisfied
+    pub fn build<'a>(
+        &self,
+        probes: ProbeConfig<'a>,
+        events: &ServerEvents,
+    ) -> rule_futures::AndRules<'a> {
+        rule_futures::AndRules::new(
+            self.rules
+                .iter()
+                .map(|rule| rule.build(probes, events))
+                .collect(),
+            self.negated
+                .iter()
+                .map(|rule| rule.build(probes, events))
+                .collect(),
+        )
+    }
+
+    fn uses_notify(&self) -> bool {
+        self.rules
+            .iter()
+            .chain(&self.negated)
+            .any(|term| term.uses_notify())
+    }
+
+    fn uses_output(&self, stream: Stream) -> bool {
+        self.rules
+            .iter()
+            .chain(&self.negated)
+            .any(|term| term.uses_output(stream))
+    }
+}
+
+impl<T: Into<Term>> From<T> for AndRules {
+    fn from(term: T) -> Self {
+        AndRules::new(vec![term.into()], Vec::new())
+    }
+}
+
+/// A set of alternatives, any of which may be satisfied (`a or b`). This is
+/// the top level of a rule expression.
+#[derive(Debug, Clone)]
+pub struct OrRules {
+    rules: Vec<AndRules>,
+}
+
+impl OrRules {
+    pub fn new(rules: Vec<AndRules>) -> Self {
+        Self { rules }
+    }
+
+    /// Add an alternative
+    pub fn or(mut self, rules: impl Into<AndRules>) -> Self {
+        self.rules.push(rules.into());
+        self
+    }
+
+    /// Create the future that waits for any alternative to be satisfied
+    pub fn build<'a>(
+        &self,
+        probes: ProbeConfig<'a>,
+        events: &ServerEvents,
+    ) -> rule_futures::OrRules<'a> {
+        rule_futures::OrRules::new(
+            self.rules
+                .iter()
+                .map(|rule| rule.build(probes, events))
+                .collect(),
+        )
+    }
+
+    /// Check if any of these rules is a `notify` rule, which needs
+    /// defibrillator to provide a notification socket to the server
+    pub fn uses_notify(&self) -> bool {
+        self.rules.iter().any(|rules| rules.uses_notify())
+    }
+
+    /// Check if any of these rules is a `matches` rule that watches `stream`,
+    /// which needs defibrillator to read the server's output from it
+    pub fn uses_output(&self, stream: Stream) -> bool {
+        self.rules.iter().any(|rules| rules.uses_output(stream))
+    }
+
+    /// Wait for these rules to be satisfied, polling at the default interval.
+    /// `matches` rules watch `log_lines`; `notify` rules are never satisfied.
+    pub async fn wait(
+        &self,
+        client: &Client,
+        log_lines: &Sender<LogLine>,
+    ) -> Result<rule_futures::Satisfied, rule_futures::Unsatisfiable> {
+        let probes = ProbeConfig {
+            client,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            timeout: DEFAULT_PROBE_TIMEOUT,
+        };
+
+        let events = ServerEvents {
+            log_lines: log_lines.clone(),
+            notifications: broadcast::channel(1).0,
+            variables: Variables::default(),
+            strip_ansi: true,
+            pending: PendingRules::default(),
+        };
+
+        self.build(probes, &events).wait().await
+    }
+}
+
+impl From<AndRules> for OrRules {
+    fn from(rules: AndRules) -> Self {
+        OrRules::new(vec![rules])
+    }
+}
+
+/// The complete set of startup rules: the rules that signal readiness, plus
+/// an optional `fails-if` set that, when satisfied, signals that the server
+/// has failed to start.
+#[derive(Debug, Clone)]
+pub struct Rules {
+    ready: OrRules,
+    fails_if: Option<OrRules>,
+}
+
+impl Rules {
+    pub fn new(ready: OrRules, fails_if: Option<OrRules>) -> Self {
+        Self { ready, fails_if }
+    }
+
+    pub fn ready(&self) -> &OrRules {
+        &self.ready
+    }
+
+    pub fn fails_if(&self) -> Option<&OrRules> {
+        self.fails_if.as_ref()
+    }
+
+    pub fn uses_notify(&self) -> bool {
+        self.ready.uses_notify() || self.fails_if.iter().any(|rules| rules.uses_notify())
+    }
+
+    pub fn uses_output(&self, stream: Stream) -> bool {
+        self.ready.uses_output(stream)
+            || self.fails_if.iter().any(|rules| rules.uses_output(stream))
+    }
+}
+
+// Descriptions of rules for --explain. Each rule is written on one line, with
+// the defaults that apply to it filled in; groups are written as an indented
+// tree.
+
+impl fmt::Display for Port {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Port::Number(port) => write!(f, "{}", port),
+            Port::Variable(name) => write!(f, "${}", name),
+        }
+    }
+}
+
+/// Write a network rule's address, with the default host filled in
+fn write_address(f: &mut fmt::Formatter<'_>, host: &Option<String>, port: &Port) -> fmt::Result {
+    write!(f, "{}:{}", host.as_deref().unwrap_or(DEFAULT_HOST), port)
+}
+
+/// Write a rule's poll interval, if it has its own
+fn write_interval(f: &mut fmt::Formatter<'_>, interval: Option<Duration>) -> fmt::Result {
+    match interval {
+        Some(interval) => write!(f, " every {:?}", interval),
+        None => Ok(()),
+    }
+}
+
+fn write_payload(f: &mut fmt::Formatter<'_>, payload: &[u8]) -> fmt::Result {
+    write!(f, " send {:?}", String::from_utf8_lossy(payload))
+}
+
+fn write_pattern(f: &mut fmt::Formatter<'_>, pattern: &Regex) -> fmt::Result {
+    write!(f, "{:?}", pattern.as_str())
+}
+
+impl fmt::Display for After {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "after {:?}", self.duration)
+    }
+}
+
+impl HttpOptions {
+    fn describe(&self, f: &mut fmt::Formatter<'_>, scheme: &str, default_port: u16) -> fmt::Result {
+        let port = self
+            .port
+            .clone()
+            .unwrap_or_else(|| Port::Number(NonZeroU16::new(default_port).unwrap()));
+
+        write!(f, "{} {} {}://", scheme, self.request_method(), scheme)?;
+
+        // Only the host is used with a Unix socket, for the Host header
+        match self.socket {
+            Some(..) => write!(f, "{}", self.host.as_deref().unwrap_or(DEFAULT_HOST))?,
+            None => write_address(f, &self.host, &port)?,
+        }
+        write!(f, "{}", self.path.as_deref().unwrap_or("/"))?;
+
+        if let Some(ref socket) = self.socket {
+            write!(f, " over unix {}", socket.display())?;
+        }
+
+        if let Some(ref json) = self.json {
+            write!(f, " json {}", json)?;
+        }
+
+        if let Some(ref body) = self.body {
+            write!(f, " body matches ")?;
+            write_pattern(f, body)?;
+        }
+
+        // Header values are left out, since they're often credentials
+        for (name, _) in &self.headers {
+            write!(f, " header {}", name)?;
+        }
+
+        match self.statuses.is_empty() {
+            true if !self.headers.is_empty() => write!(f, " status any but 401")?,
+            true => write!(f, " status any")?,
+            false => {
+                write!(f, " status ")?;
+
+                for (idx, range) in self.statuses.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ",")?;
+                    }
+
+                    match range.start() == range.end() {
+                        true => write!(f, "{}", range.start())?,
+                        false => write!(f, "{}-{}", range.start(), range.end())?,
+                    }
+                }
+            }
+        }
+
+        if self.insecure {
+            write!(f, " insecure")?;
+        }
+
+        if self.ca.is_some() {
+            write!(f, " ca <certificate>")?;
+        }
+
+        write_interval(f, self.interval)?;
+
+        match self.timeout {
+            Some(timeout) => write!(f, " timeout {:?}", timeout),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Http {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.options.describe(f, "http", 80)
+    }
+}
+
+impl fmt::Display for Https {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.options.describe(f, "https", 443)
+    }
+}
+
+impl fmt::Display for Tcp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "tcp ")?;
+        write_address(f, &self.host, &self.port)?;
+
+        if let Some(ref payload) = self.payload {
+            write_payload(f, payload)?;
+        }
+
+        if let Some(ref expect) = self.expect {
+            write!(f, " expect ")?;
+            write_pattern(f, expect)?;
+        }
+
+        write_interval(f, self.interval)
+    }
+}
+
+impl fmt::Display for Tls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let host = self.host.as_deref().unwrap_or(DEFAULT_HOST);
+
+        write!(f, "tls ")?;
+        write_address(f, &self.host, &self.port)?;
+        write!(f, " name {}", self.name.as_deref().unwrap_or(host))?;
+        write_interval(f, self.interval)
+    }
+}
+
+impl Service {
+    fn describe(&self, f: &mut fmt::Formatter<'_>, name: &str, default_port: u16) -> fmt::Result {
+        let port = self
+            .port
+            .clone()
+            .unwrap_or_else(|| Port::Number(NonZeroU16::new(default_port).unwrap()));
+
+        write!(f, "{} ", name)?;
+        write_address(f, &self.host, &port)
+    }
+}
+
+impl fmt::Display for Postgres {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.service.describe(f, "postgres", 5432)?;
+        write!(f, " user {}", self.user.as_deref().unwrap_or("postgres"))?;
+
+        if let Some(ref database) = self.database {
+            write!(f, " database {}", database)?;
+        }
+
+        write_interval(f, self.service.interval)
+    }
+}
+
+impl fmt::Display for Mongodb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.service.describe(f, "mongodb", 27017)?;
+        write_interval(f, self.service.interval)
+    }
+}
+
+impl fmt::Display for Zookeeper {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.service.describe(f, "zookeeper", 2181)?;
+        write_interval(f, self.service.interval)
+    }
+}
+
+impl fmt::Display for Mqtt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.service.describe(f, "mqtt", 1883)?;
+
+        if let Some(ref user) = self.user {
+            write!(f, " user {}", user)?;
+        }
+
+        if self.password.is_some() {
+            write!(f, " auth <password>")?;
+        }
+
+        write_interval(f, self.service.interval)
+    }
+}
+
+impl fmt::Display for Elasticsearch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.service.describe(f, "elasticsearch", 9200)?;
+        write!(
+            f,
+            " status {}",
+            self.status.unwrap_or(ClusterHealth::Yellow)
+        )?;
+        write_interval(f, self.service.interval)
+    }
+}
+
+impl fmt::Display for Etcd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.service.describe(f, "etcd", 2379)?;
+        write_interval(f, self.service.interval)
+    }
+}
+
+impl fmt::Display for Consul {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.service.describe(f, "consul", 8500)?;
+        write_interval(f, self.service.interval)
+    }
+}
+
+impl fmt::Display for Smtp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.service.describe(f, "smtp", 25)?;
+
+        if let Some(ref domain) = self.ehlo {
+            write!(f, " ehlo {}", domain)?;
+        }
+
+        write_interval(f, self.service.interval)
+    }
+}
+
+impl fmt::Display for Ssh {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.service.describe(f, "ssh", 22)?;
+        write_interval(f, self.service.interval)
+    }
+}
+
+impl fmt::Display for Ldap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.service.describe(f, "ldap", 389)?;
+        write_interval(f, self.service.interval)
+    }
+}
+
+impl fmt::Display for Redis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.service.describe(f, "redis", 6379)?;
+
+        if let Some(ref user) = self.user {
+            write!(f, " user {}", user)?;
+        }
+
+        if self.password.is_some() {
+            write!(f, " auth <password>")?;
+        }
+
+        write_interval(f, self.service.interval)
+    }
+}
+
+impl fmt::Display for Udp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "udp ")?;
+        write_address(f, &self.host, &self.port)?;
+        write_payload(f, &self.payload)?;
+
+        match self.expect {
+            Some(ref expect) => {
+                write!(f, " expect ")?;
+                write_pattern(f, expect)?;
+            }
+            None => write!(f, " expect any response")?,
+        }
+
+        write_interval(f, self.interval)
+    }
+}
+
+impl fmt::Display for File {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "file {}", self.path.display())?;
+
+        match self.pattern {
+            Some(ref pattern) => {
+                write!(f, " matches ")?;
+                write_pattern(f, pattern)?;
+            }
+            None => write!(f, " exists")?,
+        }
+
+        write_interval(f, self.interval)
+    }
+}
+
+impl fmt::Display for Pidfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pidfile {}", self.path.display())?;
+        write_interval(f, self.interval)
+    }
+}
+
+impl fmt::Display for Matches {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let multiline = self.window.get() > 1;
+
+        // A `contains` rule can't have a window, so one that does is written
+        // out with the pattern that it's matched with instead
+        let text = self.text.as_ref().filter(|_| !multiline);
+
+        let keyword = match (text, multiline) {
+            (Some(..), _) => "contains",
+            (None, true) => "matches-multiline",
+            (None, false) => "matches",
+        };
+        write!(f, "{} ", keyword)?;
+
+        if self.case_insensitive {
+            write!(f, "-i ")?;
+        }
+
+        match text {
+            Some(text) => write!(f, "{:?}", text)?,
+            None => write_pattern(f, &self.pattern)?,
+        }
+
+        if multiline {
+            write!(f, " window {}", self.window)?;
+        }
+
+        let stream = match self.stream {
+            Some(Stream::Stdout) => "stdout",
+            Some(Stream::Stderr) => "stderr",
+            None => "any",
+        };
+        write!(f, " on {} times {}", stream, self.times)
+    }
+}
+
+impl fmt::Display for Cmd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cmd {:?}", self.command)?;
+        write_interval(f, self.interval)
+    }
+}
+
+impl fmt::Display for Notify {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "notify ready")
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rule::After(after) => after.fmt(f),
+            Rule::Tcp(tcp) => tcp.fmt(f),
+            Rule::Postgres(postgres) => postgres.fmt(f),
+            Rule::Redis(redis) => redis.fmt(f),
+            Rule::Mongodb(mongodb) => mongodb.fmt(f),
+            Rule::Mqtt(mqtt) => mqtt.fmt(f),
+            Rule::Zookeeper(zookeeper) => zookeeper.fmt(f),
+            Rule::Elasticsearch(elasticsearch) => elasticsearch.fmt(f),
+            Rule::Etcd(etcd) => etcd.fmt(f),
+            Rule::Consul(consul) => consul.fmt(f),
+            Rule::Smtp(smtp) => smtp.fmt(f),
+            Rule::Ssh(ssh) => ssh.fmt(f),
+            Rule::Ldap(ldap) => ldap.fmt(f),
+            Rule::Udp(udp) => udp.fmt(f),
+            Rule::Tls(tls) => tls.fmt(f),
+            Rule::Http(http) => http.fmt(f),
+            Rule::Https(https) => https.fmt(f),
+            Rule::Matches(matches) => matches.fmt(f),
+            Rule::File(file) => file.fmt(f),
+            Rule::Pidfile(pidfile) => pidfile.fmt(f),
+            Rule::Cmd(cmd) => cmd.fmt(f),
+            Rule::Notify(notify) => notify.fmt(f),
+        }
+    }
+}
+
+fn write_indent(f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+    write!(f, "{:width$}", "", width = depth * 2)
+}
+
+impl Term {
+    fn explain(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        match self {
+            Term::Rule(rule) => {
+                write_indent(f, depth)?;
+                writeln!(f, "{}", rule)
+            }
+            Term::Group(group) => group.explain(f, depth),
+            Term::Sequence(sequence) => {
+                write_indent(f, depth)?;
+                writeln!(f, "in order:")?;
+
+                sequence
+                    .terms
+                    .iter()
+                    .try_for_each(|term| term.explain(f, depth + 1))
+            }
+            Term::Within(within) => {
+                write_indent(f, depth)?;
+                match within.fatal {
+                    true => writeln!(f, "within {:?}, else fail:", within.limit)?,
+                    false => writeln!(f, "within {:?}:", within.limit)?,
+                }
+
+                within.term.explain(f, depth + 1)
+            }
+            Term::Labeled(labeled) => match *labeled.term {
+                Term::Rule(ref rule) => {
+                    write_indent(f, depth)?;
+                    writeln!(f, "[{}] {}", labeled.label, rule)
+                }
+                ref term => {
+                    write_indent(f, depth)?;
+                    writeln!(f, "[{}]:", labeled.label)?;
+                    term.explain(f, depth + 1)
+                }
+            },
+        }
+    }
+}
+
+impl AndRules {
+    fn explain(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        if let ([term], []) = (self.rules.as_slice(), self.negated.as_slice()) {
+            return term.explain(f, depth);
+        }
+
+        write_indent(f, depth)?;
+        writeln!(f, "all of:")?;
+
+        for term in &self.rules {
+            term.explain(f, depth + 1)?;
+        }
+
+        for term in &self.negated {
+            write_indent(f, depth + 1)?;
+            writeln!(f, "not:")?;
+            term.explain(f, depth + 2)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl OrRules {
+    fn explain(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        if let [rules] = self.rules.as_slice() {
+            return rules.explain(f, depth);
+        }
+
+        write_indent(f, depth)?;
+        writeln!(f, "any of:")?;
+
+        self.rules
+            .iter()
+            .try_for_each(|rules| rules.explain(f, depth + 1))
+    }
+}
+
+impl fmt::Display for OrRules {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.explain(f, 0)
+    }
+}
+
+impl fmt::Display for Rules {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.ready.explain(f, 0)?;
+
+        if let Some(ref fails_if) = self.fails_if {
+            writeln!(f, "fails if:")?;
+            fails_if.explain(f, 1)?;
+        }
+
+        Ok(())
+    }
+}
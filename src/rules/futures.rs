@@ -1,20 +1,128 @@
+//! Futures that wait for readiness rules to be satisfied. These are created
+//! by the `build` methods of the rule types in the parent module.
+
+#[cfg(unix)]
+use std::path::Path;
 use std::{
-    net::{Ipv4Addr, SocketAddrV4},
-    num::NonZeroU16,
+    borrow::Cow,
+    collections::VecDeque,
+    fmt,
+    future::Future,
+    io,
+    io::SeekFrom,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    num::{NonZeroU16, NonZeroU32, NonZeroUsize},
+    ops::RangeInclusive,
+    path::PathBuf,
+    process::Stdio,
     time::Duration,
 };
 
-use bytes::Bytes;
-use futures::{future::pending, stream::FuturesUnordered, StreamExt};
-use regex::bytes::Regex;
-use reqwest::Client;
+use futures::{
+    future::{pending, FutureExt},
+    select_biased,
+    stream::FuturesUnordered,
+    StreamExt,
+};
+use regex::bytes::{Captures, Regex};
+use reqwest::{
+    header::{HeaderName, HeaderValue},
+    Client, Method, StatusCode,
+};
 use tokio::{
-    net::TcpStream,
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt},
+    net::{lookup_host, TcpStream, UdpSocket},
+    process::Child,
     sync::broadcast::{error::RecvError, Receiver},
-    time::{sleep, sleep_until, Instant},
+    time::{sleep, sleep_until, timeout, timeout_at, Instant},
 };
+#[cfg(unix)]
+use tokio::{net::UnixStream, process::Command};
+use tokio_native_tls::TlsConnector;
 use tracing::{debug, debug_span, error, trace, warn, Instrument, Level};
 
+use super::{descriptors, probes, PendingRules, ProbeConfig, ServerEvents, Variables};
+use crate::{
+    output::{strip_ansi, LogLine, Stream},
+    sd_notify::Notification,
+};
+
+/// Connect to `host:port` and run `probe` on the connection, once per
+/// interval, until it reports that the server is ready. Each attempt,
+/// including connecting, may take up to `limit`.
+async fn poll_connection<F, Fut>(
+    host: &str,
+    port: NonZeroU16,
+    interval: Duration,
+    limit: Duration,
+    mut probe: F,
+) where
+    F: FnMut(TcpStream) -> Fut,
+    Fut: Future<Output = io::Result<bool>>,
+{
+    loop {
+        let now = Instant::now();
+
+        trace!("connecting...");
+        let attempt = async {
+            // Resolve the host on every attempt, in case it's a DNS name for
+            // something that's also starting up
+            let stream = TcpStream::connect((host, port.get())).await?;
+            probe(stream).await
+        };
+
+        match timeout(limit, attempt).await {
+            Ok(Ok(true)) => {
+                debug!("server is ready");
+                return;
+            }
+            Ok(Ok(false)) => trace!("server isn't ready yet"),
+            Ok(Err(err)) => trace!(error = %err, "probe failed"),
+            Err(..) => trace!("probe timed out"),
+        }
+
+        // Make at most 1 attempt per interval.
+        sleep_until(now + interval).await
+    }
+}
+
+/// Repeatedly send a GET request to a JSON health API, until `check` accepts
+/// a response. This is the HTTP counterpart to `poll_connection`, for rules
+/// like `elasticsearch`.
+async fn poll_json<F>(client: &Client, url: &str, interval: Duration, limit: Duration, mut check: F)
+where
+    F: FnMut(&serde_json::Value) -> bool,
+{
+    loop {
+        let now = Instant::now();
+
+        trace!("sending request...");
+        let attempt = async {
+            client
+                .get(url)
+                .timeout(limit)
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<serde_json::Value>()
+                .await
+        };
+
+        match attempt.await {
+            Ok(response) if check(&response) => {
+                debug!("server is ready");
+                return;
+            }
+            Ok(..) => trace!("server isn't ready yet"),
+            Err(err) => trace!(error = %err, "request failed"),
+        }
+
+        // Make at most 1 attempt per interval.
+        sleep_until(now + interval).await
+    }
+}
+
 #[derive(Debug)]
 pub struct After {
     duration: Duration,
@@ -32,87 +140,811 @@ impl After {
     }
 }
 
+/// A fully resolved HTTP readiness request
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub host: String,
+    pub port: NonZeroU16,
+    pub method: Method,
+    pub path: String,
+
+    /// The set of acceptable status codes; if empty, any response is
+    /// accepted, except a 401 when headers are sent
+    pub statuses: Vec<RangeInclusive<u16>>,
+
+    /// How long each request may take
+    pub timeout: Duration,
+
+    /// A Unix socket to send the request over, rather than connecting to the
+    /// host and port. The host is still sent in the `Host` header.
+    pub socket: Option<PathBuf>,
+
+    /// A condition on the JSON response body, if the body is checked at all
+    pub json: Option<descriptors::JsonField>,
+
+    /// A pattern the response body must match
+    pub body: Option<Regex>,
+
+    /// Extra request headers, including any credentials
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+fn url(protocol: &str, host: &str, port: NonZeroU16, path: &str) -> String {
+    // IPv6 literals need to be bracketed in URLs
+    match host.parse::<Ipv6Addr>() {
+        Ok(..) => format!("{}://[{}]:{}{}", protocol, host, port, path),
+        Err(..) => format!("{}://{}:{}{}", protocol, host, port, path),
+    }
+}
+
+impl HttpRequest {
+    fn url(&self, protocol: &str) -> String {
+        url(protocol, &self.host, self.port, &self.path)
+    }
+
+    fn accepts(&self, status: StatusCode) -> bool {
+        match self.statuses.is_empty() {
+            // A 401 means the server is up, but that it rejected our
+            // credentials, so whatever they guard can't be probed
+            true => self.headers.is_empty() || status != StatusCode::UNAUTHORIZED,
+            false => self
+                .statuses
+                .iter()
+                .any(|range| range.contains(&status.as_u16())),
+        }
+    }
+
+    /// Whether the response body needs to be read to check it
+    fn reads_body(&self) -> bool {
+        self.json.is_some() || self.body.is_some()
+    }
+
+    fn accepts_body(&self, body: &[u8]) -> bool {
+        self.json.iter().all(|json| json.matches(body))
+            && self.body.iter().all(|pattern| pattern.is_match(body))
+    }
+}
+
 #[tracing::instrument(name = "http", level = Level::DEBUG, skip(client))]
-async fn http_family_ready(protocol: &str, port: NonZeroU16, client: &Client) {
-    let request = client
-        .head(format!("{}://127.0.0.1:{}", protocol, port))
-        .timeout(Duration::from_secs(60));
+async fn http_family_ready(
+    protocol: &str,
+    request: &HttpRequest,
+    interval: Duration,
+    client: &Client,
+) {
+    let builder = request.headers.iter().fold(
+        client
+            .request(request.method.clone(), request.url(protocol))
+            .timeout(request.timeout),
+        |builder, (name, value)| builder.header(name, value),
+    );
+
+    loop {
+        let now = Instant::now();
+
+        trace!("sending request...");
+        match builder.try_clone().unwrap().send().await {
+            Ok(response) if !request.accepts(response.status()) => {
+                trace!(status = %response.status(), "unexpected status")
+            }
+            Ok(response) if !request.reads_body() => {
+                debug!(status = %response.status(), "request successful");
+                return;
+            }
+            Ok(response) => {
+                let status = response.status();
+
+                match response.bytes().await {
+                    Ok(body) if request.accepts_body(&body) => {
+                        debug!(%status, "request successful");
+                        return;
+                    }
+                    Ok(..) => trace!(%status, "unexpected body"),
+                    Err(err) => trace!(error = %err, "failed to read body"),
+                }
+            }
+            Err(..) => {}
+        }
 
+        // Make at most 1 attempt per interval.
+        sleep_until(now + interval).await
+    }
+}
+
+/// Like `http_family_ready`, but for a request sent over a Unix socket
+#[cfg(unix)]
+#[tracing::instrument(name = "http", level = Level::DEBUG, skip(request), fields(socket = %socket.display()))]
+async fn http_unix_ready(socket: &Path, request: &HttpRequest, interval: Duration) {
     loop {
-        // At most 1 attempt per second
         let now = Instant::now();
 
         trace!("sending request...");
-        match request.try_clone().unwrap().send().await {
-            // We don't care *what* the response is, only that a response was received
-            Ok(..) => {
-                debug!("request successful");
+        let attempt = async {
+            let stream = UnixStream::connect(socket).await?;
+            probes::http_unix(
+                stream,
+                &request.method,
+                &request.host,
+                &request.path,
+                &request.headers,
+                request.reads_body(),
+            )
+            .await
+        };
+
+        match timeout(request.timeout, attempt).await {
+            Ok(Ok((status, _))) if !request.accepts(status) => {
+                trace!(%status, "unexpected status")
+            }
+            Ok(Ok((status, Some(body)))) if !request.accepts_body(&body) => {
+                trace!(%status, "unexpected body")
+            }
+            Ok(Ok((status, _))) => {
+                debug!(%status, "request successful");
                 return;
             }
-            // Make at most 1 attempt per second.
-            Err(..) => sleep_until(now + Duration::from_secs(1)).await,
+            Ok(Err(err)) => trace!(error = %err, "request failed"),
+            Err(..) => trace!("request timed out"),
         }
+
+        // Make at most 1 attempt per interval.
+        sleep_until(now + interval).await
     }
 }
 
 #[derive(Debug)]
 pub struct Http<'a> {
-    port: NonZeroU16,
+    request: Box<HttpRequest>,
+    interval: Duration,
     client: &'a Client,
 }
 
 impl<'a> Http<'a> {
-    pub(super) fn new(port: NonZeroU16, client: &'a Client) -> Self {
-        Self { port, client }
+    pub(super) fn new(request: HttpRequest, interval: Duration, client: &'a Client) -> Self {
+        Self {
+            request: Box::new(request),
+            interval,
+            client,
+        }
     }
 
     pub async fn wait(self) {
-        http_family_ready("http", self.port, self.client).await
+        match self.request.socket {
+            #[cfg(unix)]
+            Some(ref socket) => http_unix_ready(socket, &self.request, self.interval).await,
+            _ => http_family_ready("http", &self.request, self.interval, self.client).await,
+        }
     }
 }
 #[derive(Debug)]
 pub struct Https<'a> {
-    port: NonZeroU16,
-    client: &'a Client,
+    request: Box<HttpRequest>,
+    interval: Duration,
+    client: Cow<'a, Client>,
 }
 
 impl<'a> Https<'a> {
-    pub(super) fn new(port: NonZeroU16, client: &'a Client) -> Self {
-        Self { port, client }
+    pub(super) fn new(request: HttpRequest, interval: Duration, client: Cow<'a, Client>) -> Self {
+        Self {
+            request: Box::new(request),
+            interval,
+            client,
+        }
     }
 
     pub async fn wait(self) {
-        http_family_ready("http", self.port, self.client).await
+        http_family_ready("https", &self.request, self.interval, &self.client).await
     }
 }
 
 #[derive(Debug)]
 pub struct Tcp {
+    host: String,
     port: NonZeroU16,
+    payload: Option<Vec<u8>>,
+    expect: Option<Regex>,
+    interval: Duration,
+    timeout: Duration,
 }
 
 impl Tcp {
-    pub(super) fn new(port: NonZeroU16) -> Self {
-        Self { port }
+    pub(super) fn new(
+        host: String,
+        port: NonZeroU16,
+        payload: Option<Vec<u8>>,
+        expect: Option<Regex>,
+        interval: Duration,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            host,
+            port,
+            payload,
+            expect,
+            interval,
+            timeout,
+        }
+    }
+
+    #[tracing::instrument(name = "tcp", level = Level::DEBUG, skip(self), fields(host = %self.host, port = ?self.port))]
+    pub async fn wait(self) {
+        poll_connection(
+            &self.host,
+            self.port,
+            self.interval,
+            self.timeout,
+            |stream| probes::send_expect(stream, self.payload.as_deref(), self.expect.as_ref()),
+        )
+        .await
+    }
+}
+
+pub struct Tls {
+    name: String,
+    host: String,
+    port: NonZeroU16,
+    connector: TlsConnector,
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl Tls {
+    pub(super) fn new(
+        name: String,
+        host: String,
+        port: NonZeroU16,
+        connector: TlsConnector,
+        interval: Duration,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            name,
+            host,
+            port,
+            connector,
+            interval,
+            timeout,
+        }
+    }
+
+    #[tracing::instrument(name = "tls", level = Level::DEBUG, skip(self), fields(host = %self.host, port = ?self.port, name = %self.name))]
+    pub async fn wait(self) {
+        poll_connection(
+            &self.host,
+            self.port,
+            self.interval,
+            self.timeout,
+            |stream| probes::tls(stream, &self.connector, &self.name),
+        )
+        .await
+    }
+}
+
+// tokio_native_tls::TlsConnector doesn't implement Debug
+impl fmt::Debug for Tls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tls")
+            .field("name", &self.name)
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("interval", &self.interval)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+pub struct Postgres {
+    host: String,
+    port: NonZeroU16,
+    user: String,
+    database: Option<String>,
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl Postgres {
+    pub(super) fn new(
+        host: String,
+        port: NonZeroU16,
+        user: String,
+        database: Option<String>,
+        interval: Duration,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            host,
+            port,
+            user,
+            database,
+            interval,
+            timeout,
+        }
+    }
+
+    #[tracing::instrument(name = "postgres", level = Level::DEBUG, skip(self), fields(host = %self.host, port = ?self.port))]
+    pub async fn wait(self) {
+        poll_connection(
+            &self.host,
+            self.port,
+            self.interval,
+            self.timeout,
+            |stream| probes::postgres(stream, &self.user, self.database.as_deref()),
+        )
+        .await
+    }
+}
+
+#[derive(Debug)]
+pub struct Redis {
+    host: String,
+    port: NonZeroU16,
+    user: Option<String>,
+    password: Option<String>,
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl Redis {
+    pub(super) fn new(
+        host: String,
+        port: NonZeroU16,
+        user: Option<String>,
+        password: Option<String>,
+        interval: Duration,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            host,
+            port,
+            user,
+            password,
+            interval,
+            timeout,
+        }
+    }
+
+    #[tracing::instrument(name = "redis", level = Level::DEBUG, skip(self), fields(host = %self.host, port = ?self.port))]
+    pub async fn wait(self) {
+        poll_connection(
+            &self.host,
+            self.port,
+            self.interval,
+            self.timeout,
+            |stream| probes::redis(stream, self.user.as_deref(), self.password.as_deref()),
+        )
+        .await
+    }
+}
+
+#[derive(Debug)]
+pub struct Mongodb {
+    host: String,
+    port: NonZeroU16,
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl Mongodb {
+    pub(super) fn new(
+        host: String,
+        port: NonZeroU16,
+        interval: Duration,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            host,
+            port,
+            interval,
+            timeout,
+        }
+    }
+
+    #[tracing::instrument(name = "mongodb", level = Level::DEBUG, skip(self), fields(host = %self.host, port = ?self.port))]
+    pub async fn wait(self) {
+        poll_connection(
+            &self.host,
+            self.port,
+            self.interval,
+            self.timeout,
+            probes::mongodb,
+        )
+        .await
+    }
+}
+
+#[derive(Debug)]
+pub struct Smtp {
+    host: String,
+    port: NonZeroU16,
+    ehlo: Option<String>,
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl Smtp {
+    pub(super) fn new(
+        host: String,
+        port: NonZeroU16,
+        ehlo: Option<String>,
+        interval: Duration,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            host,
+            port,
+            ehlo,
+            interval,
+            timeout,
+        }
+    }
+
+    #[tracing::instrument(name = "smtp", level = Level::DEBUG, skip(self), fields(host = %self.host, port = ?self.port))]
+    pub async fn wait(self) {
+        poll_connection(
+            &self.host,
+            self.port,
+            self.interval,
+            self.timeout,
+            |stream| probes::smtp(stream, self.ehlo.as_deref()),
+        )
+        .await
+    }
+}
+
+#[derive(Debug)]
+pub struct Ssh {
+    host: String,
+    port: NonZeroU16,
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl Ssh {
+    pub(super) fn new(
+        host: String,
+        port: NonZeroU16,
+        interval: Duration,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            host,
+            port,
+            interval,
+            timeout,
+        }
+    }
+
+    #[tracing::instrument(name = "ssh", level = Level::DEBUG, skip(self), fields(host = %self.host, port = ?self.port))]
+    pub async fn wait(self) {
+        poll_connection(
+            &self.host,
+            self.port,
+            self.interval,
+            self.timeout,
+            probes::ssh,
+        )
+        .await
+    }
+}
+
+#[derive(Debug)]
+pub struct Ldap {
+    host: String,
+    port: NonZeroU16,
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl Ldap {
+    pub(super) fn new(
+        host: String,
+        port: NonZeroU16,
+        interval: Duration,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            host,
+            port,
+            interval,
+            timeout,
+        }
+    }
+
+    #[tracing::instrument(name = "ldap", level = Level::DEBUG, skip(self), fields(host = %self.host, port = ?self.port))]
+    pub async fn wait(self) {
+        poll_connection(
+            &self.host,
+            self.port,
+            self.interval,
+            self.timeout,
+            probes::ldap,
+        )
+        .await
+    }
+}
+
+#[derive(Debug)]
+pub struct Zookeeper {
+    host: String,
+    port: NonZeroU16,
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl Zookeeper {
+    pub(super) fn new(
+        host: String,
+        port: NonZeroU16,
+        interval: Duration,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            host,
+            port,
+            interval,
+            timeout,
+        }
+    }
+
+    #[tracing::instrument(name = "zookeeper", level = Level::DEBUG, skip(self), fields(host = %self.host, port = ?self.port))]
+    pub async fn wait(self) {
+        poll_connection(
+            &self.host,
+            self.port,
+            self.interval,
+            self.timeout,
+            probes::zookeeper,
+        )
+        .await
+    }
+}
+
+#[derive(Debug)]
+pub struct Mqtt {
+    host: String,
+    port: NonZeroU16,
+    user: Option<String>,
+    password: Option<String>,
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl Mqtt {
+    pub(super) fn new(
+        host: String,
+        port: NonZeroU16,
+        user: Option<String>,
+        password: Option<String>,
+        interval: Duration,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            host,
+            port,
+            user,
+            password,
+            interval,
+            timeout,
+        }
     }
 
-    #[tracing::instrument(name = "tcp", level = Level::DEBUG, skip(self), fields(port = ?self.port))]
+    #[tracing::instrument(name = "mqtt", level = Level::DEBUG, skip(self), fields(host = %self.host, port = ?self.port))]
     pub async fn wait(self) {
-        let localhost = Ipv4Addr::new(127, 0, 0, 1);
-        let socket = SocketAddrV4::new(localhost, self.port.get());
+        poll_connection(
+            &self.host,
+            self.port,
+            self.interval,
+            self.timeout,
+            |stream| probes::mqtt(stream, self.user.as_deref(), self.password.as_deref()),
+        )
+        .await
+    }
+}
+
+#[derive(Debug)]
+pub struct Elasticsearch<'a> {
+    host: String,
+    port: NonZeroU16,
+    status: descriptors::ClusterHealth,
+    interval: Duration,
+    timeout: Duration,
+    client: &'a Client,
+}
 
+impl<'a> Elasticsearch<'a> {
+    pub(super) fn new(
+        host: String,
+        port: NonZeroU16,
+        status: descriptors::ClusterHealth,
+        interval: Duration,
+        timeout: Duration,
+        client: &'a Client,
+    ) -> Self {
+        Self {
+            host,
+            port,
+            status,
+            interval,
+            timeout,
+            client,
+        }
+    }
+
+    #[tracing::instrument(name = "elasticsearch", level = Level::DEBUG, skip(self), fields(host = %self.host, port = ?self.port))]
+    pub async fn wait(self) {
+        let url = url("http", &self.host, self.port, "/_cluster/health");
+        let status = self.status;
+
+        poll_json(self.client, &url, self.interval, self.timeout, |health| {
+            let health: Option<descriptors::ClusterHealth> = health["status"]
+                .as_str()
+                .and_then(|health| health.parse().ok());
+
+            trace!(health = ?health, "received cluster health");
+            matches!(health, Some(health) if health >= status)
+        })
+        .await
+    }
+}
+
+#[derive(Debug)]
+pub struct Etcd<'a> {
+    host: String,
+    port: NonZeroU16,
+    interval: Duration,
+    timeout: Duration,
+    client: &'a Client,
+}
+
+impl<'a> Etcd<'a> {
+    pub(super) fn new(
+        host: String,
+        port: NonZeroU16,
+        interval: Duration,
+        timeout: Duration,
+        client: &'a Client,
+    ) -> Self {
+        Self {
+            host,
+            port,
+            interval,
+            timeout,
+            client,
+        }
+    }
+
+    #[tracing::instrument(name = "etcd", level = Level::DEBUG, skip(self), fields(host = %self.host, port = ?self.port))]
+    pub async fn wait(self) {
+        let url = url("http", &self.host, self.port, "/health");
+
+        // etcd reports its health as a string
+        poll_json(self.client, &url, self.interval, self.timeout, |health| {
+            trace!(%health, "received health");
+            health["health"] == "true"
+        })
+        .await
+    }
+}
+
+#[derive(Debug)]
+pub struct Consul<'a> {
+    host: String,
+    port: NonZeroU16,
+    interval: Duration,
+    timeout: Duration,
+    client: &'a Client,
+}
+
+impl<'a> Consul<'a> {
+    pub(super) fn new(
+        host: String,
+        port: NonZeroU16,
+        interval: Duration,
+        timeout: Duration,
+        client: &'a Client,
+    ) -> Self {
+        Self {
+            host,
+            port,
+            interval,
+            timeout,
+            client,
+        }
+    }
+
+    #[tracing::instrument(name = "consul", level = Level::DEBUG, skip(self), fields(host = %self.host, port = ?self.port))]
+    pub async fn wait(self) {
+        let url = url("http", &self.host, self.port, "/v1/status/leader");
+
+        // The leader's address, or an empty string while there's no leader
+        poll_json(self.client, &url, self.interval, self.timeout, |leader| {
+            trace!(%leader, "received leader");
+            matches!(leader.as_str(), Some(leader) if !leader.is_empty())
+        })
+        .await
+    }
+}
+
+#[derive(Debug)]
+pub struct Udp {
+    host: String,
+    port: NonZeroU16,
+    payload: Vec<u8>,
+    expect: Option<Regex>,
+    interval: Duration,
+}
+
+impl Udp {
+    pub(super) fn new(
+        host: String,
+        port: NonZeroU16,
+        payload: Vec<u8>,
+        expect: Option<Regex>,
+        interval: Duration,
+    ) -> Self {
+        Self {
+            host,
+            port,
+            payload,
+            expect,
+            interval,
+        }
+    }
+
+    /// Send the payload, then wait until `deadline` for a matching response
+    async fn attempt(&self, deadline: Instant) -> io::Result<bool> {
+        let address = lookup_host((self.host.as_str(), self.port.get()))
+            .await?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host has no addresses"))?;
+
+        let local: SocketAddr = match address {
+            SocketAddr::V4(..) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(..) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(address).await?;
+        socket.send(&self.payload).await?;
+
+        let mut buffer = vec![0; 65536];
+
+        loop {
+            let len = match timeout_at(deadline, socket.recv(&mut buffer)).await {
+                Ok(result) => result?,
+                Err(..) => return Ok(false),
+            };
+
+            let response = &buffer[..len];
+            trace!(len, "received response");
+
+            match self.expect {
+                Some(ref expect) if !expect.is_match(response) => {
+                    trace!("response didn't match")
+                }
+                _ => return Ok(true),
+            }
+        }
+    }
+
+    #[tracing::instrument(name = "udp", level = Level::DEBUG, skip(self), fields(host = %self.host, port = ?self.port))]
+    pub async fn wait(self) {
         loop {
             let now = Instant::now();
 
-            trace!("connecting...");
-            match TcpStream::connect(socket).await {
-                Ok(..) => {
-                    debug!("connection established");
+            // Each attempt waits for a response for up to one interval
+            trace!("sending datagram...");
+            match self.attempt(now + self.interval).await {
+                Ok(true) => {
+                    debug!("received expected response");
                     return;
                 }
-                // Make at most 1 attempt per second.
-                Err(..) => sleep_until(now + Duration::from_secs(1)).await,
+                Ok(false) => trace!("no matching response"),
+                Err(err) => trace!(error = %err, "probe failed"),
             }
+
+            sleep_until(now + self.interval).await
         }
     }
 }
@@ -120,25 +952,92 @@ impl Tcp {
 #[derive(Debug)]
 pub struct Matches {
     pattern: Regex,
-    log_lines: Receiver<Bytes>,
+    stream: Option<Stream>,
+    times: NonZeroU32,
+    window: NonZeroUsize,
+    log_lines: Receiver<LogLine>,
+    variables: Variables,
+    strip_ansi: bool,
 }
 
 impl Matches {
-    pub(super) fn new(pattern: Regex, log_lines: Receiver<Bytes>) -> Self {
-        Self { pattern, log_lines }
+    pub(super) fn new(
+        pattern: Regex,
+        stream: Option<Stream>,
+        times: NonZeroU32,
+        window: NonZeroUsize,
+        log_lines: Receiver<LogLine>,
+        variables: Variables,
+        strip_ansi: bool,
+    ) -> Self {
+        Self {
+            pattern,
+            stream,
+            times,
+            window,
+            log_lines,
+            variables,
+            strip_ansi,
+        }
+    }
+
+    /// Store the values of the pattern's named groups
+    fn capture(&self, captures: &Captures<'_>) {
+        for name in self.pattern.capture_names().flatten() {
+            if let Some(value) = captures.name(name) {
+                let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+                debug!(name, value = %value, "captured variable");
+                self.variables.set(name.to_owned(), value);
+            }
+        }
     }
 
     #[tracing::instrument(name = "matches", skip(self), fields(pattern = %self.pattern))]
     pub async fn wait(mut self) {
+        let mut remaining = self.times.get();
+
+        // The most recent lines, without their line endings, when matching
+        // against a window of several lines
+        let mut recent: VecDeque<Vec<u8>> = VecDeque::with_capacity(self.window.get());
+
         loop {
             match self.log_lines.recv().await {
-                Ok(line) => {
+                Ok(line) if self.stream.is_none_or(|stream| stream == line.stream) => {
                     trace!("testing log line");
-                    if self.pattern.is_match(&line) {
-                        debug!("log line matched");
-                        return;
+
+                    let content = match self.strip_ansi {
+                        true => strip_ansi(&line.content),
+                        false => Cow::Borrowed(&line.content[..]),
+                    };
+
+                    let content = match self.window.get() {
+                        1 => content,
+                        window => {
+                            if recent.len() == window {
+                                recent.pop_front();
+                            }
+
+                            let line = content.strip_suffix(b"\n").unwrap_or(&content);
+                            let line = line.strip_suffix(b"\r").unwrap_or(line);
+                            recent.push_back(line.to_vec());
+
+                            Cow::Owned(recent.make_contiguous().join(&b'\n'))
+                        }
+                    };
+
+                    if let Some(captures) = self.pattern.captures(&content) {
+                        // Don't count the same lines again for the next match
+                        recent.clear();
+                        self.capture(&captures);
+                        remaining -= 1;
+                        debug!(remaining, "log line matched");
+
+                        if remaining == 0 {
+                            return;
+                        }
                     }
                 }
+                Ok(_) => {}
                 Err(err) => match err {
                     RecvError::Closed => {
                         warn!("log lines channel closed");
@@ -155,13 +1054,311 @@ impl Matches {
     }
 }
 
+#[derive(Debug)]
+pub struct File {
+    path: PathBuf,
+    pattern: Option<Regex>,
+    interval: Duration,
+}
+
+/// The read position in a file being tailed
+#[derive(Debug, Default)]
+struct Tail {
+    offset: u64,
+
+    /// The incomplete last line read so far
+    partial: Vec<u8>,
+}
+
+impl File {
+    pub(super) fn new(path: PathBuf, pattern: Option<Regex>, interval: Duration) -> Self {
+        Self {
+            path,
+            pattern,
+            interval,
+        }
+    }
+
+    /// Read everything appended to the file since the last read, and check
+    /// each complete line against the pattern
+    async fn read_lines(&self, pattern: &Regex, tail: &mut Tail) -> io::Result<bool> {
+        let mut file = fs::File::open(&self.path).await?;
+        let len = file.metadata().await?.len();
+
+        // The file was truncated or replaced, so start over
+        if len < tail.offset {
+            trace!("file was truncated");
+            *tail = Tail::default();
+        }
+
+        file.seek(SeekFrom::Start(tail.offset)).await?;
+        let mut content = std::mem::take(&mut tail.partial);
+        tail.offset += file.read_to_end(&mut content).await? as u64;
+
+        let mut lines = content.split(|&b| b == b'\n').peekable();
+        while let Some(line) = lines.next() {
+            if lines.peek().is_none() {
+                tail.partial = line.to_vec();
+            } else if pattern.is_match(line) {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    #[tracing::instrument(name = "file", level = Level::DEBUG, skip(self), fields(path = %self.path.display()))]
+    pub async fn wait(self) {
+        // Only lines written from now on are matched, so that a log left over
+        // from a previous run doesn't count
+        let mut tail = Tail {
+            offset: fs::metadata(&self.path)
+                .await
+                .map(|metadata| metadata.len())
+                .unwrap_or(0),
+            partial: Vec::new(),
+        };
+
+        loop {
+            let now = Instant::now();
+
+            let result = match self.pattern {
+                None => fs::metadata(&self.path).await.map(|_| true),
+                Some(ref pattern) => self.read_lines(pattern, &mut tail).await,
+            };
+
+            match result {
+                Ok(true) => {
+                    debug!("file is ready");
+                    return;
+                }
+                Ok(false) => trace!("no matching lines"),
+                Err(err) => trace!(error = %err, "failed to read file"),
+            }
+
+            sleep_until(now + self.interval).await
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Pidfile {
+    path: PathBuf,
+    interval: Duration,
+}
+
+impl Pidfile {
+    pub(super) fn new(path: PathBuf, interval: Duration) -> Self {
+        Self { path, interval }
+    }
+
+    /// Read the PID from the file, and check that the process exists
+    #[cfg(unix)]
+    async fn check(&self) -> io::Result<libc::pid_t> {
+        let content = fs::read_to_string(&self.path).await?;
+        // kill treats 0 and negative PIDs as process groups, which would
+        // always appear to exist
+        let pid: libc::pid_t = content
+            .trim()
+            .parse()
+            .ok()
+            .filter(|&pid| pid > 0)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid PID"))?;
+
+        // Safety: signal 0 only checks that the process exists. EPERM means
+        // it exists, but belongs to someone else.
+        match unsafe { libc::kill(pid, 0) } {
+            0 => Ok(pid),
+            _ => match io::Error::last_os_error() {
+                err if err.raw_os_error() == Some(libc::EPERM) => Ok(pid),
+                err => Err(err),
+            },
+        }
+    }
+
+    /// Read the PID from the file, and check that the process is running
+    #[cfg(windows)]
+    async fn check(&self) -> io::Result<u32> {
+        let content = fs::read_to_string(&self.path).await?;
+        let pid: u32 = content
+            .trim()
+            .parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid PID"))?;
+
+        crate::windows::check_process(pid)?;
+        Ok(pid)
+    }
+
+    #[tracing::instrument(name = "pidfile", level = Level::DEBUG, skip(self), fields(path = %self.path.display()))]
+    pub async fn wait(self) {
+        loop {
+            let now = Instant::now();
+
+            match self.check().await {
+                Ok(pid) => {
+                    debug!(pid, "process is running");
+                    return;
+                }
+                Err(err) => trace!(error = %err, "process isn't running"),
+            }
+
+            sleep_until(now + self.interval).await
+        }
+    }
+}
+
+/// A probe command's process group, which is killed when this is dropped.
+/// The shell may fork the command rather than exec it, so killing the shell
+/// alone, when the probe times out or is cancelled, could leave the command
+/// running.
+#[cfg(unix)]
+struct ProbeGroup(Option<libc::pid_t>);
+
+#[cfg(unix)]
+impl ProbeGroup {
+    fn new(child: &Child) -> io::Result<Self> {
+        Ok(Self(child.id().map(|pid| pid as libc::pid_t)))
+    }
+}
+
+#[cfg(unix)]
+impl Drop for ProbeGroup {
+    fn drop(&mut self) {
+        if let Some(group) = self.0 {
+            // Safety: kill has no memory safety implications
+            unsafe { libc::kill(-group, libc::SIGKILL) };
+        }
+    }
+}
+
+/// On Windows, the probe command's job object plays the part of its process
+/// group; closing it kills everything in it
+#[cfg(windows)]
+struct ProbeGroup {
+    _job: crate::windows::Job,
+}
+
+#[cfg(windows)]
+impl ProbeGroup {
+    fn new(child: &Child) -> io::Result<Self> {
+        let _job = crate::windows::Job::containing(child)?;
+        Ok(Self { _job })
+    }
+}
+
+#[derive(Debug)]
+pub struct Cmd {
+    command: String,
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl Cmd {
+    pub(super) fn new(command: String, interval: Duration, timeout: Duration) -> Self {
+        Self {
+            command,
+            interval,
+            timeout,
+        }
+    }
+
+    #[tracing::instrument(name = "cmd", level = Level::DEBUG, skip(self), fields(command = %self.command))]
+    pub async fn wait(self) {
+        #[cfg(unix)]
+        let mut command = Command::new("sh");
+        #[cfg(unix)]
+        command.arg("-c").arg(&self.command).process_group(0);
+
+        #[cfg(windows)]
+        let mut command = crate::windows::shell(&self.command);
+
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true);
+
+        loop {
+            let now = Instant::now();
+
+            let status = async {
+                let mut child = command.spawn()?;
+                let _group = ProbeGroup::new(&child)?;
+                child.wait().await
+            };
+
+            trace!("running probe command...");
+            match timeout(self.timeout, status).await {
+                Ok(Ok(status)) if status.success() => {
+                    debug!("probe command succeeded");
+                    return;
+                }
+                Ok(Ok(status)) => trace!(%status, "probe command failed"),
+                Ok(Err(err)) => warn!(error = %err, "probe command failed to spawn"),
+                Err(..) => trace!("probe command timed out"),
+            }
+
+            // Make at most 1 attempt per interval.
+            sleep_until(now + self.interval).await
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Notify {
+    notifications: Receiver<Notification>,
+}
+
+impl Notify {
+    pub(super) fn new(notifications: Receiver<Notification>) -> Self {
+        Self { notifications }
+    }
+
+    #[tracing::instrument(name = "notify", skip(self))]
+    pub async fn wait(mut self) {
+        loop {
+            match self.notifications.recv().await {
+                Ok(notification) if notification.contains("READY=1") => {
+                    debug!("server reported that it's ready");
+                    return;
+                }
+                Ok(_) => {}
+                Err(RecvError::Closed) => {
+                    warn!("notifications channel closed");
+                    pending().await
+                }
+                Err(RecvError::Lagged(count)) => {
+                    warn!(missed = count, "notifications channel lagged")
+                }
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Rule<'a> {
     After(After),
     Http(Http<'a>),
     Https(Https<'a>),
     Tcp(Tcp),
+    Postgres(Postgres),
+    Redis(Redis),
+    Mongodb(Mongodb),
+    Mqtt(Mqtt),
+    Zookeeper(Zookeeper),
+    Elasticsearch(Elasticsearch<'a>),
+    Etcd(Etcd<'a>),
+    Consul(Consul<'a>),
+    Smtp(Smtp),
+    Ssh(Ssh),
+    Ldap(Ldap),
+    Udp(Udp),
+    Tls(Tls),
     Matches(Matches),
+    File(File),
+    Pidfile(Pidfile),
+    Cmd(Cmd),
+    Notify(Notify),
 }
 
 impl Rule<'_> {
@@ -171,34 +1368,279 @@ impl Rule<'_> {
             Rule::Http(http) => http.wait().await,
             Rule::Https(https) => https.wait().await,
             Rule::Tcp(tcp) => tcp.wait().await,
+            Rule::Postgres(postgres) => postgres.wait().await,
+            Rule::Redis(redis) => redis.wait().await,
+            Rule::Mongodb(mongodb) => mongodb.wait().await,
+            Rule::Mqtt(mqtt) => mqtt.wait().await,
+            Rule::Zookeeper(zookeeper) => zookeeper.wait().await,
+            Rule::Elasticsearch(elasticsearch) => elasticsearch.wait().await,
+            Rule::Etcd(etcd) => etcd.wait().await,
+            Rule::Consul(consul) => consul.wait().await,
+            Rule::Smtp(smtp) => smtp.wait().await,
+            Rule::Ssh(ssh) => ssh.wait().await,
+            Rule::Ldap(ldap) => ldap.wait().await,
+            Rule::Udp(udp) => udp.wait().await,
+            Rule::Tls(tls) => tls.wait().await,
             Rule::Matches(matches) => matches.wait().await,
+            Rule::File(file) => file.wait().await,
+            Rule::Pidfile(pidfile) => pidfile.wait().await,
+            Rule::Cmd(cmd) => cmd.wait().await,
+            Rule::Notify(notify) => notify.wait().await,
         }
     }
 }
 
+/// Error indicating that a set of rules can never be satisfied, because a
+/// negated rule was satisfied first, or a rule ran out of time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Unsatisfiable;
+
+/// The reason a term, or group of terms, stopped waiting without being
+/// satisfied
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// This alternative can never be satisfied, but others might be
+    Unsatisfiable,
+
+    /// A `within ... else fail` term ran out of time, so none of the
+    /// alternatives can be satisfied
+    Fatal,
+}
+
+/// A rule that was satisfied, as part of the alternative that satisfied a set
+/// of rules
+#[derive(Debug, Clone)]
+pub struct SatisfiedRule {
+    /// A description of the rule, like `tcp 127.0.0.1:5432`
+    pub rule: String,
+
+    /// When the rule was satisfied
+    pub at: Instant,
+}
+
+/// How a set of rules was satisfied
+#[derive(Debug, Clone)]
+pub struct Satisfied {
+    /// The index of the alternative, in the top-level `or`, that was
+    /// satisfied
+    pub group: usize,
+
+    /// The rules in that alternative, other than `not` rules, in the order
+    /// that they were satisfied
+    pub rules: Vec<SatisfiedRule>,
+}
+
+#[derive(Debug)]
+pub enum Term<'a> {
+    Rule {
+        rule: Rule<'a>,
+
+        /// The rule's description, for reporting how the rules were
+        /// satisfied
+        description: String,
+
+        /// Where the rule is listed while it's being waited for
+        pending: PendingRules,
+    },
+    Group(OrRules<'a>),
+    Sequence(Sequence<'a>),
+    Within(Within<'a>),
+    Labeled(Labeled<'a>),
+
+    /// A rule that couldn't be built, because it refers to a variable that
+    /// hasn't been captured
+    Invalid(descriptors::VariableError),
+}
+
+impl Term<'_> {
+    /// Wait for the term to be satisfied, returning the rules that satisfied
+    /// it
+    pub async fn wait(self) -> Result<Vec<SatisfiedRule>, Failure> {
+        match self {
+            Term::Rule {
+                rule,
+                description,
+                pending,
+            } => {
+                let _pending = pending.start(description.clone());
+                rule.wait().await;
+
+                Ok(vec![SatisfiedRule {
+                    rule: description,
+                    at: Instant::now(),
+                }])
+            }
+            // Groups and sequences are recursive, so their futures need to
+            // be boxed
+            Term::Group(group) => Box::pin(group.evaluate())
+                .await
+                .map(|satisfied| satisfied.rules),
+            Term::Sequence(sequence) => Box::pin(sequence.wait()).await,
+            Term::Within(within) => Box::pin(within.wait()).await,
+            Term::Labeled(labeled) => Box::pin(labeled.wait()).await,
+            Term::Invalid(err) => {
+                let err: &dyn std::error::Error = &err;
+                error!(error = err, "rule can't be evaluated");
+                Err(Failure::Unsatisfiable)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Within<'a> {
+    term: Box<Term<'a>>,
+    limit: Duration,
+    fatal: bool,
+}
+
+impl<'a> Within<'a> {
+    pub(super) fn new(term: Term<'a>, limit: Duration, fatal: bool) -> Self {
+        Self {
+            term: Box::new(term),
+            limit,
+            fatal,
+        }
+    }
+
+    pub async fn wait(self) -> Result<Vec<SatisfiedRule>, Failure> {
+        match timeout(self.limit, self.term.wait()).await {
+            Ok(result) => result,
+            Err(..) if self.fatal => {
+                warn!(limit = ?self.limit, "rule wasn't satisfied in time");
+                Err(Failure::Fatal)
+            }
+            Err(..) => {
+                debug!(limit = ?self.limit, "rule wasn't satisfied in time");
+                Err(Failure::Unsatisfiable)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Labeled<'a> {
+    label: String,
+    term: Box<Term<'a>>,
+}
+
+impl<'a> Labeled<'a> {
+    pub(super) fn new(label: String, term: Term<'a>) -> Self {
+        Self {
+            label,
+            term: Box::new(term),
+        }
+    }
+
+    /// Wait for the term to be satisfied. It's reported as a single rule,
+    /// named by the label, rather than as the rules inside it.
+    pub async fn wait(self) -> Result<Vec<SatisfiedRule>, Failure> {
+        let label = self.label;
+
+        self.term
+            .wait()
+            .instrument(debug_span!("labeled", label = %label))
+            .await?;
+
+        debug!("rule {:?} satisfied", label);
+
+        Ok(vec![SatisfiedRule {
+            rule: label,
+            at: Instant::now(),
+        }])
+    }
+}
+
+#[derive(Debug)]
+pub struct Sequence<'a> {
+    terms: Vec<descriptors::Term>,
+    probes: ProbeConfig<'a>,
+    events: ServerEvents,
+}
+
+impl<'a> Sequence<'a> {
+    pub(super) fn new(
+        terms: Vec<descriptors::Term>,
+        probes: ProbeConfig<'a>,
+        events: ServerEvents,
+    ) -> Self {
+        Self {
+            terms,
+            probes,
+            events,
+        }
+    }
+
+    /// Wait for each term in turn, only building each one once the previous
+    /// one is satisfied
+    pub async fn wait(self) -> Result<Vec<SatisfiedRule>, Failure> {
+        let mut satisfied = Vec::new();
+
+        for (id, term) in self.terms.iter().enumerate() {
+            let rules = term
+                .build(self.probes, &self.events)
+                .wait()
+                .instrument(debug_span!("step", id))
+                .await?;
+
+            satisfied.extend(rules);
+        }
+
+        Ok(satisfied)
+    }
+}
+
 #[derive(Debug)]
 pub struct AndRules<'a> {
-    rules: Vec<Rule<'a>>,
+    rules: Vec<Term<'a>>,
+    negated: Vec<Term<'a>>,
 }
 
 impl<'a> AndRules<'a> {
-    pub(super) fn new(rules: Vec<Rule<'a>>) -> Self {
-        Self { rules }
+    pub(super) fn new(rules: Vec<Term<'a>>, negated: Vec<Term<'a>>) -> Self {
+        Self { rules, negated }
     }
 
-    pub async fn wait(mut self) {
-        if self.rules.len() == 1 {
-            self.rules.pop().unwrap().wait().await
-        } else {
-            let futures: FuturesUnordered<_> = self
-                .rules
-                .into_iter()
-                .enumerate()
-                .map(|(id, rule)| rule.wait().instrument(debug_span!("rule", id)))
-                .collect();
+    pub async fn wait(self) -> Result<Vec<SatisfiedRule>, Failure> {
+        let mut satisfied = Vec::new();
 
-            futures.collect().instrument(debug_span!("rules")).await
+        let mut rules: FuturesUnordered<_> = self
+            .rules
+            .into_iter()
+            .enumerate()
+            .map(|(id, rule)| rule.wait().instrument(debug_span!("rule", id)))
+            .collect();
+
+        let mut negated: FuturesUnordered<_> = self
+            .negated
+            .into_iter()
+            .enumerate()
+            .map(|(id, rule)| rule.wait().instrument(debug_span!("negated rule", id)))
+            .collect();
+
+        async move {
+            loop {
+                select_biased! {
+                    result = negated.select_next_some() => match result {
+                        Ok(..) => {
+                            debug!("negated rule was satisfied");
+                            return Err(Failure::Unsatisfiable);
+                        }
+                        // A negated rule that can never be satisfied can't
+                        // interfere with the group, unless it failed outright
+                        Err(Failure::Unsatisfiable) => {}
+                        Err(Failure::Fatal) => return Err(Failure::Fatal),
+                    },
+                    result = rules.next() => match result {
+                        None => return Ok(satisfied),
+                        Some(Ok(rules)) => satisfied.extend(rules),
+                        Some(Err(failure)) => return Err(failure),
+                    },
+                }
+            }
         }
+        .instrument(debug_span!("rules"))
+        .await
     }
 }
 
@@ -211,18 +1653,42 @@ impl<'a> OrRules<'a> {
     pub(super) fn new(rules: Vec<AndRules<'a>>) -> Self {
         Self { rules }
     }
-    pub async fn wait(mut self) {
-        if self.rules.len() == 1 {
-            self.rules.pop().unwrap().wait().await
-        } else {
-            let mut futures: FuturesUnordered<_> = self
-                .rules
-                .into_iter()
-                .enumerate()
-                .map(|(id, rule)| rule.wait().instrument(debug_span!("rule group", id)))
-                .collect();
-
-            let _ = futures.next().instrument(debug_span!("rule groups")).await;
+
+    /// Wait for any group of rules to be satisfied, returning which group it
+    /// was. Returns an error if every group becomes unsatisfiable, or a
+    /// `within ... else fail` rule runs out of time.
+    pub async fn wait(self) -> Result<Satisfied, Unsatisfiable> {
+        self.evaluate().await.map_err(|_| Unsatisfiable)
+    }
+
+    async fn evaluate(self) -> Result<Satisfied, Failure> {
+        let mut futures: FuturesUnordered<_> = self
+            .rules
+            .into_iter()
+            .enumerate()
+            .map(|(id, rule)| {
+                rule.wait()
+                    .map(move |result| result.map(|rules| (id, rules)))
+                    .instrument(debug_span!("rule group", id))
+            })
+            .collect();
+
+        async move {
+            while let Some(result) = futures.next().await {
+                match result {
+                    Ok((group, mut rules)) => {
+                        rules.sort_by_key(|rule| rule.at);
+                        return Ok(Satisfied { group, rules });
+                    }
+                    Err(Failure::Unsatisfiable) => {}
+                    Err(Failure::Fatal) => return Err(Failure::Fatal),
+                }
+            }
+
+            debug!("every rule group is unsatisfiable");
+            Err(Failure::Unsatisfiable)
         }
+        .instrument(debug_span!("rule groups"))
+        .await
     }
 }
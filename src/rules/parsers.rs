@@ -1,25 +1,44 @@
-use std::{num::NonZeroU16, str::FromStr};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::{
+    fs, iter,
+    num::{NonZeroU32, NonZeroUsize},
+    ops::RangeInclusive,
+    str::FromStr,
+    time::Duration,
+};
 
 use nom::{
     self,
     branch::alt,
-    bytes::complete::{escaped_transform, take_till1},
-    character::complete::{char, digit1, space0, space1},
+    bytes::complete::{escaped_transform, take_till1, take_while1, take_while_m_n},
+    character::complete::{alpha1, char, digit1, space0, space1},
     combinator::eof,
-    IResult, Parser,
+    error::{ErrorKind, ParseError},
+    multi::{fold_many0, many0, separated_list1},
+    Err as NomErr, IResult, Parser,
 };
 use nom_supreme::{
     error::ErrorTree,
     final_parser::{final_parser, Location},
     multi::collect_separated_terminated,
     parser_ext::ParserExt,
-    tag::complete::tag_no_case,
+    tag::complete::{tag, tag_no_case},
 };
 use regex::bytes::Regex;
+use reqwest::{
+    header::{HeaderName, HeaderValue},
+    Certificate, Method,
+};
 
-use crate::duration::parse_duration;
+use crate::{duration::parse_duration, output::Stream};
 
-use super::descriptors::{After, AndRules, Http, Https, Matches, OrRules, Rule, Tcp};
+use super::descriptors::{
+    After, AndRules, Cmd, Consul, Elasticsearch, Etcd, File, Http, HttpOptions, Https,
+    InvalidClusterHealth, JsonField, Labeled, Ldap, Matches, Mongodb, Mqtt, Notify, OrRules,
+    Pidfile, Port, Postgres, Redis, Rule, Rules, Sequence, Service, Smtp, Ssh, Tcp, Term, Tls,
+    TlsOptions, Udp, Within, Zookeeper,
+};
 
 fn parse_after(input: &str) -> IResult<&str, After, ErrorTree<&str>> {
     tag_no_case("after")
@@ -29,41 +48,312 @@ fn parse_after(input: &str) -> IResult<&str, After, ErrorTree<&str>> {
         .parse(input)
 }
 
-fn parse_port(input: &str) -> IResult<&str, NonZeroU16, ErrorTree<&str>> {
+/// Parse a `$name` variable, as captured by a named group in a `matches`
+/// rule
+fn parse_variable(input: &str) -> IResult<&str, String, ErrorTree<&str>> {
+    take_while1(|c: char| c.is_ascii_alphanumeric() || c == '_')
+        .preceded_by(char('$'))
+        .map(String::from)
+        .parse(input)
+}
+
+fn parse_port(input: &str) -> IResult<&str, Port, ErrorTree<&str>> {
     tag_no_case("port")
         .terminated(space1)
-        .precedes(digit1)
-        .parse_from_str()
+        .precedes(alt((
+            digit1.parse_from_str().map(Port::Number),
+            parse_variable.map(Port::Variable),
+        )))
+        .parse(input)
+}
+
+fn parse_host(input: &str) -> IResult<&str, String, ErrorTree<&str>> {
+    tag_no_case("host")
+        .terminated(space1)
+        .precedes(
+            alt((
+                // Bracketed IPv6 literal, as in a URL
+                take_till1(|c| c == ']')
+                    .preceded_by(char('['))
+                    .terminated(char(']')),
+                take_till1(|c: char| c.is_whitespace()),
+            ))
+            .cut(),
+        )
+        .map(String::from)
         .parse(input)
 }
 
-trait FromMaybePort: Sized {
-    fn from_maybe_port(port: Option<NonZeroU16>) -> Self;
+trait FromHttpOptions: Sized {
+    /// Whether this rule accepts TLS clauses
+    const TLS: bool;
+
+    fn from_http_options(options: HttpOptions) -> reqwest::Result<Self>;
 }
 
-impl FromMaybePort for Http {
-    fn from_maybe_port(port: Option<NonZeroU16>) -> Self {
-        Self::new(port)
+impl FromHttpOptions for Http {
+    const TLS: bool = false;
+
+    fn from_http_options(options: HttpOptions) -> reqwest::Result<Self> {
+        Ok(Self::new(options))
     }
 }
 
-impl FromMaybePort for Https {
-    fn from_maybe_port(port: Option<NonZeroU16>) -> Self {
-        Self::new(port)
+impl FromHttpOptions for Https {
+    const TLS: bool = true;
+
+    fn from_http_options(options: HttpOptions) -> reqwest::Result<Self> {
+        Self::new(options)
     }
 }
 
-fn parse_http_family<'i, T: FromMaybePort>(
-    protocol: &'static str,
-) -> impl Parser<&'i str, T, ErrorTree<&'i str>> {
+#[derive(Debug, Clone)]
+enum HttpClause {
+    Host(String),
+    Port(Port),
+    Method(Method),
+    Path(String),
+    Status(Vec<RangeInclusive<u16>>),
+    Insecure,
+    Ca(Certificate),
+    #[cfg(unix)]
+    Unix(PathBuf),
+    Json(JsonField),
+    Body(Regex),
+    Header(HeaderName, HeaderValue),
+    Auth(String, Option<String>),
+}
+
+fn parse_method(input: &str) -> IResult<&str, Method, ErrorTree<&str>> {
+    tag_no_case("method")
+        .terminated(space1)
+        .precedes(
+            alpha1
+                .map_res(|method: &str| Method::from_bytes(method.to_ascii_uppercase().as_bytes()))
+                .cut(),
+        )
+        .parse(input)
+}
+
+fn parse_path(input: &str) -> IResult<&str, String, ErrorTree<&str>> {
+    tag_no_case("path")
+        .terminated(space1)
+        .precedes(
+            take_till1(|c: char| c.is_whitespace())
+                .verify(|path: &&str| path.starts_with('/'))
+                .context("absolute path")
+                .cut(),
+        )
+        .map(String::from)
+        .parse(input)
+}
+
+/// Parse the value in a `json` clause: either a quoted string, or a bare JSON
+/// number, `true`, `false`, or `null`
+fn parse_json_value(input: &str) -> IResult<&str, serde_json::Value, ErrorTree<&str>> {
     alt((
-        parse_port
-            .map(Some)
-            .terminated(space1)
-            .terminated(tag_no_case("ready")),
-        tag_no_case("ready").value(None),
+        parse_quoted_string.map(serde_json::Value::String),
+        take_till1(|c: char| c.is_whitespace()).map_res(serde_json::from_str),
+    ))
+    .context("JSON value")
+    .parse(input)
+}
+
+/// Parse a `json /pointer == value` clause
+fn parse_json(input: &str) -> IResult<&str, JsonField, ErrorTree<&str>> {
+    tag_no_case("json")
+        .terminated(space1)
+        .precedes(
+            take_till1(|c: char| c.is_whitespace())
+                .verify(|pointer: &&str| pointer.starts_with('/'))
+                .context("JSON pointer")
+                .terminated(tag("==").delimited_by(space1))
+                .and(parse_json_value)
+                .cut(),
+        )
+        .map(|(pointer, value)| JsonField::new(pointer.to_owned(), value))
+        .parse(input)
+}
+
+/// Parse a `body matches <pattern>` clause
+fn parse_body(input: &str) -> IResult<&str, Regex, ErrorTree<&str>> {
+    tag_no_case("body")
+        .terminated(space1)
+        .precedes(
+            tag_no_case("matches")
+                .terminated(space1)
+                .precedes(alt((parse_quoted_pattern, parse_raw_pattern)))
+                .cut(),
+        )
+        .parse(input)
+}
+
+/// Parse a `header "Name: value"` clause
+fn parse_header(input: &str) -> IResult<&str, (HeaderName, HeaderValue), ErrorTree<&str>> {
+    tag_no_case("header")
+        .terminated(space1)
+        .precedes(
+            parse_argument
+                .map_res(|header| {
+                    let (name, value) = header.split_once(':').unwrap_or((&header, ""));
+
+                    // Headers often carry credentials, so keep the value
+                    // out of the logs
+                    let mut value = HeaderValue::from_str(value.trim())?;
+                    value.set_sensitive(true);
+
+                    Ok::<_, hyper::http::Error>((
+                        HeaderName::from_bytes(name.trim().as_bytes())?,
+                        value,
+                    ))
+                })
+                .context("header")
+                .cut(),
+        )
+        .parse(input)
+}
+
+/// Parse an `auth user:password` clause, for HTTP basic auth. The password
+/// may be omitted.
+fn parse_auth(input: &str) -> IResult<&str, (String, Option<String>), ErrorTree<&str>> {
+    tag_no_case("auth")
+        .terminated(space1)
+        .precedes(parse_argument.cut())
+        .map(|credentials| match credentials.split_once(':') {
+            Some((username, password)) => (username.to_owned(), Some(password.to_owned())),
+            None => (credentials, None),
+        })
+        .parse(input)
+}
+
+fn parse_status_code(input: &str) -> IResult<&str, u16, ErrorTree<&str>> {
+    digit1
+        .parse_from_str()
+        .verify(|&code: &u16| (100..=999).contains(&code))
+        .context("status code")
+        .parse(input)
+}
+
+fn parse_status_range(input: &str) -> IResult<&str, RangeInclusive<u16>, ErrorTree<&str>> {
+    parse_status_code
+        .and(char('-').precedes(parse_status_code).opt())
+        .map(|(low, high)| low..=high.unwrap_or(low))
+        .parse(input)
+}
+
+fn parse_statuses(input: &str) -> IResult<&str, Vec<RangeInclusive<u16>>, ErrorTree<&str>> {
+    tag_no_case("status")
+        .terminated(space1)
+        .precedes(separated_list1(char(','), parse_status_range).cut())
+        .parse(input)
+}
+
+/// Read the PEM file at the path given by an argument
+fn parse_pem_file(input: &str) -> IResult<&str, Vec<u8>, ErrorTree<&str>> {
+    parse_argument.map_res(fs::read).parse(input)
+}
+
+fn parse_ca(input: &str) -> IResult<&str, Certificate, ErrorTree<&str>> {
+    tag_no_case("ca")
+        .terminated(space1)
+        .precedes(
+            parse_pem_file
+                .map_res(|pem| Certificate::from_pem(&pem))
+                .context("CA certificate")
+                .cut(),
+        )
+        .parse(input)
+}
+
+fn parse_http_clause(input: &str) -> IResult<&str, HttpClause, ErrorTree<&str>> {
+    alt((
+        parse_host.map(HttpClause::Host),
+        parse_port.map(HttpClause::Port),
+        parse_method.map(HttpClause::Method),
+        parse_path.map(HttpClause::Path),
+        parse_statuses.map(HttpClause::Status),
+        parse_json.map(HttpClause::Json),
+        parse_body.map(HttpClause::Body),
+        parse_header.map(|(name, value)| HttpClause::Header(name, value)),
+        parse_auth.map(|(username, password)| HttpClause::Auth(username, password)),
+    ))
+    .parse(input)
+}
+
+/// Parse a `unix <path>` clause, for sending HTTP requests over a Unix socket
+#[cfg(unix)]
+fn parse_unix_clause(input: &str) -> IResult<&str, HttpClause, ErrorTree<&str>> {
+    tag_no_case("unix")
+        .terminated(space1)
+        .precedes(parse_argument.cut())
+        .map(|path| HttpClause::Unix(path.into()))
+        .parse(input)
+}
+
+/// Requests can't be sent over Unix sockets on Windows, so a `unix <path>`
+/// clause is rejected there, rather than being mistaken for the end of the
+/// rule
+#[cfg(windows)]
+fn parse_unix_clause(input: &str) -> IResult<&str, HttpClause, ErrorTree<&str>> {
+    tag_no_case("unix")
+        .terminated(space1)
+        .precedes(parse_argument.cut())
+        .map_res_cut(|_| Err(crate::windows::unsupported("HTTP over a Unix socket")))
+        .parse(input)
+}
+
+fn parse_tls_clause(input: &str) -> IResult<&str, HttpClause, ErrorTree<&str>> {
+    alt((
+        tag_no_case("insecure").value(HttpClause::Insecure),
+        parse_ca.map(HttpClause::Ca),
     ))
-    .map(T::from_maybe_port)
+    .parse(input)
+}
+
+fn parse_http_family<'i, T: FromHttpOptions>(
+    protocol: &'static str,
+) -> impl Parser<&'i str, T, ErrorTree<&'i str>> {
+    let parse_clause = move |input| match T::TLS {
+        true => alt((parse_http_clause, parse_tls_clause)).parse(input),
+        false => alt((parse_http_clause, parse_unix_clause)).parse(input),
+    };
+
+    fold_many0(
+        parse_clause.terminated(space1),
+        HttpOptions::default(),
+        |options, clause| match clause {
+            HttpClause::Host(host) => options.host(host),
+            HttpClause::Port(port) => options.port(port),
+            HttpClause::Method(method) => options.method(method),
+            HttpClause::Path(path) => options.path(path),
+            HttpClause::Status(statuses) => options.statuses(statuses),
+            HttpClause::Insecure => options.insecure(),
+            HttpClause::Ca(ca) => options.ca(ca),
+            #[cfg(unix)]
+            HttpClause::Unix(path) => options.socket(path),
+            HttpClause::Json(json) => options.json(json),
+            HttpClause::Body(body) => options.body(body),
+            HttpClause::Header(name, value) => options.header(name, value),
+            HttpClause::Auth(username, password) => {
+                options.basic_auth(&username, password.as_deref())
+            }
+        },
+    )
+    .terminated(tag_no_case("ready"))
+    .and(parse_every.opt())
+    .and(parse_timeout.opt())
+    .map(|((options, interval), timeout)| {
+        let options = match interval {
+            Some(interval) => options.interval(interval),
+            None => options,
+        };
+
+        match timeout {
+            Some(timeout) => options.timeout(timeout),
+            None => options,
+        }
+    })
+    .map_res(T::from_http_options)
     .cut()
     .preceded_by(tag_no_case(protocol).terminated(space1))
 }
@@ -79,57 +369,720 @@ fn parse_https(input: &str) -> IResult<&str, Https, ErrorTree<&str>> {
 fn parse_tcp(input: &str) -> IResult<&str, Tcp, ErrorTree<&str>> {
     tag_no_case("tcp")
         .terminated(space1.cut())
-        .precedes(parse_port.cut())
+        .precedes(
+            parse_host
+                .terminated(space1)
+                .opt()
+                .and(parse_port)
+                .and(parse_host.preceded_by(space1).opt())
+                .and(parse_send.preceded_by(space1).opt())
+                .and(parse_expect.preceded_by(space1).opt())
+                .terminated(space1)
+                .terminated(tag_no_case("ready"))
+                .and(parse_every.opt())
+                .cut(),
+        )
+        .map(|(((((host, port), host2), payload), expect), interval)| {
+            let tcp = Tcp::new(host.or(host2), port, interval);
+            let tcp = match payload {
+                Some(payload) => tcp.send(payload),
+                None => tcp,
+            };
+
+            match expect {
+                Some(expect) => tcp.expect(expect),
+                None => tcp,
+            }
+        })
+        .parse(input)
+}
+
+/// Rule-specific options of a network service rule, like `user postgres`
+#[derive(Debug, Clone, Default)]
+struct ServiceOptions {
+    options: Vec<(&'static str, String)>,
+}
+
+impl ServiceOptions {
+    /// Get the value of an option; if it was given more than once, the last
+    /// one wins
+    fn get(&self, name: &str) -> Option<String> {
+        self.options
+            .iter()
+            .rev()
+            .find(|&&(option, _)| option == name)
+            .map(|(_, value)| value.clone())
+    }
+}
+
+#[derive(Debug, Clone)]
+enum ServiceClause {
+    Host(String),
+    Port(Port),
+    Option(&'static str, String),
+}
+
+/// Parse one of the named options, followed by its argument
+fn parse_service_option(
+    options: &'static [&'static str],
+) -> impl Fn(&str) -> IResult<&str, (&'static str, String), ErrorTree<&str>> {
+    move |input| {
+        for &option in options {
+            let keyword: IResult<&str, &str, ErrorTree<&str>> =
+                tag_no_case(option).terminated(space1).parse(input);
+
+            if let Ok((tail, _)) = keyword {
+                return parse_argument
+                    .cut()
+                    .map(|value| (option, value))
+                    .parse(tail);
+            }
+        }
+
+        Err(NomErr::Error(ErrorTree::from_error_kind(
+            input,
+            ErrorKind::Tag,
+        )))
+    }
+}
+
+/// Parse a rule that probes a network service, like `postgres port 5432
+/// ready`. The host and port clauses, plus any of the rule's own `options`,
+/// may appear in any order before `ready`.
+fn parse_service<'i>(
+    name: &'static str,
+    options: &'static [&'static str],
+) -> impl Parser<&'i str, (Service, ServiceOptions), ErrorTree<&'i str>> {
+    let parse_clause = alt((
+        parse_host.map(ServiceClause::Host),
+        parse_port.map(ServiceClause::Port),
+        parse_service_option(options).map(|(option, value)| ServiceClause::Option(option, value)),
+    ));
+
+    fold_many0(
+        parse_clause.terminated(space1),
+        (None, None, ServiceOptions::default()),
+        |(host, port, mut options), clause| match clause {
+            ServiceClause::Host(host) => (Some(host), port, options),
+            ServiceClause::Port(port) => (host, Some(port), options),
+            ServiceClause::Option(option, value) => {
+                options.options.push((option, value));
+                (host, port, options)
+            }
+        },
+    )
+    .terminated(tag_no_case("ready"))
+    .and(parse_every.opt())
+    .map(|((host, port, options), interval)| (Service::new(host, port, interval), options))
+    .cut()
+    .preceded_by(tag_no_case(name).terminated(space1))
+}
+
+fn parse_postgres(input: &str) -> IResult<&str, Postgres, ErrorTree<&str>> {
+    parse_service("postgres", &["user", "database"])
+        .map(|(service, options)| {
+            Postgres::new(service, options.get("user"), options.get("database"))
+        })
+        .parse(input)
+}
+
+fn parse_redis(input: &str) -> IResult<&str, Redis, ErrorTree<&str>> {
+    parse_service("redis", &["user", "auth"])
+        .map(|(service, options)| Redis::new(service, options.get("user"), options.get("auth")))
+        .parse(input)
+}
+
+fn parse_mongodb(input: &str) -> IResult<&str, Mongodb, ErrorTree<&str>> {
+    parse_service("mongodb", &[])
+        .map(|(service, _)| Mongodb::new(service))
+        .parse(input)
+}
+
+fn parse_mqtt(input: &str) -> IResult<&str, Mqtt, ErrorTree<&str>> {
+    parse_service("mqtt", &["user", "auth"])
+        .map_res_cut(|(service, options)| {
+            Mqtt::new(service, options.get("user"), options.get("auth"))
+        })
+        .parse(input)
+}
+
+fn parse_zookeeper(input: &str) -> IResult<&str, Zookeeper, ErrorTree<&str>> {
+    parse_service("zookeeper", &[])
+        .map(|(service, _)| Zookeeper::new(service))
+        .parse(input)
+}
+
+fn parse_elasticsearch(input: &str) -> IResult<&str, Elasticsearch, ErrorTree<&str>> {
+    parse_service("elasticsearch", &["status"])
+        .map_res_cut(|(service, options)| {
+            let status = options.get("status").map(|s| s.parse()).transpose()?;
+            Ok::<_, InvalidClusterHealth>(Elasticsearch::new(service, status))
+        })
+        .parse(input)
+}
+
+fn parse_etcd(input: &str) -> IResult<&str, Etcd, ErrorTree<&str>> {
+    parse_service("etcd", &[])
+        .map(|(service, _)| Etcd::new(service))
+        .parse(input)
+}
+
+fn parse_consul(input: &str) -> IResult<&str, Consul, ErrorTree<&str>> {
+    parse_service("consul", &[])
+        .map(|(service, _)| Consul::new(service))
+        .parse(input)
+}
+
+fn parse_smtp(input: &str) -> IResult<&str, Smtp, ErrorTree<&str>> {
+    parse_service("smtp", &["ehlo"])
+        .map(|(service, options)| Smtp::new(service, options.get("ehlo")))
+        .parse(input)
+}
+
+fn parse_ssh(input: &str) -> IResult<&str, Ssh, ErrorTree<&str>> {
+    parse_service("ssh", &[])
+        .map(|(service, _)| Ssh::new(service))
+        .parse(input)
+}
+
+fn parse_ldap(input: &str) -> IResult<&str, Ldap, ErrorTree<&str>> {
+    parse_service("ldap", &[])
+        .map(|(service, _)| Ldap::new(service))
+        .parse(input)
+}
+
+fn parse_udp(input: &str) -> IResult<&str, Udp, ErrorTree<&str>> {
+    tag_no_case("udp")
         .terminated(space1.cut())
-        .terminated(tag_no_case("ready").cut())
-        .map(Tcp::new)
+        .precedes(
+            parse_host
+                .terminated(space1)
+                .opt()
+                .and(parse_port)
+                .and(parse_host.preceded_by(space1).opt())
+                .and(parse_send.preceded_by(space1).opt())
+                .and(parse_expect.preceded_by(space1).opt())
+                .and(parse_every.opt())
+                .cut(),
+        )
+        .map(|(((((host, port), host2), payload), expect), interval)| {
+            Udp::new(
+                host.or(host2),
+                port,
+                payload.unwrap_or_default(),
+                expect,
+                interval,
+            )
+        })
         .parse(input)
 }
 
-fn parse_quoted_pattern(input: &str) -> IResult<&str, Regex, ErrorTree<&str>> {
+#[derive(Debug, Clone)]
+enum TlsClause {
+    Name(String),
+    Insecure,
+    Ca(Vec<u8>),
+}
+
+fn parse_tls_rule_clause(input: &str) -> IResult<&str, TlsClause, ErrorTree<&str>> {
+    alt((
+        tag_no_case("name")
+            .terminated(space1)
+            .precedes(parse_argument.cut())
+            .map(TlsClause::Name),
+        tag_no_case("insecure").value(TlsClause::Insecure),
+        tag_no_case("ca")
+            .terminated(space1)
+            .precedes(parse_pem_file.context("CA certificate").cut())
+            .map(TlsClause::Ca),
+    ))
+    .parse(input)
+}
+
+fn parse_tls(input: &str) -> IResult<&str, Tls, ErrorTree<&str>> {
+    tag_no_case("tls")
+        .terminated(space1.cut())
+        .precedes(
+            parse_host
+                .terminated(space1)
+                .opt()
+                .and(parse_port)
+                .and(parse_host.preceded_by(space1).opt())
+                .and(fold_many0(
+                    parse_tls_rule_clause.preceded_by(space1),
+                    TlsOptions::default(),
+                    |options, clause| match clause {
+                        TlsClause::Name(name) => options.name(name),
+                        TlsClause::Insecure => options.insecure(),
+                        TlsClause::Ca(pem) => options.ca(pem),
+                    },
+                ))
+                .terminated(space1)
+                .terminated(tag_no_case("ready"))
+                .and(parse_every.opt())
+                .map_res(|((((host, port), host2), options), interval)| {
+                    Tls::new(host.or(host2), port, options, interval)
+                })
+                .cut(),
+        )
+        .parse(input)
+}
+
+/// Parse a quoted string, in which `\"` and `\\` are a quote and a backslash
+fn parse_quoted_string(input: &str) -> IResult<&str, String, ErrorTree<&str>> {
     escaped_transform(
         take_till1(|c| c == '"' || c == '\\'),
         '\\',
-        char('"').value('"'),
+        alt((char('"').value('"'), char('\\').value('\\'))),
+    )
+    .delimited_by(char('"'))
+    .parse(input)
+}
+
+/// Parse a single argument: either a quoted string, or everything up to the
+/// next whitespace
+fn parse_argument(input: &str) -> IResult<&str, String, ErrorTree<&str>> {
+    alt((
+        parse_quoted_string,
+        take_till1(|c: char| c.is_whitespace()).map(String::from),
+    ))
+    .parse(input)
+}
+
+/// Parse a backslash escape in a payload: `\n`, `\r`, `\t`, `\0`, `\\`,
+/// `\"`, or a hex byte like `\x1b`
+fn parse_escape(input: &str) -> IResult<&str, u8, ErrorTree<&str>> {
+    alt((
+        char('n').value(b'\n'),
+        char('r').value(b'\r'),
+        char('t').value(b'\t'),
+        char('0').value(b'\0'),
+        char('\\').value(b'\\'),
+        char('"').value(b'"'),
+        char('x').precedes(
+            take_while_m_n(2, 2, |c: char| c.is_ascii_hexdigit())
+                .map_res(|hex| u8::from_str_radix(hex, 16)),
+        ),
+    ))
+    .context("escape")
+    .cut()
+    .preceded_by(char('\\'))
+    .parse(input)
+}
+
+/// Parse a quoted payload of arbitrary bytes, which may contain escapes
+fn parse_payload(input: &str) -> IResult<&str, Vec<u8>, ErrorTree<&str>> {
+    fold_many0(
+        alt((
+            parse_escape.map(|byte| vec![byte]),
+            take_till1(|c| c == '"' || c == '\\').map(|s: &str| s.as_bytes().to_vec()),
+        )),
+        Vec::new(),
+        |mut payload, chunk| {
+            payload.extend(chunk);
+            payload
+        },
     )
-    .map_res(|s| Regex::new(&s))
     .delimited_by(char('"'))
     .parse(input)
 }
 
+fn parse_send(input: &str) -> IResult<&str, Vec<u8>, ErrorTree<&str>> {
+    tag_no_case("send")
+        .terminated(space1)
+        .precedes(parse_payload.cut())
+        .parse(input)
+}
+
+fn parse_expect(input: &str) -> IResult<&str, Regex, ErrorTree<&str>> {
+    tag_no_case("expect")
+        .terminated(space1)
+        .precedes(parse_quoted_pattern.cut())
+        .parse(input)
+}
+
+fn parse_quoted_pattern(input: &str) -> IResult<&str, Regex, ErrorTree<&str>> {
+    parse_quoted_string.map_res(|s| Regex::new(&s)).parse(input)
+}
+
+/// Take an unquoted pattern: everything up to the next whitespace, or up to an
+/// unbalanced `)`, which closes an enclosing group
+fn take_raw_pattern(input: &str) -> IResult<&str, &str, ErrorTree<&str>> {
+    let mut depth: usize = 0;
+    let mut escaped = false;
+
+    let end = input
+        .char_indices()
+        .find(|&(_, c)| {
+            let end = match c {
+                _ if escaped => false,
+                c if c.is_whitespace() => true,
+                ')' if depth == 0 => true,
+                ')' => {
+                    depth -= 1;
+                    false
+                }
+                '(' => {
+                    depth += 1;
+                    false
+                }
+                _ => false,
+            };
+
+            escaped = !escaped && c == '\\';
+            end
+        })
+        .map(|(idx, _)| idx)
+        .unwrap_or_else(|| input.len());
+
+    match end {
+        0 => Err(NomErr::Error(ErrorTree::from_error_kind(
+            input,
+            ErrorKind::TakeTill1,
+        ))),
+        end => Ok((&input[end..], &input[..end])),
+    }
+}
+
 fn parse_raw_pattern(input: &str) -> IResult<&str, Regex, ErrorTree<&str>> {
-    take_till1(|c: char| c.is_whitespace())
-        .map_res(Regex::new)
+    take_raw_pattern.map_res(Regex::new).parse(input)
+}
+
+fn parse_stream(input: &str) -> IResult<&str, Option<Stream>, ErrorTree<&str>> {
+    tag_no_case("on")
+        .terminated(space1.cut())
+        .precedes(
+            alt((
+                tag_no_case("stdout").value(Some(Stream::Stdout)),
+                tag_no_case("stderr").value(Some(Stream::Stderr)),
+                tag_no_case("any").value(None),
+            ))
+            .cut(),
+        )
+        .preceded_by(space1)
+        .parse(input)
+}
+
+fn parse_times(input: &str) -> IResult<&str, NonZeroU32, ErrorTree<&str>> {
+    tag_no_case("times")
+        .terminated(space1.cut())
+        .precedes(digit1.parse_from_str().cut())
+        .preceded_by(space1)
+        .parse(input)
+}
+
+/// Parse the pattern of a `matches`, `imatches`, or `contains` rule, with an
+/// optional `-i` flag that makes it case-insensitive. `contains` patterns are
+/// plain strings, rather than regular expressions. The flag has to be followed
+/// by whitespace; otherwise, it's the start of the pattern, like `-info`.
+fn parse_output_pattern<'a>(
+    literal: bool,
+    case_insensitive: bool,
+) -> impl Parser<&'a str, Matches, ErrorTree<&'a str>> {
+    tag("-i")
+        .terminated(space1)
+        .opt()
+        .and(alt((
+            parse_quoted_string,
+            take_raw_pattern.map(String::from),
+        )))
+        .map_res(move |(flag, pattern)| {
+            let matches = match literal {
+                true => Matches::contains(&pattern, Some(Stream::Stdout)),
+                false => Matches::new(Regex::new(&pattern)?, Some(Stream::Stdout)),
+            };
+
+            match case_insensitive || flag.is_some() {
+                true => matches.case_insensitive(),
+                false => Ok(matches),
+            }
+        })
+}
+
+fn parse_window(input: &str) -> IResult<&str, NonZeroUsize, ErrorTree<&str>> {
+    tag_no_case("window")
+        .terminated(space1.cut())
+        .precedes(digit1.parse_from_str().cut())
+        .preceded_by(space1)
         .parse(input)
 }
 
 fn parse_matches(input: &str) -> IResult<&str, Matches, ErrorTree<&str>> {
-    tag_no_case("matches")
+    alt((
+        // This has to come before `matches`, which it starts with
+        tag_no_case("matches-multiline")
+            .terminated(space1.cut())
+            .precedes(parse_output_pattern(false, false).cut())
+            .and(parse_window.cut())
+            .map(|(matches, window)| matches.window(window)),
+        tag_no_case("matches")
+            .terminated(space1.cut())
+            .precedes(parse_output_pattern(false, false).cut()),
+        tag_no_case("imatches")
+            .terminated(space1.cut())
+            .precedes(parse_output_pattern(false, true).cut()),
+        tag_no_case("contains")
+            .terminated(space1.cut())
+            .precedes(parse_output_pattern(true, false).cut()),
+    ))
+    .and(parse_stream.opt())
+    .and(parse_times.opt())
+    .map(|((matches, stream), times)| {
+        let matches = match stream {
+            Some(stream) => matches.stream(stream),
+            None => matches,
+        };
+
+        match times {
+            Some(times) => matches.times(times),
+            None => matches,
+        }
+    })
+    .parse(input)
+}
+
+fn parse_file(input: &str) -> IResult<&str, File, ErrorTree<&str>> {
+    tag_no_case("file")
+        .terminated(space1.cut())
+        .precedes(
+            parse_argument
+                .terminated(space1)
+                .and(alt((
+                    tag_no_case("exists").value(None),
+                    tag_no_case("matches")
+                        .terminated(space1.cut())
+                        .precedes(alt((parse_quoted_pattern, parse_raw_pattern)).cut())
+                        .map(Some),
+                )))
+                .and(parse_every.opt())
+                .cut(),
+        )
+        .map(|((path, pattern), interval)| File::new(path.into(), pattern, interval))
+        .parse(input)
+}
+
+fn parse_pidfile(input: &str) -> IResult<&str, Pidfile, ErrorTree<&str>> {
+    tag_no_case("pidfile")
+        .terminated(space1.cut())
+        .precedes(
+            parse_argument
+                .terminated(space1)
+                .terminated(tag_no_case("ready"))
+                .and(parse_every.opt())
+                .cut(),
+        )
+        .map(|(path, interval)| Pidfile::new(path.into(), interval))
+        .parse(input)
+}
+
+/// Parse an `every` clause, setting how often a rule is polled. A zero
+/// interval would poll in a busy loop, so it's rejected.
+fn parse_every(input: &str) -> IResult<&str, Duration, ErrorTree<&str>> {
+    tag_no_case("every")
         .terminated(space1.cut())
-        .precedes(alt((parse_quoted_pattern, parse_raw_pattern)).cut())
-        .map(Matches::new)
+        .precedes(
+            parse_duration
+                .verify(|interval: &Duration| !interval.is_zero())
+                .context("nonzero interval")
+                .cut(),
+        )
+        .preceded_by(space1)
         .parse(input)
 }
 
+/// Parse a `timeout` clause, limiting how long each request may take
+fn parse_timeout(input: &str) -> IResult<&str, Duration, ErrorTree<&str>> {
+    tag_no_case("timeout")
+        .terminated(space1.cut())
+        .precedes(parse_duration.cut())
+        .preceded_by(space1)
+        .parse(input)
+}
+
+fn parse_cmd(input: &str) -> IResult<&str, Cmd, ErrorTree<&str>> {
+    alt((tag_no_case("cmd"), tag_no_case("exec")))
+        .terminated(space1.cut())
+        .precedes(parse_argument.cut())
+        .and(parse_every.opt())
+        .map(|(command, interval)| Cmd::new(command, interval))
+        .parse(input)
+}
+
+fn parse_notify(input: &str) -> IResult<&str, Notify, ErrorTree<&str>> {
+    tag_no_case("notify")
+        .terminated(space1.cut())
+        .terminated(tag_no_case("ready").cut())
+        .value(Notify)
+        .parse(input)
+}
+
+/// Parse one of the rules that probe a specific kind of network service. These
+/// are separate from `parse_rule` only because `alt` takes at most 21
+/// alternatives.
+fn parse_service_rule(input: &str) -> IResult<&str, Rule, ErrorTree<&str>> {
+    alt((
+        parse_postgres.map(Rule::Postgres).context("postgres"),
+        parse_redis.map(Rule::Redis).context("redis"),
+        parse_mongodb.map(Rule::Mongodb).context("mongodb"),
+        parse_mqtt.map(Rule::Mqtt).context("mqtt"),
+        parse_zookeeper.map(Rule::Zookeeper).context("zookeeper"),
+        parse_elasticsearch
+            .map(Rule::Elasticsearch)
+            .context("elasticsearch"),
+        parse_etcd.map(Rule::Etcd).context("etcd"),
+        parse_consul.map(Rule::Consul).context("consul"),
+        parse_smtp.map(Rule::Smtp).context("smtp"),
+        parse_ssh.map(Rule::Ssh).context("ssh"),
+        parse_ldap.map(Rule::Ldap).context("ldap"),
+    ))
+    .parse(input)
+}
+
 fn parse_rule(input: &str) -> IResult<&str, Rule, ErrorTree<&str>> {
     alt((
         parse_after.map(Rule::After).context("after"),
         parse_tcp.map(Rule::Tcp).context("tcp"),
+        parse_service_rule,
+        parse_udp.map(Rule::Udp).context("udp"),
+        parse_tls.map(Rule::Tls).context("tls"),
         parse_http.map(Rule::Http).context("http"),
         parse_https.map(Rule::Https).context("https"),
         parse_matches.map(Rule::Matches).context("matches"),
+        parse_file.map(Rule::File).context("file"),
+        parse_pidfile.map(Rule::Pidfile).context("pidfile"),
+        parse_cmd.map(Rule::Cmd).context("cmd"),
+        parse_notify.map(Rule::Notify).context("notify"),
     ))
     .parse(input)
 }
 
+fn parse_group(input: &str) -> IResult<&str, OrRules, ErrorTree<&str>> {
+    char('(')
+        .terminated(space0)
+        .precedes(parse_or_rules.cut())
+        .terminated(space0)
+        .terminated(char(')').cut())
+        .parse(input)
+}
+
+fn parse_term(input: &str) -> IResult<&str, Term, ErrorTree<&str>> {
+    alt((
+        parse_group.map(Term::Group).context("group"),
+        parse_rule.map(Term::Rule),
+    ))
+    .parse(input)
+}
+
+/// Parse a trailing `within <duration>` clause, optionally followed by
+/// `else fail`. Returns the time limit, and whether exceeding it is fatal.
+fn parse_within(input: &str) -> IResult<&str, (Duration, bool), ErrorTree<&str>> {
+    parse_duration
+        .cut()
+        .preceded_by(tag_no_case("within").delimited_by(space1))
+        .and(
+            tag_no_case("fail")
+                .cut()
+                .preceded_by(tag_no_case("else").delimited_by(space1))
+                .opt()
+                .map(|fail| fail.is_some()),
+        )
+        .parse(input)
+}
+
+/// Parse a term, possibly bounded by a `within` clause
+fn parse_bounded_term(input: &str) -> IResult<&str, Term, ErrorTree<&str>> {
+    parse_term
+        .and(parse_within.context("within").opt())
+        .map(|(term, within)| match within {
+            None => term,
+            Some((limit, false)) => Term::Within(Within::new(term, limit)),
+            Some((limit, true)) => Term::Within(Within::new(term, limit).fatal()),
+        })
+        .parse(input)
+}
+
+/// Parse one or more terms joined by `then`, which binds more tightly than
+/// `and` and `or`
+fn parse_sequence(input: &str) -> IResult<&str, Term, ErrorTree<&str>> {
+    parse_bounded_term
+        .and(many0(
+            parse_bounded_term
+                .cut()
+                .preceded_by(tag_no_case("then").delimited_by(space1)),
+        ))
+        .map(|(first, rest)| match rest.is_empty() {
+            true => first,
+            false => Term::Sequence(Sequence::new(iter::once(first).chain(rest).collect())),
+        })
+        .parse(input)
+}
+
+/// Parse a label like `[web]`, made of letters, digits, `-`, `_`, and `.`
+fn parse_label(input: &str) -> IResult<&str, String, ErrorTree<&str>> {
+    take_while1(|c: char| c.is_alphanumeric() || "-_.".contains(c))
+        .cut()
+        .delimited_by(space0)
+        .preceded_by(char('['))
+        .terminated(char(']').cut())
+        .map(String::from)
+        .parse(input)
+}
+
+/// Parse a sequence, possibly preceded by a label
+fn parse_labeled_sequence(input: &str) -> IResult<&str, Term, ErrorTree<&str>> {
+    parse_label
+        .context("label")
+        .terminated(space0)
+        .opt()
+        .and(parse_sequence)
+        .map(|(label, term)| match label {
+            None => term,
+            Some(label) => Term::Labeled(Labeled::new(label, term)),
+        })
+        .parse(input)
+}
+
+/// Parse a sequence, possibly labeled and possibly negated with `not`.
+/// Returns true if it was negated.
+fn parse_condition(input: &str) -> IResult<&str, (bool, Term), ErrorTree<&str>> {
+    alt((
+        tag_no_case("not")
+            .terminated(space1)
+            .precedes(parse_labeled_sequence.cut())
+            .map(|term| (true, term)),
+        parse_labeled_sequence.map(|term| (false, term)),
+    ))
+    .parse(input)
+}
+
+/// Parse the end of a group of rules, without consuming it: either the end of
+/// the input, or the closing parenthesis of an enclosing group
+fn parse_group_end(input: &str) -> IResult<&str, (), ErrorTree<&str>> {
+    alt((
+        eof.value(()),
+        char(')').value(()),
+        tag_no_case("fails-if").terminated(space1).value(()),
+    ))
+    .preceded_by(space0)
+    .peek()
+    .parse(input)
+}
+
 fn parse_and_rules(input: &str) -> IResult<&str, AndRules, ErrorTree<&str>> {
     collect_separated_terminated(
-        parse_rule.context("rule"),
+        parse_condition.context("rule"),
         tag_no_case("and").delimited_by(space1),
-        eof.preceded_by(space0)
-            .or(tag_no_case("or").preceded_by(space1).peek()),
+        parse_group_end.or(tag_no_case("or").preceded_by(space1).value(()).peek()),
     )
-    .map(AndRules::new)
+    .map(|conditions: Vec<(bool, Term)>| {
+        let (negated, rules): (Vec<_>, Vec<_>) =
+            conditions.into_iter().partition(|&(negated, _)| negated);
+
+        AndRules::new(
+            rules.into_iter().map(|(_, term)| term).collect(),
+            negated.into_iter().map(|(_, term)| term).collect(),
+        )
+    })
     .parse(input)
 }
 
@@ -137,7 +1090,7 @@ fn parse_or_rules(input: &str) -> IResult<&str, OrRules, ErrorTree<&str>> {
     collect_separated_terminated(
         parse_and_rules.context("rule group"),
         tag_no_case("or").delimited_by(space1),
-        eof.preceded_by(space0),
+        parse_group_end,
     )
     .map(OrRules::new)
     .parse(input)
@@ -147,6 +1100,199 @@ impl FromStr for OrRules {
     type Err = ErrorTree<Location>;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        final_parser(parse_or_rules)(s)
+        final_parser(parse_or_rules.terminated(space0))(s)
+    }
+}
+
+fn parse_rules(input: &str) -> IResult<&str, Rules, ErrorTree<&str>> {
+    parse_or_rules
+        .and(
+            parse_or_rules
+                .cut()
+                .context("fails-if")
+                .preceded_by(tag_no_case("fails-if").delimited_by(space1))
+                .opt(),
+        )
+        .map(|(ready, fails_if)| Rules::new(ready, fails_if))
+        .parse(input)
+}
+
+impl FromStr for Rules {
+    type Err = ErrorTree<Location>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        final_parser(parse_rules.terminated(space0))(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run a parser over all of `input`, panicking if it fails
+    fn parse_all<'i, T>(
+        mut parser: impl Parser<&'i str, T, ErrorTree<&'i str>>,
+        input: &'i str,
+    ) -> T {
+        match parser.parse(input) {
+            Ok(("", value)) => value,
+            Ok((rest, _)) => panic!("{:?} left {:?} unparsed", input, rest),
+            Err(err) => panic!("failed to parse {:?}: {:?}", input, err),
+        }
+    }
+
+    #[test]
+    fn timeout_clause() {
+        assert_eq!(
+            parse_all(parse_timeout, " timeout 5s"),
+            Duration::from_secs(5)
+        );
+        assert!(parse_timeout(" timeout soon").is_err());
+
+        let http = parse_all(
+            parse_http,
+            "http port 8080 path /health ready every 2s timeout 500ms",
+        );
+        assert_eq!(
+            http.to_string(),
+            "http HEAD http://127.0.0.1:8080/health status any every 2s timeout 500ms"
+        );
+    }
+
+    #[test]
+    fn json_clause() {
+        let json = parse_all(parse_json, r#"json /status == "ok""#);
+        assert_eq!(json.to_string(), r#"/status == "ok""#);
+        assert!(json.matches(br#"{"status": "ok", "version": 3}"#));
+        assert!(!json.matches(br#"{"status": "starting"}"#));
+        assert!(!json.matches(b"ok"));
+
+        let json = parse_all(parse_json, "json /checks/0/healthy == true");
+        assert!(json.matches(br#"{"checks": [{"healthy": true}]}"#));
+        assert!(!json.matches(br#"{"checks": [{"healthy": "true"}]}"#));
+
+        let json = parse_all(parse_json, "json /replicas == 3");
+        assert!(json.matches(br#"{"replicas": 3}"#));
+    }
+
+    #[test]
+    fn json_clause_invalid() {
+        // Pointers are absolute, and bare values have to be JSON
+        assert!(parse_json("json status == \"ok\"").is_err());
+        assert!(parse_json("json /status == ok").is_err());
+        assert!(parse_json("json /status = \"ok\"").is_err());
+    }
+
+    #[test]
+    fn header_clause() {
+        let (name, value) = parse_all(parse_header, r#"header "Authorization: Bearer abc""#);
+        assert_eq!(name, "authorization");
+        assert_eq!(value, "Bearer abc");
+        assert!(value.is_sensitive());
+
+        let (name, value) = parse_all(parse_header, "header X-Empty");
+        assert_eq!(name, "x-empty");
+        assert_eq!(value, "");
+
+        assert!(parse_header(r#"header "Bad Name: value""#).is_err());
+    }
+
+    #[test]
+    fn body_clause() {
+        let body = parse_all(parse_body, "body matches ^ok$");
+        assert_eq!(body.as_str(), "^ok$");
+
+        let body = parse_all(parse_body, r#"body matches "status: (up|ready)""#);
+        assert_eq!(body.as_str(), "status: (up|ready)");
+
+        assert!(parse_body("body matches (").is_err());
+        assert!(parse_body("body contains ok").is_err());
+    }
+
+    #[test]
+    fn http_clauses() {
+        let http = parse_all(
+            parse_http,
+            r#"http port 80 json /ok == true body matches "up" header "X-Token: secret" ready"#,
+        );
+        assert_eq!(
+            http.to_string(),
+            r#"http GET http://127.0.0.1:80/ json /ok == true body matches "up" header x-token status any but 401"#
+        );
+    }
+
+    /// Parse a rule, print it, and check that what's printed parses back to
+    /// the same rule
+    fn round_trip(rule: &str) -> String {
+        let printed = parse_all(parse_rule, rule).to_string();
+        let reprinted = parse_all(parse_rule, &printed).to_string();
+        assert_eq!(printed, reprinted, "{:?} didn't round-trip", rule);
+        printed
+    }
+
+    #[test]
+    fn round_trip_matches() {
+        assert_eq!(
+            round_trip("matches ^ready$"),
+            r#"matches "^ready$" on stdout times 1"#
+        );
+        assert_eq!(
+            round_trip(r#"matches "port (\\d+)" on any"#),
+            r#"matches "port (\\d+)" on any times 1"#
+        );
+        assert_eq!(
+            round_trip("matches-multiline a\\nb window 3 on stderr times 2"),
+            r#"matches-multiline "a\\nb" window 3 on stderr times 2"#
+        );
+    }
+
+    #[test]
+    fn round_trip_other_rules() {
+        assert_eq!(round_trip("after 1.5s"), "after 1.5s");
+        assert_eq!(
+            round_trip(r#"cmd "test -e /tmp/ready" every 2s"#),
+            r#"cmd "test -e /tmp/ready" every 2s"#
+        );
+    }
+
+    #[test]
+    fn round_trip_case_insensitive() {
+        assert_eq!(
+            round_trip("matches -i listening on stderr times 2"),
+            r#"matches -i "listening" on stderr times 2"#
+        );
+        assert_eq!(
+            round_trip("imatches Listening"),
+            r#"matches -i "Listening" on stdout times 1"#
+        );
+    }
+
+    #[test]
+    fn round_trip_contains() {
+        assert_eq!(
+            round_trip("contains a.b"),
+            r#"contains "a.b" on stdout times 1"#
+        );
+        assert_eq!(
+            round_trip(r#"contains -i "say \"hi\"" on stderr"#),
+            r#"contains -i "say \"hi\"" on stderr times 1"#
+        );
+
+        // `-i` is only a flag when it's followed by whitespace
+        assert_eq!(
+            round_trip("contains -info"),
+            r#"contains "-info" on stdout times 1"#
+        );
+    }
+
+    #[test]
+    fn every_clause() {
+        assert_eq!(
+            parse_all(parse_every, " every 1m 30s"),
+            Duration::from_secs(90)
+        );
+        assert!(parse_every(" every 0s").is_err());
+        assert!(parse_every(" every 0ms 0s").is_err());
+        assert!(parse_rule("tcp port 80 ready every 0s").is_err());
     }
 }
@@ -0,0 +1,740 @@
+//! Wire protocol handshakes used by the rules that probe specific kinds of
+//! servers. Each probe takes a freshly connected stream and reports whether
+//! the server on the other end is ready to serve requests.
+
+use std::{
+    convert::{TryFrom, TryInto},
+    io,
+};
+
+#[cfg(unix)]
+use hyper::{
+    body::{to_bytes, Bytes},
+    client::conn,
+    header::{HeaderName, HeaderValue, HOST},
+    Body, Method, Request, StatusCode,
+};
+use regex::bytes::Regex;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_native_tls::TlsConnector;
+use tracing::{debug, trace};
+
+/// The largest message body we're willing to read from a probed server
+const MAX_MESSAGE_LEN: usize = 64 * 1024;
+
+fn invalid_data(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Read a length-prefixed message body of `len` bytes, refusing anything
+/// unreasonably large
+async fn read_body(stream: &mut (impl AsyncRead + Unpin), len: usize) -> io::Result<Vec<u8>> {
+    if len > MAX_MESSAGE_LEN {
+        return Err(invalid_data("message is too large"));
+    }
+
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body).await?;
+    Ok(body)
+}
+
+/// Send `payload`, if there is one, then wait for the data received from the
+/// server to match `expect`. Without a pattern, a successful connection is
+/// enough.
+pub async fn send_expect(
+    mut stream: TcpStream,
+    payload: Option<&[u8]>,
+    expect: Option<&Regex>,
+) -> io::Result<bool> {
+    if let Some(payload) = payload {
+        stream.write_all(payload).await?;
+    }
+
+    let expect = match expect {
+        Some(expect) => expect,
+        None => return Ok(true),
+    };
+
+    let mut received = Vec::new();
+    let mut buffer = [0; 4096];
+
+    while received.len() <= MAX_MESSAGE_LEN {
+        let len = stream.read(&mut buffer).await?;
+        if len == 0 {
+            trace!(received = %String::from_utf8_lossy(&received), "connection closed without a match");
+            return Ok(false);
+        }
+
+        received.extend_from_slice(&buffer[..len]);
+        if expect.is_match(&received) {
+            return Ok(true);
+        }
+    }
+
+    Err(invalid_data("response is too large"))
+}
+
+/// Complete a TLS handshake, verifying the server's certificate against
+/// `name` unless the connector was configured to skip verification
+pub async fn tls(stream: TcpStream, connector: &TlsConnector, name: &str) -> io::Result<bool> {
+    match connector.connect(name, stream).await {
+        Ok(..) => Ok(true),
+        Err(err) => {
+            debug!(error = %err, "TLS handshake failed");
+            Ok(false)
+        }
+    }
+}
+
+/// The PostgreSQL protocol version 3.0, as sent in the startup message
+const POSTGRES_PROTOCOL_VERSION: u32 = 196608;
+
+/// The SQLSTATE sent by PostgreSQL while it's starting up, shutting down, or
+/// in crash recovery
+const POSTGRES_CANNOT_CONNECT_NOW: &[u8] = b"57P03";
+
+/// Send a PostgreSQL startup message and inspect the first response. Like
+/// `pg_isready`, this considers the server ready as soon as it asks us to
+/// authenticate or rejects us for any reason other than still starting up,
+/// so no password is needed.
+pub async fn postgres(
+    mut stream: TcpStream,
+    user: &str,
+    database: Option<&str>,
+) -> io::Result<bool> {
+    let mut parameters = Vec::new();
+
+    for (key, value) in [("user", Some(user)), ("database", database)].iter() {
+        if let Some(value) = value {
+            parameters.extend_from_slice(key.as_bytes());
+            parameters.push(0);
+            parameters.extend_from_slice(value.as_bytes());
+            parameters.push(0);
+        }
+    }
+    parameters.push(0);
+
+    let mut message = Vec::with_capacity(8 + parameters.len());
+    message.extend_from_slice(&(8 + parameters.len() as u32).to_be_bytes());
+    message.extend_from_slice(&POSTGRES_PROTOCOL_VERSION.to_be_bytes());
+    message.extend_from_slice(&parameters);
+    stream.write_all(&message).await?;
+
+    let kind = stream.read_u8().await?;
+    let len = stream.read_u32().await? as usize;
+
+    match kind {
+        // An authentication request
+        b'R' => Ok(true),
+        b'E' => {
+            let body = read_body(&mut stream, len.saturating_sub(4)).await?;
+
+            // The body is a series of fields, each a type byte followed by a
+            // nul-terminated string; the 'C' field is the SQLSTATE
+            let code = body
+                .split(|&b| b == 0)
+                .find_map(|field| field.strip_prefix(b"C"));
+
+            trace!(code = ?code.map(String::from_utf8_lossy), "server sent an error");
+            Ok(code != Some(POSTGRES_CANNOT_CONNECT_NOW))
+        }
+        _ => Err(invalid_data("unexpected response to startup message")),
+    }
+}
+
+/// Read a single line, terminated by CRLF, as used by text protocols like
+/// Redis
+async fn read_line(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Vec<u8>> {
+    let mut line = Vec::new();
+
+    loop {
+        match stream.read_u8().await? {
+            b'\n' => break,
+            byte => line.push(byte),
+        }
+
+        if line.len() > MAX_MESSAGE_LEN {
+            return Err(invalid_data("line is too long"));
+        }
+    }
+
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+
+    Ok(line)
+}
+
+/// Send a Redis command, encoded as an array of bulk strings, and read the
+/// single-line reply
+async fn redis_command(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    args: &[&str],
+) -> io::Result<Vec<u8>> {
+    let mut command = format!("*{}\r\n", args.len());
+    for arg in args {
+        command.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+    }
+
+    stream.write_all(command.as_bytes()).await?;
+    read_line(stream).await
+}
+
+/// Authenticate, if there's a password, then send `PING`. Redis replies to
+/// commands with a `-LOADING` error while it's still loading its dataset, so
+/// the server is only ready once it answers `+PONG`.
+pub async fn redis(
+    mut stream: TcpStream,
+    user: Option<&str>,
+    password: Option<&str>,
+) -> io::Result<bool> {
+    if let Some(password) = password {
+        let reply = match user {
+            Some(user) => redis_command(&mut stream, &["AUTH", user, password]).await?,
+            None => redis_command(&mut stream, &["AUTH", password]).await?,
+        };
+
+        if reply != b"+OK" {
+            trace!(reply = %String::from_utf8_lossy(&reply), "authentication failed");
+            return Ok(false);
+        }
+    }
+
+    let reply = redis_command(&mut stream, &["PING"]).await?;
+    trace!(reply = %String::from_utf8_lossy(&reply), "received reply to PING");
+    Ok(reply == b"+PONG")
+}
+
+/// The opcode of OP_MSG, the message format used by MongoDB 3.6 and later
+const MONGODB_OP_MSG: i32 = 2013;
+
+fn read_i32_le(bytes: &[u8]) -> Option<i32> {
+    Some(i32::from_le_bytes(bytes.get(..4)?.try_into().ok()?))
+}
+
+/// Get the length of a BSON value of the given type, at the start of `value`
+fn bson_value_len(kind: u8, value: &[u8]) -> Option<usize> {
+    // A negative length is malformed, rather than a huge one
+    let prefixed = |extra: usize| {
+        usize::try_from(read_i32_le(value)?)
+            .ok()?
+            .checked_add(extra)
+    };
+
+    match kind {
+        // Null, undefined, min key, and max key
+        0x06 | 0x0A | 0x7F | 0xFF => Some(0),
+        // Boolean
+        0x08 => Some(1),
+        // Int32
+        0x10 => Some(4),
+        // Double, datetime, timestamp, and int64
+        0x01 | 0x09 | 0x11 | 0x12 => Some(8),
+        // ObjectId
+        0x07 => Some(12),
+        // Decimal128
+        0x13 => Some(16),
+        // String, JavaScript code, and symbol
+        0x02 | 0x0D | 0x0E => prefixed(4),
+        // Document, array, and code with scope, which include their length
+        0x03 | 0x04 | 0x0F => prefixed(0),
+        // Binary
+        0x05 => prefixed(5),
+        // DBPointer
+        0x0C => prefixed(4 + 12),
+        // Regex: a pattern and options, both nul-terminated
+        0x0B => {
+            let pattern = memchr::memchr(0, value)? + 1;
+            Some(pattern + memchr::memchr(0, &value[pattern..])? + 1)
+        }
+        _ => None,
+    }
+}
+
+/// Find a top-level field of a BSON document, returning its type and value
+fn bson_field<'a>(document: &'a [u8], name: &str) -> Option<(u8, &'a [u8])> {
+    let mut elements = document.get(4..)?;
+
+    loop {
+        let (&kind, rest) = elements.split_first()?;
+        if kind == 0 {
+            return None;
+        }
+
+        let name_len = memchr::memchr(0, rest)?;
+        let (field, rest) = (&rest[..name_len], &rest[name_len + 1..]);
+        let value = rest.get(..bson_value_len(kind, rest)?)?;
+
+        if field == name.as_bytes() {
+            return Some((kind, value));
+        }
+
+        elements = &rest[value.len()..];
+    }
+}
+
+/// Check whether a BSON field is true, or is a number equal to 1, as is
+/// usual for flags like `ok` in MongoDB replies
+fn bson_is_true(document: &[u8], name: &str) -> bool {
+    match bson_field(document, name) {
+        Some((0x08, value)) => value == [1],
+        Some((0x01, value)) => value.try_into().ok().map(f64::from_le_bytes) == Some(1.0),
+        Some((0x10, value)) => value.try_into().ok().map(i32::from_le_bytes) == Some(1),
+        Some((0x12, value)) => value.try_into().ok().map(i64::from_le_bytes) == Some(1),
+        _ => false,
+    }
+}
+
+/// Send an `isMaster` command, and check that the server replies that it's a
+/// primary or a secondary. MongoDB answers while it's still recovering, but
+/// as neither, and `isMaster` doesn't need authentication.
+pub async fn mongodb(mut stream: TcpStream) -> io::Result<bool> {
+    let mut document = Vec::new();
+    document.push(0x10);
+    document.extend_from_slice(b"isMaster\0");
+    document.extend_from_slice(&1i32.to_le_bytes());
+    document.push(0x02);
+    document.extend_from_slice(b"$db\0");
+    document.extend_from_slice(&6i32.to_le_bytes());
+    document.extend_from_slice(b"admin\0");
+    document.push(0);
+
+    // The header is the total length, the request ID, the ID of the request
+    // this responds to, and the opcode, followed by flags and a single body
+    // section with the document
+    let len = 16 + 4 + 1 + 4 + document.len();
+    let mut message = Vec::with_capacity(len);
+    message.extend_from_slice(&(len as i32).to_le_bytes());
+    message.extend_from_slice(&1i32.to_le_bytes());
+    message.extend_from_slice(&0i32.to_le_bytes());
+    message.extend_from_slice(&MONGODB_OP_MSG.to_le_bytes());
+    message.extend_from_slice(&0u32.to_le_bytes());
+    message.push(0);
+    message.extend_from_slice(&((4 + document.len()) as i32).to_le_bytes());
+    message.extend_from_slice(&document);
+    stream.write_all(&message).await?;
+
+    let len = stream.read_i32_le().await?;
+    let reply = read_body(&mut stream, (len as usize).saturating_sub(4)).await?;
+
+    // Skip the rest of the header and the flags, to the body section
+    let document = match reply.get(8..12).and_then(read_i32_le) {
+        Some(MONGODB_OP_MSG) if reply.get(16) == Some(&0) => &reply[17..],
+        _ => return Err(invalid_data("unexpected reply to isMaster")),
+    };
+
+    let ok = bson_is_true(document, "ok");
+    let primary = bson_is_true(document, "ismaster");
+    let secondary = bson_is_true(document, "secondary");
+
+    trace!(ok, primary, secondary, "received reply to isMaster");
+    Ok(ok && (primary || secondary))
+}
+
+/// Read an SMTP reply, which may span several lines, returning its code and
+/// its last line
+async fn smtp_reply(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    loop {
+        let line = read_line(stream).await?;
+
+        // Every line but the last has a `-` after the code
+        match line.get(3) {
+            Some(b'-') => continue,
+            _ => match line.get(..3) {
+                Some(code) => return Ok((code.to_vec(), line)),
+                None => return Err(invalid_data("malformed SMTP reply")),
+            },
+        }
+    }
+}
+
+/// Wait for an SMTP server's `220` greeting, then, if there's a domain to
+/// greet the server as, check that it accepts `EHLO`. Servers that aren't
+/// initialized yet greet clients with `421` or `554` instead.
+pub async fn smtp(mut stream: TcpStream, ehlo: Option<&str>) -> io::Result<bool> {
+    let (code, greeting) = smtp_reply(&mut stream).await?;
+    trace!(greeting = %String::from_utf8_lossy(&greeting), "received greeting");
+
+    if code != b"220" {
+        return Ok(false);
+    }
+
+    if let Some(domain) = ehlo {
+        stream
+            .write_all(format!("EHLO {}\r\n", domain).as_bytes())
+            .await?;
+
+        let (code, reply) = smtp_reply(&mut stream).await?;
+        trace!(reply = %String::from_utf8_lossy(&reply), "received reply to EHLO");
+
+        if code != b"250" {
+            return Ok(false);
+        }
+    }
+
+    // Say goodbye, so the server doesn't log an aborted session
+    let _ = stream.write_all(b"QUIT\r\n").await;
+    Ok(true)
+}
+
+/// Wait for an SSH server's version banner, which sshd only sends once it's
+/// loaded its host keys. Servers may send other lines before the banner.
+pub async fn ssh(mut stream: TcpStream) -> io::Result<bool> {
+    loop {
+        let line = read_line(&mut stream).await?;
+
+        if line.starts_with(b"SSH-") {
+            trace!(banner = %String::from_utf8_lossy(&line), "received banner");
+            return Ok(line.starts_with(b"SSH-2.0-") || line.starts_with(b"SSH-1.99-"));
+        }
+    }
+}
+
+/// The LDAP result codes sent while a directory server can't serve requests
+/// yet: busy and unavailable
+const LDAP_NOT_READY: &[u8] = &[51, 52];
+
+/// Read the length of a BER element, in its short or long form
+async fn read_ber_len(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<usize> {
+    match stream.read_u8().await? {
+        len if len < 0x80 => Ok(len as usize),
+        len @ 0x81..=0x84 => {
+            let mut value = 0;
+            for _ in 0..(len & 0x7F) {
+                value = (value << 8) | stream.read_u8().await? as usize;
+            }
+            Ok(value)
+        }
+        _ => Err(invalid_data("unsupported BER length")),
+    }
+}
+
+/// Split the first BER element from `bytes`, returning its tag, its
+/// contents, and the bytes after it
+fn ber_element(bytes: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = bytes.split_first()?;
+    let (&len, rest) = rest.split_first()?;
+
+    let (len, rest) = match len {
+        len if len < 0x80 => (len as usize, rest),
+        len @ 0x81..=0x84 => {
+            let (len_bytes, rest) = rest.split_at_checked((len & 0x7F) as usize)?;
+            let len = len_bytes
+                .iter()
+                .fold(0, |len, &byte| (len << 8) | byte as usize);
+            (len, rest)
+        }
+        _ => return None,
+    };
+
+    let (contents, rest) = rest.split_at_checked(len)?;
+    Some((tag, contents, rest))
+}
+
+/// Send an anonymous LDAP simple bind. Like the `postgres` probe, this
+/// considers the server ready when it answers with anything but busy or
+/// unavailable, since servers may refuse anonymous binds.
+pub async fn ldap(mut stream: TcpStream) -> io::Result<bool> {
+    // A message with ID 1 holding a bind request for LDAPv3, with an empty
+    // name and an empty simple password
+    stream
+        .write_all(&[
+            0x30, 0x0C, 0x02, 0x01, 0x01, 0x60, 0x07, 0x02, 0x01, 0x03, 0x04, 0x00, 0x80, 0x00,
+        ])
+        .await?;
+
+    if stream.read_u8().await? != 0x30 {
+        return Err(invalid_data("unexpected response to bind request"));
+    }
+
+    let len = read_ber_len(&mut stream).await?;
+    let message = read_body(&mut stream, len).await?;
+
+    // Skip the message ID to the bind response, whose first element is the
+    // result code
+    let code = ber_element(&message)
+        .and_then(|(_, _, rest)| ber_element(rest))
+        .filter(|&(tag, _, _)| tag == 0x61)
+        .and_then(|(_, response, _)| ber_element(response))
+        .and_then(|(tag, code, _)| match (tag, code) {
+            (0x0A, [code]) => Some(*code),
+            _ => None,
+        })
+        .ok_or_else(|| invalid_data("malformed bind response"))?;
+
+    trace!(code, "received bind response");
+
+    // Unbind, as message 2, so the server doesn't log an aborted connection
+    let _ = stream
+        .write_all(&[0x30, 0x05, 0x02, 0x01, 0x02, 0x42, 0x00])
+        .await;
+
+    Ok(!LDAP_NOT_READY.contains(&code))
+}
+
+/// Send ZooKeeper the `srvr` four letter word, and check that it reports its
+/// mode. `ruok` isn't enough, since it answers `imok` before the server has
+/// joined a quorum, and `srvr` is the only word that's allowed by default.
+pub async fn zookeeper(mut stream: TcpStream) -> io::Result<bool> {
+    stream.write_all(b"srvr").await?;
+
+    // ZooKeeper closes the connection after responding
+    let mut response = Vec::new();
+    (&mut stream)
+        .take(MAX_MESSAGE_LEN as u64)
+        .read_to_end(&mut response)
+        .await?;
+
+    let mode = response
+        .split(|&b| b == b'\n')
+        .find_map(|line| line.strip_prefix(b"Mode: "));
+
+    match mode {
+        Some(mode) => {
+            trace!(mode = %String::from_utf8_lossy(mode), "server is serving requests");
+            Ok(true)
+        }
+        None => {
+            trace!(response = %String::from_utf8_lossy(&response), "server isn't serving requests");
+            Ok(false)
+        }
+    }
+}
+
+/// Append a string to an MQTT packet, prefixed by its length
+fn mqtt_string(packet: &mut Vec<u8>, value: &str) {
+    packet.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    packet.extend_from_slice(value.as_bytes());
+}
+
+/// Send an MQTT 3.1.1 CONNECT packet, with credentials if there are any, and
+/// check that the broker accepts the session in its CONNACK
+pub async fn mqtt(
+    mut stream: TcpStream,
+    user: Option<&str>,
+    password: Option<&str>,
+) -> io::Result<bool> {
+    // Start a clean session, with a 10 second keep alive
+    let mut flags = 0x02;
+    let mut body = Vec::new();
+    mqtt_string(&mut body, "MQTT");
+    body.push(4);
+    body.push(0);
+    body.extend_from_slice(&10u16.to_be_bytes());
+    // An empty client ID asks the broker to assign one, so that concurrent
+    // probes don't take over each other's sessions
+    mqtt_string(&mut body, "");
+
+    if let Some(user) = user {
+        flags |= 0x80;
+        mqtt_string(&mut body, user);
+    }
+
+    if let Some(password) = password {
+        flags |= 0x40;
+        mqtt_string(&mut body, password);
+    }
+
+    // The flags come after the protocol name and level
+    body[7] = flags;
+
+    // The remaining length is encoded 7 bits at a time
+    let mut packet = vec![0x10];
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+
+        match len {
+            0 => {
+                packet.push(byte);
+                break;
+            }
+            _ => packet.push(byte | 0x80),
+        }
+    }
+    packet.extend_from_slice(&body);
+    stream.write_all(&packet).await?;
+
+    let mut connack = [0; 4];
+    stream.read_exact(&mut connack).await?;
+
+    match connack {
+        [0x20, 0x02, _, code] => {
+            trace!(code, "received CONNACK");
+
+            if code == 0 {
+                // Disconnect cleanly, so the broker doesn't log an error
+                let _ = stream.write_all(&[0xE0, 0x00]).await;
+            }
+
+            Ok(code == 0)
+        }
+        _ => Err(invalid_data("unexpected response to CONNECT")),
+    }
+}
+
+/// Send an HTTP/1.1 request over a Unix socket, returning the response's
+/// status, and its body if `read_body` is set. reqwest can only connect over
+/// TCP, so this uses hyper directly.
+#[cfg(unix)]
+pub async fn http_unix(
+    stream: UnixStream,
+    method: &Method,
+    host: &str,
+    path: &str,
+    headers: &[(HeaderName, HeaderValue)],
+    read_body: bool,
+) -> io::Result<(StatusCode, Option<Bytes>)> {
+    let to_io = |err: hyper::Error| io::Error::other(err);
+
+    let (mut sender, connection) = conn::handshake(stream).await.map_err(to_io)?;
+
+    // The connection has to be driven alongside the request and the body. It
+    // finishes on its own once they're done with, or dropped.
+    tokio::spawn(async move {
+        if let Err(err) = connection.await {
+            trace!(error = %err, "connection failed");
+        }
+    });
+
+    let request = headers
+        .iter()
+        .fold(
+            Request::builder()
+                .method(method.clone())
+                .uri(path)
+                .header(HOST, host),
+            |builder, (name, value)| builder.header(name, value),
+        )
+        .body(Body::empty())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+    let response = sender.send_request(request).await.map_err(to_io)?;
+    let status = response.status();
+
+    let body = match read_body {
+        true => Some(to_bytes(response.into_body()).await.map_err(to_io)?),
+        false => None,
+    };
+
+    Ok((status, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a BSON document from its encoded elements
+    fn document(elements: &[(u8, &str, &[u8])]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for &(kind, name, value) in elements {
+            body.push(kind);
+            body.extend_from_slice(name.as_bytes());
+            body.push(0);
+            body.extend_from_slice(value);
+        }
+        body.push(0);
+
+        let mut document = ((body.len() + 4) as i32).to_le_bytes().to_vec();
+        document.extend_from_slice(&body);
+        document
+    }
+
+    fn string(value: &str) -> Vec<u8> {
+        let mut encoded = ((value.len() + 1) as i32).to_le_bytes().to_vec();
+        encoded.extend_from_slice(value.as_bytes());
+        encoded.push(0);
+        encoded
+    }
+
+    #[test]
+    fn bson_field_skips_earlier_fields() {
+        let host = string("db:27017");
+        let document = document(&[
+            (0x02, "me", &host),
+            (0x0A, "nothing", &[]),
+            (0x08, "ismaster", &[1]),
+            (0x01, "ok", &1.0f64.to_le_bytes()),
+        ]);
+
+        assert_eq!(bson_field(&document, "me"), Some((0x02, host.as_slice())));
+        assert_eq!(bson_field(&document, "ismaster"), Some((0x08, &[1][..])));
+        assert_eq!(bson_field(&document, "secondary"), None);
+
+        assert!(bson_is_true(&document, "ismaster"));
+        assert!(bson_is_true(&document, "ok"));
+        assert!(!bson_is_true(&document, "secondary"));
+    }
+
+    #[test]
+    fn bson_is_true_numbers() {
+        let document = document(&[
+            (0x10, "int32", &1i32.to_le_bytes()),
+            (0x12, "int64", &1i64.to_le_bytes()),
+            (0x10, "zero", &0i32.to_le_bytes()),
+            (0x08, "false", &[0]),
+        ]);
+
+        assert!(bson_is_true(&document, "int32"));
+        assert!(bson_is_true(&document, "int64"));
+        assert!(!bson_is_true(&document, "zero"));
+        assert!(!bson_is_true(&document, "false"));
+    }
+
+    #[test]
+    fn bson_field_rejects_malformed_lengths() {
+        // A string whose length is negative, or past the end of the document
+        let negative = document(&[(0x02, "me", &(-1i32).to_le_bytes()), (0x08, "ok", &[1])]);
+        assert_eq!(bson_field(&negative, "ok"), None);
+
+        let overflow = document(&[(0x05, "me", &i32::MAX.to_le_bytes()), (0x08, "ok", &[1])]);
+        assert_eq!(bson_field(&overflow, "ok"), None);
+
+        // Truncated in the middle of a value
+        let truncated = document(&[(0x01, "ok", &1.0f64.to_le_bytes())]);
+        assert_eq!(bson_field(&truncated[..truncated.len() - 4], "ok"), None);
+
+        // An unknown type
+        assert_eq!(bson_field(&document(&[(0x42, "ok", &[1])]), "ok"), None);
+    }
+
+    #[test]
+    fn ber_element_short_form() {
+        assert_eq!(
+            ber_element(&[0x30, 0x03, 1, 2, 3, 0xFF]),
+            Some((0x30, &[1, 2, 3][..], &[0xFF][..]))
+        );
+        assert_eq!(ber_element(&[0x04, 0x00]), Some((0x04, &[][..], &[][..])));
+    }
+
+    #[test]
+    fn ber_element_long_form() {
+        assert_eq!(
+            ber_element(&[0x04, 0x81, 0x02, 9, 9, 7]),
+            Some((0x04, &[9, 9][..], &[7][..]))
+        );
+
+        let mut long = vec![0x04, 0x82, 0x01, 0x00];
+        long.extend_from_slice(&[0; 256]);
+        assert_eq!(ber_element(&long), Some((0x04, &[0; 256][..], &[][..])));
+    }
+
+    #[test]
+    fn ber_element_malformed() {
+        // Empty, truncated contents, and a truncated length
+        assert_eq!(ber_element(&[]), None);
+        assert_eq!(ber_element(&[0x30]), None);
+        assert_eq!(ber_element(&[0x30, 0x03, 1, 2]), None);
+        assert_eq!(ber_element(&[0x30, 0x82, 0x01]), None);
+
+        // Indefinite and oversized lengths aren't supported
+        assert_eq!(ber_element(&[0x30, 0x80, 0, 0]), None);
+        assert_eq!(ber_element(&[0x30, 0x85, 0, 0, 0, 0, 1, 0]), None);
+    }
+}
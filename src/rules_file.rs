@@ -0,0 +1,79 @@
+use std::{error::Error, fs, io, path::Path};
+
+use defibrillator::rules::Rules;
+use nom_supreme::{error::ErrorTree, final_parser::Location};
+use tracing::warn;
+
+use crate::template::TemplateVars;
+
+/// Remove a `#` comment from the end of a line. A `#` inside a quoted string
+/// doesn't start a comment.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (idx, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..idx],
+            _ => {}
+        }
+    }
+
+    line
+}
+
+/// The rules, along with the text they were parsed from. `${NAME}`
+/// references in the text are expanded again for each attempt, since some
+/// variables, like `DEFIBRILLATOR_ATTEMPT`, change between attempts.
+#[derive(Debug, Clone)]
+pub struct RulesTemplate {
+    source: String,
+    rules: Rules,
+}
+
+impl RulesTemplate {
+    /// Expand `source` with `vars`, and parse it, to make sure that it's valid
+    pub fn parse(source: String, vars: &TemplateVars) -> Result<Self, ErrorTree<Location>> {
+        let rules = vars.expand_rules(&source).parse()?;
+        Ok(Self { source, rules })
+    }
+
+    /// The rules, as they were first parsed
+    pub fn rules(&self) -> &Rules {
+        &self.rules
+    }
+
+    /// Expand and parse the rules again with `vars`. If they no longer parse,
+    /// they're used as they were first parsed instead.
+    pub fn expand(&self, vars: &TemplateVars) -> Rules {
+        match vars.expand_rules(&self.source).parse() {
+            Ok(rules) => rules,
+            Err(err) => {
+                let err: &dyn Error = &err;
+                warn!(
+                    error = err,
+                    "the rules don't parse with this attempt's variables; using them as they were"
+                );
+                self.rules.clone()
+            }
+        }
+    }
+}
+
+/// Read a rule expression from a file. The expression may span several
+/// lines, and may contain `#` comments. `${NAME}` references are expanded
+/// from `vars`.
+pub fn read_rules_file(path: &Path, vars: &TemplateVars) -> io::Result<RulesTemplate> {
+    let source = fs::read_to_string(path)?
+        .lines()
+        .map(|line| strip_comment(line).trim())
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    RulesTemplate::parse(source, vars)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
@@ -0,0 +1,184 @@
+use std::io;
+#[cfg(unix)]
+use std::{
+    env,
+    error::Error,
+    ffi::OsString,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            net::{SocketAddr, UnixDatagram},
+        },
+    },
+    process,
+};
+
+use bytes::Bytes;
+#[cfg(unix)]
+use tokio::net::UnixDatagram as AsyncUnixDatagram;
+use tokio::sync::broadcast::Sender;
+#[cfg(unix)]
+use tracing::{debug, trace, warn};
+
+/// The environment variable systemd uses to pass the notification socket to
+/// services with `Type=notify`
+pub const NOTIFY_SOCKET: &str = "NOTIFY_SOCKET";
+
+/// A connection to systemd's service notification socket, used to report
+/// state changes with the `sd_notify` datagram protocol.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct Notifier {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+}
+
+#[cfg(unix)]
+impl Notifier {
+    /// Create a notifier for the socket named by `$NOTIFY_SOCKET`, if it's
+    /// set. A leading `@` denotes a socket in the abstract namespace.
+    pub fn from_env() -> io::Result<Option<Self>> {
+        match env::var_os(NOTIFY_SOCKET) {
+            None => Ok(None),
+            Some(path) => Self::new(path).map(Some),
+        }
+    }
+
+    fn new(path: OsString) -> io::Result<Self> {
+        let addr = match path.as_bytes() {
+            [] => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "NOTIFY_SOCKET is empty",
+                ))
+            }
+            [b'@', name @ ..] => SocketAddr::from_abstract_name(name)?,
+            _ => SocketAddr::from_pathname(path)?,
+        };
+
+        Ok(Self {
+            socket: UnixDatagram::unbound()?,
+            addr,
+        })
+    }
+
+    /// Send a state string, like `READY=1`, to systemd. Failures are logged,
+    /// but otherwise ignored.
+    pub fn notify(&self, state: &str) {
+        debug!(state, "sending sd_notify state");
+
+        if let Err(err) = self.socket.send_to_addr(state.as_bytes(), &self.addr) {
+            let err: &dyn Error = &err;
+            warn!(error = err, state, "failed to send sd_notify state");
+        }
+    }
+}
+
+/// A single datagram sent by the server to defibrillator's notification
+/// socket, containing newline-separated `KEY=VALUE` assignments.
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub content: Bytes,
+}
+
+impl Notification {
+    /// Check if the notification contains the given assignment, like
+    /// `READY=1`
+    pub fn contains(&self, state: &str) -> bool {
+        self.content
+            .split(|&b| b == b'\n')
+            .any(|line| line == state.as_bytes())
+    }
+}
+
+/// A notification socket, owned by defibrillator, that the server can use
+/// to report its own state with the `sd_notify` protocol. Its address is
+/// passed to the server in `$NOTIFY_SOCKET`.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct NotifyListener {
+    socket: AsyncUnixDatagram,
+    address: String,
+}
+
+#[cfg(unix)]
+impl NotifyListener {
+    /// Bind a new socket in the abstract namespace, so that there's no file
+    /// to clean up afterwards
+    pub fn bind() -> io::Result<Self> {
+        let name = format!("defibrillator/{}", process::id());
+        let socket = UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(&name)?)?;
+        socket.set_nonblocking(true)?;
+
+        Ok(Self {
+            socket: AsyncUnixDatagram::from_std(socket)?,
+            address: format!("@{}", name),
+        })
+    }
+
+    /// The address of the socket, in the format used by `$NOTIFY_SOCKET`
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Discard any notifications that have already been sent, such as
+    /// leftovers from a previous instance of the server
+    pub fn drain(&self) {
+        let mut buffer = [0; 4096];
+        while self.socket.try_recv(&mut buffer).is_ok() {}
+    }
+
+    /// Receive notifications forever, publishing them to `notifications`
+    pub async fn forward(&self, notifications: Sender<Notification>) -> io::Result<()> {
+        let mut buffer = vec![0; 4096];
+
+        loop {
+            let len = self.socket.recv(&mut buffer).await?;
+            let content = Bytes::copy_from_slice(&buffer[..len]);
+            trace!(?content, "received notification");
+            let _ = notifications.send(Notification { content });
+        }
+    }
+}
+
+/// There's no systemd on Windows, so there's never a socket to notify
+#[cfg(windows)]
+#[derive(Debug)]
+pub enum Notifier {}
+
+#[cfg(windows)]
+impl Notifier {
+    pub fn from_env() -> io::Result<Option<Self>> {
+        Ok(None)
+    }
+
+    pub fn notify(&self, _state: &str) {
+        match *self {}
+    }
+}
+
+/// Windows has no Unix datagram sockets to receive notifications on, so
+/// `notify` rules can't be used there
+#[cfg(windows)]
+#[derive(Debug)]
+pub enum NotifyListener {}
+
+#[cfg(windows)]
+impl NotifyListener {
+    pub fn bind() -> io::Result<Self> {
+        Err(crate::windows::unsupported("the notify rule"))
+    }
+
+    pub fn address(&self) -> &str {
+        match *self {}
+    }
+
+    pub fn drain(&self) {
+        match *self {}
+    }
+
+    pub async fn forward(&self, _notifications: Sender<Notification>) -> io::Result<()> {
+        match *self {}
+    }
+}
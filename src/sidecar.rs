@@ -0,0 +1,137 @@
+use std::{error::Error, future::Future, io, process::Stdio, sync::Arc, time::Duration};
+
+use defibrillator::output::{handle_output, LogBuffer, LogLine, Stream};
+use futures::future::{join, join_all};
+use libc::c_int;
+#[cfg(unix)]
+use libc::pid_t;
+use tokio::{io::AsyncRead, process::Child, sync::broadcast::Sender};
+use tracing::{event, Level};
+
+use crate::init::{spawn_tracked, Tracked};
+#[cfg(windows)]
+use crate::signals::pid_t;
+use crate::signals::{kill_group, stop_child};
+use crate::task::ScopedTask;
+use crate::{exit_code, shell};
+
+/// Where a sidecar's output goes, shared by every sidecar of an attempt
+#[derive(Debug, Clone)]
+pub struct SidecarOutput {
+    pub log_lines: Sender<LogLine>,
+    pub log_buffer: LogBuffer,
+    pub max_line_length: Option<usize>,
+}
+
+/// An auxiliary process started alongside the server with --sidecar. It runs
+/// in its own process group, and its output is published to the same log
+/// lines as the server's, so `matches` rules, the --log-file, and the
+/// --crash-context all see it.
+#[derive(Debug)]
+pub struct Sidecar {
+    command: String,
+    child: Child,
+    /// Only held to keep the reaper away from the child
+    _tracked: Tracked,
+    group: Option<pid_t>,
+    output_task: ScopedTask<(io::Result<()>, io::Result<()>)>,
+}
+
+/// Publish the lines read from one of the sidecar's pipes. They aren't
+/// written anywhere directly: the sidecar only runs while the server does, and
+/// the server's own output tasks forward every line that's published.
+fn publish<T>(
+    pipe: T,
+    stream: Stream,
+    output: &SidecarOutput,
+) -> impl Future<Output = io::Result<()>>
+where
+    T: Unpin + AsyncRead,
+{
+    handle_output(
+        pipe,
+        tokio::io::sink(),
+        stream,
+        output.log_lines.clone(),
+        output.log_buffer.clone(),
+        output.max_line_length,
+        Arc::default(),
+    )
+}
+
+impl Sidecar {
+    /// Run `command` with the shell, forwarding its output
+    pub fn spawn(command: &str, output: &SidecarOutput) -> io::Result<Self> {
+        let mut builder = shell(command);
+
+        builder
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+
+        // On Windows, every child gets a console process group of its own
+        #[cfg(unix)]
+        builder.process_group(0);
+
+        let (mut child, tracked) = spawn_tracked(&mut builder)?;
+
+        let group = child.id().map(|pid| pid as pid_t);
+        event!(Level::INFO, command, pid = child.id(), "started sidecar");
+
+        // Unwrap safety: both pipes were requested above
+        let stdout = child.stdout.take().unwrap();
+        let stderr = child.stderr.take().unwrap();
+
+        let output_task = ScopedTask::new(tokio::spawn(join(
+            publish(stdout, Stream::Stdout, output),
+            publish(stderr, Stream::Stderr, output),
+        )));
+
+        Ok(Self {
+            command: command.to_owned(),
+            child,
+            _tracked: tracked,
+            group,
+            output_task,
+        })
+    }
+
+    /// Stop the sidecar, if it hasn't already exited, along with anything
+    /// left in its process group, and finish forwarding its output
+    pub async fn stop(mut self, signal: c_int, grace_period: Duration) {
+        let status = match self.child.try_wait() {
+            Ok(Some(status)) => Ok(status),
+            _ => stop_child(&mut self.child, self.group, signal, grace_period).await,
+        };
+
+        match status {
+            Ok(..) => event!(
+                Level::DEBUG,
+                command = %self.command,
+                exit_code = exit_code(&status),
+                "sidecar stopped"
+            ),
+            Err(ref err) => {
+                let err: &dyn Error = err;
+                event!(Level::WARN, error = err, command = %self.command, "failed to stop sidecar");
+            }
+        }
+
+        if let Some(group) = self.group {
+            kill_group(group);
+        }
+
+        let _ = self.output_task.await;
+    }
+}
+
+/// Stop every sidecar of an attempt, concurrently
+pub async fn stop_sidecars(sidecars: Vec<Sidecar>, signal: c_int, grace_period: Duration) {
+    join_all(
+        sidecars
+            .into_iter()
+            .map(|sidecar| sidecar.stop(signal, grace_period)),
+    )
+    .await;
+}
@@ -0,0 +1,324 @@
+use std::{error::Error, fmt, io, process::ExitStatus, str::FromStr, time::Duration};
+
+#[cfg(unix)]
+use futures::future::pending;
+use futures::{select_biased, FutureExt};
+use libc::c_int;
+#[cfg(unix)]
+use libc::pid_t;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
+#[cfg(windows)]
+use tokio::signal::windows::{
+    ctrl_break, ctrl_c, ctrl_close, ctrl_logoff, ctrl_shutdown, CtrlBreak, CtrlC, CtrlClose,
+    CtrlLogoff, CtrlShutdown,
+};
+use tokio::{process::Child, time::timeout};
+use tracing::{debug, warn};
+
+/// Windows has no `pid_t`. Processes, and the console process groups that
+/// they lead, are identified by their process IDs.
+#[cfg(windows)]
+#[allow(non_camel_case_types)]
+pub type pid_t = u32;
+
+/// The signal number that the C runtime uses for CTRL_BREAK, which libc
+/// doesn't define
+#[cfg(windows)]
+pub const SIGBREAK: c_int = 21;
+
+/// Listener for the signals that should cause defibrillator to shut down
+/// (SIGINT, SIGTERM, and, unless it's handled some other way, SIGHUP).
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct TerminationSignals {
+    interrupt: Signal,
+    terminate: Signal,
+    hangup: Option<Signal>,
+}
+
+#[cfg(unix)]
+impl TerminationSignals {
+    pub fn new(hangup: bool) -> io::Result<Self> {
+        Ok(Self {
+            interrupt: signal(SignalKind::interrupt())?,
+            terminate: signal(SignalKind::terminate())?,
+            hangup: match hangup {
+                true => Some(signal(SignalKind::hangup())?),
+                false => None,
+            },
+        })
+    }
+
+    /// Wait for the next termination signal, returning its signal number
+    pub async fn recv(&mut self) -> c_int {
+        let hangup = &mut self.hangup;
+        let hangup = async move {
+            match hangup {
+                Some(hangup) => hangup.recv().await,
+                None => pending().await,
+            }
+        };
+
+        select_biased! {
+            _ = self.interrupt.recv().fuse() => libc::SIGINT,
+            _ = self.terminate.recv().fuse() => libc::SIGTERM,
+            _ = hangup.fuse() => libc::SIGHUP,
+        }
+    }
+}
+
+/// Send a signal to the child process, or to the process group it leads if
+/// `group` is given. Does nothing if the child has already been reaped.
+#[cfg(unix)]
+pub fn send_signal(child: &Child, group: Option<pid_t>, signal: c_int) -> io::Result<()> {
+    let target = match (group, child.id()) {
+        (_, None) => return Ok(()),
+        (Some(group), Some(_)) => -group,
+        (None, Some(pid)) => pid as pid_t,
+    };
+
+    // Safety: kill has no memory safety implications
+    match unsafe { libc::kill(target, signal) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Kill every process remaining in a process group. Errors are ignored,
+/// since the group is usually already empty.
+#[cfg(unix)]
+pub fn kill_group(group: pid_t) {
+    // Safety: kill has no memory safety implications
+    if unsafe { libc::kill(-group, libc::SIGKILL) } == 0 {
+        debug!(
+            group,
+            "killed leftover processes in the server's process group"
+        );
+    }
+}
+
+/// Listener for the console control events that should cause defibrillator
+/// to shut down, reported as the signals that they correspond to: SIGINT for
+/// CTRL_C, SIGBREAK for CTRL_BREAK, and SIGTERM for the console being closed,
+/// or the user logging off or shutting down. Windows only waits a few
+/// seconds for a process to exit after those last three, so a longer
+/// --stop-timeout may be cut short.
+#[cfg(windows)]
+#[derive(Debug)]
+pub struct TerminationSignals {
+    interrupt: CtrlC,
+    brk: CtrlBreak,
+    close: CtrlClose,
+    logoff: CtrlLogoff,
+    shutdown: CtrlShutdown,
+}
+
+#[cfg(windows)]
+impl TerminationSignals {
+    /// There's no SIGHUP on Windows, so `hangup` has no effect
+    pub fn new(_hangup: bool) -> io::Result<Self> {
+        Ok(Self {
+            interrupt: ctrl_c()?,
+            brk: ctrl_break()?,
+            close: ctrl_close()?,
+            logoff: ctrl_logoff()?,
+            shutdown: ctrl_shutdown()?,
+        })
+    }
+
+    /// Wait for the next console control event, returning the number of the
+    /// signal that it corresponds to
+    pub async fn recv(&mut self) -> c_int {
+        select_biased! {
+            _ = self.interrupt.recv().fuse() => libc::SIGINT,
+            _ = self.brk.recv().fuse() => SIGBREAK,
+            _ = self.close.recv().fuse() => libc::SIGTERM,
+            _ = self.logoff.recv().fuse() => libc::SIGTERM,
+            _ = self.shutdown.recv().fuse() => libc::SIGTERM,
+        }
+    }
+}
+
+/// Ask the child to stop. Windows has no signals; the closest thing is
+/// CTRL_BREAK, which can only be sent to a whole console process group, so
+/// it's sent to the one that the child leads, whatever `signal` and `group`
+/// are. Does nothing if the child has already been reaped.
+#[cfg(windows)]
+pub fn send_signal(child: &Child, _group: Option<pid_t>, _signal: c_int) -> io::Result<()> {
+    match child.id() {
+        Some(pid) => defibrillator::windows::ctrl_break(pid),
+        None => Ok(()),
+    }
+}
+
+/// Kill every process remaining in the job object of the child that leads
+/// `group`, the Windows counterpart of its process group
+#[cfg(windows)]
+pub fn kill_group(group: pid_t) {
+    if crate::init::terminate_job(group) {
+        debug!(
+            group,
+            "killed leftover processes in the server's job object"
+        );
+    }
+}
+
+/// Send `signal` to the child (or its process group), then give it
+/// `grace_period` to exit before killing it outright. If the signal can't be
+/// sent, the child is killed right away.
+pub async fn stop_child(
+    child: &mut Child,
+    group: Option<pid_t>,
+    signal: c_int,
+    grace_period: Duration,
+) -> io::Result<ExitStatus> {
+    debug!(signal, "sending signal to child");
+
+    match send_signal(child, group, signal) {
+        Ok(()) => match timeout(grace_period, child.wait()).await {
+            Ok(status) => return status,
+            Err(..) => warn!(
+                ?grace_period,
+                "child didn't exit after grace period; killing it"
+            ),
+        },
+        Err(err) => {
+            let err: &dyn Error = &err;
+            warn!(error = err, signal, "failed to signal child; killing it");
+        }
+    }
+
+    match group {
+        Some(group) => kill_group(group),
+        None => child.kill().await?,
+    }
+
+    child.wait().await
+}
+
+/// The signals that can be named on the command line, with and without their
+/// `SIG` prefix
+#[cfg(unix)]
+const SIGNAL_NAMES: &[(&str, c_int)] = &[
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("ILL", libc::SIGILL),
+    ("ABRT", libc::SIGABRT),
+    ("BUS", libc::SIGBUS),
+    ("FPE", libc::SIGFPE),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("SEGV", libc::SIGSEGV),
+    ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+];
+
+/// The signals that the C runtime defines on Windows. They can't be sent to
+/// other processes, but they may be reported as the reason defibrillator
+/// is stopping.
+#[cfg(windows)]
+const SIGNAL_NAMES: &[(&str, c_int)] = &[
+    ("INT", libc::SIGINT),
+    ("ILL", libc::SIGILL),
+    ("FPE", libc::SIGFPE),
+    ("SEGV", libc::SIGSEGV),
+    ("TERM", libc::SIGTERM),
+    ("BREAK", SIGBREAK),
+    ("ABRT", libc::SIGABRT),
+];
+
+/// The highest signal number
+#[cfg(unix)]
+fn max_signal() -> c_int {
+    libc::SIGRTMAX()
+}
+
+#[cfg(windows)]
+fn max_signal() -> c_int {
+    libc::NSIG - 1
+}
+
+/// The name of a signal, like `SIGKILL`, for reporting. Signals without a
+/// known name are reported by number.
+pub fn signal_name(signal: c_int) -> String {
+    match SIGNAL_NAMES
+        .iter()
+        .find(|&&(_, candidate)| candidate == signal)
+    {
+        Some((name, _)) => format!("SIG{}", name),
+        None => signal.to_string(),
+    }
+}
+
+/// A signal, parsed from a name like `TERM` or `SIGTERM`, or a number
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignalArg(c_int);
+
+impl SignalArg {
+    pub fn get(self) -> c_int {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UnknownSignal(String);
+
+impl fmt::Display for UnknownSignal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown signal {:?}", self.0)
+    }
+}
+
+impl FromStr for SignalArg {
+    type Err = UnknownSignal;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Numbers have to be real signals; 0 would only check that the
+        // process exists
+        if let Ok(number) = s.parse() {
+            return match (1..=max_signal()).contains(&number) {
+                true => Ok(SignalArg(number)),
+                false => Err(UnknownSignal(s.to_owned())),
+            };
+        }
+
+        let upper = s.to_ascii_uppercase();
+        let name = upper.strip_prefix("SIG").unwrap_or(&upper);
+
+        SIGNAL_NAMES
+            .iter()
+            .find(|&&(candidate, _)| candidate == name)
+            .map(|&(_, signal)| SignalArg(signal))
+            .ok_or_else(|| UnknownSignal(s.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn signal_arg_names() {
+        assert_eq!("TERM".parse::<SignalArg>().unwrap().get(), libc::SIGTERM);
+        assert_eq!("sigkill".parse::<SignalArg>().unwrap().get(), libc::SIGKILL);
+        assert!("SIGNOPE".parse::<SignalArg>().is_err());
+    }
+
+    #[test]
+    fn signal_arg_numbers() {
+        assert_eq!("15".parse::<SignalArg>().unwrap().get(), 15);
+        assert_eq!(
+            max_signal().to_string().parse::<SignalArg>().unwrap().get(),
+            max_signal()
+        );
+
+        for number in ["0", "-3", "99"] {
+            assert!(number.parse::<SignalArg>().is_err(), "{} parsed", number);
+        }
+    }
+}
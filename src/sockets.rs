@@ -0,0 +1,183 @@
+use std::{fmt, io, net::SocketAddr, path::PathBuf, str::FromStr};
+#[cfg(unix)]
+use std::{
+    fs,
+    net::TcpListener,
+    os::unix::{
+        fs::FileTypeExt,
+        io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        net::UnixListener,
+    },
+    sync::Arc,
+};
+
+#[cfg(windows)]
+use defibrillator::windows;
+use tokio::process::Command;
+
+/// The first file descriptor used for passed sockets, as defined by the
+/// systemd socket activation protocol
+#[cfg(unix)]
+const LISTEN_FDS_START: RawFd = 3;
+
+/// A listening socket for --bind, given as `tcp:ADDRESS:PORT` or
+/// `unix:PATH`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BindAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+pub struct InvalidBindAddr(String);
+
+impl fmt::Display for InvalidBindAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid bind address {:?}; expected tcp:ADDRESS:PORT or unix:PATH",
+            self.0
+        )
+    }
+}
+
+impl FromStr for BindAddr {
+    type Err = InvalidBindAddr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("tcp", addr)) => addr
+                .parse()
+                .map(BindAddr::Tcp)
+                .map_err(|_| InvalidBindAddr(s.to_owned())),
+            Some(("unix", path)) if !path.is_empty() => Ok(BindAddr::Unix(path.into())),
+            _ => Err(InvalidBindAddr(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for BindAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BindAddr::Tcp(addr) => write!(f, "tcp:{}", addr),
+            BindAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl BindAddr {
+    /// Create a listening socket at this address. A stale Unix socket left
+    /// behind by a previous run is replaced.
+    fn bind(&self) -> io::Result<OwnedFd> {
+        match self {
+            BindAddr::Tcp(addr) => TcpListener::bind(addr).map(OwnedFd::from),
+            BindAddr::Unix(path) => {
+                match fs::symlink_metadata(path) {
+                    Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+                    _ => {}
+                }
+
+                UnixListener::bind(path).map(OwnedFd::from)
+            }
+        }
+    }
+}
+
+/// Listening sockets created by defibrillator, and passed to each instance of
+/// the server with the `LISTEN_FDS` protocol. Since they outlive the server,
+/// connections made while it's restarting wait in the kernel's queue, rather
+/// than being refused.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct ListenSockets {
+    fds: Arc<Vec<OwnedFd>>,
+}
+
+#[cfg(unix)]
+impl ListenSockets {
+    pub fn bind(addrs: &[BindAddr]) -> io::Result<Self> {
+        let sockets = addrs
+            .iter()
+            .map(|addr| {
+                addr.bind().map_err(|err| {
+                    io::Error::new(err.kind(), format!("failed to bind {}: {}", addr, err))
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        // Each socket is moved onto its descriptor in the server with dup2.
+        // Keep them all above that range, so that moving one never closes
+        // another that hasn't been moved yet.
+        let lowest = LISTEN_FDS_START + sockets.len() as RawFd;
+
+        let fds = sockets
+            .iter()
+            .map(|socket| {
+                // Safety: fcntl doesn't touch memory
+                match unsafe { libc::fcntl(socket.as_raw_fd(), libc::F_DUPFD_CLOEXEC, lowest) } {
+                    -1 => Err(io::Error::last_os_error()),
+                    // Safety: the descriptor was just created, and nothing
+                    // else owns it
+                    fd => Ok(unsafe { OwnedFd::from_raw_fd(fd) }),
+                }
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(Self { fds: Arc::new(fds) })
+    }
+
+    /// Create a command that runs `program` with the sockets, starting at
+    /// descriptor 3, and with `LISTEN_FDS` and `LISTEN_PID` set to describe
+    /// them.
+    ///
+    /// LISTEN_PID has to be the server's own PID, which isn't known until it's
+    /// forked, and can't be set in the environment after that. Instead, the
+    /// program is run by a shell, which sets it to its own PID, then execs the
+    /// program in its place.
+    pub fn command(&self, program: &str) -> Command {
+        let fds = Arc::clone(&self.fds);
+        let mut command = Command::new("sh");
+
+        command
+            .arg("-c")
+            .arg("LISTEN_PID=$$; export LISTEN_PID; exec \"$@\"")
+            .arg("sh")
+            .arg(program)
+            .env("LISTEN_FDS", fds.len().to_string())
+            .env_remove("LISTEN_FDNAMES");
+
+        // Safety: dup2 is async-signal-safe
+        unsafe {
+            command.pre_exec(move || {
+                for (target, fd) in (LISTEN_FDS_START..).zip(fds.iter()) {
+                    // dup2 clears close-on-exec on the new descriptor
+                    if libc::dup2(fd.as_raw_fd(), target) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+
+                Ok(())
+            });
+        }
+
+        command
+    }
+}
+
+/// Passing sockets to the server relies on it inheriting file descriptors,
+/// which Windows doesn't do
+#[cfg(windows)]
+#[derive(Debug)]
+pub enum ListenSockets {}
+
+#[cfg(windows)]
+impl ListenSockets {
+    pub fn bind(_addrs: &[BindAddr]) -> io::Result<Self> {
+        Err(windows::unsupported("--bind"))
+    }
+
+    pub fn command(&self, _program: &str) -> Command {
+        match *self {}
+    }
+}
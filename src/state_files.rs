@@ -0,0 +1,84 @@
+use std::{
+    error::Error,
+    fs, io,
+    path::{Path, PathBuf},
+    process,
+};
+
+use tracing::warn;
+
+/// Write `contents` to `path` by renaming a temporary file over it, so that
+/// anything polling the file never sees it partially written
+fn write_atomically(path: &Path, contents: &str) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+
+    fs::write(&temporary, contents)?;
+    fs::rename(&temporary, path)
+}
+
+fn remove(path: &Path) {
+    match fs::remove_file(path) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => {
+            let err: &dyn Error = &err;
+            warn!(error = err, path = %path.display(), "failed to remove file");
+        }
+    }
+}
+
+fn write(path: &Path, contents: &str) {
+    if let Err(err) = write_atomically(path, contents) {
+        let err: &dyn Error = &err;
+        warn!(error = err, path = %path.display(), "failed to write file");
+    }
+}
+
+/// Files that describe the server's state, for classic init tooling and shell
+/// scripts: the --pid-file, the --supervisor-pid-file, and the --ready-file
+#[derive(Debug, Default)]
+pub struct StateFiles {
+    pub pid_file: Option<PathBuf>,
+    pub supervisor_pid_file: Option<PathBuf>,
+    pub ready_file: Option<PathBuf>,
+}
+
+impl StateFiles {
+    /// Write defibrillator's own PID to the --supervisor-pid-file
+    pub fn write_supervisor_pid(&self) -> io::Result<()> {
+        match self.supervisor_pid_file {
+            Some(ref path) => write_atomically(path, &format!("{}\n", process::id())),
+            None => Ok(()),
+        }
+    }
+
+    /// Record the PID of a newly launched instance of the server
+    pub fn spawned(&self, pid: Option<u32>) {
+        if let (Some(path), Some(pid)) = (&self.pid_file, pid) {
+            write(path, &format!("{}\n", pid));
+        }
+    }
+
+    pub fn ready(&self) {
+        if let Some(ref path) = self.ready_file {
+            write(path, "");
+        }
+    }
+
+    /// The server has exited, so its PID and readiness no longer apply
+    pub fn stopped(&self) {
+        for path in [&self.pid_file, &self.ready_file].iter().copied().flatten() {
+            remove(path);
+        }
+    }
+
+    /// Remove every file, before defibrillator exits
+    pub fn remove_all(&self) {
+        self.stopped();
+
+        if let Some(ref path) = self.supervisor_pid_file {
+            remove(path);
+        }
+    }
+}
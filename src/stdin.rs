@@ -0,0 +1,118 @@
+use std::{error::Error, fmt, io, process::Stdio, str::FromStr, sync::Arc};
+
+use bytes::{Bytes, BytesMut};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    process::ChildStdin,
+    sync::{mpsc, Mutex},
+};
+use tracing::{debug, warn};
+
+/// What the server's stdin is connected to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StdinMode {
+    /// The server shares our stdin
+    Inherit,
+
+    /// The server's stdin is empty
+    Null,
+
+    /// Our stdin is read and passed on to the server through a pipe, which
+    /// is replaced for each attempt
+    Pipe,
+}
+
+impl StdinMode {
+    pub fn stdio(self) -> Stdio {
+        match self {
+            StdinMode::Inherit => Stdio::inherit(),
+            StdinMode::Null => Stdio::null(),
+            StdinMode::Pipe => Stdio::piped(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InvalidStdinMode(String);
+
+impl fmt::Display for InvalidStdinMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown stdin mode {:?}; expected inherit, null, or pipe",
+            self.0
+        )
+    }
+}
+
+impl FromStr for StdinMode {
+    type Err = InvalidStdinMode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "inherit" => Ok(StdinMode::Inherit),
+            "null" => Ok(StdinMode::Null),
+            "pipe" => Ok(StdinMode::Pipe),
+            _ => Err(InvalidStdinMode(s.to_owned())),
+        }
+    }
+}
+
+/// Our own stdin, which is read in the background so that it can be passed on
+/// to each attempt's server in turn. Clones share the same stdin.
+#[derive(Debug, Clone)]
+pub struct StdinForwarder {
+    chunks: Arc<Mutex<mpsc::Receiver<Bytes>>>,
+}
+
+impl StdinForwarder {
+    /// Start reading stdin. This must be called from within the runtime.
+    pub fn spawn() -> Self {
+        let (sender, receiver) = mpsc::channel(16);
+
+        tokio::spawn(async move {
+            let mut stdin = tokio::io::stdin();
+
+            loop {
+                let mut buffer = BytesMut::with_capacity(4096);
+
+                match stdin.read_buf(&mut buffer).await {
+                    Ok(0) => {
+                        debug!("stdin closed");
+                        break;
+                    }
+                    Ok(..) => {
+                        if sender.send(buffer.freeze()).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                    Err(err) => {
+                        let err: &dyn Error = &err;
+                        warn!(error = err, "failed to read stdin");
+                        break;
+                    }
+                }
+            }
+        });
+
+        Self {
+            chunks: Arc::new(Mutex::new(receiver)),
+        }
+    }
+
+    /// Write our stdin to a server's stdin, until either is closed. Once our
+    /// stdin is closed, so is the server's. A chunk that was being written
+    /// when the server closed its stdin is lost.
+    pub async fn forward(self, mut pipe: ChildStdin) {
+        let mut chunks = self.chunks.lock().await;
+
+        while let Some(mut chunk) = chunks.recv().await {
+            if let Err(err) = pipe.write_all_buf(&mut chunk).await {
+                let err: &dyn Error = &err;
+                debug!(error = err, "server's stdin was closed");
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use tracing::{event, Level};
+
+/// A record of every attempt to run the server, which is logged when
+/// defibrillator exits
+#[derive(Debug, Default)]
+pub struct Summary {
+    attempts: AtomicU64,
+    restarts: AtomicU64,
+
+    /// The time from spawning the server until its rules were satisfied, for
+    /// each attempt that became ready
+    times_to_ready: Mutex<Vec<Duration>>,
+}
+
+impl Summary {
+    pub fn record_attempt(&self) {
+        self.attempts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_restart(&self) {
+        self.restarts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_ready(&self, time_to_ready: Duration) {
+        self.times_to_ready.lock().unwrap().push(time_to_ready);
+    }
+
+    pub fn log(&self) {
+        let attempts = self.attempts.load(Ordering::Relaxed);
+        let restarts = self.restarts.load(Ordering::Relaxed);
+
+        let mut times = self.times_to_ready.lock().unwrap().clone();
+        times.sort_unstable();
+
+        let (min, max) = match (times.first(), times.last()) {
+            (Some(&min), Some(&max)) => (min, max),
+            _ => {
+                event!(
+                    Level::INFO,
+                    attempts,
+                    restarts,
+                    "summary: the server never became ready"
+                );
+                return;
+            }
+        };
+
+        let middle = times.len() / 2;
+        let median = match times.len() % 2 {
+            0 => (times[middle - 1] + times[middle]) / 2,
+            _ => times[middle],
+        };
+
+        event!(
+            Level::INFO,
+            attempts,
+            restarts,
+            ready = times.len(),
+            ?min,
+            ?median,
+            ?max,
+            "summary: time to ready"
+        );
+    }
+}
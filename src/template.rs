@@ -0,0 +1,254 @@
+use std::{borrow::Cow, collections::HashMap, env, sync::LazyLock};
+
+use regex::{Captures, Regex};
+
+/// A `${NAME}` or `${NAME:-default}` reference, or a bare `$NAME`
+static VARIABLE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\$(?:\{([A-Za-z0-9_]+)(?::-([^}]*))?\}|([A-Za-z0-9_]+))").unwrap()
+});
+
+/// The variables that can be referred to in the command and the rules: the
+/// server's environment, as set up by --env, --env-file, and --clear-env,
+/// plus each --alloc-port
+#[derive(Debug, Clone, Default)]
+pub struct TemplateVars {
+    values: HashMap<String, String>,
+}
+
+impl TemplateVars {
+    /// Start with defibrillator's own environment, unless `clear` is set.
+    /// Variables that aren't valid UTF-8 are skipped.
+    pub fn from_env(clear: bool) -> Self {
+        let values = match clear {
+            true => HashMap::new(),
+            false => env::vars_os()
+                .filter_map(|(key, value)| {
+                    Some((key.into_string().ok()?, value.into_string().ok()?))
+                })
+                .collect(),
+        };
+
+        Self { values }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+
+    pub fn set(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.values.insert(name.into(), value.into());
+    }
+
+    /// Add the variables that describe an attempt, which the server is also
+    /// given in its environment
+    pub fn for_attempt(&self, attempt: u64, started_at: u64, last_exit_code: Option<i32>) -> Self {
+        let mut vars = self.clone();
+        vars.set("DEFIBRILLATOR_ATTEMPT", attempt.to_string());
+        vars.set("DEFIBRILLATOR_STARTED_AT", started_at.to_string());
+
+        match last_exit_code {
+            Some(code) => vars.set("DEFIBRILLATOR_LAST_EXIT_CODE", code.to_string()),
+            None => {
+                vars.values.remove("DEFIBRILLATOR_LAST_EXIT_CODE");
+            }
+        }
+
+        vars
+    }
+
+    /// The value to substitute for a reference, if there is one
+    fn lookup(
+        &self,
+        captures: &Captures<'_>,
+        bare: impl Fn(&str) -> Option<String>,
+    ) -> Option<String> {
+        match (captures.get(1), captures.get(3)) {
+            (Some(name), _) => match (self.get(name.as_str()), captures.get(2)) {
+                (Some(value), None) => Some(value.to_owned()),
+                (Some(value), Some(..)) if !value.is_empty() => Some(value.to_owned()),
+                (_, Some(default)) => Some(default.as_str().to_owned()),
+                (None, None) => None,
+            },
+            (None, Some(name)) => bare(name.as_str()),
+            (None, None) => None,
+        }
+    }
+
+    /// Replace each `${NAME}` in `template` with the variable's value, or
+    /// with the default in `${NAME:-default}` if it's unset or empty. Unset
+    /// variables without a default are left as they are. Bare `$NAME`
+    /// references are only replaced if `bare` returns a value for them, since
+    /// they usually mean something else, like a rule variable.
+    pub fn expand<'a>(
+        &self,
+        template: &'a str,
+        bare: impl Fn(&str) -> Option<String>,
+    ) -> Cow<'a, str> {
+        VARIABLE.replace_all(template, |captures: &Captures<'_>| {
+            self.lookup(captures, &bare)
+                .unwrap_or_else(|| captures[0].to_owned())
+        })
+    }
+
+    /// Like `expand`, but for the rules, where a value has to stay a single
+    /// argument, whatever it contains. Inside a quoted string, its quotes and
+    /// backslashes are escaped; elsewhere, it's quoted if it's empty or
+    /// contains whitespace, quotes, or parentheses. Bare `$NAME` references
+    /// are left alone.
+    pub fn expand_rules(&self, rules: &str) -> String {
+        let mut expanded = String::with_capacity(rules.len());
+        let mut quoted = false;
+        let mut escaped = false;
+        let mut end = 0;
+
+        for captures in VARIABLE.captures_iter(rules) {
+            // Unwrap safety: group 0 is always the whole match
+            let reference = captures.get(0).unwrap();
+            let before = &rules[end..reference.start()];
+            end = reference.end();
+
+            for c in before.chars() {
+                match c {
+                    _ if escaped => escaped = false,
+                    '\\' => escaped = true,
+                    '"' => quoted = !quoted,
+                    _ => {}
+                }
+            }
+
+            expanded.push_str(before);
+            escaped = false;
+
+            match self.lookup(&captures, |_| None) {
+                None => expanded.push_str(reference.as_str()),
+                Some(value) if quoted => expanded.push_str(&escape_quoted(&value)),
+                Some(value) if needs_quotes(&value) => {
+                    expanded.push('"');
+                    expanded.push_str(&escape_quoted(&value));
+                    expanded.push('"');
+                }
+                Some(value) => expanded.push_str(&value),
+            }
+        }
+
+        expanded.push_str(&rules[end..]);
+        expanded
+    }
+}
+
+/// Whether a value substituted into the rules has to be quoted to stay a
+/// single argument
+fn needs_quotes(value: &str) -> bool {
+    value.is_empty()
+        || value
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '"' | '(' | ')'))
+}
+
+/// Escape a value to go inside a quoted string in the rules
+fn escape_quoted(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> TemplateVars {
+        let mut vars = TemplateVars::default();
+        vars.set("PORT", "8080");
+        vars.set("EMPTY", "");
+        vars.set("GREETING", "hello \"big\" world");
+        vars.set("PATTERN", r"\d+");
+        vars
+    }
+
+    #[test]
+    fn expand_braced() {
+        let vars = vars();
+
+        assert_eq!(vars.expand("--port=${PORT}", |_| None), "--port=8080");
+        assert_eq!(vars.expand("${PORT}${PORT}", |_| None), "80808080");
+        assert_eq!(vars.expand("${UNSET}", |_| None), "${UNSET}");
+    }
+
+    #[test]
+    fn expand_defaults() {
+        let vars = vars();
+
+        assert_eq!(vars.expand("${PORT:-80}", |_| None), "8080");
+        assert_eq!(vars.expand("${UNSET:-80}", |_| None), "80");
+        assert_eq!(vars.expand("${EMPTY:-80}", |_| None), "80");
+        assert_eq!(vars.expand("${UNSET:-}", |_| None), "");
+        assert_eq!(vars.expand("${EMPTY}", |_| None), "");
+    }
+
+    #[test]
+    fn expand_bare() {
+        let vars = vars();
+
+        assert_eq!(vars.expand("$PORT", |_| None), "$PORT");
+        assert_eq!(
+            vars.expand("$PORT $OTHER", |name| match name {
+                "PORT" => Some("1".to_owned()),
+                _ => None,
+            }),
+            "1 $OTHER"
+        );
+    }
+
+    #[test]
+    fn expand_for_attempt() {
+        let vars = vars().for_attempt(2, 1000, Some(3));
+
+        assert_eq!(
+            vars.expand(
+                "${DEFIBRILLATOR_ATTEMPT} ${DEFIBRILLATOR_LAST_EXIT_CODE}",
+                |_| None
+            ),
+            "2 3"
+        );
+
+        let vars = vars.for_attempt(1, 1000, None);
+        assert_eq!(
+            vars.expand("${DEFIBRILLATOR_LAST_EXIT_CODE:-none}", |_| None),
+            "none"
+        );
+    }
+
+    #[test]
+    fn expand_rules_quotes_values() {
+        let vars = vars();
+
+        assert_eq!(
+            vars.expand_rules("tcp port ${PORT} ready"),
+            "tcp port 8080 ready"
+        );
+        assert_eq!(
+            vars.expand_rules("contains ${GREETING}"),
+            r#"contains "hello \"big\" world""#
+        );
+        assert_eq!(vars.expand_rules("contains ${EMPTY}"), r#"contains """#);
+        assert_eq!(vars.expand_rules("contains $PORT"), "contains $PORT");
+    }
+
+    #[test]
+    fn expand_rules_escapes_inside_quotes() {
+        let vars = vars();
+
+        assert_eq!(
+            vars.expand_rules(r#"contains "say ${GREETING}""#),
+            r#"contains "say hello \"big\" world""#
+        );
+        assert_eq!(
+            vars.expand_rules(r#"matches "${PATTERN} items""#),
+            r#"matches "\\d+ items""#
+        );
+
+        // An escaped quote doesn't end the string
+        assert_eq!(
+            vars.expand_rules(r#"contains "\"${GREETING}""#),
+            r#"contains "\"hello \"big\" world""#
+        );
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    ffi::{CStr, CString},
+    fmt, io,
+    mem::MaybeUninit,
+    os::raw::c_char,
+    ptr,
+};
+
+use libc::{gid_t, uid_t};
+
+#[derive(Debug)]
+pub enum LookupError {
+    UnknownUser(String),
+    UnknownGroup(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for LookupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LookupError::UnknownUser(user) => write!(f, "unknown user {:?}", user),
+            LookupError::UnknownGroup(group) => write!(f, "unknown group {:?}", group),
+            LookupError::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for LookupError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LookupError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// An entry from the user database
+#[derive(Debug, Clone)]
+pub struct User {
+    pub name: CString,
+    pub uid: uid_t,
+    pub gid: gid_t,
+    pub home: CString,
+}
+
+/// Call one of the reentrant `getpw*_r` or `getgr*_r` functions, growing
+/// the buffer until it's large enough. Returns None if there's no entry.
+fn lookup<T>(
+    mut call: impl FnMut(*mut T, *mut c_char, usize, *mut *mut T) -> libc::c_int,
+) -> io::Result<Option<(T, Vec<c_char>)>> {
+    let mut buffer: Vec<c_char> = vec![0; 1024];
+
+    loop {
+        let mut entry = MaybeUninit::<T>::uninit();
+        let mut result = ptr::null_mut();
+
+        match call(
+            entry.as_mut_ptr(),
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        ) {
+            libc::ERANGE => buffer.resize(buffer.len() * 2, 0),
+            0 if result.is_null() => return Ok(None),
+            // Safety: the entry was initialized, and its pointers refer to
+            // `buffer`, which is returned alongside it
+            0 => return Ok(Some((unsafe { entry.assume_init() }, buffer))),
+            code => return Err(io::Error::from_raw_os_error(code)),
+        }
+    }
+}
+
+/// Look up a user by name or numeric ID
+fn lookup_user(user: &str) -> Result<User, LookupError> {
+    let name = CString::new(user).map_err(|_| LookupError::UnknownUser(user.to_owned()))?;
+    let uid: Option<uid_t> = user.parse().ok();
+
+    // Safety: the arguments are valid for the duration of each call
+    let found = lookup(|entry, buffer, len, result| unsafe {
+        match uid {
+            Some(uid) => libc::getpwuid_r(uid, entry, buffer, len, result),
+            None => libc::getpwnam_r(name.as_ptr(), entry, buffer, len, result),
+        }
+    })
+    .map_err(LookupError::Io)?;
+
+    match found {
+        // Safety: the entry's strings point into its buffer, which is still
+        // alive
+        Some((entry, _buffer)) => Ok(unsafe {
+            User {
+                name: CStr::from_ptr(entry.pw_name).to_owned(),
+                uid: entry.pw_uid,
+                gid: entry.pw_gid,
+                home: CStr::from_ptr(entry.pw_dir).to_owned(),
+            }
+        }),
+        None => Err(LookupError::UnknownUser(user.to_owned())),
+    }
+}
+
+/// Look up a group ID by name or numeric ID
+fn lookup_group(group: &str) -> Result<gid_t, LookupError> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+
+    let name = CString::new(group).map_err(|_| LookupError::UnknownGroup(group.to_owned()))?;
+
+    // Safety: the arguments are valid for the duration of each call
+    let found = lookup(|entry, buffer, len, result| unsafe {
+        libc::getgrnam_r(name.as_ptr(), entry, buffer, len, result)
+    })
+    .map_err(LookupError::Io)?;
+
+    match found {
+        Some((entry, _buffer)) => Ok(entry.gr_gid),
+        None => Err(LookupError::UnknownGroup(group.to_owned())),
+    }
+}
+
+/// The supplementary groups of a user, including `gid`, the group that the
+/// user is run as. This is the user's primary group unless it's overridden.
+fn lookup_groups(user: &User, gid: gid_t) -> Result<Vec<gid_t>, LookupError> {
+    let mut groups: Vec<gid_t> = vec![0; 64];
+
+    loop {
+        let mut count = groups.len() as libc::c_int;
+
+        // Safety: count is the length of groups, which getgrouplist won't
+        // write past
+        let result =
+            unsafe { libc::getgrouplist(user.name.as_ptr(), gid, groups.as_mut_ptr(), &mut count) };
+
+        if result == -1 {
+            // count has been updated to the required size, but only grow
+            // the buffer if it actually needs to grow, to guarantee progress
+            let required = (count as usize).max(groups.len() * 2);
+            groups.resize(required, 0);
+        } else {
+            groups.truncate(count as usize);
+            return Ok(groups);
+        }
+    }
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// The identity the server is run as, resolved ahead of time so that it can
+/// be applied in the child without touching the user database
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub user: Option<User>,
+    gid: gid_t,
+    groups: Vec<gid_t>,
+}
+
+impl Credentials {
+    /// Resolve the credentials for `--user` and `--group`. The group defaults
+    /// to the user's primary group, and the supplementary groups are the
+    /// group plus the groups that list the user as a member, if there is a
+    /// user, or just the group otherwise.
+    pub fn resolve(user: Option<&str>, group: Option<&str>) -> Result<Option<Self>, LookupError> {
+        let user = user.map(lookup_user).transpose()?;
+        let group = group.map(lookup_group).transpose()?;
+
+        let (gid, groups) = match (&user, group) {
+            (None, None) => return Ok(None),
+            (Some(user), gid) => {
+                let gid = gid.unwrap_or(user.gid);
+                (gid, lookup_groups(user, gid)?)
+            }
+            (None, Some(gid)) => (gid, vec![gid]),
+        };
+
+        Ok(Some(Self { user, gid, groups }))
+    }
+
+    /// Switch the current process to these credentials. This is called in
+    /// the child between fork and exec, so it doesn't allocate.
+    pub fn apply(&self) -> io::Result<()> {
+        // Safety: groups is a valid slice of its stated length
+        unsafe {
+            check(libc::setgroups(
+                self.groups.len() as _,
+                self.groups.as_ptr(),
+            ))?;
+            check(libc::setgid(self.gid))?;
+
+            if let Some(ref user) = self.user {
+                check(libc::setuid(user.uid))?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,280 @@
+#[cfg(unix)]
+use std::{
+    collections::HashMap,
+    ffi::{CString, OsString},
+    mem,
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        io::{AsRawFd, FromRawFd, OwnedFd},
+    },
+    ptr,
+};
+use std::{error::Error, fmt, io, path::PathBuf, str::FromStr, time::Duration};
+
+#[cfg(windows)]
+use defibrillator::windows;
+use regex::bytes::Regex;
+use tokio::sync::mpsc;
+#[cfg(unix)]
+use tokio::{
+    io::unix::AsyncFd,
+    time::{timeout_at, Instant},
+};
+#[cfg(unix)]
+use tracing::{info, warn};
+
+use crate::control::Command;
+
+/// A file to watch for --watch. The file name, but not the directory, may
+/// contain `*` and `?` wildcards.
+#[derive(Debug, Clone)]
+#[cfg_attr(windows, allow(dead_code))]
+pub struct WatchPath {
+    dir: PathBuf,
+    name: Regex,
+}
+
+#[derive(Debug, Clone)]
+pub struct InvalidWatchPath(String);
+
+impl fmt::Display for InvalidWatchPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid watch path {:?}; wildcards are only allowed in the file name",
+            self.0
+        )
+    }
+}
+
+impl Error for InvalidWatchPath {}
+
+impl FromStr for WatchPath {
+    type Err = InvalidWatchPath;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (dir, name) = match s.rsplit_once('/') {
+            Some(("", name)) => ("/", name),
+            Some((dir, name)) => (dir, name),
+            None => (".", s),
+        };
+
+        if name.is_empty() || dir.contains(&['*', '?'][..]) {
+            return Err(InvalidWatchPath(s.to_owned()));
+        }
+
+        let pattern: String = name
+            .split('*')
+            .map(|part| {
+                part.split('?')
+                    .map(regex::escape)
+                    .collect::<Vec<_>>()
+                    .join(".")
+            })
+            .collect::<Vec<_>>()
+            .join(".*");
+
+        Ok(Self {
+            dir: dir.into(),
+            name: Regex::new(&format!("^{}$", pattern))
+                .map_err(|_| InvalidWatchPath(s.to_owned()))?,
+        })
+    }
+}
+
+/// The changes that count as modifying a watched file. Directories are
+/// watched, rather than the files themselves, so that files which are
+/// replaced (as editors and package managers tend to do) are still noticed.
+#[cfg(unix)]
+const WATCH_MASK: u32 = libc::IN_CLOSE_WRITE
+    | libc::IN_MOVED_TO
+    | libc::IN_MOVED_FROM
+    | libc::IN_CREATE
+    | libc::IN_DELETE
+    | libc::IN_ATTRIB;
+
+/// The most that a burst of changes can delay a restart by, in multiples of
+/// the debounce interval
+#[cfg(unix)]
+const MAX_DEBOUNCES: u32 = 10;
+
+/// An inotify instance watching the directories of a set of `WatchPath`s
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct Watcher {
+    inotify: AsyncFd<OwnedFd>,
+
+    /// The patterns of the files watched in each watched directory, by watch
+    /// descriptor
+    watches: HashMap<libc::c_int, (PathBuf, Vec<Regex>)>,
+}
+
+#[cfg(unix)]
+impl Watcher {
+    /// Start watching `paths`. Their directories have to exist, but the files
+    /// themselves don't.
+    pub fn new(paths: &[WatchPath]) -> io::Result<Self> {
+        // Safety: inotify_init1 doesn't touch memory
+        let fd = match unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) } {
+            -1 => return Err(io::Error::last_os_error()),
+            // Safety: the descriptor was just created, and nothing else owns it
+            fd => unsafe { OwnedFd::from_raw_fd(fd) },
+        };
+
+        let mut watches: HashMap<_, (PathBuf, Vec<Regex>)> = HashMap::new();
+
+        for path in paths {
+            let dir = CString::new(path.dir.as_os_str().as_bytes())
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+            // Safety: dir is a valid C string. Watching the same directory
+            // twice returns the same descriptor.
+            let wd = match unsafe {
+                libc::inotify_add_watch(fd.as_raw_fd(), dir.as_ptr(), WATCH_MASK)
+            } {
+                -1 => {
+                    let err = io::Error::last_os_error();
+                    return Err(io::Error::new(
+                        err.kind(),
+                        format!("failed to watch {}: {}", path.dir.display(), err),
+                    ));
+                }
+                wd => wd,
+            };
+
+            watches
+                .entry(wd)
+                .or_insert_with(|| (path.dir.clone(), Vec::new()))
+                .1
+                .push(path.name.clone());
+        }
+
+        Ok(Self {
+            inotify: AsyncFd::new(fd)?,
+            watches,
+        })
+    }
+
+    /// Wait for a batch of inotify events, returning the paths of any watched
+    /// files among them
+    async fn read_changes(&self) -> io::Result<Vec<PathBuf>> {
+        // Large enough for at least one event with the longest file name
+        let mut buffer = [0u8; 4096 + mem::size_of::<libc::inotify_event>()];
+
+        let len = loop {
+            let mut guard = self.inotify.readable().await?;
+
+            // Safety: the buffer is valid for its whole length
+            let result = guard.try_io(|fd| {
+                match unsafe {
+                    libc::read(
+                        fd.as_raw_fd(),
+                        buffer.as_mut_ptr() as *mut libc::c_void,
+                        buffer.len(),
+                    )
+                } {
+                    -1 => Err(io::Error::last_os_error()),
+                    len => Ok(len as usize),
+                }
+            });
+
+            match result {
+                Ok(result) => break result?,
+                Err(_would_block) => continue,
+            }
+        };
+
+        let mut changes = Vec::new();
+        let mut offset = 0;
+
+        while offset + mem::size_of::<libc::inotify_event>() <= len {
+            // Safety: the kernel only writes whole events, and the header is
+            // read without assuming it's aligned
+            let event: libc::inotify_event =
+                unsafe { ptr::read_unaligned(buffer[offset..].as_ptr() as *const _) };
+
+            let name_start = offset + mem::size_of::<libc::inotify_event>();
+            let name_end = name_start + event.len as usize;
+            offset = name_end;
+
+            if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                warn!("too many file changes to keep up with");
+                continue;
+            }
+
+            // The name is padded with nul bytes
+            let name = &buffer[name_start..name_end.min(len)];
+            let name = &name[..memchr::memchr(0, name).unwrap_or(name.len())];
+
+            if let Some((dir, patterns)) = self.watches.get(&event.wd) {
+                if patterns.iter().any(|pattern| pattern.is_match(name)) {
+                    changes.push(dir.join(OsString::from_vec(name.to_vec())));
+                }
+            }
+        }
+
+        Ok(changes)
+    }
+
+    /// Wait for a watched file to change, then for the changes to settle, so
+    /// that a burst of changes is only reported once. Only changes to watched
+    /// files extend the wait, and it's capped at `MAX_DEBOUNCES` times
+    /// `debounce`, so that a file that changes constantly (or an unrelated
+    /// one in the same directory) can't hold off the restart forever. Returns
+    /// the first file that changed.
+    pub async fn changed(&self, debounce: Duration) -> io::Result<PathBuf> {
+        let path = loop {
+            if let Some(path) = self.read_changes().await?.into_iter().next() {
+                break path;
+            }
+        };
+
+        let limit = Instant::now() + debounce * MAX_DEBOUNCES;
+        let mut settled = Instant::now() + debounce;
+
+        while let Ok(changes) = timeout_at(settled.min(limit), self.read_changes()).await {
+            if !changes?.is_empty() {
+                settled = Instant::now() + debounce;
+            }
+        }
+
+        Ok(path)
+    }
+
+    /// Restart the server each time a watched file changes, until the
+    /// supervision loop stops listening for commands
+    pub async fn restart_on_change(self, debounce: Duration, commands: mpsc::Sender<Command>) {
+        loop {
+            match self.changed(debounce).await {
+                Ok(path) => {
+                    info!(path = %path.display(), "watched file changed; restarting server");
+
+                    if commands.send(Command::Restart).await.is_err() {
+                        return;
+                    }
+                }
+                Err(err) => {
+                    let err: &dyn Error = &err;
+                    warn!(error = err, "failed to watch files; no longer watching");
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Watching files is built on inotify, which Windows doesn't have
+#[cfg(windows)]
+#[derive(Debug)]
+pub enum Watcher {}
+
+#[cfg(windows)]
+impl Watcher {
+    pub fn new(_paths: &[WatchPath]) -> io::Result<Self> {
+        Err(windows::unsupported("--watch"))
+    }
+
+    pub async fn restart_on_change(self, _debounce: Duration, _commands: mpsc::Sender<Command>) {
+        match self {}
+    }
+}
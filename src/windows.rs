@@ -0,0 +1,192 @@
+//! Process management on Windows, which has neither process groups nor
+//! signals. Instead, processes are placed in job objects, which kill whatever
+//! is left in them once they're closed, and are asked to stop with console
+//! control events.
+
+use std::{
+    ffi::c_void,
+    io, mem,
+    os::windows::io::{AsRawHandle, FromRawHandle, OwnedHandle},
+    ptr,
+};
+
+use tokio::process::{Child, Command};
+use windows_sys::Win32::{
+    Foundation::{ERROR_ACCESS_DENIED, STILL_ACTIVE},
+    System::{
+        Console::{GenerateConsoleCtrlEvent, CTRL_BREAK_EVENT},
+        JobObjects::{
+            AssignProcessToJobObject, CreateJobObjectW, JobObjectExtendedLimitInformation,
+            SetInformationJobObject, TerminateJobObject, JOBOBJECT_EXTENDED_LIMIT_INFORMATION,
+            JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE,
+        },
+        ProcessStatus::{K32GetProcessMemoryInfo, PROCESS_MEMORY_COUNTERS},
+        Threading::{
+            GetExitCodeProcess, OpenProcess, CREATE_NEW_PROCESS_GROUP,
+            PROCESS_QUERY_LIMITED_INFORMATION,
+        },
+    },
+};
+
+/// The error for features that rely on Unix, like pseudo-terminals or Unix
+/// sockets
+pub fn unsupported(feature: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{} isn't supported on Windows", feature),
+    )
+}
+
+/// Create a command that runs `command` with `cmd /C`, the Windows
+/// equivalent of `sh -c`. The command is passed along as is, since cmd
+/// doesn't follow the usual rules for quoting arguments.
+pub fn shell(command: &str) -> Command {
+    let mut builder = Command::new("cmd");
+    builder.arg("/C").raw_arg(command);
+    builder
+}
+
+/// Start the process in its own console process group, so that it can be
+/// sent CTRL_BREAK without defibrillator receiving it too
+pub fn new_process_group(command: &mut Command) -> &mut Command {
+    command.creation_flags(CREATE_NEW_PROCESS_GROUP)
+}
+
+/// Send CTRL_BREAK to the console process group led by `pid`, which is the
+/// closest thing Windows has to SIGTERM. The process must have been started
+/// with `new_process_group`.
+pub fn ctrl_break(pid: u32) -> io::Result<()> {
+    // Safety: this has no memory safety implications
+    match unsafe { GenerateConsoleCtrlEvent(CTRL_BREAK_EVENT, pid) } {
+        0 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Open a process to query its state
+fn open_process(pid: u32) -> io::Result<OwnedHandle> {
+    // Safety: OpenProcess has no memory safety implications
+    let handle = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid) };
+
+    match handle.is_null() {
+        true => Err(io::Error::last_os_error()),
+        // Safety: the handle was just opened, and nothing else owns it
+        false => Ok(unsafe { OwnedHandle::from_raw_handle(handle) }),
+    }
+}
+
+/// Check that the process with this ID is still running. A process that
+/// defibrillator isn't allowed to open still exists.
+pub fn check_process(pid: u32) -> io::Result<()> {
+    let handle = match open_process(pid) {
+        Ok(handle) => handle,
+        Err(err) if err.raw_os_error() == Some(ERROR_ACCESS_DENIED as i32) => return Ok(()),
+        Err(err) => return Err(err),
+    };
+
+    let mut code = 0;
+
+    // Safety: the handle is valid, and `code` outlives the call. Handles
+    // remain valid after the process exits, so it has to be checked for
+    // an exit code.
+    match unsafe { GetExitCodeProcess(handle.as_raw_handle(), &mut code) } {
+        0 => Err(io::Error::last_os_error()),
+        _ if code == STILL_ACTIVE as u32 => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "the process has exited",
+        )),
+    }
+}
+
+/// The size of the working set of the process with this ID, in bytes, which
+/// is the Windows counterpart of its resident set size
+pub fn working_set(pid: u32) -> io::Result<u64> {
+    let handle = open_process(pid)?;
+
+    // Safety: the counters are plain data, which the call fills in
+    let mut counters: PROCESS_MEMORY_COUNTERS = unsafe { mem::zeroed() };
+
+    // Safety: the handle is valid, and the counters are the size that's
+    // given, and outlive the call
+    let result = unsafe {
+        K32GetProcessMemoryInfo(
+            handle.as_raw_handle(),
+            &mut counters,
+            mem::size_of_val(&counters) as u32,
+        )
+    };
+
+    match result {
+        0 => Err(io::Error::last_os_error()),
+        _ => Ok(counters.WorkingSetSize as u64),
+    }
+}
+
+/// A job object, the Windows counterpart of a process group. Every process
+/// in the job, including the ones that its processes start, is killed when
+/// the job is terminated or dropped.
+#[derive(Debug)]
+pub struct Job {
+    handle: OwnedHandle,
+}
+
+impl Job {
+    pub fn new() -> io::Result<Self> {
+        // Safety: both arguments are optional
+        let handle = unsafe { CreateJobObjectW(ptr::null(), ptr::null()) };
+
+        if handle.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Safety: the handle was just created, and nothing else owns it
+        let handle = unsafe { OwnedHandle::from_raw_handle(handle) };
+
+        // Safety: the limits are plain data, for which zero means unset
+        let mut limits: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = unsafe { mem::zeroed() };
+        limits.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+
+        // Safety: the limits are the size that's given, and outlive the call
+        let result = unsafe {
+            SetInformationJobObject(
+                handle.as_raw_handle(),
+                JobObjectExtendedLimitInformation,
+                &limits as *const JOBOBJECT_EXTENDED_LIMIT_INFORMATION as *const c_void,
+                mem::size_of_val(&limits) as u32,
+            )
+        };
+
+        match result {
+            0 => Err(io::Error::last_os_error()),
+            _ => Ok(Self { handle }),
+        }
+    }
+
+    /// Create a job containing `child`. Processes that the child started
+    /// before it was added aren't included.
+    pub fn containing(child: &Child) -> io::Result<Self> {
+        let job = Self::new()?;
+
+        // A child that's already been waited for has no handle, and nothing
+        // left to contain
+        if let Some(process) = child.raw_handle() {
+            // Safety: both handles are valid for the duration of the call
+            if unsafe { AssignProcessToJobObject(job.handle.as_raw_handle(), process) } == 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(job)
+    }
+
+    /// Kill every process in the job
+    pub fn terminate(&self) -> io::Result<()> {
+        // Safety: the handle is valid for as long as `self` is. The exit code
+        // matches the one tokio's `Child::kill` uses.
+        match unsafe { TerminateJobObject(self.handle.as_raw_handle(), 1) } {
+            0 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+}